@@ -0,0 +1,661 @@
+//! Command line configuration for the fuzzer
+
+use std::path::PathBuf;
+use fuzz_with_emus::emulator::CycleWeights;
+use fuzz_with_emus::mutator::MutatorConfig;
+use fuzz_with_emus::DEFAULT_COVERAGE_BITS;
+
+/// Usage information printed when invalid arguments are supplied
+const USAGE: &str = r#"Usage: fuzz_with_emus [options]
+
+Options:
+    --stats-format <csv|json>  Format of the samples written to stats.txt
+                               (default: csv)
+    --stdin                    Provide the fuzz input to the guest via stdin
+                               rather than through the "testfn" file
+    --fuzz-file <name>         Back the file <name> by the fuzz input rather
+                               than "testfn", may be repeated. With several
+                               files, each but the last takes a u32 length
+                               prefixed chunk of the input, the last takes
+                               the rest. --stdin serves the first file.
+    --open-fuzz-file <name>    Open the fuzz file <name> before the program
+                               starts, for harnesses which expect it open
+                               already. Files get fds from 3 in order, may
+                               be repeated
+    --argv                     Fuzz the program arguments, the fuzz input is
+                               split on nul bytes into arguments
+    --harness <pc>             Fuzz the function at <pc> rather than the
+                               whole program, calling it with a pointer to
+                               the fuzz input and its length every fuzz
+                               case, like LLVMFuzzerTestOneInput. The case
+                               ends when it returns. The snapshot is taken
+                               at the entry point.
+    --harness-args <i>[,<l>]   Pass the fuzz input in argument register a<i>
+                               and its length in a<l>, if given, to the
+                               --harness function (default: 0,1)
+    --ignore-syscall <num>     Fail syscall <num> with -ENOSYS rather than
+                               handling it, may be repeated
+    --stub-syscall <num>       Return 0 from syscall <num> rather than
+                               handling it, may be repeated
+    --fault-injection <p>      Make syscalls which can fail (eg. open, read)
+                               fail with probability <p>, from 0 to 1, to
+                               exercise error paths. Failures are derived
+                               from --seed (default: 0), such that replaying
+                               an input with the same seed reproduces them.
+    --dry-run                  Run every seed in inputs/ once before fuzzing
+                               and report the ones which crash, time out, or
+                               take no coverage edges
+    --import-corpus <dir>      Resume from a corpus exported by a previous
+                               campaign, restoring the coverage it found
+    --export-corpus <dir>      On shutdown, export the corpus along with the
+                               coverage each input found to <dir>
+    --host-dir <dir>           Directory which whitelisted host files are
+                               served from, read-only
+    --host-file <name>         Allow the guest to open the file <name> from
+                               the host directory, may be repeated
+    --timeout <cycles>         Maximum number of cycles a fuzz case may
+                               execute (default: 50000000)
+    --cycle-weight <class>=<n> Make instructions of <class> cost <n> cycles
+                               towards the timeout rather than 1, where
+                               <class> is one of load, store, branch, jump,
+                               system or alu. May be repeated.
+    --max-len <bytes>          Maximum size of a fuzz input, larger inputs
+                               are truncated (default: 0x100000)
+    --max-memory <bytes>       Size guest memory may grow up to as the guest
+                               allocates (default: 0x10000000)
+    --detect-uninit            Report reads of uninitialized heap and stack
+                               memory as crashes, at the risk of false
+                               positives (eg. reading struct padding)
+    --randomize-layout         Offset the stack and the heap by up to 1 MiB
+                               each, derived from --seed (default: random,
+                               printed at startup), to tell crashes which
+                               depend on absolute addresses (eg. NULL
+                               dereferences) apart from layout coincidences
+    --context-coverage         Mix the calling context into coverage edges,
+                               each context of an edge takes its own bit in
+                               the coverage bitmap, so it fills up and
+                               collides far sooner
+    --block-coverage           Track the blocks entered rather than the
+                               edges taken, which fills up the coverage
+                               bitmap far slower, for very large targets
+    --coverage-map-size <bits> Number of bits in the coverage bitmap, a
+                               power of two (default: 0x4000000). Raise it
+                               if many edge collisions are reported
+    --coverage-blacklist <s>-<e>
+                               Ignore the coverage of branches and jumps in
+                               the code from <s> up to <e>, eg. hash loops
+                               which keep finding meaningless edges. May be
+                               repeated.
+    --coverage-blacklist-file <file>
+                               Ignore coverage in the ranges listed in
+                               <file>, one <s>-<e> per line, # comments
+    --deliver-signals          Deliver memory faults to the SIGSEGV handler
+                               of the target if it installed one, rather
+                               than saving them as crashes, for targets
+                               which recover from faults
+    --fuzz-end <pc>            End fuzz cases cleanly once <pc> is reached,
+                               eg. right after the input is parsed, may be
+                               repeated
+    --no-stack-guard           Don't save calls to __stack_chk_fail as
+                               crashes, by default they are if the target
+                               has symbols for it
+    --crash-alloc-offset       Tell crashes in heap allocations apart by the
+                               offset into the allocation they happened at,
+                               such that distinct heap overflows at the same
+                               PC are saved separately
+    --no-deterministic         Skip the deterministic stage, which walks
+                               every new corpus input with bit flips, byte
+                               flips, arithmetic and interesting values
+                               before moving on to random mutations
+    --mutator-weight <s>=<n>   Pick the mutation strategy <s> with relative
+                               weight <n>, where <s> is one of havoc (6),
+                               duplicate (2), splice (1) or crash (1), given
+                               with their default weights. crash splices in
+                               inputs which found crashes. A weight of 0
+                               disables the strategy. May be repeated.
+    --havoc-depth <n>          Randomize up to <n> bytes per havoc mutation
+                               (default: 128)
+    --adaptive-depth           Scale the havoc depth by how long ago fuzz
+                               cases derived from the input found coverage,
+                               mutating inputs which just did more gently
+    --no-jit                   Use the interpreter rather than the JIT
+    --verify                   Run every fuzz case through both the JIT and
+                               the interpreter and stop if they disagree
+    --clear-jit-cache          Delete the JIT code cached by previous runs
+    --jit-block-size <instrs>  Maximum number of instructions lifted into
+                               one JIT compile unit (default: 1000)
+    --jit-compiler <cxx>       C++ compiler used to compile JIT code
+                               (default: clang++)
+    --jit-flag <flag>          Extra flag passed to the JIT compiler after
+                               the default ones, may be repeated
+    --jit-ldscript <file>      Linker script JIT code is linked with
+                               (default: ldscript.ld)
+    --jit-max-size <bytes>     Evict the least recently used JIT code once
+                               more than <bytes> of it are loaded
+    --jit-cache-max-size <bytes>
+                               Delete the oldest files in the on-disk JIT
+                               cache once it exceeds <bytes>
+    --dump-jit <pc>            Rather than fuzzing, lift the code at <pc>
+                               from the snapshot and write the C++ for the
+                               JIT to jit_<pc>.cpp without compiling it
+    --gdb <port>               Rather than fuzzing, wait for GDB to attach on
+                               127.0.0.1:<port> and debug a single fuzz case
+    --gdb-input <file>         Fuzz input to use for the case debugged with
+                               --gdb (default: empty)
+    --replay <file>            Rather than fuzzing, run a single fuzz case with
+                               <file> as the input and report how it exited
+    --branch-trace <n>         Record the last <n> branches taken during the
+                               --replay fuzz case and report them, too
+    --threads <n>              Number of worker threads (default: 192)
+    --batch-cases <n>          Have workers report their statistics every <n>
+                               fuzz cases rather than every 500M TSC ticks,
+                               such that the work per batch does not depend
+                               on the clock speed
+    --seed <n>                 Seed the random number generators, with
+                               --threads 1 the same inputs are then produced
+                               in the same order on every run
+"#;
+
+/// Format of the samples written to the statistics log
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatsFormat {
+    /// `elapsed,fuzz_cases,coverage,crashes,inputs` lines
+    Csv,
+
+    /// One JSON object per line
+    Json,
+}
+
+/// Fuzzer configuration
+#[derive(Debug)]
+pub struct Config {
+    /// Format of the statistics log
+    pub stats_format: StatsFormat,
+
+    /// Serve the fuzz input on stdin
+    pub stdin_input: bool,
+
+    /// Derive the program arguments from the fuzz input
+    pub argv_input: bool,
+
+    /// Function fuzzed rather than the whole program, if any
+    pub harness: Option<u64>,
+
+    /// Argument registers the fuzz input and its length are passed in to the
+    /// harness function
+    pub harness_args: Option<(usize, Option<usize>)>,
+
+    /// Names of the files backed by the fuzz input, if not the default
+    pub fuzz_files: Vec<String>,
+
+    /// Names of the fuzz files to open before the program starts
+    pub open_fuzz_files: Vec<String>,
+
+    /// Syscalls which fail with `-ENOSYS` rather than being handled
+    pub ignore_syscalls: Vec<u64>,
+
+    /// Syscalls which return 0 rather than being handled
+    pub stub_syscalls: Vec<u64>,
+
+    /// Probability with which syscalls which can fail are made to fail
+    pub fault_injection: Option<f64>,
+
+    /// Run every seed once before fuzzing, reporting the broken ones
+    pub dry_run: bool,
+
+    /// Exported corpus to resume from
+    pub import_corpus: Option<PathBuf>,
+
+    /// Directory to export the corpus to on shutdown
+    pub export_corpus: Option<PathBuf>,
+
+    /// Directory the guest-openable host files live in
+    pub host_dir: Option<PathBuf>,
+
+    /// Names of the files in `host_dir` the guest may open
+    pub host_files: Vec<String>,
+
+    /// Cycle timeout per fuzz case, if not the default
+    pub timeout: Option<u64>,
+
+    /// Cycles each class of instruction costs towards the timeout
+    pub cycle_weights: CycleWeights,
+
+    /// Maximum size of a fuzz input, if not the default
+    pub max_len: Option<usize>,
+
+    /// Maximum size of guest memory, if not the default
+    pub max_memory: Option<usize>,
+
+    /// Report reads of uninitialized memory as crashes
+    pub detect_uninit: bool,
+
+    /// Offset the stack and the heap by amounts derived from the seed
+    pub randomize_layout: bool,
+
+    /// Make coverage sensitive to the calling context
+    pub context_coverage: bool,
+
+    /// Track blocks entered rather than edges taken
+    pub block_coverage: bool,
+
+    /// Number of bits in the coverage bitmap
+    pub coverage_bits: usize,
+
+    /// Ranges of code, from start up to end, which do not report coverage
+    pub coverage_blacklist: Vec<(u64, u64)>,
+
+    /// Deliver memory faults to the guest's `SIGSEGV` handler
+    pub signal_delivery: bool,
+
+    /// Don't report calls to `__stack_chk_fail()` as crashes
+    pub no_stack_guard: bool,
+
+    /// PCs which end fuzz cases
+    pub fuzz_ends: Vec<u64>,
+
+    /// Include the offset into the faulting allocation in the crash key
+    pub crash_alc_offset: bool,
+
+    /// Skip the deterministic mutation stage
+    pub no_deterministic: bool,
+
+    /// Weights of the mutation strategies and the havoc depth
+    pub mutator: MutatorConfig,
+
+    /// Use the interpreter even if the JIT is available
+    pub no_jit: bool,
+
+    /// Check the JIT against the interpreter on every fuzz case
+    pub verify: bool,
+
+    /// Delete the on-disk JIT cache before starting
+    pub clear_jit_cache: bool,
+
+    /// Maximum number of instructions per JIT compile unit, if not the
+    /// default
+    pub jit_block_size: Option<usize>,
+
+    /// C++ compiler for JIT code, if not the default
+    pub jit_compiler: Option<String>,
+
+    /// Extra flags for the JIT compiler
+    pub jit_flags: Vec<String>,
+
+    /// Linker script for JIT code, if not the default
+    pub jit_ldscript: Option<PathBuf>,
+
+    /// Bytes of JIT code loaded before the least recently used is evicted
+    pub jit_max_size: Option<usize>,
+
+    /// Bytes the on-disk JIT cache is pruned down to
+    pub jit_cache_max_size: Option<u64>,
+
+    /// PC to write the JIT source for, rather than fuzzing
+    pub dump_jit: Option<u64>,
+
+    /// Port to serve a GDB stub on, rather than fuzzing
+    pub gdb: Option<u16>,
+
+    /// Fuzz input for the case debugged with GDB
+    pub gdb_input: Option<PathBuf>,
+
+    /// Input to replay a single fuzz case with, rather than fuzzing
+    pub replay: Option<PathBuf>,
+
+    /// Number of branches recorded in the replayed fuzz case, if any
+    pub branch_trace: Option<usize>,
+
+    /// Number of worker threads
+    pub threads: usize,
+
+    /// Number of fuzz cases in a batch of a worker, if batches are not
+    /// bounded by time
+    pub batch_cases: Option<u64>,
+
+    /// Seed for the random number generators, if they should be
+    /// deterministic
+    pub seed: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            stats_format: StatsFormat::Csv,
+            stdin_input:  false,
+            argv_input:   false,
+            harness:      None,
+            harness_args: None,
+            fuzz_files:   Vec::new(),
+            open_fuzz_files: Vec::new(),
+            ignore_syscalls: Vec::new(),
+            stub_syscalls: Vec::new(),
+            fault_injection: None,
+            dry_run:      false,
+            import_corpus: None,
+            export_corpus: None,
+            host_dir:     None,
+            host_files:   Vec::new(),
+            timeout:      None,
+            cycle_weights: CycleWeights::default(),
+            max_len:      None,
+            max_memory:   None,
+            detect_uninit: false,
+            randomize_layout: false,
+            context_coverage: false,
+            block_coverage: false,
+            coverage_bits: DEFAULT_COVERAGE_BITS,
+            coverage_blacklist: Vec::new(),
+            signal_delivery: false,
+            no_stack_guard: false,
+            fuzz_ends:    Vec::new(),
+            crash_alc_offset: false,
+            no_deterministic: false,
+            mutator:      MutatorConfig::default(),
+            no_jit:       false,
+            verify:       false,
+            clear_jit_cache: false,
+            jit_block_size: None,
+            jit_compiler: None,
+            jit_flags:    Vec::new(),
+            jit_ldscript: None,
+            jit_max_size: None,
+            jit_cache_max_size: None,
+            dump_jit:     None,
+            gdb:          None,
+            gdb_input:    None,
+            replay:       None,
+            branch_trace: None,
+            threads:      192,
+            batch_cases:  None,
+            seed:         None,
+        }
+    }
+}
+
+impl Config {
+    /// Parse the configuration from the command line arguments of this
+    /// process. On failure, returns an error message including the usage.
+    pub fn from_args() -> Result<Self, String> {
+        Self::parse(std::env::args().skip(1))
+            .map_err(|err| format!("{}\n\n{}", err, USAGE))
+    }
+
+    /// Parse the configuration from `args`, not including the program name
+    fn parse<I: Iterator<Item = String>>(mut args: I)
+            -> Result<Self, String> {
+        let mut config = Config::default();
+
+        while let Some(arg) = args.next() {
+            // Get the value for an option which requires one
+            let mut value = || {
+                args.next().ok_or_else(|| format!("{} requires a value", arg))
+            };
+
+            match arg.as_str() {
+                "--stats-format" => {
+                    config.stats_format = match value()?.as_str() {
+                        "csv"  => StatsFormat::Csv,
+                        "json" => StatsFormat::Json,
+                        other  => return Err(
+                            format!("Unknown stats format {:?}", other)),
+                    };
+                }
+                "--stdin" => config.stdin_input = true,
+                "--argv" => config.argv_input = true,
+                "--fuzz-file" => config.fuzz_files.push(value()?),
+                "--open-fuzz-file" => config.open_fuzz_files.push(value()?),
+                "--harness" => config.harness = Some(parse_int(&value()?)?),
+                "--harness-args" => {
+                    config.harness_args = Some(parse_harness_args(&value()?)?);
+                }
+                "--ignore-syscall" => {
+                    config.ignore_syscalls.push(parse_int(&value()?)?);
+                }
+                "--stub-syscall" => {
+                    config.stub_syscalls.push(parse_int(&value()?)?);
+                }
+                "--fault-injection" => {
+                    let value = value()?;
+                    let probability: f64 = value.parse().map_err(|_| {
+                        format!("Invalid probability {:?}", value)
+                    })?;
+                    if !(0. ..=1.).contains(&probability) {
+                        return Err(
+                            "--fault-injection must be from 0 to 1".into());
+                    }
+                    config.fault_injection = Some(probability);
+                }
+                "--dry-run" => config.dry_run = true,
+                "--import-corpus" => {
+                    config.import_corpus = Some(value()?.into());
+                }
+                "--export-corpus" => {
+                    config.export_corpus = Some(value()?.into());
+                }
+                "--host-dir" => config.host_dir = Some(value()?.into()),
+                "--host-file" => config.host_files.push(value()?),
+                "--timeout" => config.timeout = Some(parse_int(&value()?)?),
+                "--cycle-weight" => {
+                    let value = value()?;
+                    let mut split = value.splitn(2, '=');
+                    let class = split.next().unwrap();
+                    let weight = parse_int(split.next().ok_or_else(|| {
+                        format!("Invalid cycle weight {:?}", value)
+                    })?)?;
+                    if weight == 0 {
+                        return Err("--cycle-weight must be non-zero".into());
+                    }
+                    *config.cycle_weights.class_mut(class).ok_or_else(|| {
+                        format!("Unknown instruction class {:?}", class)
+                    })? = weight;
+                }
+                "--mutator-weight" => {
+                    let value = value()?;
+                    let mut split = value.splitn(2, '=');
+                    let strategy = split.next().unwrap();
+                    let weight = parse_int(split.next().ok_or_else(|| {
+                        format!("Invalid mutator weight {:?}", value)
+                    })?)?;
+                    *config.mutator.strategy_mut(strategy).ok_or_else(|| {
+                        format!("Unknown mutation strategy {:?}", strategy)
+                    })? = weight;
+                }
+                "--havoc-depth" => {
+                    let depth = parse_int(&value()?)? as usize;
+                    if depth == 0 {
+                        return Err("--havoc-depth must be non-zero".into());
+                    }
+                    config.mutator.havoc_depth = depth;
+                }
+                "--adaptive-depth" => config.mutator.adaptive_depth = true,
+                "--max-len" => {
+                    let len = parse_int(&value()?)? as usize;
+                    if len == 0 {
+                        return Err("--max-len must be non-zero".into());
+                    }
+                    config.max_len = Some(len);
+                }
+                "--max-memory" => {
+                    config.max_memory = Some(parse_int(&value()?)? as usize);
+                }
+                "--detect-uninit" => config.detect_uninit = true,
+                "--randomize-layout" => config.randomize_layout = true,
+                "--context-coverage" => config.context_coverage = true,
+                "--block-coverage" => config.block_coverage = true,
+                "--coverage-map-size" => {
+                    let bits = parse_int(&value()?)? as usize;
+                    if bits.count_ones() != 1 || bits < 64 {
+                        return Err("--coverage-map-size must be a power of \
+                                    two of at least 64".into());
+                    }
+                    config.coverage_bits = bits;
+                }
+                "--coverage-blacklist" => {
+                    config.coverage_blacklist.push(parse_range(&value()?)?);
+                }
+                "--coverage-blacklist-file" => {
+                    let filename = value()?;
+                    let contents = std::fs::read_to_string(&filename)
+                        .map_err(|err| {
+                            format!("Failed to read {:?}: {}", filename, err)
+                        })?;
+
+                    // One range per line, ignoring blank lines and comments
+                    for line in contents.lines() {
+                        let line = line.splitn(2, '#').next().unwrap().trim();
+                        if !line.is_empty() {
+                            config.coverage_blacklist.push(parse_range(line)?);
+                        }
+                    }
+                }
+                "--deliver-signals" => config.signal_delivery = true,
+                "--no-stack-guard" => config.no_stack_guard = true,
+                "--fuzz-end" => config.fuzz_ends.push(parse_int(&value()?)?),
+                "--crash-alloc-offset" => config.crash_alc_offset = true,
+                "--no-deterministic" => config.no_deterministic = true,
+                "--no-jit" => config.no_jit = true,
+                "--verify" => config.verify = true,
+                "--clear-jit-cache" => config.clear_jit_cache = true,
+                "--jit-block-size" => {
+                    let instrs = parse_int(&value()?)? as usize;
+                    if instrs == 0 {
+                        return Err("--jit-block-size must be non-zero".into());
+                    }
+                    config.jit_block_size = Some(instrs);
+                }
+                "--jit-compiler" => config.jit_compiler = Some(value()?),
+                "--jit-flag" => config.jit_flags.push(value()?),
+                "--jit-ldscript" => config.jit_ldscript = Some(value()?.into()),
+                "--jit-max-size" => {
+                    config.jit_max_size = Some(parse_int(&value()?)? as usize);
+                }
+                "--jit-cache-max-size" => {
+                    config.jit_cache_max_size = Some(parse_int(&value()?)?);
+                }
+                "--dump-jit" => config.dump_jit = Some(parse_int(&value()?)?),
+                "--gdb" => {
+                    let port = value()?;
+                    config.gdb = Some(port.parse()
+                        .map_err(|_| format!("Invalid port {:?}", port))?);
+                }
+                "--gdb-input" => config.gdb_input = Some(value()?.into()),
+                "--replay" => config.replay = Some(value()?.into()),
+                "--branch-trace" => {
+                    let edges = parse_int(&value()?)? as usize;
+                    if edges == 0 {
+                        return Err("--branch-trace must be non-zero".into());
+                    }
+                    config.branch_trace = Some(edges);
+                }
+                "--threads" => {
+                    config.threads = parse_int(&value()?)? as usize;
+                    if config.threads == 0 {
+                        return Err("--threads must be non-zero".into());
+                    }
+                }
+                "--batch-cases" => {
+                    let cases = parse_int(&value()?)?;
+                    if cases == 0 {
+                        return Err("--batch-cases must be non-zero".into());
+                    }
+                    config.batch_cases = Some(cases);
+                }
+                "--seed" => config.seed = Some(parse_int(&value()?)?),
+                _ => return Err(format!("Unknown argument {:?}", arg)),
+            }
+        }
+
+        if !config.host_files.is_empty() && config.host_dir.is_none() {
+            return Err("--host-file requires --host-dir".into());
+        }
+
+        if config.argv_input && config.stdin_input {
+            return Err("--argv and --stdin are mutually exclusive".into());
+        }
+
+        if config.harness_args.is_some() && config.harness.is_none() {
+            return Err("--harness-args requires --harness".into());
+        }
+
+        if config.harness.is_some() &&
+                (config.argv_input || config.stdin_input) {
+            return Err("--harness is mutually exclusive with --argv and \
+                        --stdin".into());
+        }
+
+        if config.gdb_input.is_some() && config.gdb.is_none() {
+            return Err("--gdb-input requires --gdb".into());
+        }
+
+        if config.verify && config.no_jit {
+            return Err("--verify and --no-jit are mutually exclusive".into());
+        }
+
+        if config.dump_jit.is_some() && config.no_jit {
+            return Err("--dump-jit and --no-jit are mutually exclusive".into());
+        }
+
+        if config.mutator.total_weight() == 0 {
+            return Err("--mutator-weight disabled every strategy".into());
+        }
+
+        if config.replay.is_some() && config.gdb.is_some() {
+            return Err("--replay and --gdb are mutually exclusive".into());
+        }
+
+        if config.branch_trace.is_some() && config.replay.is_none() {
+            return Err("--branch-trace requires --replay".into());
+        }
+
+        for name in &config.open_fuzz_files {
+            let known = if config.fuzz_files.is_empty() {
+                name == "testfn"
+            } else {
+                config.fuzz_files.contains(name)
+            };
+            if !known {
+                return Err(format!("--open-fuzz-file {:?} is not a fuzz \
+                                    file", name));
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// Parse a `<start>-<end>` range of addresses, the end being exclusive
+fn parse_range(value: &str) -> Result<(u64, u64), String> {
+    let mut split = value.splitn(2, '-');
+    let start = parse_int(split.next().unwrap().trim())?;
+    let end = parse_int(split.next().ok_or_else(|| {
+        format!("Invalid range {:?}", value)
+    })?.trim())?;
+    if start >= end {
+        return Err(format!("Empty range {:?}", value));
+    }
+    Ok((start, end))
+}
+
+/// Parse the `<input>[,<len>]` argument registers of the harness function
+fn parse_harness_args(value: &str)
+        -> Result<(usize, Option<usize>), String> {
+    let mut split = value.splitn(2, ',');
+    let input = parse_int(split.next().unwrap().trim())? as usize;
+    let len = split.next()
+        .map(|len| parse_int(len.trim()).map(|len| len as usize))
+        .transpose()?;
+    if input >= 8 || len.map_or(false, |len| len >= 8 || len == input) {
+        return Err(format!("Invalid harness arguments {:?}", value));
+    }
+    Ok((input, len))
+}
+
+/// Parse an integer argument, which may be in hex if prefixed with `0x`
+fn parse_int(value: &str) -> Result<u64, String> {
+    if value.starts_with("0x") {
+        u64::from_str_radix(&value[2..], 16)
+    } else {
+        value.parse()
+    }.map_err(|_| format!("Invalid integer {:?}", value))
+}
@@ -0,0 +1,212 @@
+//! Minimal ELF64 parsing, used to symbolize guest addresses and to describe
+//! the program to the guest in the aux vector
+
+use std::path::Path;
+use std::convert::TryInto;
+use crate::mmu::VirtAddr;
+
+/// Section header type for a symbol table
+const SHT_SYMTAB: u32 = 2;
+
+/// Program header types of loadable segments and the program header table
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+
+/// Symbol types which are worth symbolizing addresses with
+const STT_OBJECT: u8 = 1;
+const STT_FUNC:   u8 = 2;
+
+/// Read a little-endian `u16` from `bytes` at `off`
+fn read_u16(bytes: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(off..off.checked_add(2)?)?
+                            .try_into().ok()?))
+}
+
+/// Read a little-endian `u32` from `bytes` at `off`
+fn read_u32(bytes: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(off..off.checked_add(4)?)?
+                            .try_into().ok()?))
+}
+
+/// Read a little-endian `u64` from `bytes` at `off`
+fn read_u64(bytes: &[u8], off: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(off..off.checked_add(8)?)?
+                            .try_into().ok()?))
+}
+
+/// Where the program headers of a loaded ELF are in guest memory and its
+/// entry point, as passed to the guest in the aux vector
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ElfInfo {
+    /// Program entry point
+    pub entry: VirtAddr,
+
+    /// Address of the program header table in guest memory
+    pub phdr: VirtAddr,
+
+    /// Size of a program header
+    pub phent: usize,
+
+    /// Number of program headers
+    pub phnum: usize,
+}
+
+impl ElfInfo {
+    /// Parse the ELF header and program headers of the ELF in `contents`.
+    /// Returns `None` if this is not a little-endian ELF64 or the program
+    /// header table is not part of a loaded segment.
+    pub fn parse(contents: &[u8]) -> Option<Self> {
+        // Check the magic, 64-bit class, and little-endian data encoding
+        if contents.get(0..6)? != b"\x7fELF\x02\x01" {
+            return None;
+        }
+
+        let entry = read_u64(contents, 0x18)? as usize;
+        let phoff = read_u64(contents, 0x20)? as usize;
+        let phent = read_u16(contents, 0x36)? as usize;
+        let phnum = read_u16(contents, 0x38)? as usize;
+
+        // Get the (type, offset, vaddr, file size) of the segment at `idx`
+        let segment = |idx: usize| -> Option<(u32, usize, usize, usize)> {
+            let hdr = phoff.checked_add(idx.checked_mul(phent)?)?;
+            Some((
+                read_u32(contents, hdr)?,
+                read_u64(contents, hdr.checked_add(0x08)?)? as usize,
+                read_u64(contents, hdr.checked_add(0x10)?)? as usize,
+                read_u64(contents, hdr.checked_add(0x20)?)? as usize,
+            ))
+        };
+
+        // Use the program header table segment if there is one, otherwise
+        // find the loaded segment which contains the table
+        let mut phdr = None;
+        for idx in 0..phnum {
+            let (typ, offset, vaddr, size) = segment(idx)?;
+            if typ == PT_PHDR {
+                phdr = Some(vaddr);
+                break;
+            }
+            if typ == PT_LOAD && phdr.is_none() &&
+                    phoff >= offset && phoff - offset < size {
+                phdr = Some(vaddr.checked_add(phoff - offset)?);
+            }
+        }
+
+        Some(ElfInfo {
+            entry: VirtAddr(entry),
+            phdr:  VirtAddr(phdr?),
+            phent,
+            phnum,
+        })
+    }
+}
+
+/// The function and object symbols of an ELF, sorted by address
+#[derive(Default, Debug)]
+pub struct Symbols(Vec<(VirtAddr, String)>);
+
+impl Symbols {
+    /// Create symbols from `(address, name)` pairs, in any order
+    pub fn new(mut symbols: Vec<(VirtAddr, String)>) -> Self {
+        symbols.sort();
+        Symbols(symbols)
+    }
+
+    /// Load the symbols from the `.symtab` of the ELF `filename`
+    pub fn load<P: AsRef<Path>>(filename: P) -> Option<Self> {
+        Self::parse(&std::fs::read(filename).ok()?)
+    }
+
+    /// Parse the symbols from the `.symtab` of the ELF in `contents`. Returns
+    /// `None` if this is not a little-endian ELF64 or it has no symbol table.
+    pub fn parse(contents: &[u8]) -> Option<Self> {
+        // Check the magic, 64-bit class, and little-endian data encoding
+        if contents.get(0..6)? != b"\x7fELF\x02\x01" {
+            return None;
+        }
+
+        let shoff     = read_u64(contents, 0x28)? as usize;
+        let shentsize = read_u16(contents, 0x3a)? as usize;
+        let shnum     = read_u16(contents, 0x3c)? as usize;
+
+        // Get the (offset, size, link, entsize) of the section at `idx`
+        let section = |idx: usize| -> Option<(usize, usize, usize, usize)> {
+            let hdr = shoff.checked_add(idx.checked_mul(shentsize)?)?;
+            Some((
+                read_u64(contents, hdr.checked_add(0x18)?)? as usize,
+                read_u64(contents, hdr.checked_add(0x20)?)? as usize,
+                read_u32(contents, hdr.checked_add(0x28)?)? as usize,
+                read_u64(contents, hdr.checked_add(0x38)?)? as usize,
+            ))
+        };
+
+        // Get the type of the section at `idx`
+        let section_type = |idx: usize| -> Option<u32> {
+            let hdr = shoff.checked_add(idx.checked_mul(shentsize)?)?;
+            read_u32(contents, hdr.checked_add(4)?)
+        };
+
+        // Find the symbol table, its link is the associated string table
+        let symtab = (0..shnum)
+            .find(|&idx| section_type(idx) == Some(SHT_SYMTAB))?;
+        let (symoff, symsize, strtab, symentsize) = section(symtab)?;
+        let (stroff, strsize, _, _) = section(strtab)?;
+        let strings = contents.get(stroff..stroff.checked_add(strsize)?)?;
+
+        let mut symbols = Vec::new();
+        for sym in (symoff..symoff.checked_add(symsize)?)
+                .step_by(std::cmp::max(symentsize, 1)) {
+            let name  = read_u32(contents, sym)? as usize;
+            let info  = *contents.get(sym.checked_add(4)?)?;
+            let shndx = read_u16(contents, sym.checked_add(6)?)?;
+            let value = read_u64(contents, sym.checked_add(8)?)?;
+
+            // Skip undefined symbols and anything which isn't code or data
+            let typ = info & 0xf;
+            if shndx == 0 || (typ != STT_FUNC && typ != STT_OBJECT) {
+                continue;
+            }
+
+            // Get the nul-terminated name from the string table
+            let name = strings.get(name..)?;
+            let name = &name[..name.iter().position(|&x| x == 0)?];
+            if name.is_empty() {
+                continue;
+            }
+
+            symbols.push((VirtAddr(value as usize),
+                          String::from_utf8_lossy(name).into_owned()));
+        }
+
+        symbols.sort();
+        Some(Symbols(symbols))
+    }
+
+    /// Get the address of the symbol `name`
+    pub fn lookup(&self, name: &str) -> Option<VirtAddr> {
+        self.0.iter().find(|(_, sym)| sym == name).map(|(addr, _)| *addr)
+    }
+
+    /// Get the symbol containing `addr` and the offset into it
+    pub fn resolve(&self, addr: VirtAddr) -> Option<(&str, usize)> {
+        // Find the last symbol at or below `addr`
+        let idx = match self.0.binary_search_by_key(&addr, |(addr, _)| *addr) {
+            Ok(idx)  => idx,
+            Err(0)   => return None,
+            Err(idx) => idx - 1,
+        };
+
+        let (base, name) = &self.0[idx];
+        Some((name, addr.0 - base.0))
+    }
+
+    /// Symbolize `addr` as `function+offset`, or as a raw address if there
+    /// is no symbol for it
+    pub fn symbolize(&self, addr: VirtAddr) -> String {
+        match self.resolve(addr) {
+            Some((name, 0))   => name.into(),
+            Some((name, off)) => format!("{}+{:#x}", name, off),
+            None              => format!("{:#x}", addr.0),
+        }
+    }
+}
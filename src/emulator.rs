@@ -1,23 +1,42 @@
 //! A 64-bit RISC-V RV64i interpreter
 
 use std::fmt;
+use std::io;
 use std::mem::size_of_val;
 use std::sync::Arc;
-use std::path::Path;
-use std::time::Duration;
-use std::process::Command;
+use std::sync::atomic::Ordering;
+use std::path::{Path, PathBuf};
 use std::collections::{BTreeMap, BTreeSet, VecDeque};
-use crate::rdtsc;
-use crate::Corpus;
+use crate::{rdtsc, Rng};
+use crate::{Corpus, Input, COVERAGE_SHARD_WORDS};
 use crate::mmu::{VirtAddr, Perm, PERM_READ, PERM_WRITE, PERM_EXEC, PERM_RAW};
-use crate::mmu::{Mmu, DIRTY_BLOCK_SIZE};
-use crate::jitcache::JitCache;
+use crate::mmu::{Mmu, Section, DIRTY_BLOCK_SIZE};
+use crate::jitcache::{self, JitCache, JitWorker};
+use crate::elf::{Symbols, ElfInfo};
+use crate::syscall::{SyscallHandler, SyscallAction, DefaultSyscallHandler};
+use falkhash::FalkHasher;
 
 /// If set, all register state will be saved before the execution of every
 /// instruction.
 /// This is INCREDIBLY slow and should only be used for debugging
 const ENABLE_TRACING: bool = false;
 
+/// Maximum number of instructions interpreted in one go while waiting on JIT
+/// code to compile
+const JIT_FALLBACK_INSTRS: u64 = 10_000;
+
+/// Maximum number of fuzz cases run to trim an input which found new
+/// coverage before it is saved
+const TRIM_MAX_EXECS: usize = 256;
+
+/// Maximum number of cycles spent trimming an input, as a multiple of the
+/// cycles of the fuzz case which found the new coverage
+const TRIM_MAX_CYCLES_RATIO: u64 = 32;
+
+/// Smallest chunk size removed from an input while trimming it, as a
+/// fraction of the input size
+const TRIM_MIN_CHUNK_DIV: usize = 64;
+
 /// Make sure this stays in sync with the C++ JIT version of this structure
 #[repr(C)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -34,6 +53,14 @@ pub enum ExitReason {
     Coverage,
 }
 
+/// Number of return addresses held by the shadow call stack. Deeper calls
+/// wrap around, overwriting the outermost frames. Must be a power of two
+/// and stay in sync with the C++ JIT version of `GuestState`.
+pub const CALL_STACK_SIZE: usize = 64;
+
+/// Size the guest memory of an `EmulatorBuilder` may grow up to by default
+pub const DEFAULT_MAX_MEMORY_SIZE: usize = 256 * 1024 * 1024;
+
 /// Make sure this stays in sync with the C++ JIT version of this structure
 #[repr(C)]
 #[derive(Clone, Copy)]
@@ -44,6 +71,7 @@ struct GuestState {
     cov_to:        u64,
     regs:          [u64; 33],
     memory:        usize,
+    memory_len:    usize,
     permissions:   usize,
     dirty:         usize,
     dirty_idx:     usize,
@@ -53,7 +81,11 @@ struct GuestState {
     trace_len:     usize,
     cov_bitmap:    usize,
     instrs_execed: u64,
+    cycles_execed: u64,
     timeout:       u64,
+    call_stack:    [u64; CALL_STACK_SIZE],
+    call_depth:    u64,
+    call_hash:     u64,
 }
 
 impl Default for GuestState {
@@ -65,6 +97,7 @@ impl Default for GuestState {
             cov_to:        0,
             regs:          [0; 33],
             memory:        0,
+            memory_len:    0,
             permissions:   0,
             dirty:         0,
             dirty_idx:     0,
@@ -74,7 +107,11 @@ impl Default for GuestState {
             trace_len:     0,
             cov_bitmap:    0,
             instrs_execed: 0,
+            cycles_execed: 0,
             timeout:       50_000_000,
+            call_stack:    [0; CALL_STACK_SIZE],
+            call_depth:    0,
+            call_hash:     0,
         }
     }
 }
@@ -216,15 +253,239 @@ impl From<u32> for Utype {
     }
 }
 
+/// Disassemble a 32-bit RV64i instruction into its mnemonic and operands.
+/// Branch and jump targets are displayed as offsets from the instruction.
+pub fn disasm(inst: u32) -> String {
+    // Lower-case ABI name for a register
+    let r = |reg: Register| format!("{:?}", reg).to_lowercase();
+
+    let unknown = format!(".word {:#010x}", inst);
+
+    // Extract the opcode from the instruction
+    let opcode = inst & 0b1111111;
+
+    match opcode {
+        0b0110111 | 0b0010111 => {
+            let inst = Utype::from(inst);
+            let mnemonic = if opcode == 0b0110111 { "lui" } else { "auipc" };
+            format!("{} {}, {:#x}", mnemonic, r(inst.rd),
+                    (inst.imm as u32) >> 12)
+        }
+        0b1101111 => {
+            let inst = Jtype::from(inst);
+            format!("jal {}, {}", r(inst.rd), inst.imm)
+        }
+        0b1100111 => {
+            let inst = Itype::from(inst);
+            match inst.funct3 {
+                0b000 => format!("jalr {}, {}({})",
+                                 r(inst.rd), inst.imm, r(inst.rs1)),
+                _ => unknown,
+            }
+        }
+        0b1100011 => {
+            let inst = Btype::from(inst);
+            let mnemonic = match inst.funct3 {
+                0b000 => "beq",
+                0b001 => "bne",
+                0b100 => "blt",
+                0b101 => "bge",
+                0b110 => "bltu",
+                0b111 => "bgeu",
+                _ => return unknown,
+            };
+            format!("{} {}, {}, {}", mnemonic, r(inst.rs1), r(inst.rs2),
+                    inst.imm)
+        }
+        0b0000011 => {
+            let inst = Itype::from(inst);
+            let mnemonic = match inst.funct3 {
+                0b000 => "lb",
+                0b001 => "lh",
+                0b010 => "lw",
+                0b011 => "ld",
+                0b100 => "lbu",
+                0b101 => "lhu",
+                0b110 => "lwu",
+                _ => return unknown,
+            };
+            format!("{} {}, {}({})", mnemonic, r(inst.rd), inst.imm,
+                    r(inst.rs1))
+        }
+        0b0100011 => {
+            let inst = Stype::from(inst);
+            let mnemonic = match inst.funct3 {
+                0b000 => "sb",
+                0b001 => "sh",
+                0b010 => "sw",
+                0b011 => "sd",
+                _ => return unknown,
+            };
+            format!("{} {}, {}({})", mnemonic, r(inst.rs2), inst.imm,
+                    r(inst.rs1))
+        }
+        0b0010011 | 0b0011011 => {
+            let inst = Itype::from(inst);
+            let word = opcode == 0b0011011;
+
+            // Shift amounts are 6 bits for 64-bit shifts, 5 bits for words
+            let (mode, shamt) = if word {
+                ((inst.imm >> 5) & 0b1111111, inst.imm & 0b11111)
+            } else {
+                ((inst.imm >> 6) & 0b111111, inst.imm & 0b111111)
+            };
+
+            let (mnemonic, imm) = match (inst.funct3, mode, word) {
+                (0b000, _, false) => ("addi",  inst.imm),
+                (0b010, _, false) => ("slti",  inst.imm),
+                (0b011, _, false) => ("sltiu", inst.imm),
+                (0b100, _, false) => ("xori",  inst.imm),
+                (0b110, _, false) => ("ori",   inst.imm),
+                (0b111, _, false) => ("andi",  inst.imm),
+                (0b001, 0b000000, false) => ("slli", shamt),
+                (0b101, 0b000000, false) => ("srli", shamt),
+                (0b101, 0b010000, false) => ("srai", shamt),
+                (0b000, _, true) => ("addiw", inst.imm),
+                (0b001, 0b0000000, true) => ("slliw", shamt),
+                (0b101, 0b0000000, true) => ("srliw", shamt),
+                (0b101, 0b0100000, true) => ("sraiw", shamt),
+                _ => return unknown,
+            };
+            format!("{} {}, {}, {}", mnemonic, r(inst.rd), r(inst.rs1), imm)
+        }
+        0b0110011 | 0b0111011 => {
+            let inst = Rtype::from(inst);
+            let mnemonic = match (inst.funct7, inst.funct3,
+                                  opcode == 0b0111011) {
+                (0b0000000, 0b000, false) => "add",
+                (0b0100000, 0b000, false) => "sub",
+                (0b0000000, 0b001, false) => "sll",
+                (0b0000000, 0b010, false) => "slt",
+                (0b0000000, 0b011, false) => "sltu",
+                (0b0000000, 0b100, false) => "xor",
+                (0b0000000, 0b101, false) => "srl",
+                (0b0100000, 0b101, false) => "sra",
+                (0b0000000, 0b110, false) => "or",
+                (0b0000000, 0b111, false) => "and",
+                (0b0000000, 0b000, true)  => "addw",
+                (0b0100000, 0b000, true)  => "subw",
+                (0b0000000, 0b001, true)  => "sllw",
+                (0b0000000, 0b101, true)  => "srlw",
+                (0b0100000, 0b101, true)  => "sraw",
+                _ => return unknown,
+            };
+            format!("{} {}, {}, {}", mnemonic, r(inst.rd), r(inst.rs1),
+                    r(inst.rs2))
+        }
+        0b0001111 => {
+            let inst = Itype::from(inst);
+            match inst.funct3 {
+                0b000 => "fence".into(),
+                0b001 => "fence.i".into(),
+                _ => unknown,
+            }
+        }
+        0b1110011 => {
+            if inst == 0b00000000000000000000000001110011 {
+                "ecall".into()
+            } else if inst == 0b00000000000100000000000001110011 {
+                "ebreak".into()
+            } else {
+                unknown
+            }
+        }
+        _ => unknown,
+    }
+}
+
 /// An open file
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum EmuFile {
     Stdin,
     Stdout,
     Stderr,
 
-    // A file which is backed by the current fuzz input
-    FuzzInput { cursor: usize },
+    // A file which is backed by fuzz file `index` of the current fuzz input
+    FuzzInput { index: usize, cursor: usize },
+
+    // A read-only file which is backed by the contents of a real host file
+    HostFile { contents: Arc<Vec<u8>>, cursor: usize },
+}
+
+/// Get the contents of fuzz file `index` of `count` files which share the fuzz
+/// input `input`. Every file but the last is prefixed by its length as a
+/// little-endian `u32`, the last file is the rest of the input. Lengths are
+/// clamped to the bytes left, such that any input is a valid set of files.
+pub fn carve_fuzz_file(input: &[u8], count: usize, index: usize) -> &[u8] {
+    let mut rest = input;
+    for ii in 0..count {
+        if ii == count - 1 {
+            return if ii == index { rest } else { &[] };
+        }
+
+        // Get the length prefix, a truncated one is a length of zero
+        let mut len = [0u8; 4];
+        if rest.len() >= len.len() {
+            len.copy_from_slice(&rest[..4]);
+        }
+        rest = &rest[core::cmp::min(rest.len(), 4)..];
+
+        let len = core::cmp::min(u32::from_le_bytes(len) as usize,
+                                 rest.len());
+        if ii == index {
+            return &rest[..len];
+        }
+        rest = &rest[len..];
+    }
+
+    &[]
+}
+
+/// Create a fuzz input for the fuzz files `files`, the inverse of
+/// `carve_fuzz_file()`
+pub fn pack_fuzz_files(files: &[&[u8]]) -> Vec<u8> {
+    let mut input = Vec::new();
+    for (ii, file) in files.iter().enumerate() {
+        if ii != files.len() - 1 {
+            input.extend_from_slice(&(file.len() as u32).to_le_bytes());
+        }
+        input.extend_from_slice(file);
+    }
+    input
+}
+
+/// A read-only set of real files from a host directory, which the guest is
+/// allowed to open by name
+#[derive(Default, Debug)]
+pub struct HostFiles(BTreeMap<Vec<u8>, Arc<Vec<u8>>>);
+
+impl HostFiles {
+    /// Load the whitelisted `filenames` from the host directory `dir`. The
+    /// filenames must be plain file names, they may not reference any other
+    /// directory, this keeps the guest sandboxed to `dir`.
+    pub fn load<P: AsRef<Path>>(dir: P, filenames: &[String])
+            -> io::Result<Self> {
+        let mut files = BTreeMap::new();
+
+        for filename in filenames {
+            if filename.is_empty() || filename == "." || filename == ".." ||
+                    filename.contains(|x| x == '/' || x == '\\') {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("Host file {:?} is not a plain file name",
+                            filename)));
+            }
+
+            let contents = std::fs::read(dir.as_ref().join(filename))?;
+            files.insert(filename.as_bytes().to_vec(), Arc::new(contents));
+        }
+
+        Ok(HostFiles(files))
+    }
+
+    /// Get the contents of the whitelisted file `filename`
+    pub fn get(&self, filename: &[u8]) -> Option<&Arc<Vec<u8>>> {
+        self.0.get(filename)
+    }
 }
 
 /// A list of all open files
@@ -236,10 +497,685 @@ impl Files {
     pub fn get_file(&mut self, fd: usize) -> Option<&mut Option<EmuFile>> {
         self.0.get_mut(fd)
     }
+
+    /// Install `file` as `fd`, replacing any file already open as `fd`
+    pub fn insert(&mut self, fd: usize, file: EmuFile) {
+        if fd >= self.0.len() {
+            self.0.resize(fd + 1, None);
+        }
+        self.0[fd] = Some(file);
+    }
+}
+
+/// Seed for the random bytes served to the guest, eg. via `getrandom()`
+const GUEST_RANDOM_SEED: u64 = 0x3f1e8c2a9d47b605;
+
+/// Hash a coverage edge `from` -> `to` for indexing the coverage bitmap.
+/// Make sure this stays in sync with the JIT's `coverage_event!()`
+fn coverage_hash(from: u64, to: u64) -> u64 {
+    let mut hash = (from ^ 0xe66dd519dba260bb) ^ (to ^ 0xa50ec1c4a4065d15);
+    hash ^= hash << 13;
+    hash ^= hash >> 17;
+    hash ^= hash << 43;
+    hash
+}
+
+/// Get the index and bit of the edge `from` -> `to` in the coverage bitmap
+/// of `corpus`
+pub fn coverage_bit(from: u64, to: u64, corpus: &Corpus)
+        -> (usize, u64) {
+    let bits = corpus.coverage_bitmap.len() * 64;
+    assert!(bits.count_ones() == 1,
+        "Coverage bitmap must be a power of two");
+
+    let hash = coverage_hash(from, to) & (bits as u64 - 1);
+    ((hash / 64) as usize, 1u64 << (hash % 64))
+}
+
+/// Hash the return address of a call into the calling context hash, which is
+/// the XOR of the hashes of all active calls.
+/// Make sure this stays in sync with the JIT's `track_call!()`
+fn context_hash(retaddr: u64) -> u64 {
+    retaddr.wrapping_mul(0x9e3779b97f4a7c15)
+}
+
+/// Signal number of `SIGSEGV`, which memory faults are delivered as
+const SIGSEGV: u64 = 11;
+
+/// Largest valid signal number
+const SIGRTMAX: u64 = 64;
+
+/// `SIGSEGV` codes for an access to unmapped memory, and an access with
+/// missing permissions
+const SEGV_MAPERR: u32 = 1;
+const SEGV_ACCERR: u32 = 2;
+
+/// Handler values which are not functions, the default action and ignoring
+/// the signal. Memory faults can't be ignored, so both mean a crash.
+const SIG_IGN: u64 = 1;
+
+/// `sa_flags` bit to reset the handler to the default once it is invoked
+const SA_RESETHAND: u64 = 0x80000000;
+
+/// Size of `siginfo_t`, at the start of the signal frame
+const SIGINFO_SIZE: usize = 128;
+
+/// Offset of `uc_mcontext` in the `ucontext_t` following the `siginfo_t`.
+/// It starts with the PC followed by x1-x31.
+const UC_MCONTEXT: usize = 176;
+
+/// Size of the signal frame, `siginfo_t` followed by a RISC-V `ucontext_t`
+const SIGFRAME_SIZE: usize = SIGINFO_SIZE + UC_MCONTEXT + 32 * 8 + 528;
+
+/// Return address of a signal handler. It's never mapped, returning to it
+/// restores the registers saved in the signal frame, like the kernel's
+/// `rt_sigreturn` trampoline.
+const SIGRETURN_ADDR: VirtAddr = VirtAddr(!0xfff);
+
+/// A signal action registered by the guest with `rt_sigaction()`, in the
+/// layout of the kernel's `struct sigaction`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SigAction {
+    /// Handler, or `SIG_DFL` (0) or `SIG_IGN` (1)
+    pub handler: u64,
+
+    /// `SA_*` flags
+    pub flags: u64,
+
+    /// Signals blocked while the handler runs
+    pub mask: u64,
+}
+
+/// Compute the address `base + imm` a load or store accesses. An address
+/// which wraps around the address space is reported as a
+/// `VmExit::AddressIntegerOverflow` rather than left to miss the bounds of
+/// memory, such that these crashes are told apart from ordinary ones.
+fn access_addr(base: u64, imm: i32) -> Result<VirtAddr, VmExit> {
+    let addr = if imm < 0 {
+        base.checked_sub(-(imm as i64) as u64)
+    } else {
+        base.checked_add(imm as u64)
+    };
+    addr.map(|addr| VirtAddr(addr as usize)).ok_or_else(|| {
+        let addr = base.wrapping_add(imm as i64 as u64);
+        VmExit::AddressIntegerOverflow(VirtAddr(addr as usize))
+    })
 }
 
 /// Callback for breakpoints
-type BreakpointCallback = fn(&mut Emulator) -> Result<(), VmExit>;
+pub type BreakpointCallback = fn(&mut Emulator) -> Result<(), VmExit>;
+
+/// Symbol called by stack protector code when it finds a corrupted canary
+const STACK_CHK_FAIL: &str = "__stack_chk_fail";
+
+/// Breakpoint ending the fuzz case, see `Emulator::add_fuzz_end()`
+fn fuzz_end_bp(_emu: &mut Emulator) -> Result<(), VmExit> {
+    Err(VmExit::Exit(0))
+}
+
+/// Breakpoint on `__stack_chk_fail()`, see `Emulator::enable_stack_guard()`
+fn stack_chk_fail_bp(_emu: &mut Emulator) -> Result<(), VmExit> {
+    Err(VmExit::StackGuard)
+}
+
+/// Breakpoint implementing `memcpy()` and `memmove()` with a single bulk copy
+/// rather than running the guest's byte loop
+pub fn memcpy_bp(emu: &mut Emulator) -> Result<(), VmExit> {
+    let dst = VirtAddr(emu.reg(Register::A0) as usize);
+    let src = VirtAddr(emu.reg(Register::A1) as usize);
+    let len = emu.reg(Register::A2) as usize;
+
+    // `dst` is returned, it is still in `a0`
+    emu.memory.memmove(dst, src, len)?;
+    emu.return_from_breakpoint();
+    Ok(())
+}
+
+/// Breakpoint implementing `memset()` with a single bulk write rather than
+/// running the guest's byte loop
+pub fn memset_bp(emu: &mut Emulator) -> Result<(), VmExit> {
+    let dst = VirtAddr(emu.reg(Register::A0) as usize);
+    let val = emu.reg(Register::A1) as u8;
+    let len = emu.reg(Register::A2) as usize;
+
+    // `dst` is returned, it is still in `a0`
+    emu.memory.memset(dst, val, len)?;
+    emu.return_from_breakpoint();
+    Ok(())
+}
+
+/// Arguments a function fuzzed directly is called with, rather than running
+/// the whole program. See `Emulator::enable_harness()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FunctionHarness {
+    /// Address of the function
+    pub entry: VirtAddr,
+
+    /// Argument register which points to the fuzz input, 0 for `a0` up to 7
+    /// for `a7`
+    pub input_arg: usize,
+
+    /// Argument register which holds the length of the fuzz input, if any
+    pub len_arg: Option<usize>,
+}
+
+/// Size of the stack allocated for the guest by `Emulator::setup_stack()`
+const STACK_SIZE: usize = 32 * 1024;
+
+/// Size of the unmapped guard region left below the stack, faults in it are
+/// reported as `FaultType::StackOverflow`
+const STACK_GUARD_SIZE: usize = 64 * 1024;
+
+/// Aux vector entry types passed to the guest by `Emulator::setup_stack()`
+const AT_NULL:   u64 = 0;
+const AT_PHDR:   u64 = 3;
+const AT_PHENT:  u64 = 4;
+const AT_PHNUM:  u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY:  u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+/// Bytes `AT_RANDOM` points to, which libc seeds the stack protector canary
+/// and pointer mangling with. These are fixed such that fuzz cases are
+/// reproducible.
+const AUXV_RANDOM: [u8; 16] = [
+    0x3c, 0x8a, 0x1f, 0xe2, 0x57, 0x90, 0x6d, 0x04,
+    0xb1, 0x28, 0xc6, 0x7e, 0x95, 0x0f, 0xd3, 0x6a,
+];
+
+/// Maximum size of the gaps left in front of the stack and the heap when the
+/// memory layout is randomized
+const LAYOUT_MAX_GAP: usize = 1024 * 1024;
+
+/// Maximum number of program arguments taken from the fuzz input when fuzzing
+/// argv, such that the argv array always fits in the stack
+const MAX_INPUT_ARGS: usize = 256;
+
+/// Maximum number of bytes of the fuzz input used as program arguments when
+/// fuzzing argv
+const MAX_INPUT_ARGV_BYTES: usize = STACK_SIZE / 2;
+
+/// Number of cycles each class of instruction costs towards the timeout. With
+/// the default weights of 1 the timeout is an instruction count.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CycleWeights {
+    /// Loads
+    pub load: u64,
+
+    /// Stores
+    pub store: u64,
+
+    /// Conditional branches
+    pub branch: u64,
+
+    /// JAL and JALR
+    pub jump: u64,
+
+    /// ECALL, EBREAK and fences
+    pub system: u64,
+
+    /// Everything else, ie. arithmetic and logic
+    pub alu: u64,
+}
+
+impl Default for CycleWeights {
+    fn default() -> Self {
+        CycleWeights {
+            load:   1,
+            store:  1,
+            branch: 1,
+            jump:   1,
+            system: 1,
+            alu:    1,
+        }
+    }
+}
+
+impl CycleWeights {
+    /// Get the weight of the instruction class `name`, as used on the
+    /// command line (eg. `load`)
+    pub fn class_mut(&mut self, name: &str) -> Option<&mut u64> {
+        match name {
+            "load"   => Some(&mut self.load),
+            "store"  => Some(&mut self.store),
+            "branch" => Some(&mut self.branch),
+            "jump"   => Some(&mut self.jump),
+            "system" => Some(&mut self.system),
+            "alu"    => Some(&mut self.alu),
+            _        => None,
+        }
+    }
+
+    /// Get the number of cycles the instruction `inst` costs
+    pub fn weight(&self, inst: u32) -> u64 {
+        match inst & 0b1111111 {
+            0b0000011             => self.load,
+            0b0100011             => self.store,
+            0b1100011             => self.branch,
+            0b1101111 | 0b1100111 => self.jump,
+            0b1110011 | 0b0001111 => self.system,
+            _                     => self.alu,
+        }
+    }
+}
+
+/// Builds an emulator with a program loaded and its stack set up, ready to
+/// run from the program entry point
+pub struct EmulatorBuilder {
+    /// Size of the guest memory in bytes
+    memory_size: usize,
+
+    /// Size the guest memory may grow up to in bytes
+    max_memory_size: usize,
+
+    /// JIT cache to use, if any
+    jit_cache: Option<Arc<JitCache>>,
+
+    /// Host files the guest may open, if any
+    host_files: Option<Arc<HostFiles>>,
+
+    /// Names of the files backed by the fuzz input, if not the default
+    fuzz_files: Option<Vec<Vec<u8>>>,
+
+    /// Names of the fuzz files to open before the program runs
+    open_fuzz_files: Vec<Vec<u8>>,
+
+    /// Symbols of the program, if any
+    symbols: Option<Arc<Symbols>>,
+
+    /// Syscall handler, if not the default
+    syscall_handler: Option<Arc<dyn SyscallHandler>>,
+
+    /// Cycle timeout, if not the default
+    timeout: Option<u64>,
+
+    /// Cycles each class of instruction costs, if not the default
+    cycle_weights: Option<CycleWeights>,
+
+    /// Program to load and the sections to load from it
+    program: Option<(PathBuf, Vec<Section>)>,
+
+    /// Breakpoints to register
+    breakpoints: Vec<(VirtAddr, BreakpointCallback)>,
+
+    /// PCs which end the fuzz case
+    fuzz_ends: Vec<VirtAddr>,
+
+    /// Ranges of code which do not report coverage
+    coverage_blacklist: Vec<(VirtAddr, VirtAddr)>,
+
+    /// Program arguments, including the program name
+    args: Vec<Vec<u8>>,
+
+    /// Environment variables, as `KEY=VALUE`
+    env: Vec<Vec<u8>>,
+
+    /// Program entry point
+    entry: Option<VirtAddr>,
+
+    /// Take additional program arguments from the fuzz input
+    argv_input: bool,
+
+    /// Use context-sensitive coverage
+    context_coverage: bool,
+
+    /// Use block coverage rather than edge coverage
+    block_coverage: bool,
+
+    /// Number of branches recorded in the branch trace, if enabled
+    branch_trace: Option<usize>,
+
+    /// Function fuzzed directly rather than running the whole program
+    harness: Option<FunctionHarness>,
+
+    /// Deliver memory faults to the guest's `SIGSEGV` handler
+    signal_delivery: bool,
+
+    /// Report calls to `__stack_chk_fail()` as crashes
+    stack_guard: bool,
+
+    /// Report reads of uninitialized memory
+    detect_uninit: bool,
+
+    /// Seed the memory layout is randomized with, if any
+    layout_seed: Option<u64>,
+}
+
+impl Default for EmulatorBuilder {
+    fn default() -> Self {
+        EmulatorBuilder {
+            memory_size: 32 * 1024 * 1024,
+            max_memory_size: DEFAULT_MAX_MEMORY_SIZE,
+            jit_cache:   None,
+            host_files:  None,
+            fuzz_files:  None,
+            open_fuzz_files: Vec::new(),
+            symbols:     None,
+            syscall_handler: None,
+            timeout:     None,
+            cycle_weights: None,
+            program:     None,
+            breakpoints: Vec::new(),
+            fuzz_ends:   Vec::new(),
+            coverage_blacklist: Vec::new(),
+            args:        Vec::new(),
+            env:         Vec::new(),
+            entry:       None,
+            argv_input:  false,
+            context_coverage: false,
+            block_coverage: false,
+            branch_trace: None,
+            harness:     None,
+            signal_delivery: false,
+            stack_guard: false,
+            detect_uninit: false,
+            layout_seed: None,
+        }
+    }
+}
+
+impl EmulatorBuilder {
+    /// Create a new builder for an emulator with 32 MiB of memory, which may
+    /// grow up to 256 MiB
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the size of the guest memory in bytes
+    pub fn memory_size(mut self, size: usize) -> Self {
+        self.memory_size = size;
+        self
+    }
+
+    /// Set the size the guest memory may grow up to when allocating
+    pub fn max_memory_size(mut self, size: usize) -> Self {
+        self.max_memory_size = size;
+        self
+    }
+
+    /// Use the JIT with `jit_cache`, which has to cover the maximum memory
+    /// size as all of the memory may be made executable
+    pub fn jit(mut self, jit_cache: Arc<JitCache>) -> Self {
+        self.jit_cache = Some(jit_cache);
+        self
+    }
+
+    /// Allow the guest to open the read-only host files in `host_files`
+    pub fn host_files(mut self, host_files: Arc<HostFiles>) -> Self {
+        self.host_files = Some(host_files);
+        self
+    }
+
+    /// Back the files named `names` by the fuzz input.
+    /// See `Emulator::set_fuzz_files()`.
+    pub fn fuzz_files(mut self, names: Vec<Vec<u8>>) -> Self {
+        self.fuzz_files = Some(names);
+        self
+    }
+
+    /// Open the fuzz file `name` before the program runs, in the order the
+    /// files are added. See `Emulator::open_fuzz_file()`.
+    pub fn open_fuzz_file(mut self, name: Vec<u8>) -> Self {
+        self.open_fuzz_files.push(name);
+        self
+    }
+
+    /// Use `symbols` to symbolize guest addresses
+    pub fn symbols(mut self, symbols: Arc<Symbols>) -> Self {
+        self.symbols = Some(symbols);
+        self
+    }
+
+    /// Handle syscalls with `handler` rather than `DefaultSyscallHandler`
+    pub fn syscall_handler(mut self, handler: Arc<dyn SyscallHandler>)
+            -> Self {
+        self.syscall_handler = Some(handler);
+        self
+    }
+
+    /// Set the maximum number of cycles a fuzz case may execute, which is
+    /// the number of instructions unless `cycle_weights()` is used
+    pub fn timeout(mut self, cycles: u64) -> Self {
+        self.timeout = Some(cycles);
+        self
+    }
+
+    /// Set the number of cycles each class of instruction costs towards the
+    /// timeout. See `Emulator::set_cycle_weights()`.
+    pub fn cycle_weights(mut self, weights: CycleWeights) -> Self {
+        self.cycle_weights = Some(weights);
+        self
+    }
+
+    /// Load `sections` of the program `filename`
+    pub fn load<P: AsRef<Path>>(mut self, filename: P,
+                                sections: Vec<Section>) -> Self {
+        self.program = Some((filename.as_ref().into(), sections));
+        self
+    }
+
+    /// Register a breakpoint callback at `pc`
+    pub fn breakpoint(mut self, pc: VirtAddr,
+                      callback: BreakpointCallback) -> Self {
+        self.breakpoints.push((pc, callback));
+        self
+    }
+
+    /// End fuzz cases when `pc` is reached. See `Emulator::add_fuzz_end()`.
+    pub fn fuzz_end(mut self, pc: VirtAddr) -> Self {
+        self.fuzz_ends.push(pc);
+        self
+    }
+
+    /// Ignore the coverage of the code from `start` up to `end`.
+    /// See `Emulator::add_coverage_blacklist()`.
+    pub fn coverage_blacklist(mut self, start: VirtAddr,
+                              end: VirtAddr) -> Self {
+        self.coverage_blacklist.push((start, end));
+        self
+    }
+
+    /// Add program arguments, the first of which is the program name
+    pub fn args<I, S>(mut self, args: I) -> Self
+            where I: IntoIterator<Item = S>, S: AsRef<[u8]> {
+        self.args.extend(args.into_iter().map(|x| x.as_ref().to_vec()));
+        self
+    }
+
+    /// Add environment variables, in the form `KEY=VALUE`
+    pub fn env<I, S>(mut self, env: I) -> Self
+            where I: IntoIterator<Item = S>, S: AsRef<[u8]> {
+        self.env.extend(env.into_iter().map(|x| x.as_ref().to_vec()));
+        self
+    }
+
+    /// Set the program entry point
+    pub fn entry(mut self, entry: VirtAddr) -> Self {
+        self.entry = Some(entry);
+        self
+    }
+
+    /// Fuzz argv, with the arguments from the fuzz input following `args`.
+    /// See `Emulator::enable_argv_input()`.
+    pub fn argv_input(mut self) -> Self {
+        self.argv_input = true;
+        self
+    }
+
+    /// Make coverage sensitive to the calling context.
+    /// See `Emulator::enable_context_coverage()`.
+    pub fn context_coverage(mut self) -> Self {
+        self.context_coverage = true;
+        self
+    }
+
+    /// Track the basic blocks entered rather than the edges taken.
+    /// See `Emulator::enable_block_coverage()`.
+    pub fn block_coverage(mut self) -> Self {
+        self.block_coverage = true;
+        self
+    }
+
+    /// Record the last `edges` branches taken.
+    /// See `Emulator::enable_branch_trace()`.
+    pub fn branch_trace(mut self, edges: usize) -> Self {
+        self.branch_trace = Some(edges);
+        self
+    }
+
+    /// Fuzz the function described by `harness` rather than the whole
+    /// program. See `Emulator::enable_harness()`.
+    pub fn harness(mut self, harness: FunctionHarness) -> Self {
+        self.harness = Some(harness);
+        self
+    }
+
+    /// Deliver memory faults to the guest's `SIGSEGV` handler, if it has one.
+    /// See `Emulator::enable_signal_delivery()`.
+    pub fn signal_delivery(mut self) -> Self {
+        self.signal_delivery = true;
+        self
+    }
+
+    /// Report calls to `__stack_chk_fail()` as crashes, if the symbols have
+    /// it. See `Emulator::enable_stack_guard()`.
+    pub fn stack_guard(mut self) -> Self {
+        self.stack_guard = true;
+        self
+    }
+
+    /// Report reads of uninitialized heap and stack memory as crashes, rather
+    /// than treating newly allocated memory as readable.
+    /// See `Mmu::set_detect_uninit()`.
+    pub fn detect_uninit(mut self) -> Self {
+        self.detect_uninit = true;
+        self
+    }
+
+    /// Randomize the memory layout by leaving gaps of up to 1 MiB in front of
+    /// the stack and the heap, derived from `seed` such that the same seed
+    /// always gives the same layout
+    pub fn layout_seed(mut self, seed: u64) -> Self {
+        self.layout_seed = Some(seed);
+        self
+    }
+
+    /// Create the emulator, load the program, and set up its stack
+    pub fn build(self) -> Result<Emulator, String> {
+        let mut emu = Emulator::new(self.memory_size);
+        emu.memory.set_max_size(self.max_memory_size);
+        emu.memory.set_detect_uninit(self.detect_uninit);
+
+        if let Some(jit_cache) = self.jit_cache {
+            if jit_cache.num_blocks() * 4 < self.max_memory_size {
+                return Err(format!(
+                    "JIT cache covers {:#x} bytes of code, but memory may \
+                     grow to {:#x} bytes", jit_cache.num_blocks() * 4,
+                    self.max_memory_size));
+            }
+            emu = emu.enable_jit(jit_cache);
+        }
+        if let Some(host_files) = self.host_files {
+            emu = emu.enable_host_files(host_files);
+        }
+        if let Some(names) = self.fuzz_files {
+            emu.set_fuzz_files(names);
+        }
+        for name in &self.open_fuzz_files {
+            emu.open_fuzz_file(name).unwrap_or_else(|| {
+                panic!("{:?} is not a fuzz file",
+                       String::from_utf8_lossy(name))
+            });
+        }
+        if let Some(symbols) = self.symbols {
+            emu = emu.enable_symbols(symbols);
+        }
+        if let Some(handler) = self.syscall_handler {
+            emu.set_syscall_handler(handler);
+        }
+        if let Some(timeout) = self.timeout {
+            emu.set_timeout(timeout);
+        }
+        if let Some(weights) = self.cycle_weights {
+            emu.set_cycle_weights(weights);
+        }
+        if self.context_coverage {
+            emu = emu.enable_context_coverage();
+        }
+        if self.block_coverage {
+            emu = emu.enable_block_coverage();
+        }
+        if let Some(edges) = self.branch_trace {
+            emu = emu.enable_branch_trace(edges);
+        }
+        if self.signal_delivery {
+            emu = emu.enable_signal_delivery();
+        }
+
+        // Load the application into the emulator
+        if let Some((filename, sections)) = &self.program {
+            emu.memory.load(filename, sections).ok_or_else(|| {
+                format!("Failed to load {:?} into address space", filename)
+            })?;
+
+            // Fingerprint the program so JIT code compiled for it is never
+            // used for another one
+            let contents = std::fs::read(filename).map_err(|err| {
+                format!("Failed to read {:?}: {}", filename, err)
+            })?;
+            emu.target_hash = FalkHasher::new().hash(&contents);
+
+            // Describe the program to the guest in the aux vector
+            if let Some(info) = ElfInfo::parse(&contents) {
+                emu.set_elf_info(info);
+            }
+        }
+
+        for (pc, callback) in self.breakpoints {
+            emu.add_breakpoint(pc, callback);
+        }
+        for pc in self.fuzz_ends {
+            emu.add_fuzz_end(pc);
+        }
+        if self.stack_guard {
+            emu.enable_stack_guard();
+        }
+
+        for (start, end) in self.coverage_blacklist {
+            emu.add_coverage_blacklist(start, end);
+        }
+
+        if let Some(entry) = self.entry {
+            emu.set_reg(Register::Pc, entry.0 as u64);
+        }
+
+        // Get the page aligned size of the next gap of the randomized layout
+        let mut layout_rng = self.layout_seed.map(Rng::with_seed);
+        let mut layout_gap = || layout_rng.as_mut().map_or(0, |rng| {
+            rng.rand() % (LAYOUT_MAX_GAP / 0x1000) * 0x1000
+        });
+
+        let base = emu.memory.alloc_base();
+        emu.memory.set_alloc_base(VirtAddr(base.0 + layout_gap()));
+        emu.setup_stack(&self.args, &self.env)
+            .ok_or_else(|| "Failed to set up the stack".to_string())?;
+
+        let base = emu.memory.alloc_base();
+        emu.memory.set_alloc_base(VirtAddr(base.0 + layout_gap()));
+
+        if self.argv_input {
+            emu = emu.enable_argv_input(self.args, self.env);
+        }
+
+        if let Some(harness) = self.harness {
+            emu.enable_harness(harness).ok_or_else(|| {
+                "Failed to set up the function harness".to_string()
+            })?;
+        }
+
+        Ok(emu)
+    }
+}
 
 /// All the state of the emulated system
 pub struct Emulator {
@@ -252,18 +1188,119 @@ pub struct Emulator {
     /// Fuzz input for the program
     pub fuzz_input: Vec<u8>,
 
+    /// Names of the files backed by the fuzz input. If there are several,
+    /// the fuzz input is carved up between them with `carve_fuzz_file()`.
+    fuzz_files: Vec<Vec<u8>>,
+
     /// File handle table (indexed by file descriptor)
     pub files: Files,
 
+    /// What the guest wrote to stdout and stderr during the current fuzz
+    /// case, if output is captured
+    pub output: Option<Vec<u8>>,
+
+    /// Base and size of the stack set up by `setup_stack()`
+    stack: (VirtAddr, usize),
+
+    /// Program headers and entry point of the loaded ELF, passed to the
+    /// guest in the aux vector, if known
+    elf_info: Option<ElfInfo>,
+
     /// Breakpoint callbacks
     breakpoints: BTreeMap<VirtAddr, BreakpointCallback>,
 
     /// JIT cache, if we are using a JIT
     jit_cache: Option<Arc<JitCache>>,
 
+    /// Registration with `jit_cache`, made the first time JIT code is run
+    jit_worker: Option<JitWorker>,
+
+    /// Host files the guest is allowed to open, if any
+    host_files: Option<Arc<HostFiles>>,
+
+    /// Symbols of the guest program, if loaded
+    symbols: Option<Arc<Symbols>>,
+
+    /// Ranges of code, from start up to end, whose branches and jumps do not
+    /// report coverage
+    coverage_blacklist: Vec<(VirtAddr, VirtAddr)>,
+
+    /// Handler for the syscalls the guest performs
+    syscall_handler: Arc<dyn SyscallHandler>,
+
+    /// State of the deterministic random number generator serving the guest
+    random_state: u64,
+
+    /// Signal actions the guest registered, by signal number
+    sigactions: BTreeMap<u64, SigAction>,
+
+    /// If set, memory faults are delivered to the guest's `SIGSEGV` handler
+    /// rather than ending the fuzz case
+    signal_delivery: bool,
+
+    /// Snapshot `run_input()` resets to before running an input, if set
+    snapshot: Option<Arc<Emulator>>,
+
+    /// If set, argv is fuzzed. The program arguments and environment which
+    /// are set up on a fresh stack at the start of every fuzz case, the
+    /// arguments are followed by the ones taken from the fuzz input.
+    argv_input: Option<(Vec<Vec<u8>>, Vec<Vec<u8>>)>,
+
+    /// If set, the function is called with the fuzz input at the start of
+    /// every fuzz case, returning to the address of the sentinel which ends
+    /// the fuzz case
+    harness: Option<(FunctionHarness, VirtAddr)>,
+
+    /// Cycles spent resetting and running the VM in the last `run_input()`
+    reset_cycles: u64,
+    vm_cycles:    u64,
+
+    /// Hash of the loaded program, part of the JIT cache key. Zero if the
+    /// program was not loaded by `EmulatorBuilder`.
+    target_hash: u128,
+
+    /// If set, the calling context is mixed into coverage edges
+    context_coverage: bool,
+
+    /// If set, coverage edges only record the block they enter
+    block_coverage: bool,
+
+    /// Cycles each class of instruction costs towards the timeout
+    cycle_weights: CycleWeights,
+
+    /// Coverage edges first found during the current fuzz case. The fuzz
+    /// input is saved to the corpus once the case ends if there are any.
+    new_edges: Vec<(VirtAddr, VirtAddr)>,
+
+    /// Set if the last fuzz case found new coverage
+    found_coverage: bool,
+
+    /// If set, every coverage edge taken is logged here instead of being
+    /// reported to the corpus. Used to trim inputs.
+    edge_log: Option<BTreeSet<(VirtAddr, VirtAddr)>>,
+
+    /// Local copy of the corpus coverage bitmap. Edges are checked against
+    /// it first, such that only edges new to this emulator touch the shared
+    /// bitmap. Allocated on first use, as it is as large as the shared one.
+    cov_bitmap: Vec<u64>,
+
+    /// Number of bits set in each shard of the corpus coverage bitmap when
+    /// it was last merged into `cov_bitmap`, see `sync_coverage()`
+    cov_shards: Vec<u64>,
+
+    /// Interpreter only emulator running the fuzz cases which trim inputs,
+    /// forked from the snapshot on first use and reset like any other
+    /// emulator after that. See `trim_input()`.
+    trimmer: Option<Box<Emulator>>,
+
     /// Trace of register states prior to every instruction execution
     /// Only allocated if `ENABLE_TRACING` is `true`
     trace: Vec<[u64; 33]>,
+
+    /// Ring buffer of the last branches taken, as `from, to` pairs. The
+    /// number of branches taken this fuzz case is `state.trace_idx`. Empty
+    /// unless enabled with `enable_branch_trace()`.
+    branch_trace: Vec<u64>,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -272,13 +1309,13 @@ pub enum VmExit {
     /// The VM exited due to a syscall instruction
     Syscall,
 
-    /// The VM exited cleanly as requested by the VM
-    Exit,
+    /// The VM exited cleanly as requested by the VM, with the exit status
+    Exit(i64),
 
     /// A RISC-V software breakpoint instruction was hit
     Ebreak,
 
-    /// The instruction count limit was hit and a timeout has occurred
+    /// The cycle limit was hit and a timeout has occurred
     Timeout,
 
     /// An invalid opcode was lifted
@@ -287,12 +1324,17 @@ pub enum VmExit {
     /// A free of an invalid region was performed
     InvalidFree(VirtAddr),
 
+    /// A free of an allocation which was already freed was performed
+    DoubleFree(VirtAddr),
+
     /// An integer overflow occured during a syscall due to bad supplied
     /// arguments by the program
     SyscallIntegerOverflow,
 
-    /// A read or write memory request overflowed the address size
-    AddressIntegerOverflow,
+    /// A read or write memory request overflowed the address size, eg. a
+    /// load or store whose base register plus offset wrapped around. Holds
+    /// the wrapped address.
+    AddressIntegerOverflow(VirtAddr),
 
     /// The address requested was not in bounds of the guest memory space
     AddressMiss(VirtAddr, usize),
@@ -309,6 +1351,14 @@ pub enum VmExit {
     
     /// An write of `VirtAddr` failed due to missing permissions
     WriteFault(VirtAddr),
+
+    /// The guest sent itself `SIGABRT`, eg. through `abort()` on a failed
+    /// assertion
+    Abort,
+
+    /// The guest called `__stack_chk_fail()`, a stack protector canary was
+    /// overwritten
+    StackGuard,
 }
 
 /// Different types of faults
@@ -317,58 +1367,161 @@ pub enum FaultType {
     // Access occurred outside of program memory
     Bounds,
 
-    // Invalid free (eg, double free or corrupt free address)
+    // Invalid free (eg, corrupt free address)
     Free,
 
+    // Free of an allocation which was already freed
+    DoubleFree,
+
     // An invalid opcode was executed (or lifted)
     InvalidOpcode,
 
+    // The guest aborted itself (eg, a failed assertion)
+    Abort,
+
+    // A stack protector canary was overwritten
+    StackGuard,
+
+    // Mapped memory which is not executable (eg, data, heap, or stack) was
+    // executed, likely a hijacked control flow. See `Emulator::crash_type()`
+    ExecData,
+
+    // The guard region below the stack was accessed, the guest ran out of
+    // stack. See `Emulator::crash_type()`
+    StackOverflow,
+
+    // The address of an access wrapped around the address space (eg, a huge
+    // offset from a pointer)
+    AddressOverflow,
+
     Exec,
     Read,
     Write,
     Uninit,
 }
 
-/// Different buckets for addresses
+/// Different buckets for addresses, see `Emulator::address_type()`
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum AddressType {
-    /// Address was between [0, 32 KiB)
+    /// Address was below anything mapped, eg. a NULL pointer dereference
     Null,
 
-    /// Address was between [-32 KiB, 0)
+    /// Address was a small negative number, as far below zero as `Null`
+    /// addresses are above it
     Negative,
 
-    /// Address was normal
+    /// Address was on the stack
+    Stack,
+
+    /// Address was in a live heap allocation, or the padding following it
+    Heap,
+
+    /// Address was in a freed heap allocation
+    Freed,
+
+    /// Address was not mapped at all
+    Unmapped,
+
+    /// Address was in other mapped memory, eg. the program image
     Normal,
 }
 
-impl From<VirtAddr> for AddressType {
-    fn from(val: VirtAddr) -> Self {
-        match val.0 as i64 {
-            (0..=32767)   => AddressType::Null,
-            (-32768..=-1) => AddressType::Negative,
-            _ => AddressType::Normal,
+impl VmExit {
+    /// Names of the kinds of VM exits, indexed by `kind()`
+    pub const KINDS: [&'static str; 16] = [
+        "Syscall", "Exit", "Ebreak", "Timeout", "InvalidOpcode",
+        "InvalidFree", "SyscallIntegerOverflow", "AddressIntegerOverflow",
+        "AddressMiss", "ReadFault", "ExecFault", "UninitFault", "WriteFault",
+        "Abort", "StackGuard", "DoubleFree",
+    ];
+
+    /// Get the kind of this VM exit, ignoring the data it carries, as an
+    /// index into `KINDS`
+    pub fn kind(&self) -> usize {
+        match *self {
+            VmExit::Syscall                   => 0,
+            VmExit::Exit(_)                   => 1,
+            VmExit::Ebreak                    => 2,
+            VmExit::Timeout                   => 3,
+            VmExit::InvalidOpcode             => 4,
+            VmExit::InvalidFree(_)            => 5,
+            VmExit::SyscallIntegerOverflow    => 6,
+            VmExit::AddressIntegerOverflow(_) => 7,
+            VmExit::AddressMiss(..)           => 8,
+            VmExit::ReadFault(_)              => 9,
+            VmExit::ExecFault(_)              => 10,
+            VmExit::UninitFault(_)            => 11,
+            VmExit::WriteFault(_)             => 12,
+            VmExit::Abort                     => 13,
+            VmExit::StackGuard                => 14,
+            VmExit::DoubleFree(_)             => 15,
         }
     }
-}
 
-impl VmExit {
     /// If this is a crash it returns the faulting address and the fault type
     pub fn is_crash(&self) -> Option<(FaultType, VirtAddr)> {
         match *self {
             VmExit::AddressMiss(addr, _) => Some((FaultType::Bounds, addr)),
+            VmExit::AddressIntegerOverflow(addr) =>
+                Some((FaultType::AddressOverflow, addr)),
             VmExit::ReadFault(addr)      => Some((FaultType::Read,   addr)),
             VmExit::ExecFault(addr)      => Some((FaultType::Exec,   addr)),
             VmExit::UninitFault(addr)    => Some((FaultType::Uninit, addr)),
             VmExit::WriteFault(addr)     => Some((FaultType::Write,  addr)),
             VmExit::InvalidFree(addr)    => Some((FaultType::Free,   addr)),
+            VmExit::DoubleFree(addr) => Some((FaultType::DoubleFree, addr)),
             VmExit::InvalidOpcode =>
                 Some((FaultType::InvalidOpcode, VirtAddr(0))),
+            VmExit::Abort => Some((FaultType::Abort, VirtAddr(0))),
+            VmExit::StackGuard => Some((FaultType::StackGuard, VirtAddr(0))),
             _ => None,
         }
     }
 }
 
+/// The state of an emulator at some point of its execution, taken with
+/// `Emulator::snapshot()` and restored with `Emulator::restore()`
+#[derive(Clone)]
+pub struct Snapshot(Arc<Emulator>);
+
+/// A disagreement between the JIT and the interpreter found by
+/// `Emulator::verify_input()`
+#[derive(Debug)]
+pub struct Divergence {
+    /// PC at which both backends were last resumed with identical state, the
+    /// divergence happened somewhere between here and the VM exits
+    pub pc: VirtAddr,
+
+    /// The `(JIT, interpreter)` VM exits
+    pub exits: (VmExit, VmExit),
+
+    /// The `(JIT, interpreter)` instruction counts
+    pub instrs: (u64, u64),
+
+    /// Registers which differ, with their `(JIT, interpreter)` values
+    pub regs: Vec<(Register, u64, u64)>,
+
+    /// First address at which the dirtied memory differs
+    pub memory: Option<VirtAddr>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "JIT and interpreter diverged after pc {:#x}\n", self.pc.0)?;
+        write!(f, "  exit   {:?} vs {:?}\n", self.exits.0, self.exits.1)?;
+        if self.instrs.0 != self.instrs.1 {
+            write!(f, "  instrs {} vs {}\n", self.instrs.0, self.instrs.1)?;
+        }
+        for (reg, jit, interp) in &self.regs {
+            write!(f, "  {:<6?} {:016x} vs {:016x}\n", reg, jit, interp)?;
+        }
+        if let Some(addr) = self.memory {
+            write!(f, "  memory differs at {:#x}\n", addr.0)?;
+        }
+        Ok(())
+    }
+}
+
 impl fmt::Display for Emulator {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f,
@@ -466,6 +1619,14 @@ impl From<u32> for Register {
     }
 }
 
+impl Register {
+    /// Get all the registers, in the order of the register file returned by
+    /// `Emulator::registers()`
+    pub fn all() -> impl Iterator<Item = Register> {
+        (0..33).map(Register::from)
+    }
+}
+
 impl Emulator {
     /// Creates a new emulator with `size` bytes of memory
     pub fn new(size: usize) -> Self {
@@ -475,57 +1636,563 @@ impl Emulator {
             memory: Mmu::new(size),
             state:  GuestState::default(),
             fuzz_input: Vec::new(),
+            fuzz_files: vec![b"testfn".to_vec()],
             files: Files(vec![
                 Some(EmuFile::Stdin),
                 Some(EmuFile::Stdout),
                 Some(EmuFile::Stderr),
             ]),
+            output: None,
+            stack: (VirtAddr(0), 0),
+            elf_info: None,
             jit_cache: None,
+            jit_worker: None,
+            host_files: None,
+            symbols: None,
+            coverage_blacklist: Vec::new(),
+            syscall_handler: Arc::new(DefaultSyscallHandler),
+            random_state: GUEST_RANDOM_SEED,
+            sigactions: BTreeMap::new(),
+            signal_delivery: false,
+            snapshot: None,
+            argv_input: None,
+            harness: None,
+            reset_cycles: 0,
+            vm_cycles: 0,
+            target_hash: 0,
+            context_coverage: false,
+            block_coverage: false,
+            cycle_weights: CycleWeights::default(),
+            new_edges: Vec::new(),
+            found_coverage: false,
+            edge_log: None,
+            cov_bitmap: Vec::new(),
+            cov_shards: Vec::new(),
+            trimmer: None,
             breakpoints: BTreeMap::new(),
             trace: Vec::with_capacity(
                 if ENABLE_TRACING { 10_000_000 } else { 0 }),
+            branch_trace: Vec::new(),
         }
     }
 
     /// Fork an emulator into a new emulator which will diff from the original
     pub fn fork(&self) -> Self {
         let mut state = GuestState::default();
-        state.regs = self.state.regs;
+        state.regs       = self.state.regs;
+        state.timeout    = self.state.timeout;
+        state.call_stack = self.state.call_stack;
+        state.call_depth = self.state.call_depth;
+        state.call_hash  = self.state.call_hash;
 
         Emulator {
             memory:      self.memory.fork(),
             state:       state,
             fuzz_input:  self.fuzz_input.clone(),
+            fuzz_files:  self.fuzz_files.clone(),
             files:       self.files.clone(),
+            output:      self.output.as_ref().map(|_| Vec::new()),
+            stack:       self.stack,
+            elf_info:    self.elf_info,
             jit_cache:   self.jit_cache.clone(),
+            jit_worker:  None,
+            host_files:  self.host_files.clone(),
+            symbols:     self.symbols.clone(),
+            coverage_blacklist: self.coverage_blacklist.clone(),
+            syscall_handler: self.syscall_handler.clone(),
+            random_state: self.random_state,
+            sigactions:  self.sigactions.clone(),
+            signal_delivery: self.signal_delivery,
+            snapshot:    None,
+            argv_input:  self.argv_input.clone(),
+            harness:     self.harness,
+            reset_cycles: 0,
+            vm_cycles:   0,
+            target_hash: self.target_hash,
+            context_coverage: self.context_coverage,
+            block_coverage: self.block_coverage,
+            cycle_weights: self.cycle_weights,
+            new_edges:   Vec::new(),
+            found_coverage: false,
+            edge_log:    None,
+            cov_bitmap:  Vec::new(),
+            cov_shards:  Vec::new(),
+            trimmer:     None,
             breakpoints: self.breakpoints.clone(),
             trace: Vec::with_capacity(
                 if ENABLE_TRACING { 10_000_000 } else { 0 }),
+            branch_trace: vec![0; self.branch_trace.len()],
+        }
+    }
+
+    /// Enable the JIT and use a specified `JitCache`
+    pub fn enable_jit(mut self, jit_cache: Arc<JitCache>) -> Self {
+        self.jit_cache  = Some(jit_cache);
+        self.jit_worker = None;
+        self
+    }
+    
+    /// Allow the guest to open the read-only host files in `host_files`
+    pub fn enable_host_files(mut self, host_files: Arc<HostFiles>) -> Self {
+        self.host_files = Some(host_files);
+        self
+    }
+
+    /// Use `symbols` to symbolize guest addresses
+    pub fn enable_symbols(mut self, symbols: Arc<Symbols>) -> Self {
+        self.symbols = Some(symbols);
+        self
+    }
+
+    /// Symbolize `addr` as `function+offset` if symbols are loaded, otherwise
+    /// format it as a raw address
+    pub fn symbolize(&self, addr: VirtAddr) -> String {
+        match &self.symbols {
+            Some(symbols) => symbols.symbolize(addr),
+            None          => format!("{:#x}", addr.0),
+        }
+    }
+
+    /// Get the handler syscalls are handled with
+    pub fn syscall_handler(&self) -> Arc<dyn SyscallHandler> {
+        self.syscall_handler.clone()
+    }
+
+    /// Use `handler` rather than `DefaultSyscallHandler` to handle syscalls
+    pub fn set_syscall_handler(&mut self, handler: Arc<dyn SyscallHandler>) {
+        self.syscall_handler = handler;
+    }
+
+    /// Handle the syscall the guest requested with `ecall` using the syscall
+    /// handler, and move PC to where execution should resume
+    pub fn handle_syscall(&mut self) -> Result<(), VmExit> {
+        let num = self.reg(Register::A7);
+        let handler = self.syscall_handler.clone();
+        match handler.dispatch(self, num)? {
+            SyscallAction::Continue => {
+                // Advance PC
+                let pc = self.reg(Register::Pc);
+                self.set_reg(Register::Pc, pc.wrapping_add(4));
+            }
+            SyscallAction::Redirect => {}
+        }
+        Ok(())
+    }
+
+    /// Fuzz argv, rather than only serving the fuzz input as a file. The fuzz
+    /// input is split on nul bytes into program arguments which follow `args`,
+    /// and set up on a new stack along with `env` at the start of every fuzz
+    /// case. The snapshot must be at the program entry point for the guest to
+    /// observe the arguments.
+    pub fn enable_argv_input(mut self, args: Vec<Vec<u8>>,
+                             env: Vec<Vec<u8>>) -> Self {
+        self.argv_input = Some((args, env));
+        self
+    }
+
+    /// Fuzz a single function rather than the whole program, like
+    /// `LLVMFuzzerTestOneInput()`. Every fuzz case places the fuzz input in
+    /// its own allocation and calls `harness.entry` with the argument
+    /// registers pointing to it and holding its length, the others are
+    /// zeroed. The function returns to a sentinel address which ends the
+    /// fuzz case with a `VmExit::Exit(0)`. The stack pointer and the rest of
+    /// the state are the ones of the snapshot.
+    ///
+    /// RISC-V passes the return address in `ra` rather than on the stack,
+    /// so the sentinel is placed there. Returns `None` if the sentinel could
+    /// not be allocated.
+    pub fn enable_harness(&mut self, harness: FunctionHarness) -> Option<()> {
+        assert!(harness.input_arg < 8 && harness.len_arg.map_or(true,
+                |arg| arg < 8 && arg != harness.input_arg),
+                "Invalid function harness argument registers");
+
+        // The sentinel has to be executable for both the emulator and the
+        // JIT to reach its breakpoint, it holds a `j .` which never runs
+        let sentinel = self.memory.allocate(4)?;
+        self.memory.write(sentinel, 0x0000006fu32).ok()?;
+        self.memory.set_permissions(sentinel, 4, Perm(PERM_READ | PERM_EXEC))?;
+        self.add_breakpoint(sentinel, fuzz_end_bp);
+
+        self.harness = Some((harness, sentinel));
+        Some(())
+    }
+
+    /// Capture what the guest writes to stdout and stderr, such that it can
+    /// be retrieved with `take_output()`
+    pub fn enable_output_capture(mut self) -> Self {
+        self.output = Some(Vec::new());
+        self
+    }
+
+    /// Take what the guest wrote to stdout and stderr since the last reset.
+    /// Empty if output is not captured.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.output.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Mix the calling context into coverage edges, such that an edge taken
+    /// in a function is new coverage for every distinct set of active call
+    /// sites. This finds more states in eg. recursive parsers, but every
+    /// context of an edge takes a bit in the coverage bitmap and an entry in
+    /// the coverage table. The bitmap fills up and collides far sooner, and
+    /// the reported coverage counts contexts rather than edges.
+    pub fn enable_context_coverage(mut self) -> Self {
+        self.context_coverage = true;
+        self
+    }
+
+    /// Only record the basic block entered by every branch and jump, rather
+    /// than the edge from the block it was taken from. All edges into a block
+    /// share one bit in the coverage bitmap, which fills up far slower, at the
+    /// cost of missing new paths between known blocks. Combined with
+    /// context-sensitive coverage, each context of a block is tracked.
+    pub fn enable_block_coverage(mut self) -> Self {
+        self.block_coverage = true;
+        self
+    }
+
+    /// Record the last `edges` branches taken by the guest, rounded up to a
+    /// power of two, in a ring buffer. This is a light alternative to
+    /// `ENABLE_TRACING`, which records every register before every
+    /// instruction, for when only the path taken matters. See
+    /// `branch_trace()`.
+    pub fn enable_branch_trace(mut self, edges: usize) -> Self {
+        assert!(!ENABLE_TRACING,
+            "The branch trace shares its JIT state with ENABLE_TRACING");
+        let edges = std::cmp::max(edges, 1).next_power_of_two();
+        self.branch_trace = vec![0; edges * 2];
+        self
+    }
+
+    /// Get the last branches taken this fuzz case, as `(from, to)` tuples,
+    /// oldest first. Empty if the branch trace is not enabled.
+    pub fn branch_trace(&self) -> Vec<(VirtAddr, VirtAddr)> {
+        let edges = self.branch_trace.len() / 2;
+        let taken = self.state.trace_idx;
+        (taken.saturating_sub(edges)..taken).map(|idx| {
+            let slot = (idx & (edges - 1)) * 2;
+            (VirtAddr(self.branch_trace[slot] as usize),
+             VirtAddr(self.branch_trace[slot + 1] as usize))
+        }).collect()
+    }
+
+    /// Format the branch trace as symbolized branches, one per line, oldest
+    /// first
+    pub fn dump_branch_trace(&self) -> String {
+        self.branch_trace().iter().map(|(from, to)| {
+            format!("{:#x} -> {:#x} {}\n", from.0, to.0, self.symbolize(*to))
+        }).collect()
+    }
+
+    /// Deliver memory faults to the `SIGSEGV` handler the guest registered
+    /// with `rt_sigaction()`, rather than ending the fuzz case with the fault,
+    /// such that programs which recover from faults run their recovery. The
+    /// handler gets a `siginfo_t` with the faulting address and a `ucontext_t`
+    /// with the registers, which are restored when it returns. Faults without
+    /// a handler are still crashes, as are faults while setting up the
+    /// signal frame (eg. on stack exhaustion).
+    pub fn enable_signal_delivery(mut self) -> Self {
+        self.signal_delivery = true;
+        self
+    }
+
+    /// Get the action registered for the signal `signum`, or `None` if
+    /// `signum` is not a valid signal number
+    pub fn sigaction(&self, signum: u64) -> Option<SigAction> {
+        if signum == 0 || signum > SIGRTMAX {
+            return None;
+        }
+
+        Some(self.sigactions.get(&signum).copied().unwrap_or_default())
+    }
+
+    /// Register `action` for the valid signal number `signum`
+    pub fn set_sigaction(&mut self, signum: u64, action: SigAction) {
+        assert!(self.sigaction(signum).is_some(), "Invalid signal number");
+        self.sigactions.insert(signum, action);
+    }
+
+    /// Handle a VM exit which may be a memory fault to deliver to the guest's
+    /// `SIGSEGV` handler, or the return from the handler. Returns the VM exit
+    /// if it was neither, or the fault if the signal couldn't be delivered.
+    pub fn handle_signal(&mut self, vmexit: VmExit) -> Result<(), VmExit> {
+        if !self.signal_delivery {
+            return Err(vmexit);
+        }
+
+        let (addr, code) = match vmexit {
+            VmExit::ExecFault(SIGRETURN_ADDR) => {
+                // Returned from the handler, the stack pointer is back at
+                // the signal frame. Restore the registers saved in it, which
+                // the handler may have changed to recover.
+                let frame = self.reg(Register::Sp) as usize;
+                let regs  = frame.wrapping_add(SIGINFO_SIZE + UC_MCONTEXT);
+                for ii in 0..32 {
+                    let val = self.memory.read::<u64>(
+                        VirtAddr(regs.wrapping_add(ii * 8)))?;
+                    let reg = if ii == 0 {
+                        Register::Pc
+                    } else {
+                        Register::from(ii as u32)
+                    };
+                    self.set_reg(reg, val);
+                }
+                return Ok(());
+            }
+            VmExit::AddressMiss(addr, _) |
+                    VmExit::AddressIntegerOverflow(addr) => (addr, SEGV_MAPERR),
+            VmExit::ReadFault(addr) | VmExit::WriteFault(addr) |
+                    VmExit::ExecFault(addr) | VmExit::UninitFault(addr) => {
+                let code = if self.memory.is_mapped(addr, 1) {
+                    SEGV_ACCERR
+                } else {
+                    SEGV_MAPERR
+                };
+                (addr, code)
+            }
+            _ => return Err(vmexit),
+        };
+
+        let action = self.sigaction(SIGSEGV).unwrap();
+        if action.handler <= SIG_IGN {
+            return Err(vmexit);
+        }
+
+        // Create the signal frame, the `siginfo_t` followed by the
+        // `ucontext_t` with the registers at the fault
+        let mut frame = vec![0u8; SIGFRAME_SIZE];
+        frame[0..4].copy_from_slice(&(SIGSEGV as u32).to_le_bytes());
+        frame[8..12].copy_from_slice(&code.to_le_bytes());
+        frame[16..24].copy_from_slice(&(addr.0 as u64).to_le_bytes());
+        for ii in 0..32 {
+            let val = if ii == 0 {
+                self.reg(Register::Pc)
+            } else {
+                self.reg(Register::from(ii as u32))
+            };
+            let offset = SIGINFO_SIZE + UC_MCONTEXT + ii * 8;
+            frame[offset..offset + 8].copy_from_slice(&val.to_le_bytes());
+        }
+
+        // Push the frame on the stack, if that fails the fault is fatal
+        let sp = (self.reg(Register::Sp) as usize)
+            .wrapping_sub(SIGFRAME_SIZE) & !0xf;
+        if self.memory.write_from(VirtAddr(sp), &frame).is_err() {
+            return Err(vmexit);
+        }
+
+        if action.flags & SA_RESETHAND != 0 {
+            self.sigactions.remove(&SIGSEGV);
+        }
+
+        // Call the handler as `handler(signum, siginfo, ucontext)`
+        self.set_reg(Register::A0, SIGSEGV);
+        self.set_reg(Register::A1, sp as u64);
+        self.set_reg(Register::A2, (sp + SIGINFO_SIZE) as u64);
+        self.set_reg(Register::Sp, sp as u64);
+        self.set_reg(Register::Ra, SIGRETURN_ADDR.0 as u64);
+        self.set_reg(Register::Pc, action.handler);
+        Ok(())
+    }
+
+    /// Back the files named `names` by the fuzz input rather than the single
+    /// default `testfn`. With several names, the fuzz input is carved up
+    /// between the files in order, see `carve_fuzz_file()`.
+    pub fn set_fuzz_files(&mut self, names: Vec<Vec<u8>>) {
+        assert!(!names.is_empty(), "Must have at least one fuzz file");
+        self.fuzz_files = names;
+    }
+
+    /// Get the names of the files backed by the fuzz input
+    pub fn fuzz_files(&self) -> &[Vec<u8>] {
+        &self.fuzz_files
+    }
+
+    /// Open the fuzz file `name` as if the guest opened it, for harnesses
+    /// which expect it to be open already. Returns the new file descriptor,
+    /// or `None` if `name` is not a fuzz file.
+    ///
+    /// Files opened before the snapshot is taken are open at the start of
+    /// every fuzz case.
+    pub fn open_fuzz_file(&mut self, name: &[u8]) -> Option<usize> {
+        let index = self.fuzz_files.iter().position(|x| x == name)?;
+        let fd = self.alloc_file();
+        self.files.insert(fd, EmuFile::FuzzInput { index, cursor: 0 });
+        Some(fd)
+    }
+
+    /// Get the contents of the fuzz file `index` for the current fuzz input
+    pub fn fuzz_file(&self, index: usize) -> &[u8] {
+        carve_fuzz_file(&self.fuzz_input, self.fuzz_files.len(), index)
+    }
+
+    /// Look up the contents of a host file the guest may open by `filename`
+    pub fn host_file(&self, filename: &[u8]) -> Option<Arc<Vec<u8>>> {
+        self.host_files.as_ref()?.get(filename).cloned()
+    }
+    
+    /// Set the maximum number of cycles a fuzz case may execute before it is
+    /// stopped with a `VmExit::Timeout`
+    pub fn set_timeout(&mut self, cycles: u64) {
+        self.state.timeout = cycles;
+    }
+
+    /// Set the number of cycles each class of instruction costs towards the
+    /// timeout, such that eg. memory bound fuzz cases time out sooner than
+    /// ones doing the same number of register operations
+    pub fn set_cycle_weights(&mut self, weights: CycleWeights) {
+        self.cycle_weights = weights;
+    }
+    
+    /// Describe the loaded ELF to the guest in the aux vector set up by
+    /// `setup_stack()`
+    pub fn set_elf_info(&mut self, info: ElfInfo) {
+        self.elf_info = Some(info);
+    }
+
+    /// Allocate a stack and set up the initial program stack state with the
+    /// program arguments `args`, environment variables `env` and the aux
+    /// vector, as expected by `_start`
+    pub fn setup_stack<A, E>(&mut self, args: &[A], env: &[E]) -> Option<()>
+            where A: AsRef<[u8]>, E: AsRef<[u8]> {
+        // Set up a stack, with a guard region below it such that running out
+        // of stack faults rather than corrupting the memory below
+        let guard = self.memory.alloc_base().0.checked_add(STACK_GUARD_SIZE)?;
+        self.memory.set_alloc_base(VirtAddr(guard));
+        let stack = self.memory.allocate(STACK_SIZE)?;
+        self.set_reg(Register::Sp, (stack.0 + STACK_SIZE) as u64);
+        self.stack = (stack, STACK_SIZE);
+
+        // Write the nul-terminated strings into guest memory
+        let mut write_strings = |strings: &[&[u8]]| -> Option<Vec<u64>> {
+            strings.iter().map(|string| {
+                let addr = self.memory.allocate(string.len() + 1)?;
+                self.memory.write_cstr(addr, string).ok()?;
+                Some(addr.0 as u64)
+            }).collect()
+        };
+        let argv = write_strings(
+            &args.iter().map(|x| x.as_ref()).collect::<Vec<_>>())?;
+        let envp = write_strings(
+            &env.iter().map(|x| x.as_ref()).collect::<Vec<_>>())?;
+
+        // Build the aux vector, terminated by `AT_NULL`
+        let random = self.memory.allocate(AUXV_RANDOM.len())?;
+        self.memory.write_from(random, &AUXV_RANDOM).ok()?;
+        let mut auxv = vec![
+            (AT_PAGESZ, 4096),
+            (AT_RANDOM, random.0 as u64),
+        ];
+        if let Some(info) = self.elf_info {
+            auxv.push((AT_PHDR,  info.phdr.0 as u64));
+            auxv.push((AT_PHENT, info.phent as u64));
+            auxv.push((AT_PHNUM, info.phnum as u64));
+            auxv.push((AT_ENTRY, info.entry.0 as u64));
+        }
+        auxv.push((AT_NULL, 0));
+
+        macro_rules! push {
+            ($expr:expr) => {
+                let sp = self.reg(Register::Sp) -
+                    core::mem::size_of_val(&$expr) as u64;
+                self.memory.write(VirtAddr(sp as usize), $expr).ok()?;
+                self.set_reg(Register::Sp, sp);
+            }
+        }
+
+        // Set up the initial program stack state
+        for &(key, val) in auxv.iter().rev() {
+            push!(val); // Auxv
+            push!(key);
+        }
+        push!(0u64); // Envp end
+        for &ptr in envp.iter().rev() {
+            push!(ptr); // Envp
         }
-    }
+        push!(0u64); // Argv end
+        for &ptr in argv.iter().rev() {
+            push!(ptr); // Argv
+        }
+        push!(argv.len() as u64); // Argc
 
-    /// Enable the JIT and use a specified `JitCache`
-    pub fn enable_jit(mut self, jit_cache: Arc<JitCache>) -> Self {
-        self.jit_cache = Some(jit_cache);
-        self
+        Some(())
     }
-    
-    /// Register a new breakpoint callback
+
+    /// Register a new breakpoint callback, invoked prior to executing the
+    /// instruction at `pc`. If the callback changes PC, execution continues
+    /// at the new PC, otherwise the original instruction is executed.
     pub fn add_breakpoint(&mut self, pc: VirtAddr,
                           callback: BreakpointCallback) {
         self.breakpoints.insert(pc, callback);
     }
 
+    /// Return from a breakpoint on the entry of a function, which replaces
+    /// the function, to its caller. The call is popped off the shadow call
+    /// stack like a `ret` would.
+    pub fn return_from_breakpoint(&mut self) {
+        let pc = self.reg(Register::Pc);
+        let ra = self.reg(Register::Ra);
+        self.set_reg(Register::Pc, ra);
+        self.track_call(Register::Zero, pc, ra);
+    }
+
+    /// Ignore the coverage of branches and jumps from the code from `start` up
+    /// to `end`, eg. for hash loops which constantly find new edges without
+    /// doing anything interesting. Must be done before any code is lifted
+    /// into the JIT.
+    pub fn add_coverage_blacklist(&mut self, start: VirtAddr, end: VirtAddr) {
+        self.coverage_blacklist.push((start, end));
+    }
+
+    /// Check if the coverage of branches and jumps from `pc` is ignored
+    fn coverage_blacklisted(&self, pc: u64) -> bool {
+        self.coverage_blacklist.iter().any(|&(start, end)| {
+            pc >= start.0 as u64 && pc < end.0 as u64
+        })
+    }
+
+    /// End fuzz cases with a clean `VmExit::Exit(0)` once `pc` is reached,
+    /// eg. right after the input is parsed, rather than running the program
+    /// all the way through its teardown
+    pub fn add_fuzz_end(&mut self, pc: VirtAddr) {
+        self.add_breakpoint(pc, fuzz_end_bp);
+    }
+
+    /// Report calls to `__stack_chk_fail()` as a `VmExit::StackGuard` crash,
+    /// rather than letting the guest abort in its own way, which may not
+    /// look like a crash at all. Returns the address of `__stack_chk_fail()`,
+    /// or `None` if it is not in the symbols.
+    pub fn enable_stack_guard(&mut self) -> Option<VirtAddr> {
+        let addr = self.symbols.as_ref()?.lookup(STACK_CHK_FAIL)?;
+        self.add_breakpoint(addr, stack_chk_fail_bp);
+        Some(addr)
+    }
+
+    /// Get the breakpoint callback registered at `pc`, if any
+    pub fn breakpoint(&self, pc: VirtAddr) -> Option<BreakpointCallback> {
+        self.breakpoints.get(&pc).copied()
+    }
+
+    /// Disassemble the instruction at `pc`
+    pub fn disasm_at(&self, pc: VirtAddr) -> String {
+        match self.memory.read_perms::<u32>(pc, Perm(PERM_EXEC)) {
+            Ok(inst) => disasm(inst),
+            Err(_)   => "<not executable>".into(),
+        }
+    }
+
     /// Reset the state of `self` to `other`, assuming that `self` is
     /// forked off of `other`. If it is not, the results are invalid.
     pub fn reset(&mut self, other: &Self) {
         if ENABLE_TRACING {
             let mut tracestr = String::new();
             let mut pctracestr = String::new();
-            for trace in &self.trace {
-                self.state.regs = *trace;
+            for ii in 0..self.trace.len() {
+                self.set_registers(self.trace[ii]);
                 tracestr += &format!("{}\n", self);
-                pctracestr += &format!("{:x}\n", self.reg(Register::Pc));
+                let pc = VirtAddr(self.reg(Register::Pc) as usize);
+                pctracestr += &format!("{:x} {:<32} {}\n", pc.0,
+                                       self.symbolize(pc), self.disasm_at(pc));
             }
             if self.trace.len() > 0 {
                 std::fs::write("trace.txt", tracestr).unwrap();
@@ -540,12 +2207,39 @@ impl Emulator {
         // Reset memory state
         self.memory.reset(&other.memory);
 
+        // Forget the output of the last fuzz case
+        if let Some(output) = &mut self.output {
+            output.clear();
+        }
+
         // Reset register state
         self.state.regs = other.state.regs;
+        self.stack      = other.stack;
+
+        // Reset the shadow call stack
+        self.state.call_stack = other.state.call_stack;
+        self.state.call_depth = other.state.call_depth;
+        self.state.call_hash  = other.state.call_hash;
 
         // Reset file state
         self.files.0.clear();
         self.files.0.extend_from_slice(&other.files.0);
+
+        // Reset the random number generator, such that every fuzz case sees
+        // the same random bytes
+        self.random_state = other.random_state;
+
+        // Reset the signal handlers
+        self.sigactions.clone_from(&other.sigactions);
+    }
+
+    /// Generate a deterministic random number for the guest
+    pub fn guest_random(&mut self) -> u64 {
+        let val = self.random_state;
+        self.random_state ^= self.random_state << 13;
+        self.random_state ^= self.random_state >> 17;
+        self.random_state ^= self.random_state << 43;
+        val
     }
 
     /// Allocate a new file descriptor
@@ -579,24 +2273,604 @@ impl Emulator {
         }
     }
 
+    /// Get all the registers of the guest, indexed by `Register`
+    pub fn registers(&self) -> [u64; 33] {
+        self.state.regs
+    }
+
+    /// Set all the registers of the guest, indexed by `Register`. The value
+    /// for `Register::Zero` is ignored.
+    pub fn set_registers(&mut self, regs: [u64; 33]) {
+        self.state.regs = regs;
+        self.state.regs[Register::Zero as usize] = 0;
+    }
+
+    /// Reset to `snapshot` before every `run_input()`. `self` must be forked
+    /// off of `snapshot`.
+    pub fn set_snapshot(&mut self, snapshot: Arc<Emulator>) {
+        self.snapshot = Some(snapshot);
+    }
+
+    /// Capture the current registers, memory, and files, eg. once the target
+    /// has done some expensive deterministic initialization. This copies all
+    /// of memory, but restoring it only copies what was dirtied since.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot(Arc::new(self.fork()))
+    }
+
+    /// Restore the state captured by `snapshot()`, which must have been taken
+    /// of `self` or of an emulator `self` was restored to. This also makes
+    /// it the state `run_input()` resets to, as memory dirtied before the
+    /// snapshot is no longer tracked.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.reset(&snapshot.0);
+        self.snapshot = Some(snapshot.0.clone());
+    }
+
+    /// Run a single fuzz case with `input` as the fuzz input to completion,
+    /// resetting to the snapshot first if one was set. Syscalls are handled
+    /// along the way, and the VM exit which ended the case is returned.
+    pub fn run_input(&mut self, input: &[u8], corpus: &Corpus) -> VmExit {
+        self.prepare_input(input);
+
+        // Number of instructions executed and cycles spent in the VM
+        let mut instrs_execed = 0u64;
+        let mut vm_cycles     = 0u64;
+
+        let vmexit = loop {
+            let vmexit = self.run(&mut instrs_execed, &mut vm_cycles, corpus)
+                .expect_err("Failed to execute emulator");
+
+            let ret = match vmexit {
+                VmExit::Syscall => self.handle_syscall(),
+                _ => self.handle_signal(vmexit),
+            };
+            if let Err(vmexit) = ret {
+                break vmexit;
+            }
+        };
+
+        self.vm_cycles = vm_cycles;
+        self.save_new_coverage(corpus);
+        vmexit
+    }
+
+    /// Run a single fuzz case with `input` like `run_input()`, but through
+    /// the interpreter and without reporting any coverage to the corpus.
+    /// Returns the VM exit along with every coverage edge taken, eg. to
+    /// check that seed inputs run cleanly and exercise the target.
+    pub fn dry_run(&mut self, input: &[u8], corpus: &Corpus)
+            -> (VmExit, BTreeSet<(VirtAddr, VirtAddr)>) {
+        // The JIT reports coverage straight to the corpus, bypassing the log
+        let jit_cache = self.jit_cache.take();
+        self.edge_log = Some(BTreeSet::new());
+
+        let vmexit = self.run_input(input, corpus);
+        let edges = self.edge_log.take().unwrap();
+        self.jit_cache = jit_cache;
+        (vmexit, edges)
+    }
+
+    /// Run a fuzz case with `input` through both the JIT and the interpreter
+    /// from the snapshot, checking that they agree on the registers, the
+    /// instruction count, and the dirtied memory at every VM exit. Returns
+    /// the final VM exit of the JIT, or where the backends diverged.
+    ///
+    /// Runs which time out are not compared, as the JIT only checks the
+    /// timeout on branches and thus stops at a different instruction.
+    pub fn verify_input(&mut self, input: &[u8], corpus: &Corpus)
+            -> Result<VmExit, Divergence> {
+        let snapshot = self.snapshot.clone()
+            .expect("Verifying requires a snapshot");
+        assert!(self.jit_cache.is_some(), "Verifying requires the JIT");
+
+        // Create an interpreter only emulator from the same snapshot
+        let mut interp = snapshot.fork();
+        interp.jit_cache = None;
+        interp.set_snapshot(snapshot);
+
+        // Coverage is only reported by the JIT, such that the interpreter
+        // does not need a local coverage bitmap of its own
+        interp.edge_log = Some(BTreeSet::new());
+
+        self.prepare_input(input);
+        interp.prepare_input(input);
+
+        let mut instrs   = (0u64, 0u64);
+        let mut vm_cycles = (0u64, 0u64);
+        let mut pc = VirtAddr(self.reg(Register::Pc) as usize);
+
+        let vmexit = loop {
+            let exits = (
+                self.run(&mut instrs.0, &mut vm_cycles.0, corpus)
+                    .expect_err("Failed to execute emulator"),
+                interp.run(&mut instrs.1, &mut vm_cycles.1, corpus)
+                    .expect_err("Failed to execute emulator"),
+            );
+
+            if exits.0 == VmExit::Timeout || exits.1 == VmExit::Timeout {
+                break exits.0;
+            }
+
+            let regs: Vec<_> = Register::all()
+                .filter(|&reg| self.reg(reg) != interp.reg(reg))
+                .map(|reg| (reg, self.reg(reg), interp.reg(reg)))
+                .collect();
+            let memory = self.memory.diff_dirty(&interp.memory);
+
+            if exits.0 != exits.1 || instrs.0 != instrs.1 ||
+                    !regs.is_empty() || memory.is_some() {
+                return Err(Divergence { pc, exits, instrs, regs, memory });
+            }
+
+            match exits.0 {
+                VmExit::Syscall => {
+                    let ret = self.handle_syscall();
+                    if ret != interp.handle_syscall() {
+                        return Err(Divergence {
+                            pc, exits, instrs, regs, memory });
+                    }
+                    if let Err(vmexit) = ret {
+                        break vmexit;
+                    }
+                }
+                _ => {
+                    let ret = self.handle_signal(exits.0);
+                    if ret != interp.handle_signal(exits.1) {
+                        return Err(Divergence {
+                            pc, exits, instrs, regs, memory });
+                    }
+                    if let Err(vmexit) = ret {
+                        break vmexit;
+                    }
+                }
+            }
+
+            pc = VirtAddr(self.reg(Register::Pc) as usize);
+        };
+
+        self.vm_cycles = vm_cycles.0;
+        self.save_new_coverage(corpus);
+        Ok(vmexit)
+    }
+
+    /// Save the fuzz input to the corpus if it found new coverage edges,
+    /// trimmed down to the part of it needed to still reach them
+    ///
+    /// Edges are filtered by the local and then the shared coverage bitmap
+    /// before they are noted, so the common case of a fuzz case finding
+    /// nothing new returns right away without trimming or hashing the input.
+    fn save_new_coverage(&mut self, corpus: &Corpus) {
+        if self.new_edges.is_empty() {
+            return;
+        }
+
+        self.found_coverage = true;
+        let edges = std::mem::replace(&mut self.new_edges, Vec::new());
+        let input = self.trim_input(&edges, corpus);
+
+        // Save the input and log it in the hash table
+        let hash = corpus.hasher.hash(&input);
+        corpus.input_hashes.entry_or_insert(&hash, hash as usize, || {
+            corpus.inputs.push(Box::new(Input::with_edges(input, edges)));
+            Box::new(())
+        });
+    }
+
+    /// Trim the fuzz input down to the smallest input found which still
+    /// takes all of `edges` when run from the snapshot. First the tail is cut
+    /// off with a binary search, then chunks of decreasing size are removed
+    /// from the remainder. At most `TRIM_MAX_EXECS` fuzz cases are run, which
+    /// take at most `TRIM_MAX_CYCLES_RATIO` times the cycles of the fuzz case
+    /// which found the edges.
+    ///
+    /// The fuzz cases are run by the interpreter, as the JIT only reports
+    /// edges which were never seen before.
+    fn trim_input(&mut self, edges: &[(VirtAddr, VirtAddr)],
+                  corpus: &Corpus) -> Vec<u8> {
+        let mut input = self.fuzz_input.clone();
+        let snapshot = match &self.snapshot {
+            Some(snapshot) => snapshot.clone(),
+            None => return input,
+        };
+        let max_cycles =
+            self.cycles_execed().saturating_mul(TRIM_MAX_CYCLES_RATIO);
+
+        // Fork the trimmer only once, unless the snapshot changed since
+        let forked = self.trimmer.as_ref().and_then(|trimmer| {
+            trimmer.snapshot.as_ref()
+        }).is_some_and(|forked| Arc::ptr_eq(forked, &snapshot));
+        if !forked {
+            let mut trimmer = snapshot.fork();
+            trimmer.jit_cache = None;
+            trimmer.set_snapshot(snapshot);
+            self.trimmer = Some(Box::new(trimmer));
+        }
+        let trimmer = self.trimmer.as_mut().unwrap();
+
+        // Check if `candidate` still takes all of the edges, once we ran out
+        // of fuzz cases or cycles every candidate is rejected
+        let mut execs  = 0;
+        let mut cycles = 0u64;
+        let mut keeps = |candidate: &[u8]| {
+            if execs >= TRIM_MAX_EXECS || cycles >= max_cycles {
+                return false;
+            }
+            execs += 1;
+            trimmer.edge_log = Some(BTreeSet::new());
+            trimmer.run_input(candidate, corpus);
+            cycles += trimmer.cycles_execed();
+            let log = trimmer.edge_log.take().unwrap();
+            edges.iter().all(|edge| log.contains(edge))
+        };
+
+        // Cut off the tail
+        let (mut lo, mut hi) = (0, input.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if keeps(&input[..mid]) {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+        input.truncate(hi);
+
+        // Remove chunks from the rest
+        let min_chunk = std::cmp::max(input.len() / TRIM_MIN_CHUNK_DIV, 1);
+        let mut chunk = input.len().next_power_of_two() / 2;
+        while chunk >= min_chunk {
+            let mut pos = 0;
+            while pos < input.len() {
+                let end = std::cmp::min(pos + chunk, input.len());
+                let mut candidate = input[..pos].to_vec();
+                candidate.extend_from_slice(&input[end..]);
+                if keeps(&candidate) {
+                    input = candidate;
+                } else {
+                    pos = end;
+                }
+            }
+            chunk /= 2;
+        }
+
+        input
+    }
+
+    /// Reset to the snapshot and set up the fuzz input `input` for a new
+    /// fuzz case
+    fn prepare_input(&mut self, input: &[u8]) {
+        // Reset to the snapshot
+        let it = rdtsc();
+        if let Some(snapshot) = self.snapshot.take() {
+            self.reset(&snapshot);
+            self.snapshot = Some(snapshot);
+        }
+        self.reset_cycles = rdtsc() - it;
+
+        // Forget edges found by a fuzz case which did not run to completion
+        self.new_edges.clear();
+        self.found_coverage = false;
+
+        // Start the branch trace over
+        if !ENABLE_TRACING {
+            self.state.trace_idx = 0;
+        }
+
+        // Start the timeout budget over
+        self.state.cycles_execed = 0;
+
+        // Set the fuzz input
+        self.fuzz_input.clear();
+        self.fuzz_input.extend_from_slice(input);
+
+        if let Some((args, env)) = &self.argv_input {
+            // Derive the program arguments from the fuzz input, limiting
+            // the size such that they always fit in memory and the stack.
+            // An empty input provides no additional arguments.
+            let input = &input[..input.len().min(MAX_INPUT_ARGV_BYTES)];
+            let mut argv = args.clone();
+            if !input.is_empty() {
+                argv.extend(input.split(|&x| x == 0).take(MAX_INPUT_ARGS)
+                            .map(|x| x.to_vec()));
+            }
+
+            let env = env.clone();
+            self.setup_stack(&argv, &env)
+                .expect("Failed to set up the stack with the fuzzed argv");
+        }
+
+        if let Some((harness, sentinel)) = self.harness {
+            // Place the input in its own allocation, such that the function
+            // faults as soon as it reads out of bounds
+            let addr = self.memory.allocate(input.len())
+                .expect("Failed to allocate the fuzz input");
+            self.memory.write_from(addr, input)
+                .expect("Failed to write the fuzz input");
+
+            // Call the function with the input
+            for arg in 0..8 {
+                self.set_reg(Register::from(Register::A0 as u32 + arg), 0);
+            }
+            self.set_reg(Register::from(Register::A0 as u32 +
+                                        harness.input_arg as u32),
+                         addr.0 as u64);
+            if let Some(arg) = harness.len_arg {
+                self.set_reg(Register::from(Register::A0 as u32 + arg as u32),
+                             input.len() as u64);
+            }
+            self.set_reg(Register::Ra, sentinel.0 as u64);
+            self.set_reg(Register::Pc, harness.entry.0 as u64);
+        }
+    }
+
+    /// Get the `(reset, VM)` cycles spent in the last `run_input()`
+    pub fn case_cycles(&self) -> (u64, u64) {
+        (self.reset_cycles, self.vm_cycles)
+    }
+
     /// Run the VM using either the emulator or the JIT
     pub fn run(&mut self, instrs_execed: &mut u64,
                vm_cycles: &mut u64, corpus: &Corpus)
             -> Result<(), VmExit> {
-        if self.jit_cache.is_some() {
+        let ret = if self.jit_cache.is_some() && !self.code_diverged() {
             self.run_jit(instrs_execed, vm_cycles, corpus)
         } else {
             let it = rdtsc();
             let ret = self.run_emu(instrs_execed, corpus);
             *vm_cycles += rdtsc() - it;
             ret
+        };
+
+        // Keep the instruction count in the guest state up to date for
+        // anything which inspects it on a VM exit (eg. syscalls)
+        self.state.instrs_execed = *instrs_execed;
+        ret
+    }
+
+    /// Classify `addr` by the memory it lies in, for bucketing crashes. The
+    /// `Null` and `Negative` ranges extend as far as the lowest mapped
+    /// address.
+    pub fn address_type(&self, addr: VirtAddr) -> AddressType {
+        let null_limit = self.memory.lowest_mapped().0;
+        let (stack, stack_size) = self.stack;
+
+        if addr.0 < null_limit {
+            AddressType::Null
+        } else if (addr.0 as isize) < 0 &&
+                addr.0.wrapping_neg() <= null_limit {
+            AddressType::Negative
+        } else if addr.0.wrapping_sub(stack.0) < stack_size {
+            AddressType::Stack
+        } else if self.memory.alc_offset(addr).is_some() {
+            AddressType::Heap
+        } else if self.memory.is_freed(addr) {
+            AddressType::Freed
+        } else if !self.memory.is_mapped(addr, 1) {
+            AddressType::Unmapped
+        } else {
+            AddressType::Normal
+        }
+    }
+
+    /// Get the fault type and faulting address of `vmexit` if it is a crash,
+    /// like `VmExit::is_crash()`. Execution faults are further split into
+    /// executing unmapped memory, and executing mapped memory which is not
+    /// executable. Accesses to the guard region below the stack are stack
+    /// overflows.
+    pub fn crash_type(&self, vmexit: &VmExit) -> Option<(FaultType, VirtAddr)> {
+        let (stack, stack_size) = self.stack;
+        let in_guard = |addr: VirtAddr| stack_size != 0 && addr < stack &&
+            stack.0 - addr.0 <= STACK_GUARD_SIZE;
+
+        match vmexit.is_crash()? {
+            (FaultType::Exec, addr) if self.memory.is_mapped(addr, 1) => {
+                Some((FaultType::ExecData, addr))
+            }
+            (FaultType::Read, addr) | (FaultType::Write, addr)
+                    if in_guard(addr) => {
+                Some((FaultType::StackOverflow, addr))
+            }
+            crash => Some(crash),
+        }
+    }
+
+    /// Get the shadow call stack as the return addresses of the active
+    /// calls, innermost first. At most `CALL_STACK_SIZE` frames are known.
+    pub fn call_stack(&self) -> Vec<VirtAddr> {
+        let depth = std::cmp::min(self.state.call_depth as usize,
+                                  CALL_STACK_SIZE);
+        (1..=depth).map(|ii| {
+            let idx = (self.state.call_depth as usize - ii) %
+                CALL_STACK_SIZE;
+            VirtAddr(self.state.call_stack[idx] as usize)
+        }).collect()
+    }
+
+    /// Hash the innermost `frames` frames of the shadow call stack, such
+    /// that crashes can be told apart by their call site
+    pub fn call_stack_hash(&self, frames: usize) -> u64 {
+        self.call_stack().iter().take(frames)
+            .fold(0, |hash, addr| coverage_hash(hash, addr.0 as u64))
+    }
+
+    /// Format the memory map of the guest, the stack followed by the memory
+    /// layout from `Mmu::dump_layout()`
+    pub fn dump_layout(&self) -> String {
+        let (stack, size) = self.stack;
+        format!("{:#018x}-{:#018x} stack\n{}", stack.0, stack.0 + size,
+                self.memory.dump_layout())
+    }
+
+    /// Format the shadow call stack as symbolized return addresses, one
+    /// frame per line, innermost first
+    pub fn backtrace(&self) -> String {
+        self.call_stack().iter().enumerate().map(|(ii, addr)| {
+            format!("#{:<3} {:#x} {}\n", ii, addr.0, self.symbolize(*addr))
+        }).collect()
+    }
+
+    /// Update the shadow call stack for a jump from `pc` to `target`. Jumps
+    /// which link into `rd` are calls, jumps to the innermost return
+    /// address are returns.
+    /// Make sure this stays in sync with the JIT's `track_call!()`
+    fn track_call(&mut self, rd: Register, pc: u64, target: u64) {
+        let depth = self.state.call_depth as usize;
+        if rd != Register::Zero {
+            self.state.call_stack[depth % CALL_STACK_SIZE] = pc.wrapping_add(4);
+            self.state.call_depth += 1;
+            self.state.call_hash  ^= context_hash(pc.wrapping_add(4));
+        } else if depth > 0 &&
+                self.state.call_stack[(depth - 1) % CALL_STACK_SIZE] ==
+                target {
+            self.state.call_depth -= 1;
+            self.state.call_hash  ^= context_hash(target);
+        }
+    }
+
+    /// Check if the last fuzz case found new coverage, saving its input to
+    /// the corpus
+    pub fn found_coverage(&self) -> bool {
+        self.found_coverage
+    }
+
+    /// Get the number of instructions executed this fuzz case, as of the
+    /// last VM exit
+    pub fn instrs_execed(&self) -> u64 {
+        self.state.instrs_execed
+    }
+
+    /// Get the number of cycles executed this fuzz case, which count towards
+    /// the timeout. See `set_cycle_weights()`.
+    pub fn cycles_execed(&self) -> u64 {
+        self.state.cycles_execed
+    }
+
+    /// Notify of a coverage edge `from` -> `to` taken by the interpreter.
+    /// Uses the same hash and bitmap as the JIT's `coverage_event!()` such
+    /// that edges found by either backend are only reported once.
+    fn coverage_event(&mut self, from: u64, to: u64, corpus: &Corpus) {
+        if !self.branch_trace.is_empty() {
+            let edges = self.branch_trace.len() / 2;
+            let slot  = (self.state.trace_idx & (edges - 1)) * 2;
+            self.branch_trace[slot]     = from;
+            self.branch_trace[slot + 1] = to;
+            self.state.trace_idx += 1;
+        }
+
+        if self.coverage_blacklisted(from) {
+            return;
+        }
+
+        let from = if self.block_coverage { 0 } else { from };
+        let from = if self.context_coverage {
+            from ^ self.state.call_hash
+        } else {
+            from
+        };
+
+        if let Some(log) = &mut self.edge_log {
+            log.insert((VirtAddr(from as usize), VirtAddr(to as usize)));
+            return;
+        }
+
+        let (idx, bit) = coverage_bit(from, to, corpus);
+        let local = self.local_coverage(corpus);
+        if local[idx] & bit == 0 {
+            local[idx] |= bit;
+            self.shared_coverage_event(from, to, corpus);
+        }
+    }
+
+    /// Notify of a coverage edge `from` -> `to` which is new to this
+    /// emulator, recording it if it is new to the corpus as well
+    fn shared_coverage_event(&mut self, from: u64, to: u64,
+                             corpus: &Corpus) {
+        let (idx, bit) = coverage_bit(from, to, corpus);
+        if corpus.set_coverage_bit(idx, bit) {
+            self.record_coverage(from, to, corpus);
+        }
+    }
+
+    /// Get the local coverage bitmap, allocating it and pulling in the
+    /// corpus coverage if this is its first use
+    fn local_coverage(&mut self, corpus: &Corpus) -> &mut [u64] {
+        if self.cov_bitmap.len() != corpus.coverage_bitmap.len() {
+            self.cov_bitmap = vec![0; corpus.coverage_bitmap.len()];
+            self.cov_shards = vec![0; corpus.coverage_shards.len()];
+            self.sync_coverage(corpus);
+        }
+        &mut self.cov_bitmap
+    }
+
+    /// Merge the coverage found by other emulators into the local coverage
+    /// bitmap, such that they are not reported to the corpus again. This is
+    /// only an optimization and may be done as rarely as desired.
+    ///
+    /// Only the shards in which bits were set since the last merge are
+    /// merged, such that the cost scales with the new coverage rather than
+    /// the size of the bitmap, and the pages of shards without coverage are
+    /// never touched.
+    pub fn sync_coverage(&mut self, corpus: &Corpus) {
+        if self.cov_bitmap.len() != corpus.coverage_bitmap.len() {
+            // Pulled in once the local bitmap is allocated
+            return;
+        }
+
+        for ((seen, bits), (local, shared)) in self.cov_shards.iter_mut()
+                .zip(corpus.coverage_shards.iter())
+                .zip(self.cov_bitmap.chunks_mut(COVERAGE_SHARD_WORDS)
+                     .zip(corpus.coverage_bitmap
+                          .chunks(COVERAGE_SHARD_WORDS))) {
+            let bits = bits.load(Ordering::Acquire);
+            if *seen == bits {
+                continue;
+            }
+
+            *seen = bits;
+            for (local, shared) in local.iter_mut().zip(shared.iter()) {
+                *local |= shared.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Report the new coverage edge `from` -> `to` to the corpus feedback,
+    /// noting it for the current fuzz input to be saved if it is new
+    fn record_coverage(&mut self, from: u64, to: u64, corpus: &Corpus) {
+        let (from, to) = (VirtAddr(from as usize), VirtAddr(to as usize));
+        if corpus.feedback.on_coverage(corpus, from, to, &self.fuzz_input) {
+            self.new_edges.push((from, to));
         }
     }
 
     /// Run the VM using the emulator
     pub fn run_emu(&mut self, instrs_execed: &mut u64, corpus: &Corpus)
             -> Result<(), VmExit> {
+        self.emulate(instrs_execed, corpus, false)
+    }
+
+    /// Execute only the instruction at the current PC using the emulator,
+    /// ignoring any breakpoint registered on it
+    pub fn step_emu(&mut self, instrs_execed: &mut u64, corpus: &Corpus)
+            -> Result<(), VmExit> {
+        let ret = self.emulate(instrs_execed, corpus, true);
+        self.state.instrs_execed = *instrs_execed;
+        ret
+    }
+
+    /// Emulate instructions until a VM exit, or if `single_step` is set,
+    /// until one instruction has been executed
+    fn emulate(&mut self, instrs_execed: &mut u64, corpus: &Corpus,
+               single_step: bool) -> Result<(), VmExit> {
+        // Instruction count at the start of the step
+        let step_start = *instrs_execed;
+
         'next_inst: loop {
+            if single_step && *instrs_execed != step_start {
+                // Stepped over one instruction
+                return Ok(());
+            }
+
             // Get the current program counter
             let pc = self.reg(Register::Pc);
             
@@ -616,8 +2890,10 @@ impl Emulator {
                 self.trace.push(self.state.regs);
             }
            
-            if let Some(callback) =
-                    self.breakpoints.get(&VirtAddr(pc as usize)) {
+            // Breakpoints are bypassed when stepping, as the step is how
+            // the JIT resumes after an observation-only breakpoint
+            if let Some(callback) = self.breakpoints.get(&VirtAddr(pc as usize))
+                    .filter(|_| !single_step) {
                 // Invoke the breakpoint callback
                 callback(self)?;
 
@@ -627,8 +2903,14 @@ impl Emulator {
                 }
             }
 
-            // Update number of instructions executed
+            // Check if we've hit the cycle timeout
+            if self.state.cycles_execed > self.state.timeout {
+                return Err(VmExit::Timeout);
+            }
+
+            // Update number of instructions and cycles executed
             *instrs_execed += 1;
+            self.state.cycles_execed += self.cycle_weights.weight(inst);
 
             // Extract the opcode from the instruction
             let opcode = inst & 0b1111111;
@@ -650,9 +2932,11 @@ impl Emulator {
                 0b1101111 => {
                     // JAL
                     let inst = Jtype::from(inst);
+                    let target = pc.wrapping_add(inst.imm as i64 as u64);
+                    self.coverage_event(pc, target, corpus);
+                    self.track_call(inst.rd, pc, target);
                     self.set_reg(inst.rd, pc.wrapping_add(4));
-                    self.set_reg(Register::Pc,
-                                 pc.wrapping_add(inst.imm as i64 as u64));
+                    self.set_reg(Register::Pc, target);
                     continue 'next_inst;
                 }
                 0b1100111 => {
@@ -661,9 +2945,11 @@ impl Emulator {
 
                     match inst.funct3 {
                         0b000 => {
-                            // JALR
+                            // JALR, the lowest bit of the target is cleared
                             let target = self.reg(inst.rs1).wrapping_add(
-                                    inst.imm as i64 as u64);
+                                    inst.imm as i64 as u64) & !1;
+                            self.coverage_event(pc, target, corpus);
+                            self.track_call(inst.rd, pc, target);
                             self.set_reg(inst.rd, pc.wrapping_add(4));
                             self.set_reg(Register::Pc, target);
                             continue 'next_inst;
@@ -678,66 +2964,31 @@ impl Emulator {
                     let rs1 = self.reg(inst.rs1);
                     let rs2 = self.reg(inst.rs2);
 
-                    match inst.funct3 {
-                        0b000 => {
-                            // BEQ
-                            if rs1 == rs2 {
-                                self.set_reg(Register::Pc,
-                                    pc.wrapping_add(inst.imm as i64 as u64));
-                                continue 'next_inst;
-                            }
-                        }
-                        0b001 => {
-                            // BNE
-                            if rs1 != rs2 {
-                                self.set_reg(Register::Pc,
-                                    pc.wrapping_add(inst.imm as i64 as u64));
-                                continue 'next_inst;
-                            }
-                        }
-                        0b100 => {
-                            // BLT
-                            if (rs1 as i64) < (rs2 as i64) {
-                                self.set_reg(Register::Pc,
-                                    pc.wrapping_add(inst.imm as i64 as u64));
-                                continue 'next_inst;
-                            }
-                        }
-                        0b101 => {
-                            // BGE
-                            if (rs1 as i64) >= (rs2 as i64) {
-                                self.set_reg(Register::Pc,
-                                    pc.wrapping_add(inst.imm as i64 as u64));
-                                continue 'next_inst;
-                            }
-                        }
-                        0b110 => {
-                            // BLTU
-                            if (rs1 as u64) < (rs2 as u64) {
-                                self.set_reg(Register::Pc,
-                                    pc.wrapping_add(inst.imm as i64 as u64));
-                                continue 'next_inst;
-                            }
-                        }
-                        0b111 => {
-                            // BGEU
-                            if (rs1 as u64) >= (rs2 as u64) {
-                                self.set_reg(Register::Pc,
-                                    pc.wrapping_add(inst.imm as i64 as u64));
-                                continue 'next_inst;
-                            }
-                        }
+                    let taken = match inst.funct3 {
+                        0b000 => /* BEQ  */ rs1 == rs2,
+                        0b001 => /* BNE  */ rs1 != rs2,
+                        0b100 => /* BLT  */ (rs1 as i64) <  (rs2 as i64),
+                        0b101 => /* BGE  */ (rs1 as i64) >= (rs2 as i64),
+                        0b110 => /* BLTU */ rs1 <  rs2,
+                        0b111 => /* BGEU */ rs1 >= rs2,
                         _ => unimplemented!("Unexpected 0b1100011"),
+                    };
+
+                    if taken {
+                        let target = pc.wrapping_add(inst.imm as i64 as u64);
+                        self.coverage_event(pc, target, corpus);
+                        self.set_reg(Register::Pc, target);
+                        continue 'next_inst;
                     }
+
+                    self.coverage_event(pc, pc.wrapping_add(4), corpus);
                 }
                 0b0000011 => {
                     // We know it's an Itype
                     let inst = Itype::from(inst);
 
                     // Compute the address
-                    let addr = VirtAddr(self.reg(inst.rs1)
-                        .wrapping_add(inst.imm as i64 as u64)
-                        as usize);
+                    let addr = access_addr(self.reg(inst.rs1), inst.imm)?;
 
                     match inst.funct3 {
                         0b000 => {
@@ -797,9 +3048,7 @@ impl Emulator {
                     let inst = Stype::from(inst);
 
                     // Compute the address
-                    let addr = VirtAddr(self.reg(inst.rs1)
-                        .wrapping_add(inst.imm as i64 as u64)
-                        as usize);
+                    let addr = access_addr(self.reg(inst.rs1), inst.imm)?;
 
                     match inst.funct3 {
                         0b000 => {
@@ -874,7 +3123,7 @@ impl Emulator {
                                     let shamt = inst.imm & 0b111111;
                                     self.set_reg(inst.rd, rs1 << shamt);
                                 }
-                                _ => unreachable!(),
+                                _ => return Err(VmExit::InvalidOpcode),
                             }
                         }
                         0b101 => {
@@ -892,7 +3141,7 @@ impl Emulator {
                                     self.set_reg(inst.rd,
                                         ((rs1 as i64) >> shamt) as u64);
                                 }
-                                _ => unreachable!(),
+                                _ => return Err(VmExit::InvalidOpcode),
                             }
                         }
                         _ => unreachable!(),
@@ -1003,10 +3252,19 @@ impl Emulator {
                 0b0001111 => {
                     let inst = Itype::from(inst);
 
+                    // There is only ever one hart, so all memory accesses
+                    // are already observed in program order. Instruction
+                    // fetches see stores to code right away as well: the
+                    // interpreter fetches from memory, and the JIT exits on
+                    // stores to executable memory and never runs code lifted
+                    // from before them, see `code_diverged()`.
                     match inst.funct3 {
                         0b000 => {
                             // FENCE
                         }
+                        0b001 => {
+                            // FENCE.I
+                        }
                         _ => unreachable!(),
                     }
                 }
@@ -1016,7 +3274,7 @@ impl Emulator {
                         return Err(VmExit::Syscall);
                     } else if inst == 0b00000000000100000000000001110011 {
                         // EBREAK
-                        panic!("EBREAK");
+                        return Err(VmExit::Ebreak);
                     } else {
                         unreachable!();
                     }
@@ -1044,7 +3302,7 @@ impl Emulator {
                                     self.set_reg(inst.rd,
                                         (rs1 << shamt) as i32 as i64 as u64);
                                 }
-                                _ => unreachable!(),
+                                _ => return Err(VmExit::InvalidOpcode),
                             }
                         }
                         0b101 => {
@@ -1063,7 +3321,7 @@ impl Emulator {
                                     self.set_reg(inst.rd,
                                         ((rs1 as i32) >> shamt) as i64 as u64);
                                 }
-                                _ => unreachable!(),
+                                _ => return Err(VmExit::InvalidOpcode),
                             }
                         }
                         _ => unreachable!(),
@@ -1078,17 +3336,43 @@ impl Emulator {
     }
     
     /// Run the VM using the JIT
-    pub fn run_jit(&mut self, instrs_execed: &mut u64, 
+    pub fn run_jit(&mut self, instrs_execed: &mut u64,
                    vm_cycles: &mut u64, corpus: &Corpus)
             -> Result<(), VmExit> {
-        // Get the JIT addresses
-        let (memory, perms, dirty, dirty_bitmap) = self.memory.jit_addrs();
+        if self.jit_worker.is_none() {
+            let jit_cache = self.jit_cache.as_ref().unwrap();
+            self.jit_worker = Some(jit_cache.register_worker());
+        }
 
+        let ret = self.run_jit_code(instrs_execed, vm_cycles, corpus);
+
+        // No JIT code is run until the next call, so evicted code does not
+        // have to wait on this emulator to be reclaimed
+        self.jit_worker.as_ref().unwrap().leave();
+        ret
+    }
+
+    /// Run JIT code until a VM exit, see `run_jit()`
+    fn run_jit_code(&mut self, instrs_execed: &mut u64,
+                    vm_cycles: &mut u64, corpus: &Corpus)
+            -> Result<(), VmExit> {
         // If `Some`, we re-entry the JIT by jumping directly to this address,
         // ignoring PC
         let mut override_jit_addr = None;
 
         loop {
+            if self.code_diverged() {
+                // Finish the fuzz case in the interpreter
+                let it = rdtsc();
+                let ret = self.run_emu(instrs_execed, corpus);
+                *vm_cycles += rdtsc() - it;
+                return ret;
+            }
+
+            // Publish the epoch the code we are about to run is looked up in
+            self.jit_cache.as_ref().unwrap()
+                .enter(self.jit_worker.as_ref().unwrap());
+
             let mut jit_addr = if let Some(override_jit_addr) =
                     override_jit_addr.take() {
                 override_jit_addr
@@ -1102,28 +3386,41 @@ impl Emulator {
 
                 if let Some(jit_addr) = jit_addr {
                     jit_addr
+                } else if let Some(jit_addr) =
+                        self.compile_jit(VirtAddr(pc as usize), corpus)? {
+                    jit_addr
                 } else {
-                    // Generate the JIT for this PC
-                    let tmp = self.compile_jit(VirtAddr(pc as usize), corpus)?;
-
-                    // Update the JIT tables
-                    self.jit_cache.as_ref().unwrap().add_mapping(
-                        VirtAddr(pc as usize), &tmp)
+                    // The code is being compiled in the background, use the
+                    // emulator in the meantime
+                    let it = rdtsc();
+                    let ret = self.run_emu_until_jitted(instrs_execed, corpus);
+                    *vm_cycles += rdtsc() - it;
+                    ret?;
+                    continue;
                 }
             };
 
-            // Set up the JIT state
+            // Set up the JIT state. Memory may have moved since the last
+            // time, as it grows when allocating.
+            let (memory, perms, dirty, dirty_bitmap) = self.memory.jit_addrs();
             self.state.instrs_execed = *instrs_execed;
             self.state.memory        = memory;
+            self.state.memory_len    = self.memory.len();
             self.state.permissions   = perms;
             self.state.dirty         = dirty;
             self.state.dirty_idx     = self.memory.dirty_len();
             self.state.dirty_bitmap  = dirty_bitmap;
-            self.state.trace_buffer  = self.trace.as_ptr() as usize;
-            self.state.trace_idx     = self.trace.len();
-            self.state.trace_len     = self.trace.capacity();
+            if ENABLE_TRACING {
+                self.state.trace_buffer = self.trace.as_ptr() as usize;
+                self.state.trace_idx    = self.trace.len();
+                self.state.trace_len    = self.trace.capacity();
+            } else {
+                self.state.trace_buffer =
+                    self.branch_trace.as_mut_ptr() as usize;
+                self.state.trace_len    = self.branch_trace.len() / 2;
+            }
             self.state.cov_bitmap    =
-                corpus.coverage_bitmap.as_ptr() as usize;
+                self.local_coverage(corpus).as_ptr() as usize;
                     
             let jit_cache = self.jit_cache.as_ref().unwrap();
 
@@ -1163,7 +3460,9 @@ impl Emulator {
                     
             unsafe {
                 // Update trace length
-                self.trace.set_len(self.state.trace_idx);
+                if ENABLE_TRACING {
+                    self.trace.set_len(self.state.trace_idx);
+                }
             
                 // Update the dirty state
                 self.memory.set_dirty_len(self.state.dirty_idx);
@@ -1173,25 +3472,14 @@ impl Emulator {
                 ExitReason::None => unreachable!(),
                 ExitReason::Coverage => {
                     // Update code coverage
-                    let key = (
-                        VirtAddr(self.state.cov_from as usize),
-                        VirtAddr(self.state.cov_to as usize),
-                    );
-                    corpus.code_coverage.entry_or_insert(
-                        &key, self.state.cov_to as usize, || {
-                            // Save the input and log it in the hash table
-                            let hash = corpus.hasher.hash(&self.fuzz_input);
-                            corpus.input_hashes.entry_or_insert(
-                                    &hash, hash as usize, || {
-                                corpus.inputs.push(
-                                    Box::new(self.fuzz_input.clone()));
-                                Box::new(())
-                            });
-
-                            Box::new(())
-                        });
-
-                    // Fall through to re-execute instruction
+                    self.shared_coverage_event(self.state.cov_from,
+                                               self.state.cov_to, corpus);
+
+                    // Fall through to re-execute instruction, it was already
+                    // counted so don't count it twice. This keeps the count
+                    // identical to the emulator's. The JIT already took its
+                    // cycles back.
+                    *instrs_execed -= 1;
                 }
                 ExitReason::IndirectBranch => {
                     // Just fall through to translate to JIT
@@ -1215,11 +3503,13 @@ impl Emulator {
                     // Write fault
                     // The JIT reports the address of the base of the
                     // access, invoke the emulator to get the specific
-                    // byte which caused the fault
-                    return self.run_emu(instrs_execed, corpus);
+                    // byte which caused the fault. If the write does not
+                    // fault, it was to executable memory, resume in the JIT
+                    // once the emulator has performed it.
+                    self.step_emu(instrs_execed, corpus)?;
                 }
                 ExitReason::Timeout => {
-                    // Hit the instruction count timeout
+                    // Hit the cycle timeout
                     return Err(VmExit::Timeout);
                 }
                 ExitReason::Breakpoint => {
@@ -1230,9 +3520,10 @@ impl Emulator {
                     }
 
                     if self.reg(Register::Pc) == self.state.reenter_pc {
-                        // Force execution at the return location, which
-                        // will skip over the breakpoint return
-                        panic!("WAT");
+                        // The handler only observed state, execute the real
+                        // instruction with the emulator to get past the
+                        // breakpoint, then resume in the JIT
+                        self.step_emu(instrs_execed, corpus)?;
                     } else {
                         // PC was changed by the breakpoint handler,
                         // thus we respect its change and will jump
@@ -1247,17 +3538,130 @@ impl Emulator {
         }
     }
 
-    /// Compile a JIT function for `pc` until all paths lead to indirect
-    /// jumps or calls
-    pub fn compile_jit(&mut self, pc: VirtAddr, corpus: &Corpus)
-            -> Result<Vec<u8>, VmExit> {
+    /// Check if executable memory no longer holds the code the shared JIT
+    /// cache was lifted from. Once an emulator with a snapshot modifies its
+    /// code, the rest of the fuzz case is interpreted, as JIT code it looks up
+    /// may be stale and code it lifts must not be published to the other
+    /// emulators. The reset before the next case restores the code.
+    ///
+    /// Emulators without a snapshot are not running alongside others yet, eg.
+    /// while getting to the snapshot, so they throw away all JIT code instead.
+    fn code_diverged(&mut self) -> bool {
+        if self.snapshot.is_some() {
+            return self.memory.code_dirty();
+        }
+
+        if self.memory.take_code_modified() {
+            if let Some(jit_cache) = &self.jit_cache {
+                jit_cache.invalidate();
+            }
+        }
+        false
+    }
+
+    /// Get the JIT code for `pc`, from the on-disk cache if it was compiled
+    /// before, and map it in the JIT cache. If the code has not been
+    /// compiled, it's queued to be compiled in the background and `None` is
+    /// returned.
+    pub fn compile_jit(&mut self, pc: VirtAddr, corpus: &Corpus)
+            -> Result<Option<usize>, VmExit> {
+        let program = self.lift_jit(pc, corpus)?;
+
+        // Hash the C++ file contents, including the configuration header
+        let proghash = corpus.hasher.hash(program.as_bytes());
+
+        // Create the jitcache folder
+        std::fs::create_dir_all(jitcache::CACHE_DIR)
+            .expect("Failed to create jitcache directory");
+
+        // Create the cache name
+        let cachename = Path::new(jitcache::CACHE_DIR)
+            .join(format!("{:032x}", proghash));
+
+        let jit_cache = self.jit_cache.as_ref().unwrap();
+
+        // Code the translation table does not cover is left to the
+        // interpreter
+        if pc.0 / 4 >= jit_cache.num_blocks() {
+            return Ok(None);
+        }
+
+        // If the cache exists, read the cache. It may be pruned at any time,
+        // in which case it is compiled again.
+        if let Ok(code) = std::fs::read(&cachename) {
+            return Ok(Some(jit_cache.add_mapping(pc, &code)));
+        }
+
+        JitCache::compile_background(jit_cache, pc, program, cachename);
+        Ok(None)
+    }
+
+    /// Interpret while JIT code is compiled in the background, until we get
+    /// to code which has been compiled or `JIT_FALLBACK_INSTRS` have been
+    /// executed. The limit makes sure code we reach which has not been
+    /// queued for compilation yet gets queued.
+    fn run_emu_until_jitted(&mut self, instrs_execed: &mut u64,
+                            corpus: &Corpus) -> Result<(), VmExit> {
+        let jit_cache = self.jit_cache.clone().unwrap();
+        let start = *instrs_execed;
+
+        loop {
+            let pc = VirtAddr(self.reg(Register::Pc) as usize);
+            if jit_cache.lookup(pc).is_some() ||
+                    *instrs_execed - start >= JIT_FALLBACK_INSTRS {
+                return Ok(());
+            }
+
+            // Stepping bypasses breakpoints, so invoke them here
+            if let Some(callback) = self.breakpoints.get(&pc) {
+                callback(self)?;
+
+                if self.reg(Register::Pc) != pc.0 as u64 {
+                    // Callback changed PC
+                    continue;
+                }
+            }
+
+            self.step_emu(instrs_execed, corpus)?;
+        }
+    }
+
+    /// Lift the code at `pc` to C++ for the JIT, following control flow until
+    /// all paths lead to indirect jumps or calls. Returns the translation
+    /// unit which `compile_jit()` compiles, it's not compiled or cached here,
+    /// so it can be used to inspect the generated code.
+    pub fn lift_jit(&mut self, pc: VirtAddr, corpus: &Corpus)
+            -> Result<String, VmExit> {
         let mut visited = BTreeSet::new();
         let mut queued = VecDeque::new();
+        let max_block_instrs =
+            self.jit_cache.as_ref().unwrap().max_block_instrs();
         
         // Insert the program counter into the queue
         queued.push_back(pc);
 
-        let mut program = String::new();
+        // Record everything the generated code depends on, other than the
+        // code itself, so it's part of the cache key
+        let jit_config = self.jit_cache.as_ref().unwrap().config();
+        let mut program = format!(
+            "// coverage bits: {}\n\
+             // tracing:       {}\n\
+             // target:        {:032x}\n\
+             // context cov:   {}\n\
+             // block cov:     {}\n\
+             // branch trace:  {}\n\
+             // cycle weights: {:?}\n\
+             // cov blacklist: {:x?}\n\
+             // compiler:      {} {}\n\
+             // linker script: {}\n",
+            size_of_val(corpus.coverage_bitmap.as_slice()) * 8,
+            ENABLE_TRACING, self.target_hash, self.context_coverage,
+            self.block_coverage, !self.branch_trace.is_empty(),
+            self.cycle_weights, self.coverage_blacklist,
+            jit_config.compiler, jit_config.extra_flags.join(" "),
+            jit_config.linker_script.display());
+        program += &format!("\n#define CALL_STACK_SIZE {}\n",
+                            CALL_STACK_SIZE);
         program += 
 r#"
 #include <stddef.h>
@@ -1285,6 +3689,7 @@ struct _state {
 
     uint64_t regs[33];
     uint8_t *__restrict const memory;
+    const size_t memory_len;
     uint8_t *__restrict const permissions;
     uintptr_t *__restrict const dirty;
     size_t dirty_idx;
@@ -1295,7 +3700,12 @@ struct _state {
     const size_t trace_len;
     uint64_t *const cov_bitmap;
     uint64_t instrs_execed;
+    uint64_t cycles_execed;
     const uint64_t timeout;
+
+    uint64_t call_stack[CALL_STACK_SIZE];
+    uint64_t call_depth;
+    uint64_t call_hash;
 };
 
 extern "C" void start(struct _state *__restrict state) {
@@ -1343,25 +3753,72 @@ extern "C" void start(struct _state *__restrict state) {
             }
         }
 
+        // Exit with an invalid opcode for a malformed encoding. We don't fail
+        // the lift as the encoding may be data which is never executed
+        macro_rules! invalid_opcode {
+            ($pc:expr) => {
+                program += &format!(r#"
+    state->exit_reason = InvalidOpcode;
+    state->reenter_pc  = {:#x}ULL;
+    return;
+"#, $pc.0)
+            }
+        }
+
+        // Update the shadow call stack for a jump to the C++ expression
+        // `$target` which links `$retaddr` into `$rd`
+        // Make sure this stays in sync with `Emulator::track_call()`
+        macro_rules! track_call {
+            ($rd:expr, $retaddr:expr, $target:expr) => {
+                if $rd != Register::Zero {
+                    program += &format!(r#"
+    state->call_stack[state->call_depth % CALL_STACK_SIZE] = {:#x}ULL;
+    state->call_depth++;
+    state->call_hash ^= {:#x}ULL;
+"#, $retaddr, context_hash($retaddr as u64));
+                } else {
+                    program += &format!(r#"
+    if (state->call_depth > 0 && state->call_stack[
+            (state->call_depth - 1) % CALL_STACK_SIZE] == {target}) {{
+        state->call_depth--;
+        state->call_hash ^= {target} * 0x9e3779b97f4a7c15ULL;
+    }}
+"#, target = $target);
+                }
+            }
+        }
+
         while let Some(pc) = queued.pop_front() {
             // Attempt to notify of a coverage edge ($from, $to)
+            // The hash must match `coverage_hash()` used by the interpreter
             // Note: This will cause the current instruction to be re-executed
             // if the coverage is new. Thus, it is critical that no side
-            // effects occur prior to the coverage_event!() macro use.
+            // effects occur prior to the coverage_event!() macro use. The
+            // `$weight` cycles of the instruction are taken back, such that
+            // they're only counted once.
+            // Blacklisted code only gets the timeout check, such that loops
+            // in it still time out. The branch is recorded in the branch
+            // trace once the coverage is known, such that it's not recorded
+            // again when the instruction is re-executed.
             macro_rules! coverage_event {
-                ($from:expr, $to:expr) => {
-                    let coverage_bitmap_bits =
-                        size_of_val(corpus.coverage_bitmap.as_slice()) * 8;
-                    assert!(coverage_bitmap_bits.count_ones() == 1,
-                        "Coverage bitmap must be a power of two");
+                ($from:expr, $to:expr, $weight:expr) => {
+                    let (from, to): (String, String) = ($from, $to.into());
                     program += &format!(r#"
-        if (state->instrs_execed > state->timeout) {{
+        if (state->cycles_execed > state->timeout) {{
             state->exit_reason = Timeout;
             state->reenter_pc  = {pc:#x}ULL;
             return;
         }}
+    "#, pc = pc.0);
 
-        auto hash = ({from} ^ 0xe66dd519dba260bbULL) ^
+                    let coverage_bitmap_bits =
+                        size_of_val(corpus.coverage_bitmap.as_slice()) * 8;
+                    assert!(coverage_bitmap_bits.count_ones() == 1,
+                        "Coverage bitmap must be a power of two");
+                    if !self.coverage_blacklisted(pc.0 as u64) {
+                        program += &format!(r#"
+        auto from = {from}{context};
+        auto hash = (from ^ 0xe66dd519dba260bbULL) ^
             ({to} ^ 0xa50ec1c4a4065d15ULL);
         hash ^= hash << 13;
         hash ^= hash >> 17;
@@ -1371,14 +3828,32 @@ extern "C" void start(struct _state *__restrict state) {
         auto bit = 1ULL << (hash % 64);
         if ((state->cov_bitmap[idx] & bit) == 0) {{
             state->cov_bitmap[idx] |= bit;
+            state->cycles_execed -= {weight}ULL;
             state->exit_reason = Coverage;
-            state->cov_from    = {from};
+            state->cov_from    = from;
             state->cov_to      = {to};
             state->reenter_pc  = {pc:#x}ULL;
             return;
         }}
-    "#, from = $from, to = $to, hashmask = coverage_bitmap_bits - 1,
-        pc = pc.0);
+    "#, from = if self.block_coverage { "0ULL" } else { &from },
+        to = to, hashmask = coverage_bitmap_bits - 1,
+        pc = pc.0, weight = $weight, context = if self.context_coverage {
+            " ^ state->call_hash"
+        } else {
+            ""
+        });
+                    }
+
+                    if !self.branch_trace.is_empty() {
+                        program += &format!(r#"
+        {{
+            auto slot = (state->trace_idx & (state->trace_len - 1)) * 2;
+            state->trace_buffer[slot]     = {from};
+            state->trace_buffer[slot + 1] = {to};
+            state->trace_idx++;
+        }}
+    "#, from = from, to = to);
+                    }
                 }
             }
 
@@ -1387,6 +3862,18 @@ extern "C" void start(struct _state *__restrict state) {
                 continue;
             }
 
+            if visited.len() > max_block_instrs {
+                // This unit is big enough, exit to the JIT at this PC such
+                // that the rest of the code is lifted as a separate unit
+                program += &format!(r#"inst_{:016x}: {{
+    state->exit_reason = IndirectBranch;
+    state->reenter_pc  = {:#x}ULL;
+    return;
+}}
+"#, pc.0, pc.0);
+                continue;
+            }
+
             // Check alignment
             if pc.0 & 3 != 0 {
                 // Code was unaligned, return a code fetch fault
@@ -1400,8 +3887,11 @@ extern "C" void start(struct _state *__restrict state) {
             // Create the instruction start label
             program += &format!("inst_{:016x}: {{\n", pc.0);
 
-            // Update instructions executed stats
+            // Update instructions and cycles executed stats
+            let weight = self.cycle_weights.weight(inst);
             program += "    state->instrs_execed += 1;\n";
+            program += &format!("    state->cycles_execed += {}ULL;\n",
+                                weight);
             
             if ENABLE_TRACING {
                 program += &format!(r#"
@@ -1451,9 +3941,10 @@ extern "C" void start(struct _state *__restrict state) {
                     // Record coverage
                     coverage_event!(
                         format!("{:#x}ULL", pc.0),
-                        format!("{:#x}ULL", target));
+                        format!("{:#x}ULL", target), weight);
 
                     // Set the return address
+                    track_call!(inst.rd, retaddr, format!("{:#x}ULL", target));
                     set_reg!(inst.rd, retaddr);
 
                     if inst.rd == Register::Zero {
@@ -1484,15 +3975,17 @@ extern "C" void start(struct _state *__restrict state) {
                             // JALR
                             let retaddr = pc.0.wrapping_add(4);
                             get_reg!("auto target", inst.rs1);
-                            program += &format!("    target += {:#x}ULL;\n",
+                            program += &format!(
+                                "    target = (target + {:#x}ULL) & ~1ULL;\n",
                                 inst.imm as i64 as u64);
 
                             // Record coverage
                             coverage_event!(
                                 format!("{:#x}ULL", pc.0),
-                                "target");
+                                "target", weight);
 
                             // Set the return address
+                            track_call!(inst.rd, retaddr, "target");
                             set_reg!(inst.rd, retaddr);
 
                             program +=
@@ -1531,7 +4024,7 @@ extern "C" void start(struct _state *__restrict state) {
                     // Record coverage for true condition
                     coverage_event!(
                         format!("{:#x}ULL", pc.0),
-                        format!("{:#x}ULL", target));
+                        format!("{:#x}ULL", target), weight);
 
                     program +=
                         &format!("        goto inst_{:016x};\n", target);
@@ -1540,7 +4033,7 @@ extern "C" void start(struct _state *__restrict state) {
                     // Record coverage for false condition
                     coverage_event!(
                         format!("{:#x}ULL", pc.0),
-                        format!("{:#x}ULL", pc.0.wrapping_add(4)));
+                        format!("{:#x}ULL", pc.0.wrapping_add(4)), weight);
 
                     // Queue exploration of this target
                     queued.push_back(VirtAddr(target));
@@ -1571,15 +4064,16 @@ extern "C" void start(struct _state *__restrict state) {
                     program += &format!("    addr += {:#x}ULL;\n",
                         inst.imm as i64 as u64);
 
-                    // Check the bounds and permissions of the address
+                    // Check the bounds and permissions of the address, the
+                    // emulator reports addresses which wrapped around
                     program += &format!(r#"
-    if(addr > {}ULL - sizeof({}) ||
+    if(addr < {:#x}ULL || addr > state->memory_len - sizeof({}) ||
             (*({}*)(state->permissions + addr) & {:#x}ULL) != {:#x}ULL) {{
         state->exit_reason = ReadFault;
         state->reenter_pc  = {:#x}ULL;
         return;
     }}
-    "#, self.memory.len(), loadtyp, loadtyp, perm_mask, perm_mask, pc.0);
+    "#, inst.imm.max(0), loadtyp, loadtyp, perm_mask, perm_mask, pc.0);
 
                     set_reg!(inst.rd, format!("*({}*)(state->memory + addr)",
                         loadtyp));
@@ -1597,13 +4091,17 @@ extern "C" void start(struct _state *__restrict state) {
                         _ => unreachable!(),
                     };
                     
-                    // Compute the write permission mask and the RAW permission
-                    // mask
+                    // Compute the write permission mask, the RAW permission
+                    // mask, and the execute permission mask. Writes to
+                    // executable memory exit as a write fault, such that the
+                    // emulator performs them and invalidates the JIT.
                     let mut perm_mask = 0u64;
                     let mut raw_mask = 0u64;
+                    let mut exec_mask = 0u64;
                     for ii in 0..access_size {
                         perm_mask |= (PERM_WRITE as u64) << (ii * 8);
                         raw_mask  |= (PERM_RAW as u64) << (ii * 8);
+                        exec_mask |= (PERM_EXEC as u64) << (ii * 8);
                     }
                     
                     // Compute the address
@@ -1611,19 +4109,21 @@ extern "C" void start(struct _state *__restrict state) {
                     program += &format!("    addr += {:#x}ULL;\n",
                         inst.imm as i64 as u64);
                     
-                    // Check the bounds and permissions of the address
+                    // Check the bounds and permissions of the address, the
+                    // emulator reports addresses which wrapped around
                     program += &format!(r#"
-    if(addr > {}ULL - sizeof({}) ||
+    if(addr < {:#x}ULL || addr > state->memory_len - sizeof({}) ||
             (*({}*)(state->permissions + addr) & {:#x}ULL) != {:#x}ULL) {{
         state->exit_reason = WriteFault;
         state->reenter_pc  = {:#x}ULL;
         return;
     }}
 
-    // Enable reads for memory with RAW set
+    // Enable reads for memory with RAW set, clearing RAW like the emulator
     auto perms = *({}*)(state->permissions + addr);
     perms &= {:#x}ULL;
-    *({}*)(state->permissions + addr) |= perms >> 3;
+    *({}*)(state->permissions + addr) =
+        (*({}*)(state->permissions + addr) | (perms >> 3)) & ~perms;
 
     auto block = addr / {};
     auto idx   = block / 64;
@@ -1632,9 +4132,8 @@ extern "C" void start(struct _state *__restrict state) {
         state->dirty[state->dirty_idx++] = block;
         state->dirty_bitmap[idx] |= bit;
     }}
-    "#, self.memory.len(),
-        storetyp, storetyp, perm_mask, perm_mask, pc.0, storetyp, raw_mask,
-        storetyp, DIRTY_BLOCK_SIZE);
+    "#, inst.imm.max(0), storetyp, storetyp, perm_mask | exec_mask, perm_mask,
+        pc.0, storetyp, raw_mask, storetyp, storetyp, DIRTY_BLOCK_SIZE);
 
                     // Write the memory!
                     get_reg!(format!("*({}*)(state->memory + addr)",
@@ -1652,11 +4151,12 @@ extern "C" void start(struct _state *__restrict state) {
                                 inst.imm as i64 as u64));
                         }
                         0b010 => {
-                            // SLTI
+                            // SLTI, the hex literal of a negative immediate
+                            // is unsigned so it's cast back to signed
                             get_reg!("auto rs1", inst.rs1);
-                            set_reg!(inst.rd,
-                                format!("((int64_t)rs1 < {:#x}LL) ? 1 : 0",
-                                inst.imm as i64));
+                            set_reg!(inst.rd, format!(
+                                "((int64_t)rs1 < (int64_t){:#x}ULL) ? 1 : 0",
+                                inst.imm as i64 as u64));
                         }
                         0b011 => {
                             // SLTIU
@@ -1694,7 +4194,7 @@ extern "C" void start(struct _state *__restrict state) {
                                     set_reg!(inst.rd, format!("rs1 << {}",
                                         shamt));
                                 }
-                                _ => unreachable!(),
+                                _ => invalid_opcode!(pc),
                             }
                         }
                         0b101 => {
@@ -1716,7 +4216,7 @@ extern "C" void start(struct _state *__restrict state) {
                                              format!("(int64_t)rs1 >> {}",
                                         shamt));
                                 }
-                                _ => unreachable!(),
+                                _ => invalid_opcode!(pc),
                             }
                         }
                         _ => unreachable!(),
@@ -1835,10 +4335,19 @@ extern "C" void start(struct _state *__restrict state) {
                 0b0001111 => {
                     let inst = Itype::from(inst);
 
+                    // There is only ever one hart, so all memory accesses
+                    // are already observed in program order. Instruction
+                    // fetches see stores to code right away as well: the
+                    // interpreter fetches from memory, and the JIT exits on
+                    // stores to executable memory and never runs code lifted
+                    // from before them, see `code_diverged()`.
                     match inst.funct3 {
                         0b000 => {
                             // FENCE
                         }
+                        0b001 => {
+                            // FENCE.I
+                        }
                         _ => unreachable!(),
                     }
                 }
@@ -1884,7 +4393,7 @@ extern "C" void start(struct _state *__restrict state) {
                                         format!("rs1 << {}",
                                         shamt));
                                 }
-                                _ => unreachable!(),
+                                _ => invalid_opcode!(pc),
                             }
                         }
                         0b101 => {
@@ -1907,7 +4416,7 @@ extern "C" void start(struct _state *__restrict state) {
                                         format!("(int32_t)rs1 >> {}",
                                         shamt));
                                 }
-                                _ => unreachable!(),
+                                _ => invalid_opcode!(pc),
                             }
                         }
                         _ => unreachable!(),
@@ -1925,81 +4434,1475 @@ extern "C" void start(struct _state *__restrict state) {
         // Close the function scope
         program += "}\n";
         
-        // Hash the C++ file contents
-        let proghash = corpus.hasher.hash(program.as_bytes());
+        Ok(program)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::jitcache::JitConfig;
+
+    /// Size of the guest memory of the test programs
+    const MEMORY_SIZE: usize = 1024 * 1024;
+
+    /// Environment variable which makes tests fail, rather than skip their
+    /// JIT half, if the JIT toolchain is missing
+    const REQUIRE_JIT: &str = "FWE_REQUIRE_JIT";
+
+    /// Write `prog` to a new 4 KiB executable allocation of `emu` and point
+    /// PC at it. Returns the address of the code.
+    fn write_prog(emu: &mut Emulator, prog: &[u32]) -> VirtAddr {
+        let code = emu.memory.allocate(4096).unwrap();
+        for (ii, inst) in prog.iter().enumerate() {
+            emu.memory.write(VirtAddr(code.0 + ii * 4), *inst).unwrap();
+        }
+        emu.memory.set_permissions(code, 4096,
+                                   Perm(PERM_READ | PERM_EXEC)).unwrap();
+        emu.set_reg(Register::Pc, code.0 as u64);
+        code
+    }
+
+    /// Create an emulator running `prog`, see `write_prog()`
+    fn load_prog(prog: &[u32]) -> (Emulator, VirtAddr) {
+        let mut emu = Emulator::new(MEMORY_SIZE);
+        let code = write_prog(&mut emu, prog);
+        (emu, code)
+    }
+
+    /// Fork an emulator which resets to `snapshot` before every fuzz case
+    fn fork_snapshot(snapshot: &Arc<Emulator>) -> Emulator {
+        let mut emu = snapshot.fork();
+        emu.set_snapshot(snapshot.clone());
+        emu
+    }
+
+    /// Check if the JIT halves of the tests can run. They are skipped if the
+    /// toolchain is missing, unless `FWE_REQUIRE_JIT` is set, such that CI
+    /// never silently leaves the JIT untested.
+    fn jit_available() -> bool {
+        let available = JitConfig::default().toolchain_available();
+        assert!(available || std::env::var_os(REQUIRE_JIT).is_none(),
+                "{} is set, but the JIT toolchain is missing", REQUIRE_JIT);
+        available
+    }
+
+    /// Enable the JIT on `emu` with a new JIT cache and compile all the code
+    /// its fuzz case runs. Returns the snapshot to run fuzz cases with the
+    /// JIT from, or `None` if the JIT is not available.
+    fn jit_snapshot(emu: Emulator) -> Option<Arc<Emulator>> {
+        if !jit_available() {
+            return None;
+        }
+
+        let jit_cache = Arc::new(JitCache::new(
+            VirtAddr(emu.memory.max_size()), JitConfig::default()));
+        let snapshot = Arc::new(emu.enable_jit(jit_cache.clone()));
+        run_compiled(snapshot.clone(), &jit_cache);
+        Some(snapshot)
+    }
+
+    /// Run the fuzz case of `emu` with the JIT, see `jit_snapshot()`. Returns
+    /// how the case exited and the instructions it executed, or `None` if the
+    /// JIT is not available.
+    fn run_jit(emu: Emulator) -> Option<(VmExit, u64)> {
+        let snapshot  = jit_snapshot(emu)?;
+        let jit_cache = snapshot.jit_cache.clone().unwrap();
+        Some(run_compiled(snapshot, &jit_cache))
+    }
+
+    /// Run the fuzz case of `snapshot` using `jit_cache` once all the code it
+    /// runs has been compiled, with a fresh corpus such that the JIT reports
+    /// all coverage. Returns how the case exited and the instructions it
+    /// executed.
+    fn run_compiled(snapshot: Arc<Emulator>, jit_cache: &JitCache)
+            -> (VmExit, u64) {
+        let corpus = Corpus::new();
+        let mut emu = snapshot.fork();
+        emu.set_snapshot(snapshot);
+
+        loop {
+            emu.run_input(b"", &corpus);
+            if !jit_cache.compiling() {
+                break;
+            }
+
+            while jit_cache.compiling() {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+        }
+
+        let vmexit = emu.run_input(b"", &Corpus::new());
+        (vmexit, emu.instrs_execed())
+    }
+
+    #[test]
+    fn test_disasm() {
+        assert!(disasm(0x00150513) == "addi a0, a0, 1");
+        assert!(disasm(0x00813083) == "ld ra, 8(sp)");
+        assert!(disasm(0x00113423) == "sd ra, 8(sp)");
+        assert!(disasm(0x00b50463) == "beq a0, a1, 8");
+        assert!(disasm(0x40b50533) == "sub a0, a0, a1");
+        assert!(disasm(0x00000073) == "ecall");
+        assert!(disasm(0xffffffff) == ".word 0xffffffff");
+    }
 
-        // Check if we're the first core to try to compile this
-        let first = {
-            let mut jobs = corpus.compile_jobs.lock().unwrap();
-            jobs.insert(proghash)
+    #[test]
+    fn test_setup_stack() {
+        let emu = EmulatorBuilder::new()
+            .memory_size(1024 * 1024)
+            .args(&["prog", "-x"])
+            .env(&["A=1"])
+            .build()
+            .unwrap();
+
+        // Read a nul-terminated string from guest memory
+        let string = |addr: u64| {
+            emu.memory.read_cstr(VirtAddr(addr as usize), 16).unwrap()
+                .unwrap()
+        };
+        let stack = |idx: usize| -> u64 {
+            let sp = emu.reg(Register::Sp) as usize;
+            emu.memory.read(VirtAddr(sp + idx * 8)).unwrap()
         };
-        
-        // Create the jitcache folder
-        std::fs::create_dir_all("jitcache")
-            .expect("Failed to create jitcache directory");
 
-        // Create the cache name
-        let cachename = Path::new("jitcache")
-            .join(format!("{:032x}", proghash));
+        assert!(stack(0) == 2);
+        assert!(string(stack(1)) == b"prog");
+        assert!(string(stack(2)) == b"-x");
+        assert!(stack(3) == 0);
+        assert!(string(stack(4)) == b"A=1");
+        assert!(stack(5) == 0);
+        assert!(stack(6) == AT_PAGESZ);
+        assert!(stack(7) == 4096);
+        assert!(stack(8) == AT_RANDOM);
+        let mut random = [0u8; 16];
+        emu.memory.read_into(VirtAddr(stack(9) as usize), &mut random)
+            .unwrap();
+        assert!(random == AUXV_RANDOM);
+        assert!(stack(10) == AT_NULL);
+        assert!(stack(11) == 0);
+
+        // With the program headers known, they are passed on as well
+        let mut emu = Emulator::new(1024 * 1024);
+        emu.set_elf_info(ElfInfo {
+            entry: VirtAddr(0x10100),
+            phdr:  VirtAddr(0x10040),
+            phent: 56,
+            phnum: 4,
+        });
+        emu.setup_stack(&["prog"], &[] as &[&[u8]]).unwrap();
+        let sp = emu.reg(Register::Sp) as usize;
+        let auxv: Vec<u64> = (4..18).map(|idx| {
+            emu.memory.read(VirtAddr(sp + idx * 8)).unwrap()
+        }).collect();
+        assert!(auxv[4..] == [AT_PHDR, 0x10040, AT_PHENT, 56, AT_PHNUM, 4,
+                              AT_ENTRY, 0x10100, AT_NULL, 0]);
+    }
 
-        // If we aren't the first to access the cache, idle loop until the
-        // first person has compiled the code
-        if !first {
-            while !cachename.exists() {
-                std::thread::sleep(Duration::from_millis(100));
+    #[test]
+    fn test_layout_seed() {
+        // Get the stack pointer and the first heap allocation of a layout
+        let layout = |seed: Option<u64>| {
+            let mut builder = EmulatorBuilder::new()
+                .memory_size(1024 * 1024)
+                .args(&["prog"]);
+            if let Some(seed) = seed {
+                builder = builder.layout_seed(seed);
             }
+            let mut emu = builder.build().unwrap();
+            (emu.reg(Register::Sp), emu.memory.allocate(16).unwrap())
+        };
+
+        let (sp, heap) = layout(None);
+        assert!(layout(Some(1)) == layout(Some(1)));
+        assert!(layout(Some(1)) != layout(Some(2)));
+        for seed in 0..16 {
+            let (rand_sp, rand_heap) = layout(Some(seed));
+            assert!(rand_sp >= sp && rand_heap >= heap);
+            assert!(rand_sp - sp < LAYOUT_MAX_GAP as u64);
+            assert!(rand_heap.0 - heap.0 < 2 * LAYOUT_MAX_GAP);
+            assert!(rand_sp % 0x1000 == sp % 0x1000);
         }
+    }
 
-        // If the cache exists, read the cache
-        if cachename.exists() {
-            return Ok(std::fs::read(&cachename)
-                .expect("Failed to read file from jit cache"));
+    #[test]
+    fn test_run_input() {
+        // addi a0, a0, 1; addi a0, a0, 1; li a7, 93; ecall
+        let (emu, _) = load_prog(
+            &[0x00150513, 0x00150513, 0x05d00893, 0x00000073]);
+
+        // Every run starts from the snapshot, so exits with the same status
+        let mut emu = fork_snapshot(&Arc::new(emu));
+        for _ in 0..2 {
+            assert!(emu.run_input(b"", &Corpus::new()) == VmExit::Exit(2));
+            assert!(emu.instrs_execed() == 4);
+        }
+    }
+
+    #[test]
+    fn test_open_fuzz_file() {
+        let mut emu = EmulatorBuilder::new()
+            .memory_size(1024 * 1024)
+            .fuzz_files(vec![b"a".to_vec(), b"b".to_vec()])
+            .open_fuzz_file(b"b".to_vec())
+            .build().unwrap();
+
+        // Opened after stdin, stdout and stderr
+        assert!(*emu.files.get_file(3).unwrap() ==
+                Some(EmuFile::FuzzInput { index: 1, cursor: 0 }));
+        assert!(emu.open_fuzz_file(b"a") == Some(4));
+        assert!(emu.open_fuzz_file(b"testfn").is_none());
+        assert!(emu.files.get_file(5).is_none());
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let corpus = Corpus::new();
+        let mut emu = Emulator::new(MEMORY_SIZE);
+        let data = emu.memory.allocate(8).unwrap();
+
+        // addi a0, a0, 1; addi a0, a0, 1; li a7, 93; ecall
+        write_prog(&mut emu, &[0x00150513, 0x00150513, 0x05d00893, 0x00000073]);
+        let mut emu = fork_snapshot(&Arc::new(emu));
+
+        // Snapshot after some "initialization"
+        emu.set_reg(Register::A0, 10);
+        emu.memory.write(data, 7u64).unwrap();
+        emu.files.insert(3, EmuFile::FuzzInput { index: 0, cursor: 0 });
+        let snapshot = emu.snapshot();
+
+        emu.set_reg(Register::A0, 20);
+        emu.memory.write(data, 8u64).unwrap();
+        *emu.files.get_file(3).unwrap() = None;
+        emu.restore(&snapshot);
+        assert!(emu.reg(Register::A0) == 10);
+        assert!(emu.memory.read::<u64>(data).unwrap() == 7);
+        assert!(emu.files.get_file(3).unwrap().is_some());
+
+        // Fuzz cases now start from the snapshot
+        for _ in 0..2 {
+            emu.memory.write(data, 9u64).unwrap();
+            assert!(emu.run_input(b"", &corpus) == VmExit::Exit(12));
+            assert!(emu.memory.read::<u64>(data).unwrap() == 7);
+        }
+    }
+
+    #[test]
+    fn test_argv_input() {
+        let corpus = Corpus::new();
+
+        let mut emu = EmulatorBuilder::new()
+            .memory_size(MEMORY_SIZE)
+            .args(&["prog"])
+            .argv_input()
+            .build()
+            .unwrap();
+
+        // ld a0, 0(sp); li a7, 93; ecall, exits with argc as the status
+        write_prog(&mut emu, &[0x00013503, 0x05d00893, 0x00000073]);
+        let mut emu = fork_snapshot(&Arc::new(emu));
+
+        // No input means only the fixed arguments
+        assert!(emu.run_input(b"", &corpus) == VmExit::Exit(1));
+        assert!(emu.run_input(b"-a\0-b", &corpus) == VmExit::Exit(3));
+
+        // Oversized argv is truncated rather than overflowing the stack
+        let input = vec![0u8; 1024 * 1024];
+        assert!(emu.run_input(&input, &corpus) ==
+                VmExit::Exit(1 + MAX_INPUT_ARGS as i64));
+    }
+
+    #[test]
+    fn test_jit_block_limit() {
+        if !jit_available() {
+            return;
+        }
+
+        // Run the program with the JIT limited to `max` instructions per
+        // compile unit
+        let run = |max: usize| {
+            let mut jit_cache =
+                JitCache::new(VirtAddr(MEMORY_SIZE), JitConfig::default());
+            jit_cache.set_max_block_instrs(max);
+            let jit_cache = Arc::new(jit_cache);
+
+            // li a0, 0; li a1, 10
+            // loop: addi a0, a0, 3; addi a0, a0, -1; addi a1, a1, -1
+            // bnez a1, loop; li a7, 93; ecall; j .
+            let (emu, _) = load_prog(&[
+                0x00000513, 0x00a00593, 0x00350513, 0xfff50513,
+                0xfff58593, 0xfe059ae3, 0x05d00893, 0x00000073,
+                0x0000006f,
+            ]);
+            run_compiled(Arc::new(emu.enable_jit(jit_cache.clone())),
+                         &jit_cache)
+        };
+
+        let unsplit = run(jitcache::DEFAULT_MAX_BLOCK_INSTRS);
+        assert!(unsplit == (VmExit::Exit(20), 44));
+        assert!(run(1) == unsplit);
+        assert!(run(3) == unsplit);
+    }
+
+    #[test]
+    fn test_compile_failure() {
+        if !jit_available() {
+            return;
+        }
+
+        // A compiler which rejects everything
+        let jit_cache = Arc::new(JitCache::new(VirtAddr(MEMORY_SIZE),
+            JitConfig {
+                extra_flags: vec!["-fno-such-option".into()],
+                ..JitConfig::default()
+            }));
+
+        // li a0, 5; li a7, 93; ecall; j .
+        let (emu, _) =
+            load_prog(&[0x00500513, 0x05d00893, 0x00000073, 0x0000006f]);
+
+        // The failure is reported and the code is left to the interpreter,
+        // rather than being compiled forever
+        let snapshot = Arc::new(emu.enable_jit(jit_cache.clone()));
+        assert!(run_compiled(snapshot.clone(), &jit_cache).0 ==
+                VmExit::Exit(5));
+        assert!(jit_cache.compile_failures() > 0);
+        let mut emu = fork_snapshot(&snapshot);
+        assert!(emu.run_input(b"", &Corpus::new()) == VmExit::Exit(5));
+        assert!(!jit_cache.compiling());
+    }
+
+    #[test]
+    fn test_call_stack() {
+        // jal ra, f; jal ra, g; li a7, 93; ecall
+        // f: ret
+        // g: ld a0, 0(zero); j .
+        let (emu, code) = load_prog(&[
+            0x010000ef, 0x010000ef, 0x05d00893, 0x00000073, 0x00008067,
+            0x00003503, 0x0000006f,
+        ]);
+
+        // The call to `f` returned, the call to `g` crashed
+        let check = |emu: &mut Emulator| {
+            assert!(emu.run_input(b"", &Corpus::new()).is_crash().is_some());
+            assert!(emu.call_stack() == vec![VirtAddr(code.0 + 8)]);
+        };
+
+        let mut interp = fork_snapshot(&Arc::new(emu.fork()));
+        check(&mut interp);
+        check(&mut interp);
+        assert!(interp.call_stack_hash(1) != interp.call_stack_hash(0));
+
+        if let Some(snapshot) = jit_snapshot(emu) {
+            check(&mut fork_snapshot(&snapshot));
+        }
+
+        // jal ra, f; ebreak; f: j .
+        //
+        // With a breakpoint replacing `f`, which returns to the caller
+        let (mut emu, code) = load_prog(&[0x008000ef, 0x00100073, 0x0000006f]);
+        fn ret_bp(emu: &mut Emulator) -> Result<(), VmExit> {
+            emu.return_from_breakpoint();
+            Ok(())
+        }
+        emu.add_breakpoint(VirtAddr(code.0 + 8), ret_bp);
+
+        // The call is popped as if `f` returned by itself
+        let check = |emu: &mut Emulator| {
+            assert!(emu.run_input(b"", &Corpus::new()) == VmExit::Ebreak);
+            assert!(emu.call_stack().is_empty());
+        };
+
+        check(&mut fork_snapshot(&Arc::new(emu.fork())));
+
+        if let Some(snapshot) = jit_snapshot(emu) {
+            check(&mut fork_snapshot(&snapshot));
+        }
+    }
+
+    #[test]
+    fn test_stack_guard() {
+        // jal ra, __stack_chk_fail; li a7, 93; ecall
+        // __stack_chk_fail: j .
+        let (mut emu, code) =
+            load_prog(&[0x00c000ef, 0x05d00893, 0x00000073, 0x0000006f]);
+
+        // Nothing to hook without symbols
+        assert!(emu.enable_stack_guard().is_none());
+        let symbols = Symbols::new(vec![
+            (VirtAddr(code.0 + 12), STACK_CHK_FAIL.into()),
+            (code, "main".into()),
+        ]);
+        let mut emu = emu.enable_symbols(Arc::new(symbols));
+        assert!(emu.enable_stack_guard() == Some(VirtAddr(code.0 + 12)));
+
+        let mut interp = fork_snapshot(&Arc::new(emu.fork()));
+        let vmexit = interp.run_input(b"", &Corpus::new());
+        assert!(vmexit == VmExit::StackGuard);
+        assert!(vmexit.is_crash() ==
+                Some((FaultType::StackGuard, VirtAddr(0))));
+        assert!(interp.call_stack() == vec![VirtAddr(code.0 + 4)]);
+
+        if let Some(snapshot) = jit_snapshot(emu) {
+            assert!(fork_snapshot(&snapshot).run_input(b"", &Corpus::new()) ==
+                    VmExit::StackGuard);
+        }
+    }
+
+    #[test]
+    fn test_exec_fault_type() {
+        // jr a0
+        let (mut emu, _) = load_prog(&[0x00050067]);
+        let data = emu.memory.allocate(4096).unwrap();
+
+        // Jumping into data is told apart from jumping into nowhere
+        for &(target, fault_type) in &[
+            (data, FaultType::ExecData),
+            (VirtAddr(0x10), FaultType::Exec),
+        ] {
+            emu.set_reg(Register::A0, target.0 as u64);
+            let mut interp = fork_snapshot(&Arc::new(emu.fork()));
+            let vmexit = interp.run_input(b"", &Corpus::new());
+            assert!(vmexit == VmExit::ExecFault(target));
+            assert!(vmexit.is_crash() == Some((FaultType::Exec, target)));
+            assert!(interp.crash_type(&vmexit) == Some((fault_type, target)));
+        }
+    }
+
+    #[test]
+    fn test_double_free() {
+        let mut emu = Emulator::new(1024 * 1024);
+        let alc = emu.memory.allocate(32).unwrap();
+
+        // Freeing twice is told apart from freeing something never allocated
+        assert!(emu.memory.free(alc).is_ok());
+        assert!(emu.memory.free(alc) == Err(VmExit::DoubleFree(alc)));
+        let inner = VirtAddr(alc.0 + 8);
+        assert!(emu.memory.free(inner) == Err(VmExit::InvalidFree(inner)));
+
+        assert!(VmExit::DoubleFree(alc).is_crash() ==
+                Some((FaultType::DoubleFree, alc)));
+        assert!(VmExit::InvalidFree(inner).is_crash() ==
+                Some((FaultType::Free, inner)));
+    }
+
+    #[test]
+    fn test_memset_bp() {
+        // 1: sb a1, 0(a0); addi a0, a0, 1; addi a2, a2, -1; bnez a2, 1b
+        // li a7, 93; ecall; j .
+        let prog: [u32; 7] = [
+            0x00b50023, 0x00150513, 0xfff60613, 0xfe061ae3,
+            0x05d00893, 0x00000073, 0x0000006f,
+        ];
+        let (mut emu, code) = load_prog(&prog);
+        emu.memory.set_detect_uninit(true);
+        let buf = emu.memory.allocate(4096).unwrap();
+        emu.set_reg(Register::Ra, code.0 as u64 + 16);
+        emu.set_reg(Register::A0, buf.0 as u64);
+        emu.set_reg(Register::A1, 0x41);
+        emu.set_reg(Register::A2, 4096);
+
+        let mut interp = fork_snapshot(&Arc::new(emu.fork()));
+        assert!(interp.run_input(b"", &Corpus::new()) ==
+                VmExit::Exit(buf.0 as i64 + 4096));
+        let mut bulk = emu.fork();
+        memset_bp(&mut bulk).unwrap();
+        assert!(bulk.reg(Register::Pc) == code.0 as u64 + 16);
+        assert!(bulk.reg(Register::A0) == buf.0 as u64);
+
+        // Same contents, and both made the bytes readable
+        let mut expected = vec![0u8; 4096];
+        let mut actual   = vec![0u8; 4096];
+        interp.memory.read_into(buf, &mut expected).unwrap();
+        bulk.memory.read_into(buf, &mut actual).unwrap();
+        assert!(expected == actual && actual == vec![0x41; 4096]);
+        assert!(bulk.memory.read::<u8>(VirtAddr(buf.0 + 4096)) ==
+                interp.memory.read::<u8>(VirtAddr(buf.0 + 4096)));
+
+        // Overlapping copies behave like `memmove()`
+        bulk.memory.write_from(buf, b"abcdef").unwrap();
+        bulk.set_reg(Register::A0, buf.0 as u64 + 2);
+        bulk.set_reg(Register::A1, buf.0 as u64);
+        bulk.set_reg(Register::A2, 4);
+        memcpy_bp(&mut bulk).unwrap();
+        let mut copied = [0u8; 6];
+        bulk.memory.read_into(buf, &mut copied).unwrap();
+        assert!(&copied == b"ababcd");
+
+        // Faults are reported at the first bad byte
+        let code_end = VirtAddr(code.0 + 4096);
+        assert!(bulk.memory.memset(code, 0, 4) ==
+                Err(VmExit::WriteFault(code)));
+        assert!(bulk.memory.memmove(buf, VirtAddr(code_end.0 - 2), 4) ==
+                Err(VmExit::ReadFault(code_end)));
+
+        // Bogus lengths fault before anything is copied
+        bulk.set_reg(Register::A0, buf.0 as u64);
+        bulk.set_reg(Register::A1, buf.0 as u64 + 8);
+        bulk.set_reg(Register::A2, !0);
+        assert!(memcpy_bp(&mut bulk) ==
+                Err(VmExit::AddressIntegerOverflow(VirtAddr(buf.0 + 8))));
+        bulk.set_reg(Register::A2, 1 << 40);
+        assert!(memcpy_bp(&mut bulk) ==
+                Err(VmExit::AddressMiss(VirtAddr(buf.0 + 8), 1 << 40)));
+        bulk.memory.read_into(buf, &mut copied).unwrap();
+        assert!(&copied == b"ababcd");
+    }
+
+    #[test]
+    fn test_dump_layout() {
+        let mut emu = Emulator::new(1024 * 1024);
+        emu.memory.set_permissions(VirtAddr(0x1000), 0x800,
+                                   Perm(PERM_READ | PERM_EXEC)).unwrap();
+        emu.memory.set_permissions(VirtAddr(0x1800), 0x800,
+                                   Perm(PERM_READ | PERM_WRITE)).unwrap();
+        emu.memory.set_detect_uninit(true);
+        let alc = emu.memory.allocate(0x10).unwrap();
+        let freed = emu.memory.allocate(0x20).unwrap();
+        emu.memory.free(freed).unwrap();
+        emu.memory.write(alc, 0u32).unwrap();
+
+        // Freed allocations are gone, partially initialized ones are whole
+        assert!(emu.memory.dump_layout() == format!(
+            "0x0000000000001000-0x0000000000001800 r-x-\n\
+             0x0000000000001800-0x0000000000002000 rw--\n\
+             {:#018x}-{:#018x} alloc 0x10 bytes\n", alc.0, alc.0 + 0x10));
+
+        emu.setup_stack(&[b"prog".as_ref()], &[b"A=1".as_ref()]).unwrap();
+        let (stack, size) = emu.stack;
+        assert!(emu.dump_layout().starts_with(&format!(
+            "{:#018x}-{:#018x} stack\n", stack.0, stack.0 + size)));
+        assert!(emu.dump_layout().contains(&format!(
+            "{:#018x}-{:#018x} alloc", stack.0, stack.0 + size)));
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        // f: addi sp, sp, -1024; sd ra, 0(sp); jal ra, f
+        let prog: [u32; 3] = [0xc0010113, 0x00113023, 0xff9ff0ef];
+        let (mut emu, _) = load_prog(&prog);
+        emu.setup_stack(&[b"prog".as_ref()], &[b"A=1".as_ref()]).unwrap();
+
+        // Nothing is mapped right below the stack
+        let (stack, _) = emu.stack;
+        assert!(!emu.memory.is_mapped(VirtAddr(stack.0 - STACK_GUARD_SIZE),
+                                      STACK_GUARD_SIZE));
+
+        // Unbounded recursion runs into the guard rather than the code
+        let mut interp = fork_snapshot(&Arc::new(emu.fork()));
+        let vmexit = interp.run_input(b"", &Corpus::new());
+        let sp = VirtAddr(interp.reg(Register::Sp) as usize);
+        assert!(sp < stack && vmexit == VmExit::WriteFault(sp));
+        assert!(interp.crash_type(&vmexit) ==
+                Some((FaultType::StackOverflow, sp)));
+
+        // Faults elsewhere are left alone
+        let fault = VmExit::WriteFault(VirtAddr(0x10));
+        assert!(interp.crash_type(&fault) ==
+                Some((FaultType::Write, VirtAddr(0x10))));
+    }
+
+    #[test]
+    fn test_harness() {
+        // f: add t0, a0, a1; lbu t0, -1(t0); ret
+        let prog: [u32; 3] = [0x00b502b3, 0xfff2c283, 0x00008067];
+        let (mut emu, code) = load_prog(&prog);
+        emu.setup_stack(&[b"prog".as_ref()], &[b"A=1".as_ref()]).unwrap();
+        emu.enable_harness(FunctionHarness {
+            entry:     code,
+            input_arg: 0,
+            len_arg:   Some(1),
+        }).unwrap();
+
+        // The function reads the last byte of the input and returns to the
+        // sentinel, reading before an empty input faults
+        let check = |snapshot: Arc<Emulator>| {
+            let mut emu = fork_snapshot(&snapshot);
+            assert!(emu.run_input(b"abc", &Corpus::new()) == VmExit::Exit(0));
+            assert!(emu.reg(Register::T0) == b'c' as u64);
+            assert!(emu.reg(Register::A1) == 3);
+            let addr = emu.reg(Register::A0) as usize;
+            assert!(emu.run_input(b"", &Corpus::new()) ==
+                    VmExit::ReadFault(VirtAddr(addr - 1)));
+        };
+
+        check(Arc::new(emu.fork()));
+
+        if let Some(snapshot) = jit_snapshot(emu) {
+            check(snapshot);
+        }
+    }
+
+    #[test]
+    fn test_address_overflow() {
+        // lb t0, -1(zero); j .
+        let load: [u32; 2] = [0xfff00283, 0x0000006f];
+
+        // li t1, -1; sb zero, 16(t1); j .
+        let store: [u32; 3] = [0xfff00313, 0x00030823, 0x0000006f];
+
+        for (prog, addr) in &[(&load[..], !0), (&store[..], 15)] {
+            let (emu, _) = load_prog(prog);
+
+            // Accesses which wrap around are told apart from bounds misses
+            let expected = VmExit::AddressIntegerOverflow(VirtAddr(*addr));
+            let mut interp = fork_snapshot(&Arc::new(emu.fork()));
+            let vmexit = interp.run_input(b"", &Corpus::new());
+            assert!(vmexit == expected);
+            assert!(interp.crash_type(&vmexit) ==
+                    Some((FaultType::AddressOverflow, VirtAddr(*addr))));
+
+            if let Some(snapshot) = jit_snapshot(emu) {
+                let mut jit = fork_snapshot(&snapshot);
+                assert!(jit.run_input(b"", &Corpus::new()) == expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_dry_run() {
+        // beq a0, zero, 1f; 1: li a7, 93; ecall; j .
+        let prog: [u32; 4] = [0x00050263, 0x05d00893, 0x00000073, 0x0000006f];
+        let (emu, code) = load_prog(&prog);
+
+        let mut emu = fork_snapshot(&Arc::new(emu.fork()));
+
+        // The edges taken are returned rather than saved to the corpus
+        let corpus = Corpus::new();
+        let (vmexit, edges) = emu.dry_run(b"seed", &corpus);
+        assert!(vmexit == VmExit::Exit(0));
+        assert!(edges.contains(&(VirtAddr(code.0), VirtAddr(code.0 + 4))));
+        assert!(corpus.code_coverage.len() == 0);
+        assert!(corpus.inputs.len() == 0);
+
+        // Later fuzz cases still report the edges
+        assert!(emu.run_input(b"seed", &corpus) == VmExit::Exit(0));
+        assert!(corpus.code_coverage.len() == edges.len());
+    }
+
+    #[test]
+    fn test_malformed_elf() {
+        // Header and program header offsets which overflow when indexed are
+        // rejected rather than panicking
+        let mut elf = vec![0u8; 0x40];
+        elf[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        elf[0x20..0x28].copy_from_slice(&(!0u64 - 8).to_le_bytes());
+        elf[0x28..0x30].copy_from_slice(&(!0u64 - 8).to_le_bytes());
+        elf[0x36..0x38].copy_from_slice(&0x38u16.to_le_bytes());
+        elf[0x38..0x3a].copy_from_slice(&2u16.to_le_bytes());
+        elf[0x3a..0x3c].copy_from_slice(&0x40u16.to_le_bytes());
+        elf[0x3c..0x3e].copy_from_slice(&2u16.to_le_bytes());
+        assert!(Symbols::parse(&elf).is_none());
+        assert!(ElfInfo::parse(&elf).is_none());
+    }
+
+    #[test]
+    fn test_fuzz_end() {
+        // li a0, 1; li a0, 2; li a7, 93; ecall; j .
+        let prog: [u32; 5] = [
+            0x00100513, 0x00200513, 0x05d00893, 0x00000073, 0x0000006f,
+        ];
+        let (mut emu, code) = load_prog(&prog);
+        emu.add_fuzz_end(VirtAddr(code.0 + 4));
+
+        // The case ends before the second instruction
+        let check = |emu: &mut Emulator| {
+            assert!(emu.run_input(b"", &Corpus::new()) == VmExit::Exit(0));
+            assert!(emu.reg(Register::A0) == 1);
+            assert!(emu.reg(Register::Pc) == code.0 as u64 + 4);
+        };
+
+        check(&mut fork_snapshot(&Arc::new(emu.fork())));
+
+        if let Some(snapshot) = jit_snapshot(emu) {
+            check(&mut fork_snapshot(&snapshot));
+        }
+    }
+
+    #[test]
+    fn test_coverage_sync() {
+        // jal ra, 1f; j .; 1: li a7, 93; ecall; j .
+        let prog: [u32; 5] =
+            [0x008000ef, 0x0000006f, 0x05d00893, 0x00000073, 0x0000006f];
+        let (emu, _) = load_prog(&prog);
+        let snapshot = Arc::new(emu.fork());
+        let corpus = Corpus::new();
+
+        // Nothing to merge yet
+        let mut other = fork_snapshot(&snapshot);
+        other.local_coverage(&corpus);
+        assert!(other.cov_shards.iter().all(|&bits| bits == 0));
+
+        // Every bit set in the shared bitmap is counted in its shard
+        let mut emu = fork_snapshot(&snapshot);
+        assert!(emu.run_input(b"", &corpus) == VmExit::Exit(0));
+        let bits = corpus.coverage_bits_set();
+        assert!(bits > 0);
+        assert!(corpus.coverage_shards.iter()
+            .map(|bits| bits.load(Ordering::Relaxed)).sum::<u64>() == bits);
+
+        // Only the shards with coverage are merged, and the local bitmap
+        // ends up with all of it
+        other.sync_coverage(&corpus);
+        for (seen, bits) in other.cov_shards.iter()
+                .zip(corpus.coverage_shards.iter()) {
+            assert!(*seen == bits.load(Ordering::Relaxed));
+        }
+        assert!(other.cov_shards.iter().filter(|&&bits| bits > 0).count() as
+                u64 <= bits);
+        for (local, shared) in other.cov_bitmap.iter()
+                .zip(corpus.coverage_bitmap.iter()) {
+            assert!(*local == shared.load(Ordering::Relaxed));
+        }
+
+        // Such that the other emulator finds nothing new
+        assert!(other.run_input(b"", &corpus) == VmExit::Exit(0));
+        assert!(!other.found_coverage());
+        assert!(corpus.coverage_bits_set() == bits);
+    }
+
+    #[test]
+    fn test_coverage_fast_path() {
+        // Counts the edges reported to it, which saves the input every time
+        struct CountFeedback(Arc<std::sync::atomic::AtomicU64>);
+
+        impl crate::Feedback for CountFeedback {
+            fn on_coverage(&self, _corpus: &Corpus, _from: VirtAddr,
+                           _to: VirtAddr, _input: &[u8]) -> bool {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+        }
+
+        // jal ra, 1f; j .; 1: li a7, 93; ecall; j .
+        let prog: [u32; 5] =
+            [0x008000ef, 0x0000006f, 0x05d00893, 0x00000073, 0x0000006f];
+        let (emu, _) = load_prog(&prog);
+
+        let check = |snapshot: Arc<Emulator>| {
+            let calls  = Arc::new(std::sync::atomic::AtomicU64::new(0));
+            let corpus = Corpus::new()
+                .with_feedback(Box::new(CountFeedback(calls.clone())));
+            let reported = || calls.load(Ordering::Relaxed);
+
+            // The other worker does not pull in the coverage found below,
+            // such that its edges are only filtered by the shared bitmap
+            let mut emu   = fork_snapshot(&snapshot);
+            let mut other = fork_snapshot(&snapshot);
+            other.local_coverage(&corpus);
+
+            // The first fuzz case reports its edges and saves the input once
+            assert!(emu.run_input(b"AAAA", &corpus) == VmExit::Exit(0));
+            assert!(emu.found_coverage());
+            let edges = reported();
+            assert!(edges > 0);
+            assert!(corpus.inputs.len() == 1);
+            assert!(corpus.input_hashes.len() == 1);
+
+            // Later fuzz cases taking the same edges, with inputs which were
+            // never hashed before, never reach the feedback or the input
+            // hashing, on this worker or any other
+            for _ in 0..100 {
+                for emu in [&mut emu, &mut other] {
+                    assert!(emu.run_input(b"BBBB", &corpus) ==
+                            VmExit::Exit(0));
+                    assert!(!emu.found_coverage());
+                }
+            }
+            assert!(reported() == edges);
+            assert!(corpus.inputs.len() == 1);
+            assert!(corpus.input_hashes.len() == 1);
+        };
+
+        check(Arc::new(emu.fork()));
+
+        if let Some(snapshot) = jit_snapshot(emu) {
+            check(snapshot);
+        }
+    }
+
+    #[test]
+    fn test_feedback() {
+        // Counts the edges reported to it, keeping inputs if `keep` is set
+        struct CountFeedback {
+            edges: std::sync::atomic::AtomicU64,
+            keep:  bool,
+        }
+
+        impl crate::Feedback for CountFeedback {
+            fn on_coverage(&self, _corpus: &Corpus, _from: VirtAddr,
+                           _to: VirtAddr, input: &[u8]) -> bool {
+                assert!(input == b"AAAA");
+                self.edges.fetch_add(1, Ordering::Relaxed);
+                self.keep
+            }
+        }
+
+        // jal ra, 1f; j .; 1: li a7, 93; ecall; j .
+        let prog: [u32; 5] =
+            [0x008000ef, 0x0000006f, 0x05d00893, 0x00000073, 0x0000006f];
+        let (emu, _) = load_prog(&prog);
+        let snapshot = Arc::new(emu.fork());
+
+        for &keep in &[false, true] {
+            let corpus = Corpus::new().with_feedback(Box::new(CountFeedback {
+                edges: std::sync::atomic::AtomicU64::new(0),
+                keep:  keep,
+            }));
+            let mut emu = fork_snapshot(&snapshot);
+            assert!(emu.run_input(b"AAAA", &corpus) == VmExit::Exit(0));
+
+            // Only the feedback decides, `code_coverage` is left alone
+            assert!(corpus.code_coverage.len() == 0);
+            assert!(corpus.inputs.len() == keep as usize);
+            assert!(emu.found_coverage() == keep);
+        }
+    }
+
+    #[test]
+    fn test_context_coverage() {
+        // jal ra, f; jal ra, f; li a7, 93; ecall; j .; j .; j .
+        // f: beq zero, zero, 1f; 1: ret
+        //
+        // Padded such that no two edges collide in the coverage bitmap
+        let prog: [u32; 9] = [
+            0x01c000ef, 0x018000ef, 0x05d00893, 0x00000073, 0x0000006f,
+            0x0000006f, 0x0000006f, 0x00000263, 0x00008067,
+        ];
+        let (emu, code) = load_prog(&prog);
+
+        // Get the number of coverage entries of a fuzz case
+        let coverage = |snapshot: Arc<Emulator>| {
+            let corpus = Corpus::new();
+            let mut emu = fork_snapshot(&snapshot);
+            assert!(emu.run_input(b"", &corpus) == VmExit::Exit(0));
+            corpus.code_coverage.len()
+        };
+
+        // The branch in `f` is only new coverage for the second call with
+        // context-sensitive coverage
+        assert!(coverage(Arc::new(emu.fork())) == 5);
+        let context = Arc::new(emu.fork().enable_context_coverage());
+        assert!(coverage(context.clone()) == 6);
+
+        // Both returns enter new blocks, the calls and the branch in `f`
+        // enter the same two blocks twice
+        let block = Arc::new(emu.fork().enable_block_coverage());
+        assert!(coverage(block) == 4);
+
+        // Blacklisting `f` drops the branch and both returns
+        let mut blacklisted = emu.fork();
+        blacklisted.add_coverage_blacklist(VirtAddr(code.0 + 28),
+                                           VirtAddr(code.0 + 36));
+        let blacklisted = Arc::new(blacklisted);
+        assert!(coverage(blacklisted.clone()) == 2);
+
+        // jal ra, f; ebreak; f: j .
+        //
+        // With a breakpoint replacing `f`, which returns to the caller. The
+        // context is back to the one before the call.
+        let (mut bp, bp_code) =
+            load_prog(&[0x008000ef, 0x00100073, 0x0000006f]);
+        fn ret_bp(emu: &mut Emulator) -> Result<(), VmExit> {
+            emu.return_from_breakpoint();
+            Ok(())
+        }
+        bp.add_breakpoint(VirtAddr(bp_code.0 + 8), ret_bp);
+        let bp = bp.enable_context_coverage();
+        let call_hash = bp.state.call_hash;
+        let check = |snapshot: Arc<Emulator>| {
+            let mut emu = fork_snapshot(&snapshot);
+            assert!(emu.run_input(b"", &Corpus::new()) == VmExit::Ebreak);
+            assert!(emu.state.call_hash == call_hash);
+        };
+        check(Arc::new(bp.fork()));
+        if let Some(snapshot) = jit_snapshot(bp) {
+            check(snapshot);
+        }
+
+        if !jit_available() {
+            return;
+        }
+
+        assert!(coverage(jit_snapshot(emu.fork()).unwrap()) == 5);
+        assert!(coverage(jit_snapshot(blacklisted.fork()).unwrap()) == 2);
+        let block = emu.fork().enable_block_coverage();
+        assert!(coverage(jit_snapshot(block).unwrap()) == 4);
+        let context = emu.enable_context_coverage();
+        assert!(coverage(jit_snapshot(context).unwrap()) == 6);
+    }
+
+    #[test]
+    fn test_branch_trace() {
+        // jal ra, f; jal ra, f; li a7, 93; ecall; j .
+        // f: beq zero, zero, 1f; 1: ret
+        let prog: [u32; 7] = [
+            0x014000ef, 0x010000ef, 0x05d00893, 0x00000073, 0x0000006f,
+            0x00000263, 0x00008067,
+        ];
+        let (emu, code) = load_prog(&prog);
+
+        // Both calls to `f`, of which the last 4 branches are kept
+        let edge = |from: usize, to: usize|
+            (VirtAddr(code.0 + from), VirtAddr(code.0 + to));
+        let expected = vec![
+            edge(0x18, 0x04), edge(0x04, 0x14), edge(0x14, 0x18),
+            edge(0x18, 0x08),
+        ];
+
+        let mut interp =
+            fork_snapshot(&Arc::new(emu.fork().enable_branch_trace(3)));
+        assert!(interp.branch_trace().is_empty());
+        assert!(interp.run_input(b"", &Corpus::new()) == VmExit::Exit(0));
+        assert!(interp.branch_trace() == expected);
+        assert!(interp.dump_branch_trace().lines().count() == 4);
+
+        // The trace starts over every fuzz case
+        assert!(interp.run_input(b"", &Corpus::new()) == VmExit::Exit(0));
+        assert!(interp.branch_trace() == expected);
+
+        if let Some(snapshot) = jit_snapshot(emu.enable_branch_trace(3)) {
+            let mut jit = fork_snapshot(&snapshot);
+            assert!(jit.run_input(b"", &Corpus::new()) == VmExit::Exit(0));
+            assert!(jit.branch_trace() == expected);
+        }
+    }
+
+    #[test]
+    fn test_alc_offset() {
+        let mut emu = Emulator::new(128 * 1024);
+        let a = emu.memory.allocate(0x10).unwrap();
+        let b = emu.memory.allocate(0x40).unwrap();
+
+        // Offsets into an allocation and its padding are reported, such that
+        // overflows are attributed to the allocation they overflowed
+        assert!(emu.memory.alc_offset(a) == Some(0));
+        assert!(emu.memory.alc_offset(VirtAddr(a.0 + 0x14)) == Some(0x14));
+        assert!(emu.memory.alc_offset(VirtAddr(b.0 + 0x3f)) == Some(0x3f));
+        assert!(emu.memory.alc_offset(VirtAddr(b.0 + 0x50)).is_none());
+        assert!(emu.memory.alc_offset(VirtAddr(a.0 - 1)).is_none());
+
+        // Freed allocations are no longer tracked
+        emu.memory.free(a).unwrap();
+        assert!(emu.memory.alc_offset(a).is_none());
+    }
+
+    #[test]
+    fn test_trim_input() {
+        let mut emu = Emulator::new(128 * 1024);
+        let buf = emu.memory.allocate(16).unwrap();
+        emu.files.insert(3, EmuFile::FuzzInput { index: 0, cursor: 0 });
+
+        // li a0, 3; li a2, 16; li a7, 63; ecall; lbu t0, 3(a1);
+        // li t1, 0x41; bne t0, t1, 8; nop; li a7, 93; ecall; j .
+        let prog: [u32; 11] = [
+            0x00300513, 0x01000613, 0x03f00893, 0x00000073, 0x0035c283,
+            0x04100313, 0x00629463, 0x00000013, 0x05d00893, 0x00000073,
+            0x0000006f,
+        ];
+        write_prog(&mut emu, &prog);
+        emu.set_reg(Register::A1, buf.0 as u64);
+
+        let corpus = Corpus::new();
+        let mut emu = fork_snapshot(&Arc::new(emu));
+
+        // Only the input up to and including the byte compared against is
+        // needed to take the new edge
+        emu.run_input(b"xxxAyyyyyyyyyyyy", &corpus);
+        assert!(corpus.inputs.len() == 1);
+        assert!(corpus.inputs.get(0).unwrap().data == b"xxxA");
+
+        // Inputs which find nothing new are not saved
+        emu.run_input(b"xxxAzz", &corpus);
+        assert!(corpus.inputs.len() == 1);
+
+        // The other side of the branch is trimmed by the same trimmer, which
+        // is reset rather than forked again. It is taken without any input.
+        let trimmer = &**emu.trimmer.as_ref().unwrap() as *const Emulator;
+        emu.run_input(b"xxxByyyyyyyyyyyy", &corpus);
+        assert!(corpus.inputs.len() == 2);
+        assert!(corpus.inputs.get(1).unwrap().data.is_empty());
+        assert!(&**emu.trimmer.as_ref().unwrap() as *const Emulator ==
+                trimmer);
+    }
+
+    #[test]
+    fn test_address_type() {
+        let mut emu = Emulator::new(1024 * 1024);
+        let alc = emu.memory.allocate(16).unwrap();
+        let freed = emu.memory.allocate(16).unwrap();
+        emu.memory.free(freed).unwrap();
+        emu.setup_stack::<&[u8], &[u8]>(&[], &[]).unwrap();
+        let sp = VirtAddr(emu.reg(Register::Sp) as usize);
+
+        // Nothing is mapped below the first allocation
+        assert!(emu.address_type(VirtAddr(0x18)) == AddressType::Null);
+        assert!(emu.address_type(VirtAddr(alc.0 - 1)) == AddressType::Null);
+        assert!(emu.address_type(VirtAddr(!0x17)) == AddressType::Negative);
+        assert!(emu.address_type(VirtAddr(alc.0 + 20)) == AddressType::Heap);
+        assert!(emu.address_type(freed) == AddressType::Freed);
+        assert!(emu.address_type(VirtAddr(sp.0 - 8)) == AddressType::Stack);
+        assert!(emu.address_type(VirtAddr(sp.0 + 0x1000)) ==
+                AddressType::Unmapped);
+        assert!(emu.address_type(VirtAddr(1 << 40)) ==
+                AddressType::Unmapped);
+
+        // Freed allocations are forgotten by resetting to before the free
+        let snapshot = emu.fork();
+        let mut emu = snapshot.fork();
+        let alc = emu.memory.allocate(16).unwrap();
+        emu.memory.free(alc).unwrap();
+        assert!(emu.address_type(alc) == AddressType::Freed);
+        emu.reset(&snapshot);
+        assert!(emu.address_type(alc) == AddressType::Unmapped);
+    }
+
+    #[test]
+    fn test_memory_growth() {
+        let mut emu = Emulator::new(128 * 1024);
+
+        // sd a0, 0(a1); ld a0, 0(a1); li a7, 93; ecall; j .
+        let code = write_prog(&mut emu, &[
+            0x00a5b023, 0x0005b503, 0x05d00893, 0x00000073, 0x0000006f,
+        ]);
+        emu.set_reg(Register::A0, 5);
+
+        // Allocations past the maximum size fail
+        assert!(emu.memory.allocate(256 * 1024).is_none());
+        emu.memory.set_max_size(1024 * 1024);
+
+        // Write to and read from the end of an allocation which grew memory
+        let run = |snapshot: &Arc<Emulator>| {
+            let mut emu = fork_snapshot(snapshot);
+            for _ in 0..2 {
+                let alc = emu.memory.allocate(256 * 1024).unwrap();
+                assert!(emu.memory.len() > 256 * 1024);
+                emu.set_reg(Register::A1, (alc.0 + 256 * 1024 - 8) as u64);
+                emu.set_reg(Register::Pc, code.0 as u64);
+
+                let mut instrs = 0;
+                let mut cycles = 0;
+                assert!(emu.run(&mut instrs, &mut cycles, &Corpus::new()) ==
+                        Err(VmExit::Syscall));
+                assert!(emu.reg(Register::A0) == 5);
+
+                // Resetting drops the memory it grew by
+                let snapshot = emu.snapshot.clone().unwrap();
+                emu.reset(&snapshot);
+                assert!(emu.memory.len() == 128 * 1024);
+            }
+        };
+        run(&Arc::new(emu.fork()));
+
+        if let Some(snapshot) = jit_snapshot(emu) {
+            run(&snapshot);
+        }
+    }
+
+    #[test]
+    fn test_grown_code() {
+        // JIT caches have to cover the memory the guest may grow to
+        let small = || Arc::new(
+            JitCache::new(VirtAddr(128 * 1024), JitConfig::default()));
+        assert!(EmulatorBuilder::new()
+            .memory_size(128 * 1024)
+            .max_memory_size(MEMORY_SIZE)
+            .jit(small())
+            .build().is_err());
+
+        // li a0, 5; li a7, 93; ecall; j .
+        let mut emu = Emulator::new(128 * 1024);
+        emu.memory.set_max_size(MEMORY_SIZE);
+        emu.memory.allocate(256 * 1024).unwrap();
+        let code = write_prog(
+            &mut emu, &[0x00500513, 0x05d00893, 0x00000073, 0x0000006f]);
+        assert!(code.0 >= 128 * 1024);
+
+        // Code the translation table doesn't cover is interpreted
+        let jit_cache = small();
+        let snapshot = Arc::new(emu.fork().enable_jit(jit_cache.clone()));
+        assert!(run_compiled(snapshot, &jit_cache).0 == VmExit::Exit(5));
+        assert!(jit_cache.lookup(code).is_none());
+
+        if let Some(snapshot) = jit_snapshot(emu) {
+            assert!(snapshot.jit_cache.as_ref().unwrap().lookup(code)
+                    .is_some());
+            let mut jit = fork_snapshot(&snapshot);
+            assert!(jit.run_input(b"", &Corpus::new()) == VmExit::Exit(5));
+        }
+    }
+
+    #[test]
+    fn test_detect_uninit() {
+        // Without tracking, new allocations are readable
+        let mut emu = Emulator::new(1024 * 1024);
+        let alc = emu.memory.allocate(16).unwrap();
+        assert!(emu.memory.read::<u64>(alc).is_ok());
+
+        // With tracking, they are readable once written
+        emu.memory.set_detect_uninit(true);
+        let data = emu.memory.allocate(16).unwrap();
+        assert!(emu.memory.read::<u64>(data) ==
+                Err(VmExit::UninitFault(data)));
+        emu.memory.write(data, 5u32).unwrap();
+        assert!(emu.memory.read::<u32>(data).unwrap() == 5);
+        assert!(emu.memory.read::<u64>(data) ==
+                Err(VmExit::UninitFault(VirtAddr(data.0 + 4))));
+
+        // Like `calloc()`, initializing through `peek()` makes it readable
+        let zeroed = emu.memory.allocate(16).unwrap();
+        emu.memory.peek(zeroed, 16, Perm(PERM_WRITE)).unwrap()
+            .iter_mut().for_each(|x| *x = 0);
+        assert!(emu.memory.read::<u64>(VirtAddr(zeroed.0 + 8)).unwrap() == 0);
+
+        // ld a0, 8(a1); li a7, 93; ecall; j .
+        write_prog(&mut emu,
+                   &[0x0085b503, 0x05d00893, 0x00000073, 0x0000006f]);
+        emu.set_reg(Register::A1, data.0 as u64);
+
+        // Both backends report the uninitialized read as a crash
+        let uninit = VmExit::UninitFault(VirtAddr(data.0 + 8));
+        let mut interp = emu.fork();
+        assert!(interp.run_input(b"", &Corpus::new()) == uninit);
+        assert!(uninit.is_crash().is_some());
+
+        if let Some((vmexit, _)) = run_jit(emu) {
+            assert!(vmexit == uninit);
+        }
+    }
+
+    #[test]
+    fn test_verify_input() {
+        let mut emu = Emulator::new(1024 * 1024);
+        let data = emu.memory.allocate(4096).unwrap();
+
+        // Dirtied memory only differs if the contents do
+        let mut a = emu.memory.fork();
+        let mut b = emu.memory.fork();
+        a.write(VirtAddr(data.0 + 8), 5u64).unwrap();
+        b.write(VirtAddr(data.0 + 8), 5u64).unwrap();
+        assert!(a.diff_dirty(&b) == None);
+        b.write(VirtAddr(data.0 + 9), 6u8).unwrap();
+        assert!(a.diff_dirty(&b) == Some(VirtAddr(data.0 + 9)));
+
+        // li a0, -16; srai a0, a0, 2; sd a0, 0(a1); li a7, 93; ecall; j .
+        write_prog(&mut emu, &[
+            0xff000513, 0x40255513, 0x00a5b023, 0x05d00893, 0x00000073,
+            0x0000006f,
+        ]);
+        emu.set_reg(Register::A1, data.0 as u64);
+
+        if let Some(snapshot) = jit_snapshot(emu) {
+            let mut emu = fork_snapshot(&snapshot);
+            assert!(emu.verify_input(b"", &Corpus::new()).unwrap() ==
+                    VmExit::Exit(-4));
+        }
+    }
+
+    #[test]
+    fn test_jit_prune() {
+        let mut jit_cache =
+            JitCache::new(VirtAddr(0x1000), JitConfig::default());
+        jit_cache.set_max_size(0x80);
+
+        // Identical code is mapped once
+        let old = jit_cache.add_mapping(VirtAddr(0), &[1; 0x40]);
+        jit_cache.add_mapping(VirtAddr(4), &[2; 0x40]);
+        jit_cache.add_mapping(VirtAddr(8), &[1; 0x40]);
+        assert!(jit_cache.size() == 0x80);
+        assert!(jit_cache.mapped_blocks() == 3);
+        assert!(jit_cache.prune() == 0);
+
+        // Going over the maximum size evicts the code which was not used
+        // in the previous epoch
+        jit_cache.add_mapping(VirtAddr(12), &[3; 0x40]);
+        assert!(jit_cache.prune() == 3);
+        assert!(jit_cache.size() == 0x40);
+        assert!(jit_cache.mapped_blocks() == 1);
+        assert!(jit_cache.lookup(VirtAddr(0)).is_none());
+        assert!(jit_cache.lookup(VirtAddr(12)).is_some());
+
+        // Evicted code is only reused once no worker can be running it, no
+        // matter how many epochs pass
+        let worker = jit_cache.register_worker();
+        jit_cache.enter(&worker);
+        assert!(jit_cache.add_mapping(VirtAddr(0), &[4; 0x40]) != old);
+        jit_cache.prune();
+        jit_cache.prune();
+        assert!(jit_cache.add_mapping(VirtAddr(4), &[5; 0x40]) != old);
+
+        // Such as once the worker entered the JIT in a later epoch
+        jit_cache.enter(&worker);
+        assert!(jit_cache.add_mapping(VirtAddr(8), &[6; 0x40]) == old);
+        assert!(jit_cache.size() == 0x100);
+
+        // Workers which left the JIT or were dropped hold nothing back, all
+        // the code is reclaimed after an invalidation
+        jit_cache.add_mapping(VirtAddr(16), &[7; 0x40]);
+        let dropped = jit_cache.register_worker();
+        jit_cache.enter(&dropped);
+        jit_cache.enter(&worker);
+        worker.leave();
+        jit_cache.invalidate();
+        assert!(jit_cache.mapped_blocks() == 0);
+        drop(dropped);
+        assert!(jit_cache.add_mapping(VirtAddr(0), &[8; 0x40]) == old);
+        assert!(jit_cache.size() == 0x40);
+        assert!(jit_cache.mapped_blocks() == 1);
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        let corpus = Corpus::new();
+        let jit_cache = Arc::new(
+            JitCache::new(VirtAddr(MEMORY_SIZE), JitConfig::default()));
+
+        // auipc t0, 0; li a0, 0; li t2, 2
+        // loop: addi a0, a0, 1; lw t1, 0x28(t0); sw t1, 0xc(t0)
+        // addi t2, t2, -1; bnez t2, loop; li a7, 93; ecall
+        // .word 0x00550513 (addi a0, a0, 5); j .
+        //
+        // The first iteration adds 1, then patches it to add 5
+        let prog: [u32; 12] = [
+            0x00000297, 0x00000513, 0x00200393, 0x00150513, 0x0282a303,
+            0x0062a623, 0xfff38393, 0xfe0398e3, 0x05d00893, 0x00000073,
+            0x00550513, 0x0000006f,
+        ];
+        let (mut emu, code) = load_prog(&prog);
+        emu.memory.set_permissions(code, 4096,
+            Perm(PERM_READ | PERM_WRITE | PERM_EXEC)).unwrap();
+        assert!(emu.memory.take_code_modified());
+        assert!(!emu.memory.take_code_modified());
+
+        // The patch is seen, and undone by the reset between cases
+        let snapshot = Arc::new(emu);
+        let mut emu = fork_snapshot(&snapshot);
+        for _ in 0..2 {
+            assert!(emu.run_input(b"", &corpus) == VmExit::Exit(6));
+            assert!(emu.memory.take_code_modified());
+        }
+
+        // Without a snapshot, code lifted before a modification is thrown
+        // away
+        let mut emu = snapshot.fork().enable_jit(jit_cache.clone());
+        jit_cache.add_mapping(code, &[0xc3]);
+        emu.memory.write(code, prog[0]).unwrap();
+        assert!(!emu.code_diverged());
+        assert!(jit_cache.lookup(code).is_none());
+
+        // With one, the rest of the case is interpreted instead and the code
+        // is left alone for the other emulators
+        let mut emu = fork_snapshot(&Arc::new(emu));
+        jit_cache.add_mapping(code, &[0xc3]);
+        assert!(!emu.code_diverged());
+        emu.memory.write(code, prog[0]).unwrap();
+        assert!(emu.code_diverged());
+        assert!(jit_cache.lookup(code).is_some());
+
+        if let Some((vmexit, _)) = run_jit(snapshot.fork()) {
+            assert!(vmexit == VmExit::Exit(6));
+        }
+    }
+
+    #[test]
+    fn test_shared_modified_code() {
+        if !jit_available() {
+            return;
+        }
+
+        // auipc t0, 0; ld t1, 0(a1); beqz t1, 1f
+        // lw t2, 44(t0); sw t2, 28(t0)
+        // 1: addi t3, t0, 28; jr t3
+        // li a0, 1; li a7, 93; ecall; j .
+        // .word 0x00500513 (li a0, 5)
+        //
+        // Patches the `li a0, 1` to `li a0, 5` if the flag at `a1` is set,
+        // the indirect jump has the JIT look the patched code up by its PC
+        let prog: [u32; 12] = [
+            0x00000297, 0x0005b303, 0x00030663, 0x02c2a383, 0x0072ae23,
+            0x01c28e13, 0x000e0067, 0x00100513, 0x05d00893, 0x00000073,
+            0x0000006f, 0x00500513,
+        ];
+        let (mut emu, code) = load_prog(&prog);
+        emu.memory.set_permissions(code, 4096,
+            Perm(PERM_READ | PERM_WRITE | PERM_EXEC)).unwrap();
+        let flag = emu.memory.allocate(8).unwrap();
+        emu.memory.write(flag, 0u64).unwrap();
+        emu.set_reg(Register::A1, flag.0 as u64);
+
+        let mut interp = fork_snapshot(&Arc::new(emu.fork()));
+        assert!(interp.run_input(b"", &Corpus::new()) == VmExit::Exit(1));
+
+        // Two emulators running the same code from one JIT cache, of which
+        // only one modifies it
+        let jit_cache = Arc::new(
+            JitCache::new(VirtAddr(MEMORY_SIZE), JitConfig::default()));
+        let mut patching = emu.fork();
+        patching.memory.write(flag, 1u64).unwrap();
+        let patching = Arc::new(patching.enable_jit(jit_cache.clone()));
+        let original = Arc::new(emu.enable_jit(jit_cache.clone()));
+
+        // The patched code never reaches the other emulator, and the
+        // modification does not throw away its code
+        for _ in 0..2 {
+            assert!(run_compiled(patching.clone(), &jit_cache).0 ==
+                    VmExit::Exit(5));
+            let blocks = jit_cache.size();
+            assert!(run_compiled(original.clone(), &jit_cache).0 ==
+                    VmExit::Exit(1));
+            assert!(jit_cache.lookup(code).is_some());
+            assert!(jit_cache.size() >= blocks);
+        }
+    }
+
+    #[test]
+    fn test_fence_i() {
+        // fence; fence.i; li a0, 5; li a7, 93; ecall; j .
+        let prog: [u32; 6] = [
+            0x0ff0000f, 0x0000100f, 0x00500513, 0x05d00893, 0x00000073,
+            0x0000006f,
+        ];
+        let (emu, _) = load_prog(&prog);
+        assert!(disasm(prog[1]) == "fence.i");
+
+        let mut interp = fork_snapshot(&Arc::new(emu.fork()));
+        assert!(interp.run_input(b"", &Corpus::new()) == VmExit::Exit(5));
+
+        if let Some((vmexit, _)) = run_jit(emu) {
+            assert!(vmexit == VmExit::Exit(5));
+        }
+    }
+
+    #[test]
+    fn test_malformed_shift() {
+        // slli a0, a0, 1 with bit 30 set and slliw a0, a0, 1 with bit 25 set
+        for &inst in &[0x40151513u32, 0x0215151b] {
+            let (emu, _) = load_prog(&[inst, 0x0000006f]);
+
+            let mut interp = fork_snapshot(&Arc::new(emu.fork()));
+            assert!(interp.run_input(b"", &Corpus::new()) ==
+                    VmExit::InvalidOpcode);
+
+            if let Some((vmexit, _)) = run_jit(emu) {
+                assert!(vmexit == VmExit::InvalidOpcode);
+            }
+        }
+    }
+
+    #[test]
+    fn test_cycle_weights() {
+        // li a0, 5; beq zero, zero, 4; li a7, 93; ecall; j .
+        let prog: [u32; 5] = [
+            0x00500513, 0x00000263, 0x05d00893, 0x00000073, 0x0000006f,
+        ];
+        let (mut emu, _) = load_prog(&prog);
+        emu.set_cycle_weights(CycleWeights {
+            branch: 3,
+            system: 7,
+            alu:    2,
+            ..CycleWeights::default()
+        });
+
+        let mut interp = fork_snapshot(&Arc::new(emu.fork()));
+        assert!(interp.run_input(b"", &Corpus::new()) == VmExit::Exit(5));
+        assert!(interp.instrs_execed() == 4);
+        assert!(interp.cycles_execed() == 14);
+
+        // The timeout is a cycle budget
+        interp.set_timeout(6);
+        assert!(interp.run_input(b"", &Corpus::new()) == VmExit::Timeout);
+
+        if let Some(snapshot) = jit_snapshot(emu) {
+            // Coverage is new to this emulator, so the branch is executed
+            // twice by the JIT but must only be counted once
+            let mut jit = fork_snapshot(&snapshot);
+            assert!(jit.run_input(b"", &Corpus::new()) == VmExit::Exit(5));
+            assert!(jit.cycles_execed() == 14);
         }
-        
-        print!("Compiling cache for {:#018x} -> {:032x}\n", pc.0, proghash);
-
-        let cppfn = std::env::temp_dir().join(
-            format!("fwetmp_{:?}.cpp",
-                    std::thread::current().id()));
-        let linkfn = std::env::temp_dir().join(
-            format!("fwetmp_{:?}.lunk",
-                    std::thread::current().id()));
-        let binfn = std::env::temp_dir().join(
-            format!("fwetmp_{:?}.bin",
-                    std::thread::current().id()));
-
-        // Write out the test program
-        std::fs::write(&cppfn, program)
-            .expect("Failed to write program");
-
-        // Create the ELF
-        let res = Command::new("clang++").args(&[
-            "-O3", "-march=native", "-Wall",
-            "-fno-asynchronous-unwind-tables",
-            "-Wno-unused-label",
-            "-Wno-unused-variable",
-            "-Werror",
-            "-fno-strict-aliasing",
-            "-static", "-nostdlib", "-ffreestanding",
-            "-Wl,-Tldscript.ld", "-Wl,--gc-sections", "-Wl,--build-id=none",
-            "-o", linkfn.to_str().unwrap(),
-            cppfn.to_str().unwrap()]).status()
-            .expect("Failed to launch clang++");
-        assert!(res.success(), "clang++ returned error");
-
-        // Convert the ELF to a binary
-        let res = Command::new("objcopy")
-            .args(&["-O", "binary", "--remove-section=.note.gnu.property",
-                    linkfn.to_str().unwrap(),
-                    binfn.to_str().unwrap()]).status()
-            .expect("Failed to launch objcopy");
-        assert!(res.success(), "objcopy returned error");
-
-        // Move the compiled output to the cache
-        std::fs::rename(&binfn, &cachename)
-            .expect("Failed to rename compiled JIT to cache file");
-
-        Ok(std::fs::read(&cachename).expect("Failed to read JIT code"))
     }
-}
 
+    #[test]
+    fn test_lift_jit() {
+        // li a0, 5; li a7, 93; ecall; j .
+        let prog: [u32; 4] = [0x00500513, 0x05d00893, 0x00000073, 0x0000006f];
+        let (emu, code) = load_prog(&prog);
+
+        // Lifting doesn't need the compiler
+        let jit_cache = Arc::new(
+            JitCache::new(VirtAddr(MEMORY_SIZE), JitConfig::default()));
+        let mut emu = emu.enable_jit(jit_cache);
+        let source = emu.lift_jit(code, &Corpus::new()).unwrap();
+        assert!(source.contains(&format!("inst_{:016x}:", code.0)));
+        assert!(source.contains("state->exit_reason = Ecall;"));
+        assert!(emu.lift_jit(VirtAddr(code.0 + 2), &Corpus::new()) ==
+                Err(VmExit::ExecFault(VirtAddr(code.0 + 2))));
+    }
+
+    #[test]
+    fn test_registers() {
+        assert!(Register::all().count() == 33);
+        assert!(Register::all().last() == Some(Register::Pc));
+
+        let mut emu = Emulator::new(1024 * 1024);
+        let mut regs = [0u64; 33];
+        for (ii, reg) in regs.iter_mut().enumerate() {
+            *reg = ii as u64 + 100;
+        }
+        emu.set_registers(regs);
+
+        let regs = emu.registers();
+        assert!(regs[Register::Zero as usize] == 0);
+        for reg in Register::all().skip(1) {
+            assert!(emu.reg(reg) == reg as u64 + 100);
+            assert!(regs[reg as usize] == reg as u64 + 100);
+        }
+    }
+
+    #[test]
+    fn test_ebreak() {
+        // li a0, 5; ebreak; j .
+        let prog: [u32; 3] = [0x00500513, 0x00100073, 0x0000006f];
+        let (emu, code) = load_prog(&prog);
+
+        let mut interp = fork_snapshot(&Arc::new(emu.fork()));
+        assert!(interp.run_input(b"", &Corpus::new()) == VmExit::Ebreak);
+        assert!(interp.reg(Register::Pc) == code.0 as u64 + 4);
+
+        if let Some(result) = run_jit(emu) {
+            assert!(result == (VmExit::Ebreak, 2));
+        }
+    }
+
+    #[test]
+    fn test_signal_delivery() {
+        // ld a0, 0(zero); li a7, 93; ecall; j .
+        // handler: skip the faulting instruction and set a0 to 7
+        //   ld t0, 176(a2); addi t0, t0, 4; sd t0, 176(a2); li t1, 7;
+        //   sd t1, 256(a2); ret
+        let prog: [u32; 10] = [
+            0x00003503, 0x05d00893, 0x00000073, 0x0000006f, 0x0b063283,
+            0x00428293, 0x0a563823, 0x00700313, 0x10663023, 0x00008067,
+        ];
+        let (mut emu, code) = load_prog(&prog);
+        let stack = emu.memory.allocate(64 * 1024).unwrap();
+        emu.set_reg(Register::Sp, (stack.0 + 64 * 1024) as u64);
+        emu.set_sigaction(SIGSEGV, SigAction {
+            handler: code.0 as u64 + 16,
+            ..SigAction::default()
+        });
+
+        // Without signal delivery the fault is a crash
+        let mut plain = fork_snapshot(&Arc::new(emu.fork()));
+        assert!(plain.run_input(b"", &Corpus::new()) ==
+                VmExit::ReadFault(VirtAddr(0)));
+
+        let emu = emu.enable_signal_delivery();
+        let mut interp = fork_snapshot(&Arc::new(emu.fork()));
+        assert!(interp.run_input(b"", &Corpus::new()) == VmExit::Exit(7));
+        assert!(interp.reg(Register::Sp) == (stack.0 + 64 * 1024) as u64);
+
+        if let Some((vmexit, _)) = run_jit(emu) {
+            assert!(vmexit == VmExit::Exit(7));
+        }
+    }
+}
@@ -0,0 +1,412 @@
+//! A GDB remote serial protocol stub, for attaching GDB to a single fuzz case
+//! and single-stepping it with the emulator
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::net::TcpListener;
+use std::collections::BTreeSet;
+use crate::Corpus;
+use crate::mmu::VirtAddr;
+use crate::emulator::{Emulator, Register, VmExit};
+
+/// Number of registers reported to GDB, x0-x31 followed by pc, which matches
+/// the ordering of the `Register` enum
+const NUM_REGS: usize = 33;
+
+/// Number of instructions to execute between checks for a GDB interrupt
+/// request while continuing
+const INTERRUPT_CHECK_INTERVAL: u64 = 0x10000;
+
+/// Signals reported to GDB in stop replies
+const SIGINT:  u8 = 2;
+const SIGILL:  u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGSEGV: u8 = 11;
+const SIGALRM: u8 = 14;
+
+/// Encode `bytes` as lower-case hex
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// Decode the hex string `hex` into bytes
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..hex.len()).step_by(2).map(|ii| {
+        u8::from_str_radix(hex.get(ii..ii + 2)?, 16).ok()
+    }).collect()
+}
+
+/// Parse a hex integer as sent by GDB
+fn parse_hex(hex: &str) -> Option<u64> {
+    u64::from_str_radix(hex, 16).ok()
+}
+
+/// Parse an `addr,len` pair as sent by GDB
+fn parse_addr_len(args: &str) -> Option<(VirtAddr, usize)> {
+    let mut args = args.splitn(2, ',');
+    let addr = parse_hex(args.next()?)? as usize;
+    let len  = parse_hex(args.next()?)? as usize;
+    Some((VirtAddr(addr), len))
+}
+
+/// Target description given to GDB, describing the RV64 integer registers
+fn target_xml() -> String {
+    let mut xml = String::from(r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+<architecture>riscv:rv64</architecture>
+<feature name="org.gnu.gdb.riscv.cpu">
+"#);
+
+    for reg in Register::all() {
+        let typ = match reg {
+            Register::Ra | Register::Pc => "code_ptr",
+            Register::Sp                => "data_ptr",
+            _                           => "int",
+        };
+        xml += &format!(
+            "<reg name=\"{}\" bitsize=\"64\" type=\"{}\"/>\n",
+            format!("{:?}", reg).to_lowercase(), typ);
+    }
+
+    xml += "</feature>\n</target>\n";
+    xml
+}
+
+/// Wait for GDB to connect on `port`, then serve it the guest in `emu` until
+/// it detaches, kills the guest, or the guest exits
+pub fn serve(port: u16, emu: Emulator, corpus: &Corpus) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    print!("Waiting for GDB to connect on 127.0.0.1:{}\n", port);
+
+    let (stream, addr) = listener.accept()?;
+    print!("GDB connected from {}\n", addr);
+
+    let mut stub = GdbStub {
+        stream:      stream,
+        emu:         emu,
+        corpus:      corpus,
+        breakpoints: BTreeSet::new(),
+        instrs:      0,
+    };
+    stub.run()
+}
+
+/// State of a GDB session
+struct GdbStub<'a> {
+    /// Connection to GDB
+    stream: TcpStream,
+
+    /// Emulator being debugged
+    emu: Emulator,
+
+    /// Corpus to record coverage into while stepping
+    corpus: &'a Corpus,
+
+    /// Software breakpoints set by GDB
+    breakpoints: BTreeSet<VirtAddr>,
+
+    /// Number of instructions executed by the guest
+    instrs: u64,
+}
+
+/// Result of resuming the guest
+enum Stop {
+    /// The guest stopped with a signal, but may still be resumed
+    Signal(u8),
+
+    /// The guest exited with a status
+    Exited(i64),
+}
+
+impl<'a> GdbStub<'a> {
+    /// Read a single byte from GDB
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0u8; 1];
+        self.stream.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    /// Read the next packet from GDB, acknowledging it. Interrupt requests
+    /// received while the guest is already stopped are returned as `"\x03"`.
+    fn read_packet(&mut self) -> io::Result<String> {
+        loop {
+            match self.read_byte()? {
+                b'$' => {}
+                0x03 => return Ok("\x03".into()),
+                _    => continue, // Acks, or noise
+            }
+
+            // Read the packet data up to the checksum
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+
+            // Validate the checksum, requesting a retransmit on mismatch
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let expected = data.iter().fold(0u8, |acc, &x| acc.wrapping_add(x));
+            if from_hex(&String::from_utf8_lossy(&checksum)) !=
+                    Some(vec![expected]) {
+                self.stream.write_all(b"-")?;
+                continue;
+            }
+
+            self.stream.write_all(b"+")?;
+            return Ok(String::from_utf8_lossy(&data).into_owned());
+        }
+    }
+
+    /// Send the packet `data` to GDB
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |acc, x| acc.wrapping_add(x));
+        self.stream.write_all(
+            format!("${}#{:02x}", data, checksum).as_bytes())
+    }
+
+    /// Check, without blocking, whether GDB has requested an interrupt
+    fn interrupted(&mut self) -> io::Result<bool> {
+        let mut byte = [0u8; 1];
+        self.stream.set_nonblocking(true)?;
+        let ret = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+
+        match ret {
+            Ok(1) => Ok(byte[0] == 0x03),
+            Ok(_) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Execute a single guest instruction, invoking breakpoint callbacks and
+    /// handling syscalls the same way a fuzz case would
+    fn step(&mut self) -> Result<(), VmExit> {
+        let pc = self.emu.reg(Register::Pc);
+        if let Some(callback) = self.emu.breakpoint(VirtAddr(pc as usize)) {
+            callback(&mut self.emu)?;
+
+            if self.emu.reg(Register::Pc) != pc {
+                // Callback redirected execution, which counts as the step
+                return Ok(());
+            }
+        }
+
+        match self.emu.step_emu(&mut self.instrs, self.corpus) {
+            Err(VmExit::Syscall) => self.emu.handle_syscall(),
+            ret => ret,
+        }
+    }
+
+    /// Resume the guest, either for a single instruction or until it hits a
+    /// breakpoint, exits, or faults
+    fn resume(&mut self, single_step: bool) -> io::Result<Stop> {
+        let mut steps = 0u64;
+        loop {
+            if let Err(vmexit) = self.step() {
+                let signal = match vmexit {
+                    VmExit::Exit(status) => return Ok(Stop::Exited(status)),
+                    VmExit::Ebreak        => SIGTRAP,
+                    VmExit::Timeout       => SIGALRM,
+                    VmExit::InvalidOpcode => SIGILL,
+                    VmExit::Abort | VmExit::StackGuard => SIGABRT,
+                    _ if vmexit.is_crash().is_some() => SIGSEGV,
+                    _ => SIGTRAP,
+                };
+
+                print!("Guest stopped with {:?} at {}\n", vmexit,
+                       self.emu.symbolize(
+                           VirtAddr(self.emu.reg(Register::Pc) as usize)));
+                return Ok(Stop::Signal(signal));
+            }
+
+            let pc = VirtAddr(self.emu.reg(Register::Pc) as usize);
+            if single_step || self.breakpoints.contains(&pc) {
+                return Ok(Stop::Signal(SIGTRAP));
+            }
+
+            steps += 1;
+            if steps % INTERRUPT_CHECK_INTERVAL == 0 && self.interrupted()? {
+                return Ok(Stop::Signal(SIGINT));
+            }
+        }
+    }
+
+    /// Handle GDB's requests until the session ends
+    fn run(&mut self) -> io::Result<()> {
+        loop {
+            let packet = match self.read_packet() {
+                Ok(packet) => packet,
+                Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    // GDB disconnected without detaching
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            };
+            let (cmd, args) = packet.split_at(
+                packet.chars().next().map(|x| x.len_utf8()).unwrap_or(0));
+
+            let response = match cmd {
+                "\x03" => format!("S{:02x}", SIGINT),
+                "?" => format!("S{:02x}", SIGTRAP),
+                "g" => {
+                    let mut regs = Vec::new();
+                    for val in self.emu.registers().iter() {
+                        regs.extend_from_slice(&val.to_le_bytes());
+                    }
+                    to_hex(&regs)
+                }
+                "G" => match from_hex(args) {
+                    Some(ref bytes) if bytes.len() == NUM_REGS * 8 => {
+                        let mut regs = [0u64; NUM_REGS];
+                        for (reg, val) in bytes.chunks(8).enumerate() {
+                            let mut tmp = [0u8; 8];
+                            tmp.copy_from_slice(val);
+                            regs[reg] = u64::from_le_bytes(tmp);
+                        }
+                        self.emu.set_registers(regs);
+                        "OK".into()
+                    }
+                    _ => "E01".into(),
+                },
+                "p" => match parse_hex(args) {
+                    Some(reg) if (reg as usize) < NUM_REGS => {
+                        let val = self.emu.reg(Register::from(reg as u32));
+                        to_hex(&val.to_le_bytes())
+                    }
+                    _ => "E01".into(),
+                },
+                "P" => {
+                    let mut args = args.splitn(2, '=');
+                    let reg = args.next().and_then(parse_hex);
+                    let val = args.next().and_then(from_hex);
+                    match (reg, val) {
+                        (Some(reg), Some(val))
+                                if (reg as usize) < NUM_REGS &&
+                                val.len() == 8 => {
+                            let mut tmp = [0u8; 8];
+                            tmp.copy_from_slice(&val);
+                            self.emu.set_reg(Register::from(reg as u32),
+                                             u64::from_le_bytes(tmp));
+                            "OK".into()
+                        }
+                        _ => "E01".into(),
+                    }
+                }
+                "m" => match parse_addr_len(args) {
+                    Some((addr, len)) => {
+                        let mut buf = vec![0u8; len];
+                        match self.emu.memory.read_into(addr, &mut buf) {
+                            Ok(())  => to_hex(&buf),
+                            Err(_)  => "E14".into(),
+                        }
+                    }
+                    None => "E01".into(),
+                },
+                "M" => {
+                    let mut args = args.splitn(2, ':');
+                    let target = args.next().and_then(parse_addr_len);
+                    let data   = args.next().and_then(from_hex);
+                    match (target, data) {
+                        (Some((addr, len)), Some(data))
+                                if data.len() == len => {
+                            match self.emu.memory.write_from(addr, &data) {
+                                Ok(())  => "OK".into(),
+                                Err(_)  => "E14".into(),
+                            }
+                        }
+                        _ => "E01".into(),
+                    }
+                }
+                "Z" | "z" => {
+                    // Only software breakpoints are supported
+                    let mut args = args.splitn(3, ',');
+                    let kind = args.next();
+                    let addr = args.next().and_then(parse_hex);
+                    match (kind, addr) {
+                        (Some("0"), Some(addr)) => {
+                            let addr = VirtAddr(addr as usize);
+                            if cmd == "Z" {
+                                self.breakpoints.insert(addr);
+                            } else {
+                                self.breakpoints.remove(&addr);
+                            }
+                            "OK".into()
+                        }
+                        _ => String::new(),
+                    }
+                }
+                "c" | "s" => {
+                    // Optionally resume at a new address
+                    if let Some(addr) = parse_hex(args) {
+                        self.emu.set_reg(Register::Pc, addr);
+                    }
+
+                    match self.resume(cmd == "s")? {
+                        Stop::Signal(signal) => format!("S{:02x}", signal),
+                        Stop::Exited(status) => {
+                            print!("Guest exited with status {}\n", status);
+                            self.send_packet(
+                                &format!("W{:02x}", status as u8))?;
+                            return Ok(());
+                        }
+                    }
+                }
+                "k" => return Ok(()),
+                "D" => {
+                    self.send_packet("OK")?;
+                    return Ok(());
+                }
+                "H" => "OK".into(),
+                "T" => "OK".into(),
+                "q" => self.query(args),
+                _ => String::new(),
+            };
+
+            self.send_packet(&response)?;
+        }
+    }
+
+    /// Handle a general query packet `q<args>`
+    fn query(&self, args: &str) -> String {
+        if args.starts_with("Supported") {
+            "PacketSize=4000;qXfer:features:read+".into()
+        } else if args.starts_with("Xfer:features:read:target.xml:") {
+            // Send the requested window of the target description
+            let xml = target_xml();
+            match parse_addr_len(&args["Xfer:features:read:target.xml:"
+                                       .len()..]) {
+                Some((VirtAddr(off), len)) => {
+                    let off   = std::cmp::min(off, xml.len());
+                    let end   = std::cmp::min(off.saturating_add(len),
+                                              xml.len());
+                    let chunk = &xml[off..end];
+                    if end == xml.len() {
+                        format!("l{}", chunk)
+                    } else {
+                        format!("m{}", chunk)
+                    }
+                }
+                None => "E01".into(),
+            }
+        } else if args == "Attached" {
+            "1".into()
+        } else if args == "fThreadInfo" {
+            "m1".into()
+        } else if args == "sThreadInfo" {
+            "l".into()
+        } else if args == "C" {
+            "QC1".into()
+        } else {
+            String::new()
+        }
+    }
+}
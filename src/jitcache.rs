@@ -1,6 +1,9 @@
-use std::sync::Mutex;
+use std::io;
+use std::sync::{Arc, Mutex, Weak};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use crate::mmu::VirtAddr;
 
 #[cfg(target_os="windows")]
@@ -40,6 +43,332 @@ pub fn alloc_rwx(size: usize) -> &'static mut [u8] {
     }
 }
 
+/// Directory compiled JIT code is cached in across runs, named by the hash of
+/// the C++ it was compiled from
+pub const CACHE_DIR: &str = "jitcache";
+
+/// Default maximum number of instructions lifted into one JIT compile unit
+pub const DEFAULT_MAX_BLOCK_INSTRS: usize = 1000;
+
+/// Maximum number of threads compiling JIT code in the background at once
+const COMPILE_THREADS: usize = 4;
+
+/// Size of the RWX memory JIT code is mapped into
+const JIT_SIZE: usize = 256 * 1024 * 1024;
+
+/// Epoch published by workers which are not running any JIT code
+const QUIESCENT: usize = !0;
+
+/// How JIT code is compiled
+#[derive(Clone, Debug)]
+pub struct JitConfig {
+    /// C++ compiler to invoke, eg. `clang++` or `g++`
+    pub compiler: String,
+
+    /// Flags passed to the compiler after the default ones, such that they
+    /// can override them (eg. `-march=`)
+    pub extra_flags: Vec<String>,
+
+    /// Linker script the code is linked with
+    pub linker_script: PathBuf,
+}
+
+impl Default for JitConfig {
+    fn default() -> Self {
+        JitConfig {
+            compiler:      "clang++".into(),
+            extra_flags:   Vec::new(),
+            linker_script: "ldscript.ld".into(),
+        }
+    }
+}
+
+impl JitConfig {
+    /// Check if the tools needed to compile JIT code (the compiler and
+    /// `objcopy`) can be launched
+    pub fn toolchain_available(&self) -> bool {
+        [self.compiler.as_str(), "objcopy"].iter().all(|tool| {
+            Command::new(tool).arg("--version")
+                .stdout(Stdio::null()).stderr(Stdio::null())
+                .status().map(|status| status.success()).unwrap_or(false)
+        })
+    }
+}
+
+/// Compile the C++ `program` as described by `config` into a flat binary,
+/// and store it as `cachename` in the JIT cache directory. Returns the
+/// compiled code, or why it could not be compiled.
+fn compile(config: &JitConfig, program: &str, cachename: &Path)
+        -> Result<Vec<u8>, String> {
+    let cppfn = std::env::temp_dir().join(
+        format!("fwetmp_{:?}.cpp",
+                std::thread::current().id()));
+    let linkfn = std::env::temp_dir().join(
+        format!("fwetmp_{:?}.lunk",
+                std::thread::current().id()));
+    let binfn = std::env::temp_dir().join(
+        format!("fwetmp_{:?}.bin",
+                std::thread::current().id()));
+
+    // Write out the test program
+    std::fs::write(&cppfn, program)
+        .map_err(|err| format!("Failed to write program: {}", err))?;
+
+    // Create the ELF
+    let res = Command::new(&config.compiler).args(&[
+        "-O3", "-march=native", "-Wall",
+        "-fno-asynchronous-unwind-tables",
+        "-Wno-unused-label",
+        "-Wno-unused-variable",
+        "-Werror",
+        "-fno-strict-aliasing",
+        "-static", "-nostdlib", "-ffreestanding",
+        "-Wl,--gc-sections", "-Wl,--build-id=none"])
+        .arg(format!("-Wl,-T{}", config.linker_script.display()))
+        .args(&config.extra_flags)
+        .args(&["-o", linkfn.to_str().unwrap(),
+                cppfn.to_str().unwrap()]).status()
+        .map_err(|err| {
+            format!("Failed to launch {}: {}", config.compiler, err)
+        })?;
+    if !res.success() {
+        return Err(format!("{} returned error", config.compiler));
+    }
+
+    // Convert the ELF to a binary
+    let res = Command::new("objcopy")
+        .args(&["-O", "binary", "--remove-section=.note.gnu.property",
+                linkfn.to_str().unwrap(),
+                binfn.to_str().unwrap()]).status()
+        .map_err(|err| format!("Failed to launch objcopy: {}", err))?;
+    if !res.success() {
+        return Err("objcopy returned error".into());
+    }
+
+    // Move the compiled output to the cache. The code is read first, as the
+    // cache file may be pruned as soon as it is there.
+    let code = std::fs::read(&binfn)
+        .map_err(|err| format!("Failed to read JIT code: {}", err))?;
+    std::fs::rename(&binfn, cachename).map_err(|err| {
+        format!("Failed to rename compiled JIT to cache file: {}", err)
+    })?;
+
+    Ok(code)
+}
+
+/// Delete the least recently created files in the JIT cache directory until
+/// it holds at most `max_size` bytes. Returns the number of files deleted.
+pub fn prune_cache_dir(max_size: u64) -> io::Result<usize> {
+    let mut files = Vec::new();
+    let mut total = 0;
+    for entry in std::fs::read_dir(CACHE_DIR)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            total += metadata.len();
+            files.push((metadata.modified()?, metadata.len(), entry.path()));
+        }
+    }
+
+    // Delete the oldest files first
+    files.sort();
+    let mut deleted = 0;
+    for (_, len, path) in files {
+        if total <= max_size {
+            break;
+        }
+
+        // Another process sharing the cache may have deleted it already
+        match std::fs::remove_file(&path) {
+            Ok(()) => deleted += 1,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        total -= len;
+    }
+
+    Ok(deleted)
+}
+
+/// A request to compile JIT code in the background
+struct CompileJob {
+    /// Guest address the compiled code is mapped at
+    pc: VirtAddr,
+
+    /// C++ source of the code
+    program: String,
+
+    /// File in the JIT cache directory the compiled code is stored as
+    cachename: PathBuf,
+
+    /// `JitCache::generation` when the code was lifted, the code is thrown
+    /// away if the cache has been invalidated since
+    generation: usize,
+}
+
+/// Background compile jobs of a `JitCache`
+#[derive(Default)]
+struct CompileQueue {
+    /// Jobs waiting for a compile thread
+    jobs: VecDeque<CompileJob>,
+
+    /// Cache files which are queued or being compiled, used to dedup jobs
+    pending: BTreeSet<PathBuf>,
+
+    /// Cache files which failed to compile, they are not queued again and
+    /// their code is left to the interpreter
+    failed: BTreeSet<PathBuf>,
+
+    /// Number of compile threads running
+    threads: usize,
+}
+
+/// Code mapped into the JIT memory
+struct Region {
+    /// Length of the code, the region is this rounded up to 64 bytes
+    len: usize,
+
+    /// Number of guest addresses mapped to the code
+    refs: usize,
+}
+
+/// The RWX memory JIT code is mapped into
+struct JitMemory {
+    /// The raw RWX backing
+    backing: &'static mut [u8],
+
+    /// Number of bytes at the start of `backing` which have been handed out,
+    /// bytes past this are all free
+    bump: usize,
+
+    /// Address of the code with the given contents, to dedup identical code
+    dedup: BTreeMap<Vec<u8>, usize>,
+
+    /// Code which is mapped, by its address
+    regions: BTreeMap<usize, Region>,
+
+    /// Bytes held by `regions`
+    in_use: usize,
+
+    /// Indices of the entries of `JitCache::blocks` which are mapped, such
+    /// that evicting and invalidating code never scans the whole table,
+    /// which is mostly untouched and thus takes up no host memory
+    mapped: BTreeSet<usize>,
+
+    /// `(epoch, offset, size)` of code which is no longer mapped, but which
+    /// workers may still be running as of `epoch`
+    retired: Vec<(usize, usize, usize)>,
+
+    /// Free space before `bump`, offset to size
+    free: BTreeMap<usize, usize>,
+
+    /// Epochs published by the workers running code from the cache, workers
+    /// which have been dropped are skipped
+    workers: Vec<Weak<AtomicUsize>>,
+}
+
+/// A worker running code from a `JitCache`, which publishes the epoch it
+/// looked up code in such that the code is not reclaimed while it may still
+/// be running it
+pub struct JitWorker {
+    /// Epoch published by the last `JitCache::enter()`, or `QUIESCENT`
+    epoch: Arc<AtomicUsize>,
+}
+
+impl JitWorker {
+    /// Mark the worker as not running any JIT code until the next
+    /// `JitCache::enter()`, such that reclaiming code does not wait on it
+    pub fn leave(&self) {
+        self.epoch.store(QUIESCENT, Ordering::SeqCst);
+    }
+}
+
+impl JitMemory {
+    /// Allocate `size` bytes, a multiple of 64, returning the offset into
+    /// the backing. Free space is reused before growing into fresh memory.
+    fn allocate(&mut self, size: usize) -> usize {
+        let fit = self.free.iter().find(|(_, &free)| free >= size)
+            .map(|(&offset, &free)| (offset, free));
+        if let Some((offset, free)) = fit {
+            self.free.remove(&offset);
+            if free > size {
+                self.free.insert(offset + size, free - size);
+            }
+            return offset;
+        }
+
+        let offset = self.bump;
+        assert!(self.backing.len() - offset > size, "Out of space in JIT");
+        self.bump += size;
+        offset
+    }
+
+    /// Free the `size` bytes at `offset`, merging them with the adjacent
+    /// free space
+    fn free(&mut self, mut offset: usize, mut size: usize) {
+        let prev = self.free.range(..offset).next_back()
+            .map(|(&prev, &prev_size)| (prev, prev_size));
+        if let Some((prev, prev_size)) = prev {
+            if prev + prev_size == offset {
+                self.free.remove(&prev);
+                offset = prev;
+                size  += prev_size;
+            }
+        }
+        if let Some(next_size) = self.free.remove(&(offset + size)) {
+            size += next_size;
+        }
+
+        if offset + size == self.bump {
+            self.bump = offset;
+        } else {
+            self.free.insert(offset, size);
+        }
+    }
+
+    /// Free the retired code which no worker can be running anymore, that
+    /// is once every worker has left the JIT or entered it in a later epoch
+    /// than the code was retired in
+    fn reclaim(&mut self) {
+        self.workers.retain(|worker| worker.strong_count() > 0);
+        let oldest = self.workers.iter().filter_map(|worker| {
+            worker.upgrade().map(|epoch| epoch.load(Ordering::SeqCst))
+        }).min().unwrap_or(QUIESCENT);
+
+        let mut ii = 0;
+        while ii < self.retired.len() {
+            let (retired, offset, size) = self.retired[ii];
+            if retired < oldest {
+                self.retired.swap_remove(ii);
+                self.free(offset, size);
+            } else {
+                ii += 1;
+            }
+        }
+    }
+
+    /// Drop a guest address' reference to the code at `addr`, retiring the
+    /// code in `epoch` once nothing maps to it anymore
+    fn unref(&mut self, addr: usize, epoch: usize) {
+        let region = self.regions.get_mut(&addr)
+            .expect("Mapped JIT code without a region");
+        region.refs -= 1;
+        if region.refs > 0 {
+            return;
+        }
+
+        let len = region.len;
+        self.regions.remove(&addr);
+
+        let offset = addr - self.backing.as_ptr() as usize;
+        let size = (len + 0x3f) & !0x3f;
+        let code = self.backing[offset..offset + len].to_vec();
+        self.dedup.remove(&code);
+        self.in_use -= size;
+        self.retired.push((epoch, offset, size));
+    }
+}
+
 /// A cache which stores cached JIT blocks and translation tables to them
 pub struct JitCache {
     /// A vector which contains the addresses of JIT code for the corresponding
@@ -55,9 +384,30 @@ pub struct JitCache {
     /// variant)
     blocks: Box<[AtomicUsize]>,
 
-    /// The raw JIT RWX backing, the amount of bytes in use, and a dedup
-    /// table
-    jit: Mutex<(&'static mut [u8], usize, BTreeMap<Vec<u8>, usize>)>,
+    /// Epoch in which each entry of `blocks` was last looked up or mapped,
+    /// to evict the least recently used code
+    last_use: Box<[AtomicUsize]>,
+
+    /// Current epoch, advanced by every `prune()`
+    epoch: AtomicUsize,
+
+    /// Bytes of mapped JIT code `prune()` keeps the cache below
+    max_size: usize,
+
+    /// The memory JIT code is mapped into
+    jit: Mutex<JitMemory>,
+
+    /// Code waiting to be compiled in the background
+    compile_queue: Mutex<CompileQueue>,
+
+    /// Maximum number of instructions lifted into one compile unit
+    max_block_instrs: usize,
+
+    /// Number of times the cache has been invalidated
+    generation: AtomicUsize,
+
+    /// How the JIT code is compiled
+    config: JitConfig,
 }
 
 // JIT calling convention
@@ -86,17 +436,197 @@ pub struct JitCache {
 // 7 - Breakpoint, rcx = reentry point
 // 8 - Invalid opcode
 
+/// Allocate a table of `entries` zeroed atomics. The memory comes zeroed
+/// from the allocator, such that tables covering all of a large guest memory
+/// only take up host memory for the parts which are used.
+fn zeroed_table(entries: usize) -> Box<[AtomicUsize]> {
+    let table = vec![0usize; entries].into_boxed_slice();
+
+    // `AtomicUsize` has the same in-memory representation as `usize`
+    unsafe { Box::from_raw(Box::into_raw(table) as *mut [AtomicUsize]) }
+}
+
 impl JitCache {
     /// Allocates a new `JitCache` which is capable of handling up to
-    /// `max_guest_addr` in executable code.
-    pub fn new(max_guest_addr: VirtAddr) -> Self {
+    /// `max_guest_addr` in executable code, compiled as described by
+    /// `config`.
+    pub fn new(max_guest_addr: VirtAddr, config: JitConfig) -> Self {
         JitCache {
             // Allocate a zeroed out block cache
-            blocks: (0..(max_guest_addr.0 + 3) / 4).map(|_| {
-                AtomicUsize::new(0)
-            }).collect::<Vec<_>>().into_boxed_slice(),
-            jit:
-                Mutex::new((alloc_rwx(256 * 1024 * 1024), 0, BTreeMap::new())),
+            blocks:   zeroed_table((max_guest_addr.0 + 3) / 4),
+            last_use: zeroed_table((max_guest_addr.0 + 3) / 4),
+            epoch: AtomicUsize::new(0),
+            max_size: JIT_SIZE,
+            jit: Mutex::new(JitMemory {
+                backing:  alloc_rwx(JIT_SIZE),
+                bump:     0,
+                dedup:    BTreeMap::new(),
+                regions:  BTreeMap::new(),
+                in_use:   0,
+                mapped:   BTreeSet::new(),
+                retired:  Vec::new(),
+                free:     BTreeMap::new(),
+                workers:  Vec::new(),
+            }),
+            compile_queue: Default::default(),
+            max_block_instrs: DEFAULT_MAX_BLOCK_INSTRS,
+            generation: AtomicUsize::new(0),
+            config,
+        }
+    }
+
+    /// Get the configuration JIT code is compiled with
+    pub fn config(&self) -> &JitConfig {
+        &self.config
+    }
+
+    /// Set the maximum number of instructions lifted into one compile unit.
+    /// Lifting stops once the limit is reached and the code exits to the
+    /// JIT at the next instruction instead, making that a separate unit.
+    pub fn set_max_block_instrs(&mut self, instrs: usize) {
+        assert!(instrs > 0, "JIT blocks must hold at least one instruction");
+        self.max_block_instrs = instrs;
+    }
+
+    /// Get the maximum number of instructions lifted into one compile unit
+    pub fn max_block_instrs(&self) -> usize {
+        self.max_block_instrs
+    }
+
+    /// Have `prune()` evict the least recently used code once more than
+    /// `size` bytes of code are mapped. By default nothing is evicted.
+    pub fn set_max_size(&mut self, size: usize) {
+        self.max_size = size;
+    }
+
+    /// Get the number of bytes of JIT code which are mapped
+    pub fn size(&self) -> usize {
+        self.jit.lock().unwrap().in_use
+    }
+
+    /// Get the number of guest addresses which have JIT code mapped
+    pub fn mapped_blocks(&self) -> usize {
+        self.jit.lock().unwrap().mapped.len()
+    }
+
+    /// Start a new epoch and, if more than the maximum size of code is
+    /// mapped, evict the least recently used code which was not used in the
+    /// previous epoch. Returns the number of guest addresses evicted, their
+    /// code is lifted again the next time it's executed.
+    ///
+    /// This is to be called periodically. The memory of evicted code is only
+    /// reused once no worker can be running it anymore, see `enter()`.
+    pub fn prune(&self) -> usize {
+        let mut jit = self.jit.lock().unwrap();
+        let epoch = self.epoch.fetch_add(1, Ordering::SeqCst) + 1;
+        jit.reclaim();
+
+        if jit.in_use <= self.max_size {
+            return 0;
+        }
+
+        // Get the mapped addresses not used this or the previous epoch,
+        // least recently used first
+        let mut candidates: Vec<(usize, usize)> = jit.mapped.iter()
+            .map(|&idx| (self.last_use[idx].load(Ordering::Relaxed), idx))
+            .filter(|&(last_use, _)| last_use + 1 < epoch).collect();
+        candidates.sort();
+
+        // Evict down to three quarters of the maximum size, such that we are
+        // not evicting again right away
+        let mut evicted = 0;
+        for (_, idx) in candidates {
+            if jit.in_use <= self.max_size / 4 * 3 {
+                break;
+            }
+
+            let addr = self.blocks[idx].swap(0, Ordering::SeqCst);
+            jit.mapped.remove(&idx);
+            jit.unref(addr, epoch);
+            evicted += 1;
+        }
+
+        evicted
+    }
+
+    /// Register a worker which is going to run code from this cache
+    pub fn register_worker(&self) -> JitWorker {
+        let epoch = Arc::new(AtomicUsize::new(QUIESCENT));
+        self.jit.lock().unwrap().workers.push(Arc::downgrade(&epoch));
+        JitWorker { epoch }
+    }
+
+    /// Publish the current epoch for `worker`, which is to be done before
+    /// every lookup of code to run. Code evicted or invalidated in an epoch
+    /// is only reclaimed once every worker has published a later one, or
+    /// left the JIT.
+    pub fn enter(&self, worker: &JitWorker) {
+        worker.epoch.store(self.epoch.load(Ordering::SeqCst),
+                           Ordering::SeqCst);
+    }
+
+    /// Check if any code is queued or being compiled in the background
+    pub fn compiling(&self) -> bool {
+        !self.compile_queue.lock().unwrap().pending.is_empty()
+    }
+
+    /// Get the number of compile units which failed to compile
+    pub fn compile_failures(&self) -> usize {
+        self.compile_queue.lock().unwrap().failed.len()
+    }
+
+    /// Compile `program` into `cachename` on a background thread, then map
+    /// the code at `pc` in `jit_cache`. Nothing is done if `cachename` is
+    /// already queued or being compiled, or failed to compile before.
+    ///
+    /// Compiling takes hundreds of milliseconds, so rather than waiting on it
+    /// the caller should continue with the emulator until `lookup()` finds
+    /// the code.
+    pub fn compile_background(jit_cache: &Arc<JitCache>, pc: VirtAddr,
+                              program: String, cachename: PathBuf) {
+        let mut queue = jit_cache.compile_queue.lock().unwrap();
+        if queue.failed.contains(&cachename) ||
+                !queue.pending.insert(cachename.clone()) {
+            return;
+        }
+
+        print!("Compiling cache for {:#018x} -> {:?}\n", pc.0, cachename);
+        let generation = jit_cache.generation.load(Ordering::SeqCst);
+        queue.jobs.push_back(
+            CompileJob { pc, program, cachename, generation });
+
+        // Start another compile thread if we're not at the limit, threads
+        // exit once there's nothing left to compile
+        if queue.threads < COMPILE_THREADS {
+            queue.threads += 1;
+
+            let jit_cache = jit_cache.clone();
+            std::thread::spawn(move || loop {
+                let job = {
+                    let mut queue = jit_cache.compile_queue.lock().unwrap();
+                    if let Some(job) = queue.jobs.pop_front() {
+                        job
+                    } else {
+                        queue.threads -= 1;
+                        return;
+                    }
+                };
+
+                let result = compile(&jit_cache.config, &job.program,
+                                     &job.cachename);
+                if let Ok(code) = &result {
+                    jit_cache.add_mapping_since(job.pc, code,
+                                                Some(job.generation));
+                }
+
+                let mut queue = jit_cache.compile_queue.lock().unwrap();
+                queue.pending.remove(&job.cachename);
+                if let Err(err) = result {
+                    print!("Failed to compile {:#018x} -> {:?}: {}\n",
+                           job.pc.0, job.cachename, err);
+                    queue.failed.insert(job.cachename);
+                }
+            });
         }
     }
 
@@ -118,32 +648,79 @@ impl JitCache {
             return None;
         }
 
-        let addr = self.blocks.get(addr.0 / 4)?.load(Ordering::SeqCst);
+        let idx = addr.0 / 4;
+        let addr = self.blocks.get(idx)?.load(Ordering::SeqCst);
         if addr == 0 {
             None
         } else {
+            // Record the use for pruning, only storing if it changed such
+            // that the workers do not fight over the cache line
+            let epoch = self.epoch.load(Ordering::Relaxed);
+            if self.last_use[idx].load(Ordering::Relaxed) != epoch {
+                self.last_use[idx].store(epoch, Ordering::Relaxed);
+            }
             Some(addr)
         }
     }
 
+    /// Throw away all translations, such that code is lifted again the next
+    /// time it's executed. Used when executable memory is modified before
+    /// there are other emulators sharing the cache, eg. while getting to the
+    /// snapshot.
+    ///
+    /// The JIT memory holding the old code is reclaimed like that of evicted
+    /// code, see `prune()`.
+    pub fn invalidate(&self) {
+        let mut jit = self.jit.lock().unwrap();
+        let epoch = self.epoch.load(Ordering::SeqCst);
+
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        for idx in std::mem::take(&mut jit.mapped) {
+            let addr = self.blocks[idx].swap(0, Ordering::SeqCst);
+            jit.unref(addr, epoch);
+        }
+    }
+
     /// Update the JIT for a given virtual address, returns the JIT address
     /// of the new (or existing) JIT corresponding to `addr`
     pub fn add_mapping(&self, addr: VirtAddr, code: &[u8]) -> usize {
+        self.add_mapping_since(addr, code, None).unwrap()
+    }
+
+    /// Update the JIT for a given virtual address, unless `generation` is
+    /// set and the cache has been invalidated since, as `code` may be stale,
+    /// or the address is not covered by the translation table. Returns the
+    /// JIT address of the new (or existing) JIT corresponding to `addr`.
+    fn add_mapping_since(&self, addr: VirtAddr, code: &[u8],
+                         generation: Option<usize>) -> Option<usize> {
         // Make sure the address is aligned
         assert!(addr.0 & 3 == 0, "Unaligned code address to JIT lookup");
 
+        // Checked before taking the lock, such that code mapped past the end
+        // of the table never poisons it for the other workers
+        if addr.0 / 4 >= self.blocks.len() {
+            return None;
+        }
+
         // Get exclusive access to the JIT
         let mut jit = self.jit.lock().unwrap();
 
+        // Invalidation also happens with the lock held
+        if let Some(generation) = generation {
+            if self.generation.load(Ordering::SeqCst) != generation {
+                return None;
+            }
+        }
+
         // Now that we have the lock, check if there's already an existing
         // mapping. If there is not, there is no way one could show up while
         // we have the lock held, thus we can safely continue from this point.
         if let Some(existing) = self.lookup(addr) {
-            return existing;
+            return Some(existing);
         }
 
         // Check if we already have identical code
-        let new_addr = if let Some(&existing) = jit.2.get(code) {
+        let new_addr = if let Some(&existing) = jit.dedup.get(code) {
             // We have identical code, alias this code for the requested PC
             existing
         } else {
@@ -151,31 +728,35 @@ impl JitCache {
             // vector operations because we ensure alignment of loaded JITs
             let align_size = (code.len() + 0x3f) & !0x3f;
 
-            // Number of remaining bytes in the JIT storage
-            let jit_inuse  = jit.1;
-            let jit_remain = jit.0.len() - jit_inuse;
-            assert!(jit_remain > align_size, "Out of space in JIT");
+            // Get space for the code, reusing that of retired code if we can
+            jit.reclaim();
+            let offset = jit.allocate(align_size);
 
             // Copy the new code into the JIT
-            jit.0[jit_inuse..jit_inuse + code.len()].copy_from_slice(code);
+            jit.backing[offset..offset + code.len()].copy_from_slice(code);
 
             // Compute the address of the JIT we're inserting
-            let new_addr = jit.0[jit_inuse..].as_ptr() as usize;
-            
+            let new_addr = jit.backing[offset..].as_ptr() as usize;
+
             // Update the in use for the JIT
-            jit.1 += align_size;
+            jit.in_use += align_size;
+            jit.regions.insert(new_addr, Region { len: code.len(), refs: 0 });
 
             // Update the dedup table
-            assert!(jit.2.insert(code.into(), new_addr).is_none());
+            assert!(jit.dedup.insert(code.into(), new_addr).is_none());
 
             new_addr
         };
+        jit.regions.get_mut(&new_addr).unwrap().refs += 1;
 
         // Update the JIT lookup address
+        self.last_use[addr.0 / 4].store(self.epoch.load(Ordering::SeqCst),
+                                        Ordering::Relaxed);
         self.blocks[addr.0 / 4].store(new_addr, Ordering::SeqCst);
+        jit.mapped.insert(addr.0 / 4);
 
         // Return the newly allocated JIT
-        new_addr
+        Some(new_addr)
     }
 }
 
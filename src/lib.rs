@@ -0,0 +1,554 @@
+pub mod primitive;
+pub mod mmu;
+pub mod emulator;
+pub mod jitcache;
+pub mod elf;
+pub mod gdb;
+pub mod syscall;
+pub mod mutator;
+
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use mmu::VirtAddr;
+use emulator::{FaultType, AddressType, coverage_bit};
+
+use aht::Aht;
+use falkhash::FalkHasher;
+use atomicvec::AtomicVec;
+
+/// Number of random inputs considered each time the scheduler picks an input
+/// from the corpus. The winner is picked weighted by each candidate's energy.
+const SCHEDULE_CANDIDATES: usize = 4;
+
+/// Name of the index in an exported corpus, listing the hash of every input
+/// along with the coverage edges it found
+const CORPUS_INDEX: &str = "index.txt";
+
+/// Read the timestamp counter
+pub fn rdtsc() -> u64 {
+    unsafe { std::arch::x86_64::_rdtsc() }
+}
+
+/// A xorshift random number generator
+pub struct Rng(u64);
+
+impl Rng {
+    /// Create a new random number generator
+    pub fn new() -> Self {
+        Self::with_seed(rdtsc())
+    }
+
+    /// Create a new random number generator which produces a fixed sequence
+    /// for `seed`
+    pub fn with_seed(seed: u64) -> Self {
+        // A xorshift state of zero would only ever produce zeros
+        let state = 0x8644d6eb17b7ab1a ^ seed;
+        Rng(if state == 0 { 0x8644d6eb17b7ab1a } else { state })
+    }
+
+    /// Generate a random number
+    #[inline]
+    pub fn rand(&mut self) -> usize {
+        let val = self.0;
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 43;
+        val as usize
+    }
+}
+
+/// An input in the corpus along with its scheduling metadata
+pub struct Input {
+    /// Raw bytes of the input
+    pub data: Vec<u8>,
+
+    /// Number of times this input has been selected for fuzzing
+    pub selections: AtomicU64,
+
+    /// Running average of the instructions executed by fuzz cases derived
+    /// from this input, zero until the first one is run
+    pub avg_instrs: AtomicU64,
+
+    /// Number of fuzz cases derived from this input since one last found new
+    /// coverage, or since the input was saved
+    pub staleness: AtomicU64,
+
+    /// Coverage edges (from, to) which were first found by this input
+    pub edges: Vec<(VirtAddr, VirtAddr)>,
+
+    /// Set once the deterministic mutation stage was done on this input. It
+    /// is set as soon as a worker takes the stage on, such that no other
+    /// worker repeats it.
+    pub deterministic_done: AtomicBool,
+}
+
+impl Input {
+    /// Create a new, never selected, input from `data`
+    pub fn new(data: Vec<u8>) -> Self {
+        Self::with_edges(data, Vec::new())
+    }
+
+    /// Create a new, never selected, input from `data` which found the
+    /// coverage `edges`
+    pub fn with_edges(data: Vec<u8>, edges: Vec<(VirtAddr, VirtAddr)>)
+            -> Self {
+        Input {
+            data:       data,
+            selections: AtomicU64::new(0),
+            avg_instrs: AtomicU64::new(0),
+            staleness:  AtomicU64::new(0),
+            edges:      edges,
+            deterministic_done: AtomicBool::new(false),
+        }
+    }
+
+    /// Record that a fuzz case derived from this input executed `instrs`
+    /// instructions, updating the running average
+    pub fn record_instrs(&self, instrs: u64) {
+        // Races between workers may lose an update, which is harmless
+        let avg = self.avg_instrs.load(Ordering::Relaxed);
+        let avg = if avg == 0 {
+            instrs
+        } else {
+            avg - avg / 8 + instrs / 8
+        };
+        self.avg_instrs.store(avg, Ordering::Relaxed);
+    }
+
+    /// Record that a fuzz case derived from this input was run, which found
+    /// new coverage if `found_coverage` is set
+    pub fn record_case(&self, found_coverage: bool) {
+        if found_coverage {
+            self.staleness.store(0, Ordering::Relaxed);
+        } else {
+            self.staleness.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Get the number of fuzz cases derived from this input since one last
+    /// found new coverage
+    pub fn staleness(&self) -> u64 {
+        self.staleness.load(Ordering::Relaxed)
+    }
+
+    /// Get the scheduling energy of this input. Loosely modeled on AFL, small
+    /// inputs, inputs which have been selected fewer times, and inputs which
+    /// are cheaper to execute get more energy.
+    ///
+    /// All factors are bucketed logarithmically so a 4 MiB input is only a
+    /// handful of times less likely to be picked than a 4 byte one, rather
+    /// than a million times less likely.
+    pub fn energy(&self) -> u64 {
+        let size = 65 - (self.data.len() as u64).leading_zeros() as u64;
+        let uses = 65 - self.selections.load(Ordering::Relaxed)
+            .leading_zeros() as u64;
+        let cost = 65 - self.avg_instrs.load(Ordering::Relaxed)
+            .leading_zeros() as u64;
+        (1 << 32) / (size * uses * cost)
+    }
+}
+
+/// Key crashes are deduplicated on. Tuple is (PC, FaultType, AddressType,
+/// call stack hash, offset into the faulting allocation if tracked)
+pub type CrashKey = (VirtAddr, FaultType, AddressType, u64, Option<usize>);
+
+/// A unique crash
+pub struct Crash {
+    /// Key the crash is deduplicated on
+    pub key: CrashKey,
+
+    /// Name the crash is saved under in the `crashes` directory
+    pub name: String,
+
+    /// Input which first hit the crash
+    pub input: Vec<u8>,
+
+    /// Number of times the crash has been hit
+    pub hits: AtomicU64,
+}
+
+impl Crash {
+    /// Create a new crash for `key` first hit by `input` saved as `name`,
+    /// hit once
+    pub fn new(key: CrashKey, name: String, input: Vec<u8>) -> Self {
+        Crash {
+            key:   key,
+            name:  name,
+            input: input,
+            hits:  AtomicU64::new(1),
+        }
+    }
+}
+
+/// Default number of bits in the coverage bitmap
+pub const DEFAULT_COVERAGE_BITS: usize = 64 * 1024 * 1024;
+
+/// Number of words in each shard of the coverage bitmap, a page worth
+pub const COVERAGE_SHARD_WORDS: usize = 512;
+
+/// Feedback policy of the corpus, deciding which coverage is worth keeping
+/// the fuzz input which found it
+pub trait Feedback: Send + Sync {
+    /// Handle the coverage edge `from` -> `to` taken by `input`, which is the
+    /// first time its bit was set in the coverage bitmap. Returns whether the
+    /// edge is new, in which case the input is saved to the corpus once the
+    /// fuzz case is done.
+    fn on_coverage(&self, corpus: &Corpus, from: VirtAddr, to: VirtAddr,
+                   input: &[u8]) -> bool;
+}
+
+/// Keeps inputs which found edges which are not yet in `code_coverage`
+pub struct CodeCoverageFeedback;
+
+impl Feedback for CodeCoverageFeedback {
+    fn on_coverage(&self, corpus: &Corpus, from: VirtAddr, to: VirtAddr,
+                   _input: &[u8]) -> bool {
+        corpus.code_coverage.entry_or_insert(&(from, to), to.0, || {
+            Box::new(())
+        }).inserted()
+    }
+}
+
+/// Information about inputs and coverage
+pub struct Corpus {
+    /// Input hash table to dedup inputs
+    pub input_hashes: Aht<u128, (), 1048576>,
+    
+    /// Linear list of all inputs
+    pub inputs: AtomicVec<Input, 1048576>,
+    
+    /// Unique crashes
+    pub unique_crashes: Aht<CrashKey, Arc<Crash>, 1048576>,
+
+    /// Linear list of all unique crashes, for reporting
+    pub crashes: AtomicVec<Arc<Crash>, 1048576>,
+
+    /// Code coverage, (to, from) edges for _all_ branches, including
+    /// taken, not taken, indirect, and unconditional
+    pub code_coverage: Aht<(VirtAddr, VirtAddr), (), 1048576>,
+
+    /// Hasher
+    pub hasher: FalkHasher,
+
+    /// Coverage bitmap, indexed by edge hash. Distinct edges may hash to
+    /// the same bit, see `estimated_collisions()`.
+    pub coverage_bitmap: Vec<AtomicU64>,
+
+    /// Number of bits set in each shard of `COVERAGE_SHARD_WORDS` words of
+    /// the coverage bitmap, such that emulators only merge the shards which
+    /// changed into their local bitmap
+    pub coverage_shards: Vec<AtomicU64>,
+
+    /// Feedback deciding which coverage saves inputs, `CodeCoverageFeedback`
+    /// by default
+    pub feedback: Box<dyn Feedback>,
+}
+
+impl Corpus {
+    /// Create a new, empty, corpus
+    pub fn new() -> Self {
+        Self::with_coverage_bits(DEFAULT_COVERAGE_BITS)
+    }
+
+    /// Create a new, empty, corpus with a coverage bitmap of `bits` bits,
+    /// which must be a power of two of at least 64. Larger bitmaps merge
+    /// fewer distinct edges, at the cost of memory and cache misses.
+    pub fn with_coverage_bits(bits: usize) -> Self {
+        assert!(bits.count_ones() == 1 && bits >= 64,
+            "Coverage bitmap must be a power of two of at least 64 bits");
+
+        Corpus {
+            input_hashes:    Aht::new(),
+            inputs:          AtomicVec::new(),
+            unique_crashes:  Aht::new(),
+            crashes:         AtomicVec::new(),
+            code_coverage:   Aht::new(),
+            hasher:          FalkHasher::new(),
+            coverage_bitmap: (0..bits / 64).map(|_| AtomicU64::new(0))
+                .collect(),
+            coverage_shards: (0..(bits / 64).div_ceil(COVERAGE_SHARD_WORDS))
+                .map(|_| AtomicU64::new(0)).collect(),
+            feedback:        Box::new(CodeCoverageFeedback),
+        }
+    }
+
+    /// Use `feedback` rather than `CodeCoverageFeedback` to decide which
+    /// coverage saves inputs
+    pub fn with_feedback(mut self, feedback: Box<dyn Feedback>) -> Self {
+        self.feedback = feedback;
+        self
+    }
+
+    /// Set `bit` in word `idx` of the coverage bitmap. Returns whether the
+    /// bit was clear before.
+    pub fn set_coverage_bit(&self, idx: usize, bit: u64) -> bool {
+        if self.coverage_bitmap[idx].fetch_or(bit, Ordering::Relaxed) &
+                bit != 0 {
+            return false;
+        }
+
+        // Published after the bit, such that an emulator which sees the new
+        // count merges the bit as well
+        self.coverage_shards[idx / COVERAGE_SHARD_WORDS]
+            .fetch_add(1, Ordering::Release);
+        true
+    }
+
+    /// Get the number of bits set in the coverage bitmap
+    pub fn coverage_bits_set(&self) -> u64 {
+        self.coverage_bitmap.iter()
+            .map(|x| x.load(Ordering::Relaxed).count_ones() as u64).sum()
+    }
+
+    /// Estimate the number of edges which were not reported as their hash
+    /// collided with that of an edge found before.
+    ///
+    /// Colliding edges never make it into `code_coverage`, so this is
+    /// estimated from how full the bitmap is: with `m` bits of which `k` are
+    /// set, about `-m * ln(1 - k / m)` distinct edges were hashed.
+    pub fn estimated_collisions(&self) -> u64 {
+        let bits = (self.coverage_bitmap.len() * 64) as f64;
+        let set  = self.coverage_bits_set() as f64;
+        if set >= bits {
+            // A full bitmap does not tell how many more edges there were
+            return u64::MAX;
+        }
+
+        let edges = -bits * (1. - set / bits).ln();
+        (edges - set).max(0.) as u64
+    }
+
+    /// Record a hit of the crash `key` by `input`. If the crash is new,
+    /// `save` is invoked to save it and returns the name it was saved as.
+    pub fn record_crash<F>(&self, key: &CrashKey, input: &[u8], save: F)
+            where F: FnOnce() -> String {
+        let hash = (key.0).0 ^ key.3 as usize ^ key.4.unwrap_or(0);
+        let entry = self.unique_crashes.entry_or_insert(key, hash, || {
+            let crash = Arc::new(Crash::new(*key, save(), input.to_vec()));
+            self.crashes.push(Box::new(crash.clone()));
+            Box::new(crash)
+        });
+
+        if entry.exists() {
+            entry.entry().hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Export all inputs to the directory `path`, named by their hash, along
+    /// with an index of the coverage edges each of them found. The corpus
+    /// can then be resumed from with `import_corpus()`, on another machine.
+    pub fn export_corpus(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        std::fs::create_dir_all(path)?;
+
+        // One line per input, the hash followed by `from-to` edges
+        let mut index = String::new();
+        for idx in 0..self.inputs.len() {
+            if let Some(input) = self.inputs.get(idx) {
+                let hash = self.hasher.hash(&input.data);
+                std::fs::write(path.join(format!("{:032x}", hash)),
+                               &input.data)?;
+
+                index += &format!("{:032x}", hash);
+                for (from, to) in &input.edges {
+                    index += &format!(" {:x}-{:x}", from.0, to.0);
+                }
+                index += "\n";
+            }
+        }
+
+        std::fs::write(path.join(CORPUS_INDEX), index)
+    }
+
+    /// Import a corpus exported with `export_corpus()` from the directory
+    /// `path`, rebuilding the input hash table and the coverage it found.
+    /// Returns the number of inputs which were not yet in the corpus.
+    pub fn import_corpus(&self, path: impl AsRef<Path>) -> io::Result<usize> {
+        let path  = path.as_ref();
+        let index = std::fs::read_to_string(path.join(CORPUS_INDEX))?;
+        let invalid = |line: &str| io::Error::new(io::ErrorKind::InvalidData,
+            format!("Invalid corpus index line {:?}", line));
+
+        let mut imported = 0;
+        for line in index.lines() {
+            let mut fields = line.split_whitespace();
+            let name = match fields.next() {
+                Some(name) => name,
+                None       => continue,
+            };
+
+            // Parse the edges this input found
+            let edges = fields.map(|edge| {
+                let mut addrs = edge.splitn(2, '-')
+                    .map(|addr| usize::from_str_radix(addr, 16).ok());
+                match (addrs.next().flatten(), addrs.next().flatten()) {
+                    (Some(from), Some(to)) =>
+                        Ok((VirtAddr(from), VirtAddr(to))),
+                    _ => Err(invalid(line)),
+                }
+            }).collect::<io::Result<Vec<_>>>()?;
+
+            // Make sure the input is the one the index refers to
+            let data = std::fs::read(path.join(name))?;
+            let hash = self.hasher.hash(&data);
+            if format!("{:032x}", hash) != name {
+                return Err(invalid(line));
+            }
+
+            // Restore the coverage frontier
+            for &(from, to) in &edges {
+                self.code_coverage.entry_or_insert(
+                    &(from, to), to.0, || Box::new(()));
+                let (idx, bit) =
+                    coverage_bit(from.0 as u64, to.0 as u64, self);
+                self.set_coverage_bit(idx, bit);
+            }
+
+            // Save the input and log it in the hash table
+            if self.input_hashes.entry_or_insert(&hash, hash as usize, || {
+                self.inputs.push(Box::new(Input::with_edges(data, edges)));
+                Box::new(())
+            }).inserted() {
+                imported += 1;
+            }
+        }
+
+        Ok(imported)
+    }
+
+    /// Get the `count` most hit unique crashes, most hit first
+    pub fn top_crashes(&self, count: usize) -> Vec<&Crash> {
+        let mut crashes: Vec<&Crash> = self.crashes().collect();
+        crashes.sort_by_key(|crash|
+            std::cmp::Reverse(crash.hits.load(Ordering::Relaxed)));
+        crashes.truncate(count);
+        crashes
+    }
+
+    /// Get all unique crashes found so far, in the order they were found.
+    /// New crashes can be picked up by skipping the ones seen before.
+    pub fn crashes(&self) -> impl Iterator<Item = &Crash> {
+        (0..self.crashes.len())
+            .filter_map(move |idx| self.crashes.get(idx))
+            .map(|crash| &**crash)
+    }
+
+    /// Select an input to fuzz. A few random candidates are drawn from the
+    /// corpus and one is picked with a probability proportional to its
+    /// energy. Returns `None` if the corpus is empty.
+    ///
+    /// If none of the candidates could be picked, the one with the most
+    /// energy is used rather than always falling back to the first input,
+    /// which may well be the largest seed.
+    pub fn select_input(&self, rng: &mut Rng) -> Option<&Input> {
+        let len = self.inputs.len();
+        if len == 0 {
+            return None;
+        }
+
+        // Draw the candidates and sum up their energy
+        let mut candidates = [None; SCHEDULE_CANDIDATES];
+        let mut total = 0;
+        for candidate in candidates.iter_mut() {
+            if let Some(input) = self.inputs.get(rng.rand() % len) {
+                total += input.energy();
+                *candidate = Some(input);
+            }
+        }
+
+        // Pick a candidate weighted by its energy
+        let mut pick = rng.rand() as u64 % core::cmp::max(total, 1);
+        let input = candidates.iter().filter_map(|x| *x).find(|input| {
+            let energy = input.energy();
+            if pick < energy {
+                true
+            } else {
+                pick -= energy;
+                false
+            }
+        }).or_else(|| candidates.iter().filter_map(|x| *x)
+                   .max_by_key(|input| input.energy()))
+          .or_else(|| self.inputs.get(0))?;
+
+        // Track that this input was selected
+        input.selections.fetch_add(1, Ordering::Relaxed);
+        Some(input)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crashes() {
+        let corpus = Corpus::new();
+        let null = (VirtAddr(0x1000), FaultType::Read, AddressType::Null,
+                    0, None);
+        let heap = (VirtAddr(0x2000), FaultType::Write, AddressType::Heap,
+                    0, Some(0x10));
+        corpus.record_crash(&null, b"first", || "null".into());
+        corpus.record_crash(&heap, b"heap", || "heap".into());
+        corpus.record_crash(&null, b"second", || unreachable!());
+
+        let crashes: Vec<_> = corpus.crashes()
+            .map(|crash| (crash.key, crash.hits.load(Ordering::Relaxed)))
+            .collect();
+        assert!(crashes == [(null, 2), (heap, 1)]);
+        assert!(corpus.crashes().skip(1).map(|crash| &crash.name)
+                .eq(&["heap"]));
+
+        // The input which found the crash is kept
+        assert!(corpus.crashes().next().unwrap().input == b"first");
+    }
+
+    #[test]
+    fn test_input_energy() {
+        let cheap = Input::new(vec![0; 16]);
+        let slow  = Input::new(vec![0; 16]);
+        assert!(cheap.energy() == slow.energy());
+
+        // Cheaper inputs get more energy
+        cheap.record_instrs(100);
+        slow.record_instrs(100_000);
+        assert!(cheap.avg_instrs.load(Ordering::Relaxed) == 100);
+        assert!(cheap.energy() > slow.energy());
+
+        // The cost follows the average of the recent fuzz cases
+        for _ in 0..64 {
+            slow.record_instrs(100);
+        }
+        assert!(slow.avg_instrs.load(Ordering::Relaxed) < 200);
+        assert!(cheap.energy() == slow.energy());
+
+        // Staleness counts fuzz cases up to the next one finding coverage
+        cheap.record_case(false);
+        cheap.record_case(false);
+        assert!(cheap.staleness() == 2);
+        cheap.record_case(true);
+        assert!(cheap.staleness() == 0);
+    }
+
+    #[test]
+    fn test_coverage_collisions() {
+        let corpus = Corpus::with_coverage_bits(128);
+        assert!(corpus.coverage_bitmap.len() == 2);
+        assert!(corpus.estimated_collisions() == 0);
+
+        // Few bits set hardly collide, a half full map collides a lot
+        corpus.coverage_bitmap[0].store(0xf, Ordering::Relaxed);
+        assert!(corpus.coverage_bits_set() == 4);
+        assert!(corpus.estimated_collisions() == 0);
+        corpus.coverage_bitmap[1].store(!0, Ordering::Relaxed);
+        assert!(corpus.coverage_bits_set() == 68);
+        assert!(corpus.estimated_collisions() == 28);
+        corpus.coverage_bitmap[0].store(!0, Ordering::Relaxed);
+        assert!(corpus.estimated_collisions() == u64::MAX);
+
+        // Edges are hashed into the smaller map
+        let (idx, bit) = coverage_bit(0x1000, 0x1004, &corpus);
+        assert!(idx < 2 && bit.count_ones() == 1);
+    }
+}
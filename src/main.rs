@@ -1,431 +1,334 @@
-pub mod primitive;
-pub mod mmu;
-pub mod emulator;
-pub mod jitcache;
+mod config;
 
 use std::fs::File;
 use std::io::{self, Write};
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::{Duration, Instant};
-use std::collections::BTreeSet;
-use mmu::{VirtAddr, Perm, Section, PERM_READ, PERM_WRITE, PERM_EXEC};
-use emulator::{Emulator, Register, VmExit, EmuFile, FaultType, AddressType};
-use jitcache::JitCache;
-
-use aht::Aht;
-use falkhash::FalkHasher;
-use atomicvec::AtomicVec;
-
-/// If `true` the guest writes to stdout and stderr will be printed to our own
-/// stdout and stderr
-const VERBOSE_GUEST_PRINTS: bool = false;
+use fuzz_with_emus::{rdtsc, Rng, Corpus, Input};
+use fuzz_with_emus::gdb;
+use fuzz_with_emus::mutator::Mutator;
+use fuzz_with_emus::jitcache::{self, JitCache, JitConfig};
+use fuzz_with_emus::elf::Symbols;
+use fuzz_with_emus::syscall::{IgnoreSyscalls, DefaultSyscallHandler};
+use fuzz_with_emus::syscall::FaultInjection;
+use fuzz_with_emus::mmu::{VirtAddr, Perm, Section};
+use fuzz_with_emus::mmu::{PERM_READ, PERM_WRITE, PERM_EXEC};
+use fuzz_with_emus::emulator::{Emulator, Register, VmExit, EmuFile};
+use fuzz_with_emus::emulator::{EmulatorBuilder, HostFiles, FunctionHarness};
+use fuzz_with_emus::emulator::DEFAULT_MAX_MEMORY_SIZE;
+use config::{Config, StatsFormat};
+
+/// Number of innermost call stack frames which tell crashes apart, such that
+/// a crash in a shared helper (eg. `memcpy`) is bucketed per call site
+const CRASH_STACK_FRAMES: usize = 4;
+
+/// Maximum number of characters of the symbol in crash filenames, such that
+/// long (eg. C++) symbols keep the filenames within `NAME_MAX`
+const CRASH_SYMBOL_LEN: usize = 64;
+
+/// Number of most hit crashes reported when the fuzzer stops and in the
+/// JSON statistics
+const TOP_CRASHES: usize = 10;
+
+/// Number of most hit crashes shown in the periodic statistics line
+const TOP_CRASHES_LINE: usize = 3;
+
+/// Default maximum size of a fuzz input
+const DEFAULT_MAX_LEN: usize = 1024 * 1024;
+
+/// Number of TSC ticks a worker fuzzes for before reporting its statistics,
+/// unless batches are bounded by a number of fuzz cases
+const BATCH_CYCLES: u64 = 500_000_000;
+
+/// Time a worker may go without finishing a fuzz case before it is reported
+/// as wedged
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Interval at which the JIT code and the on-disk JIT cache are pruned
+const JIT_PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Largest input the deterministic stage is done on, it runs about a hundred
+/// fuzz cases per byte of the input
+const DETERMINISTIC_MAX_LEN: usize = 4096;
+
+/// Largest amount added to or subtracted from a byte by the deterministic
+/// stage
+const ARITH_MAX: usize = 35;
+
+/// Values the deterministic stage substitutes in, as they tend to hit edge
+/// cases in size and bounds checks
+const INTERESTING_8: [i8; 9] = [-128, -1, 0, 1, 16, 32, 64, 100, 127];
+const INTERESTING_16: [i16; 10] =
+    [-32768, -129, 128, 255, 256, 512, 1000, 1024, 4096, 32767];
+const INTERESTING_32: [i32; 8] = [-2147483648, -100663046, -32769, 32768,
+    65535, 65536, 100663045, 2147483647];
+
+/// Set when a graceful shutdown of the fuzzer was requested (eg. Ctrl-C)
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+}
 
-fn rdtsc() -> u64 {
-    unsafe { std::arch::x86_64::_rdtsc() }
+/// `SIGINT` handler, requests that the fuzzer shuts down
+extern "C" fn sigint_handler(_signum: i32) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
 }
 
-struct Rng(u64);
+#[derive(Default)]
+/// Statistics during fuzzing
+struct Statistics {
+    /// Number of fuzz cases
+    fuzz_cases: u64,
 
-impl Rng {
-    /// Create a new random number generator
-    fn new() -> Self {
-        Rng(0x8644d6eb17b7ab1a ^ rdtsc())
-    }
+    /// Number of risc-v instructions executed
+    instrs_execed: u64,
+    
+    /// Total number of crashes
+    crashes: u64,
 
-    /// Generate a random number
-    #[inline]
-    fn rand(&mut self) -> usize {
-        let val = self.0;
-        self.0 ^= self.0 << 13;
-        self.0 ^= self.0 >> 17;
-        self.0 ^= self.0 << 43;
-        val as usize
-    }
-}
+    /// Total number of CPU cycles spent in the workers
+    total_cycles: u64,
 
-/// Stat structure from kernel_stat64
-#[repr(C)]
-#[derive(Default, Debug)]
-struct Stat {
-    st_dev:     u64,
-    st_ino:     u64,
-    st_mode:    u32,
-    st_nlink:   u32,
-    st_uid:     u32,
-    st_gid:     u32,
-    st_rdev:    u64,
-    __pad1:     u64,
-
-    st_size:    i64,
-    st_blksize: i32,
-    __pad2:     i32,
-
-    st_blocks: i64,
-
-    st_atime:     u64,
-    st_atimensec: u64,
-    st_mtime:     u64,
-    st_mtimensec: u64,
-    st_ctime:     u64,
-    st_ctimensec: u64,
+    /// Total number of CPU cycles spent resetting the guest
+    reset_cycles: u64,
     
-    __glibc_reserved: [i32; 2],
-}
-
-fn handle_syscall(emu: &mut Emulator) -> Result<(), VmExit> {
-    // Get the syscall number
-    let num = emu.reg(Register::A7);
+    /// Total number of CPU cycles spent emulating
+    vm_cycles: u64,
 
-    //print!("Syscall {}\n", num);
+    /// Number of fuzz cases which ended with each kind of VM exit, indexed
+    /// by `VmExit::kind()`
+    exits: [u64; VmExit::KINDS.len()],
+}
 
-    match num {
-        214 => {
-            // brk()
-            let req_base = emu.reg(Register::A0);
-            if req_base == 0 {
-                emu.set_reg(Register::A0, 0);
-                return Ok(());
-            }
+impl Statistics {
+    /// Format the number of fuzz cases which ended with each kind of VM exit
+    /// as `kind:count` pairs, leaving out kinds which never happened
+    fn exits(&self) -> String {
+        let exits: Vec<String> = self.exits.iter().enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(kind, count)| format!("{}:{}", VmExit::KINDS[kind], count))
+            .collect();
+        exits.join(" ")
+    }
 
-            panic!("Not expecting brk");
+    /// Format the number of fuzz cases which ended with each kind of VM exit
+    /// as a JSON object
+    fn exits_json(&self) -> String {
+        let exits: Vec<String> = self.exits.iter().enumerate()
+            .map(|(kind, count)| {
+                format!("\"{}\":{}", VmExit::KINDS[kind], count)
+            }).collect();
+        format!("{{{}}}", exits.join(","))
+    }
 
-            /*
-            let increment = if req_base != 0 {
-                (req_base as i64).checked_sub(cur_base.0 as i64)
-                    .ok_or(VmExit::SyscallIntegerOverflow)?
-            } else {
-                0
-            };
+    /// Format the `count` most hit crashes as `name:hits` pairs, most hit
+    /// first
+    fn top_crashes(corpus: &Corpus, count: usize) -> String {
+        let crashes: Vec<String> = corpus.top_crashes(count).iter()
+            .map(|crash| format!("{}:{}", crash.name,
+                                 crash.hits.load(Ordering::Relaxed)))
+            .collect();
+        crashes.join(" ")
+    }
 
-            // We don't handle negative brks yet
-            if increment < 0 {
-                emu.set_reg(Register::A0, cur_base.0 as u64);
-                return Ok(());
-            }
+    /// Format the `count` most hit crashes as a JSON array, most hit first.
+    /// Crash names only hold filename safe characters, so they need no
+    /// escaping.
+    fn top_crashes_json(corpus: &Corpus, count: usize) -> String {
+        let crashes: Vec<String> = corpus.top_crashes(count).iter()
+            .map(|crash| {
+                format!("{{\"name\":\"{}\",\"hits\":{}}}", crash.name,
+                        crash.hits.load(Ordering::Relaxed))
+            }).collect();
+        format!("[{}]", crashes.join(","))
+    }
 
-            // Attempt to extend data section by increment
-            if let Some(_) = emu.memory.allocate(increment as usize) {
-                let new_base = cur_base.0 + increment as usize;
-                emu.set_reg(Register::A0, new_base as u64);
-            } else {
-                emu.set_reg(Register::A0, !0);
+    /// Sum up the statistics of all workers
+    fn sum(shards: &[SharedStatistics]) -> Self {
+        let mut stats = Statistics::default();
+        for shard in shards {
+            stats.fuzz_cases    += shard.fuzz_cases.load(Ordering::Relaxed);
+            stats.instrs_execed +=
+                shard.instrs_execed.load(Ordering::Relaxed);
+            stats.crashes       += shard.crashes.load(Ordering::Relaxed);
+            stats.total_cycles  += shard.total_cycles.load(Ordering::Relaxed);
+            stats.reset_cycles  += shard.reset_cycles.load(Ordering::Relaxed);
+            stats.vm_cycles     += shard.vm_cycles.load(Ordering::Relaxed);
+            for (total, count) in stats.exits.iter_mut()
+                    .zip(shard.exits.iter()) {
+                *total += count.load(Ordering::Relaxed);
             }
-
-            Ok(())*/
         }
-        64 => {
-            // write()
-            let fd  = emu.reg(Register::A0) as usize;
-            let buf = emu.reg(Register::A1);
-            let len = emu.reg(Register::A2);
-
-            let file = emu.files.get_file(fd);
-            if let Some(Some(file)) = file {
-                if file == &EmuFile::Stdout || file == &EmuFile::Stderr {
-                    // Writes to stdout and stderr
-
-                    // Get access to the underlying bytes to write
-                    let bytes = emu.memory.peek(VirtAddr(buf as usize),
-                        len as usize, Perm(PERM_READ))?;
-
-                    if VERBOSE_GUEST_PRINTS {
-                        if let Ok(st) = core::str::from_utf8(bytes) {
-                            print!("{}", st);
-                        }
-                    }
+        stats
+    }
+}
 
-                    // Set that all bytes were read
-                    emu.set_reg(Register::A0, len);
-                } else {
-                    panic!("Write to valid but unhandled FD");
-                }
-            } else {
-                // Unknown FD
-                emu.set_reg(Register::A0, !0);
-            }
+/// Statistics of a single worker, which it adds to after every batch and the
+/// stats thread sums up on every report. Every worker has its own, such that
+/// the workers never contend on a lock or a cache line.
+#[derive(Default)]
+#[repr(align(64))]
+struct SharedStatistics {
+    /// Number of fuzz cases
+    fuzz_cases: AtomicU64,
 
-            Ok(())
-        }
-        63 => {
-            // read()
-            let fd  = emu.reg(Register::A0) as usize;
-            let buf = emu.reg(Register::A1) as usize;
-            let len = emu.reg(Register::A2) as usize;
-            
-            // Check if the FD is valid
-            let file = emu.files.get_file(fd);
-            if file.is_none() || file.as_ref().unwrap().is_none() {
-                // FD was not valid, return out with an error
-                emu.set_reg(Register::A0, !0);
-                return Ok(());
-            }
-            
-            if let Some(Some(EmuFile::FuzzInput { ref mut cursor })) = file {
-                // Compute the ending cursor from this read
-                let result_cursor = core::cmp::min(
-                    cursor.saturating_add(len),
-                    emu.fuzz_input.len());
-
-                // Write in the bytes
-                emu.memory.write_from(VirtAddr(buf),
-                    &emu.fuzz_input[*cursor..result_cursor])?;
-
-                // Compute bytes read
-                let bread = result_cursor - *cursor;
-                
-                // Update the cursor
-                *cursor = result_cursor;
-
-                // Return number of bytes read
-                emu.set_reg(Register::A0, bread as u64);
-            } else {
-                unreachable!();
-            }
+    /// Number of risc-v instructions executed
+    instrs_execed: AtomicU64,
 
-            Ok(())
-        }
-        62 => {
-            // lseek()
-            let fd     = emu.reg(Register::A0) as usize;
-            let offset = emu.reg(Register::A1) as i64;
-            let whence = emu.reg(Register::A2) as i32;
-
-            const SEEK_SET: i32 = 0;
-            const SEEK_CUR: i32 = 1;
-            const SEEK_END: i32 = 2;
-
-            // Check if the FD is valid
-            let file = emu.files.get_file(fd);
-            if file.is_none() || file.as_ref().unwrap().is_none() {
-                // FD was not valid, return out with an error
-                emu.set_reg(Register::A0, !0);
-                return Ok(());
-            }
+    /// Total number of crashes
+    crashes: AtomicU64,
 
-            if let Some(Some(EmuFile::FuzzInput { ref mut cursor })) = file {
-                let new_cursor = match whence {
-                    SEEK_SET => offset,
-                    SEEK_CUR => (*cursor as i64).saturating_add(offset),
-                    SEEK_END => (emu.fuzz_input.len() as i64)
-                        .saturating_add(offset),
-                    _ => {
-                        // Invalid whence, return error
-                        emu.set_reg(Register::A0, !0);
-                        return Ok(());
-                    }
-                };
+    /// Total number of CPU cycles spent in the worker
+    total_cycles: AtomicU64,
 
-                // Make sure the cursor falls in bounds of [0, file_size]
-                let new_cursor = core::cmp::max(0i64, new_cursor);
-                let new_cursor =
-                    core::cmp::min(new_cursor, emu.fuzz_input.len() as i64);
+    /// Total number of CPU cycles spent resetting the guest
+    reset_cycles: AtomicU64,
 
-                // Update the cursor
-                *cursor = new_cursor as usize;
+    /// Total number of CPU cycles spent emulating
+    vm_cycles: AtomicU64,
 
-                // Return the new cursor position
-                emu.set_reg(Register::A0, new_cursor as u64);
-            } else {
-                unreachable!();
-            }
+    /// Number of fuzz cases which ended with each kind of VM exit, indexed
+    /// by `VmExit::kind()`
+    exits: [AtomicU64; VmExit::KINDS.len()],
+}
 
-            Ok(())
+impl SharedStatistics {
+    /// Add the statistics of a batch
+    fn add(&self, stats: &Statistics) {
+        self.fuzz_cases.fetch_add(stats.fuzz_cases, Ordering::Relaxed);
+        self.instrs_execed.fetch_add(stats.instrs_execed, Ordering::Relaxed);
+        self.crashes.fetch_add(stats.crashes, Ordering::Relaxed);
+        self.total_cycles.fetch_add(stats.total_cycles, Ordering::Relaxed);
+        self.reset_cycles.fetch_add(stats.reset_cycles, Ordering::Relaxed);
+        self.vm_cycles.fetch_add(stats.vm_cycles, Ordering::Relaxed);
+        for (total, &count) in self.exits.iter().zip(stats.exits.iter()) {
+            total.fetch_add(count, Ordering::Relaxed);
         }
-        1024 => {
-            // open()
-            let filename = emu.reg(Register::A0) as usize;
-            let flags    = emu.reg(Register::A1);
-            let _mode    = emu.reg(Register::A2);
-
-            assert!(flags == 0, "Currently we only handle O_RDONLY");
-
-            // Determine the length of the filename
-            let mut fnlen = 0;
-            while emu.memory.read::<u8>(VirtAddr(filename + fnlen))? != 0 {
-                fnlen += 1;
-            }
-        
-            // Get the filename bytes
-            let bytes = emu.memory.peek(VirtAddr(filename),
-                fnlen, Perm(PERM_READ))?;
+    }
+}
 
-            if bytes == b"testfn" {
-                // Create a new file descriptor
-                let fd = emu.alloc_file();
+/// Progress of a worker, which the stats thread watches to notice workers
+/// which died or got stuck. Aligned to a cache line, as every worker updates
+/// its own after every fuzz case.
+#[derive(Default)]
+#[repr(align(64))]
+struct WorkerStatus {
+    /// Number of fuzz cases the worker finished
+    fuzz_cases: AtomicU64,
 
-                // Get access to the file, unwrap here is safe because there's
-                // no way the file is not a valid FD if we got it from our own
-                // APIs
-                let file = emu.files.get_file(fd).unwrap();
+    /// PC the last fuzz case of the worker ended at
+    last_pc: AtomicU64,
+}
 
-                // Mark that this file should be backed by our fuzz input
-                *file = Some(EmuFile::FuzzInput { cursor: 0 });
+/// AFL-style deterministic stage, which walks an input performing every
+/// single bit flip, byte flip, small addition and subtraction, and
+/// substitution of an interesting value (as little-endian 8, 16 and 32-bit
+/// values) at every offset, one per fuzz case
+struct Deterministic {
+    /// Input the mutations are done on
+    base: Vec<u8>,
 
-                // Return a new fd
-                emu.set_reg(Register::A0, fd as u64);
-            } else {
-                // Unknown filename
-                emu.set_reg(Register::A0, !0);
-            }
+    /// Index of the next mutation
+    step: usize,
+}
 
-            Ok(())
-        }
-        1038 => {
-            // stat()
-            let filename = emu.reg(Register::A0) as usize;
-            let statbuf  = emu.reg(Register::A1);
-            
-            // Determine the length of the filename
-            let mut fnlen = 0;
-            while emu.memory.read::<u8>(VirtAddr(filename + fnlen))? != 0 {
-                fnlen += 1;
-            }
-        
-            // Get the filename bytes
-            let bytes = emu.memory.peek(VirtAddr(filename),
-                fnlen, Perm(PERM_READ))?;
-
-            if bytes == b"testfn" {
-                let mut stat = Stat::default();
-                stat.st_dev = 0x803;
-                stat.st_ino = 0x81889;
-                stat.st_mode = 0x81a4;
-                stat.st_nlink = 0x1;
-                stat.st_uid = 0x3e8;
-                stat.st_gid = 0x3e8;
-                stat.st_rdev = 0x0;
-                stat.st_size = emu.fuzz_input.len() as i64;
-                stat.st_blksize = 0x1000;
-                stat.st_blocks = (emu.fuzz_input.len() as i64 + 511) / 512;
-                stat.st_atime = 0x5f0fe246;
-                stat.st_mtime = 0x5f0fe244;
-                stat.st_ctime = 0x5f0fe244;
-
-                // Cast the stat structure to raw bytes
-                let stat = unsafe {
-                    core::slice::from_raw_parts(
-                        &stat as *const Stat as *const u8,
-                        core::mem::size_of_val(&stat))
-                };
+impl Deterministic {
+    /// Start the deterministic stage on `base`
+    fn new(base: Vec<u8>) -> Self {
+        Deterministic { base, step: 0 }
+    }
 
-                // Write in the stat data
-                emu.memory.write_from(VirtAddr(statbuf as usize), stat)?;
-                emu.set_reg(Register::A0, 0);
+    /// Write the next mutation of the base input to `input`. Returns `false`
+    /// once all mutations were done, leaving `input` untouched.
+    fn next(&mut self, input: &mut Vec<u8>) -> bool {
+        let len = self.base.len();
+
+        // Number of mutations of each kind, in the order they are done
+        let counts = [
+            len * 8,
+            len,
+            len * ARITH_MAX * 2,
+            len * INTERESTING_8.len(),
+            len.saturating_sub(1) * INTERESTING_16.len(),
+            len.saturating_sub(3) * INTERESTING_32.len(),
+        ];
+
+        // Find the kind of the next mutation and the step within that kind
+        let mut step = self.step;
+        let kind = counts.iter().position(|&count| {
+            if step < count {
+                true
             } else {
-                // Error
-                emu.set_reg(Register::A0, !0);
+                step -= count;
+                false
             }
+        });
 
-            Ok(())
-        }
-        80 => {
-            // fstat()
-            let fd      = emu.reg(Register::A0) as usize;
-            let statbuf = emu.reg(Register::A1);
-
-            // Check if the FD is valid
-            let file = emu.files.get_file(fd);
-            if file.is_none() || file.as_ref().unwrap().is_none() {
-                // FD was not valid, return out with an error
-                emu.set_reg(Register::A0, !0);
-                return Ok(());
+        let mut mutated = self.base.clone();
+        match kind {
+            Some(0) => {
+                // Single bit flips
+                mutated[step / 8] ^= 0x80 >> (step % 8);
             }
-
-            if let Some(Some(EmuFile::FuzzInput { .. })) = file {
-                let mut stat = Stat::default();
-                stat.st_dev = 0x803;
-                stat.st_ino = 0x81889;
-                stat.st_mode = 0x81a4;
-                stat.st_nlink = 0x1;
-                stat.st_uid = 0x3e8;
-                stat.st_gid = 0x3e8;
-                stat.st_rdev = 0x0;
-                stat.st_size = emu.fuzz_input.len() as i64;
-                stat.st_blksize = 0x1000;
-                stat.st_blocks = (emu.fuzz_input.len() as i64 + 511) / 512;
-                stat.st_atime = 0x5f0fe246;
-                stat.st_mtime = 0x5f0fe244;
-                stat.st_ctime = 0x5f0fe244;
-
-                // Cast the stat structure to raw bytes
-                let stat = unsafe {
-                    core::slice::from_raw_parts(
-                        &stat as *const Stat as *const u8,
-                        core::mem::size_of_val(&stat))
-                };
-
-                // Write in the stat data
-                emu.memory.write_from(VirtAddr(statbuf as usize), stat)?;
-                emu.set_reg(Register::A0, 0);
-            } else {
-                // Error
-                emu.set_reg(Register::A0, !0);
+            Some(1) => {
+                // Byte flips
+                mutated[step] ^= 0xff;
             }
-
-            Ok(())
-        }
-        57 => {
-            // close()
-            let fd = emu.reg(Register::A0) as usize;
-
-            if let Some(file) = emu.files.get_file(fd) {
-                if file.is_some() {
-                    // File was present and currently open, close it
-                   
-                    // Close the file
-                    *file = None;
-
-                    // Just return success for now
-                    emu.set_reg(Register::A0, 0);
+            Some(2) => {
+                // Add or subtract 1 to `ARITH_MAX`
+                let offset = step / (ARITH_MAX * 2);
+                let delta  = step % (ARITH_MAX * 2);
+                mutated[offset] = if delta < ARITH_MAX {
+                    mutated[offset].wrapping_add(delta as u8 + 1)
                 } else {
-                    // File was in a closed state
-                    emu.set_reg(Register::A0, !0);
-                }
-            } else {
-                // FD out of bounds
-                emu.set_reg(Register::A0, !0);
+                    mutated[offset].wrapping_sub((delta - ARITH_MAX) as u8 + 1)
+                };
             }
-
-            Ok(())
-        }
-        93 => {
-            // exit()
-            Err(VmExit::Exit)
-        }
-        _ => {
-            panic!("Unhandled syscall {} @ {:#x}\n", num,
-                   emu.reg(Register::Pc));
+            Some(3) => {
+                let val = INTERESTING_8[step % INTERESTING_8.len()];
+                mutated[step / INTERESTING_8.len()] = val as u8;
+            }
+            Some(4) => {
+                let val = INTERESTING_16[step % INTERESTING_16.len()];
+                let offset = step / INTERESTING_16.len();
+                mutated[offset..offset + 2]
+                    .copy_from_slice(&val.to_le_bytes());
+            }
+            Some(5) => {
+                let val = INTERESTING_32[step % INTERESTING_32.len()];
+                let offset = step / INTERESTING_32.len();
+                mutated[offset..offset + 4]
+                    .copy_from_slice(&val.to_le_bytes());
+            }
+            _ => return false,
         }
+
+        self.step += 1;
+        *input = mutated;
+        true
     }
 }
 
-#[derive(Default)]
-/// Statistics during fuzzing
-struct Statistics {
-    /// Number of fuzz cases
-    fuzz_cases: u64,
-
-    /// Number of risc-v instructions executed
-    instrs_execed: u64,
-    
-    /// Total number of crashes
-    crashes: u64,
+fn worker(mut emu: Emulator, original: Arc<Emulator>,
+          stats: Arc<Vec<SharedStatistics>>, corpus: Arc<Corpus>,
+          mut mutator: Mutator,
+          status: Arc<Vec<WorkerStatus>>, thr_id: usize,
+          verify: bool, crash_alc_offset: bool, max_len: usize,
+          deterministic: bool, batch_cases: Option<u64>) {
+    // Reset to the original state before every fuzz case
+    emu.set_snapshot(original);
 
-    /// Total number of CPU cycles spent in the workers
-    total_cycles: u64,
+    // Input for the current fuzz case
+    let mut input = Vec::new();
 
-    /// Total number of CPU cycles spent resetting the guest
-    reset_cycles: u64,
-    
-    /// Total number of CPU cycles spent emulating
-    vm_cycles: u64,
-}
+    // Deterministic stage in progress, if any
+    let mut stage: Option<Deterministic> = None;
 
-fn worker(mut emu: Emulator, original: Arc<Emulator>,
-          stats: Arc<Mutex<Statistics>>, corpus: Arc<Corpus>) {
-    // Create a new random number generator
-    let mut rng = Rng::new();
+    // Corpus input the current input was derived from, if any
+    let mut parent: Option<&Input> = None;
 
     loop {
         // Start a timer
@@ -433,123 +336,171 @@ fn worker(mut emu: Emulator, original: Arc<Emulator>,
         
         let mut local_stats = Statistics::default();
 
+        // Pull in the coverage the other workers found since the last batch,
+        // such that we do not report it to the corpus again
+        emu.sync_coverage(&corpus);
+
+        // Run fuzz cases until the batch is done, either after a fixed number
+        // of fuzz cases or after a fixed time
         let it = rdtsc();
-        while (rdtsc() - it) < 500_000_000 {
-            // Reset emu to original state
-            let it = rdtsc();
-            emu.reset(&*original);
-            local_stats.reset_cycles += rdtsc() - it;
-
-            // Number of instructions executed this fuzz case
-            let mut run_instrs = 0u64;
-
-            // Clear the fuzz input
-            emu.fuzz_input.clear();
-
-            // Pick a random file from the corpus as an input
-            let sel = rng.rand() % corpus.inputs.len();
-            if let Some(input) = corpus.inputs.get(sel) {
-                emu.fuzz_input.extend_from_slice(input);
-            }
+        let batch_done = |stats: &Statistics| match batch_cases {
+            Some(cases) => stats.fuzz_cases >= cases,
+            None        => rdtsc() - it >= BATCH_CYCLES,
+        };
+        while !batch_done(&local_stats) && !SHUTDOWN.load(Ordering::Relaxed) {
+            // Take the next case of the deterministic stage if one is in
+            // progress, otherwise pick a file from the corpus as an input
+            let mut havoc = !stage.as_mut()
+                .map_or(false, |stage| stage.next(&mut input));
+            if havoc {
+                stage = None;
+                let corpus_input = corpus.select_input(&mut mutator.rng);
+                parent = corpus_input;
+                if let Some(corpus_input) = corpus_input {
+                    // On the first selection of an input, walk it with the
+                    // deterministic stage before moving on to havoc
+                    if deterministic &&
+                            corpus_input.data.len() <= DETERMINISTIC_MAX_LEN &&
+                            !corpus_input.deterministic_done
+                                .swap(true, Ordering::Relaxed) {
+                        let mut new_stage =
+                            Deterministic::new(corpus_input.data.clone());
+                        havoc = !new_stage.next(&mut input);
+                        stage = Some(new_stage);
+                    }
 
-            // The worlds best mutator
-            if emu.fuzz_input.len() > 0 {
-                for _ in 0..rng.rand() % 128 {
-                    let sel = rng.rand() % emu.fuzz_input.len();
-                    emu.fuzz_input[sel] = rng.rand() as u8;
+                    if havoc {
+                        input.clear();
+                        input.extend_from_slice(&corpus_input.data);
+                    }
+                } else {
+                    input.clear();
                 }
             }
 
-            let vmexit = loop {
-                let vmexit = emu.run(&mut run_instrs,
-                                     &mut local_stats.vm_cycles,
-                                     &*corpus)
-                    .expect_err("Failed to execute emulator");
+            // The worlds best mutator
+            if havoc {
+                let staleness = parent.map_or(0, |parent| parent.staleness());
+                mutator.mutate(&mut input, &corpus, staleness);
+            }
 
-                match vmexit {
-                    VmExit::Syscall => {
-                        if let Err(vmexit) = handle_syscall(&mut emu) {
-                            break vmexit;
-                        }
-            
-                        // Advance PC
-                        let pc = emu.reg(Register::Pc);
-                        emu.set_reg(Register::Pc, pc.wrapping_add(4));
+            // Keep the input within the maximum length
+            input.truncate(max_len);
+
+            // Run the fuzz case from the original state
+            let vmexit = if verify {
+                match emu.verify_input(&input, &*corpus) {
+                    Ok(vmexit) => vmexit,
+                    Err(divergence) => {
+                        // Save the input and report, then stop fuzzing
+                        let name = format!("{:#x}_divergence", divergence.pc.0);
+                        std::fs::write(Path::new("crashes")
+                                       .join(format!("{}.crash", name)),
+                            &input).expect("Failed to write fuzz input");
+                        std::fs::write(Path::new("crashes")
+                                       .join(format!("{}.txt", name)),
+                            format!("{}", divergence))
+                            .expect("Failed to write divergence report");
+
+                        print!("{}", divergence);
+                        SHUTDOWN.store(true, Ordering::SeqCst);
+                        break;
                     }
-                    _ => break vmexit,
                 }
+            } else {
+                emu.run_input(&input, &*corpus)
             };
+            // Track the execution cost of the input for the scheduler
+            if let Some(parent) = parent {
+                parent.record_instrs(emu.instrs_execed());
+                parent.record_case(emu.found_coverage());
+            }
+
+            let (reset_cycles, vm_cycles) = emu.case_cycles();
+            local_stats.reset_cycles += reset_cycles;
+            local_stats.vm_cycles    += vm_cycles;
+            local_stats.exits[vmexit.kind()] += 1;
 
-            if let Some((fault_type, vaddr)) = vmexit.is_crash() {
+            if let Some((fault_type, vaddr)) = emu.crash_type(&vmexit) {
                 // Update crash stats
                 local_stats.crashes += 1;
 
+                // Tell heap crashes apart by where in the allocation they
+                // happened, if requested
+                let offset = if crash_alc_offset {
+                    emu.memory.alc_offset(vaddr)
+                } else {
+                    None
+                };
+
                 // Attempt to update hash table
-                let pc  = VirtAddr(emu.reg(Register::Pc) as usize);
-                let key = (pc, fault_type, AddressType::from(vaddr));
-                corpus.unique_crashes.entry_or_insert(&key, pc.0, || {
+                let pc    = VirtAddr(emu.reg(Register::Pc) as usize);
+                let stack = emu.call_stack_hash(CRASH_STACK_FRAMES);
+                let key   = (pc, fault_type, emu.address_type(vaddr), stack,
+                             offset);
+                corpus.record_crash(&key, &emu.fuzz_input, || {
                     // Save the input and log it in the hash table
                     let hash = corpus.hasher.hash(&emu.fuzz_input);
                     corpus.input_hashes.entry_or_insert(
                             &hash, hash as usize, || {
-                        corpus.inputs.push(Box::new(emu.fuzz_input.clone()));
+                        corpus.inputs.push(Box::new(
+                            Input::new(emu.fuzz_input.clone())));
                         Box::new(())
                     });
 
-                    // Save the crashing file
-                    std::fs::write(Path::new("crashes").join(
-                        format!("{:#x}_{:?}_{:?}.crash",
-                                (key.0).0, key.1, key.2)),
+                    // Save the crashing file, the symbol is truncated and
+                    // stripped of characters which are unsafe in filenames
+                    let symbol: String = emu.symbolize(key.0).chars()
+                        .map(|c| if c.is_ascii_alphanumeric() ||
+                             "_+.-".contains(c) { c } else { '_' })
+                        .take(CRASH_SYMBOL_LEN).collect();
+                    let mut name = format!("{:#x}_{}_{:?}_{:?}_{:016x}",
+                                           (key.0).0, symbol, key.1, key.2,
+                                           key.3);
+                    if let Some(offset) = key.4 {
+                        name += &format!("_{:#x}", offset);
+                    }
+                    std::fs::write(Path::new("crashes")
+                                   .join(format!("{}.crash", name)),
                         &emu.fuzz_input).expect("Failed to write fuzz input");
 
-                    Box::new(())
+                    // Save a report with the faulting instruction, the
+                    // register state, and the call stack at the time of the
+                    // crash
+                    std::fs::write(Path::new("crashes")
+                                   .join(format!("{}.txt", name)),
+                        format!("{:?} at {:#x} ({}): {}\n\n{}\n\n\
+                                 Call stack:\n{}", vmexit,
+                                pc.0, emu.symbolize(pc), emu.disasm_at(pc),
+                                emu, emu.backtrace()))
+                        .expect("Failed to write crash report");
+
+                    name
                 });
             }
 
-            local_stats.instrs_execed += run_instrs;
+            local_stats.instrs_execed += emu.instrs_execed();
             local_stats.fuzz_cases    += 1;
+
+            // Let the watchdog know we are still alive
+            status[thr_id].last_pc.store(emu.reg(Register::Pc),
+                                         Ordering::Relaxed);
+            status[thr_id].fuzz_cases.fetch_add(1, Ordering::Relaxed);
         }
 
-        // Get access to statistics
-        let mut stats = stats.lock().unwrap();
+        // Compute amount of time during the batch
+        local_stats.total_cycles = rdtsc() - batch_start;
 
-        stats.fuzz_cases    += local_stats.fuzz_cases;
-        stats.crashes       += local_stats.crashes;
-        stats.instrs_execed += local_stats.instrs_execed;
-        stats.reset_cycles  += local_stats.reset_cycles;
-        stats.vm_cycles     += local_stats.vm_cycles;
+        // Publish the statistics of the batch
+        stats[thr_id].add(&local_stats);
 
-        // Compute amount of time during the batch
-        let batch_elapsed = rdtsc() - batch_start;
-        stats.total_cycles += batch_elapsed;
+        // Stop fuzzing if a shutdown was requested, our stats are flushed
+        if SHUTDOWN.load(Ordering::SeqCst) {
+            return;
+        }
     }
 }
 
-/// Information about inputs and coverage
-pub struct Corpus {
-    /// Input hash table to dedup inputs
-    pub input_hashes: Aht<u128, (), 1048576>,
-    
-    /// Linear list of all inputs
-    pub inputs: AtomicVec<Vec<u8>, 1048576>,
-    
-    /// Unique crashes
-    /// Tuple is (PC, FaultType, AddressType)
-    pub unique_crashes: Aht<(VirtAddr, FaultType, AddressType), (), 1048576>,
-
-    /// Code coverage, (to, from) edges for _all_ branches, including
-    /// taken, not taken, indirect, and unconditional
-    pub code_coverage: Aht<(VirtAddr, VirtAddr), (), 1048576>,
-
-    /// Hasher
-    pub hasher: FalkHasher,
-
-    /// Coverage bitmap
-    pub coverage_bitmap: Vec<u64>,
-
-    /// Active compile jobs
-    compile_jobs: Mutex<BTreeSet<u128>>,
-}
 
 fn malloc_bp(emu: &mut Emulator) -> Result<(), VmExit> {
     if let Some(alc) = emu.memory.allocate(emu.reg(Register::A1) as usize) {
@@ -558,10 +509,12 @@ fn malloc_bp(emu: &mut Emulator) -> Result<(), VmExit> {
         emu.set_reg(Register::A0, 0);
     }
 
-    emu.set_reg(Register::Pc, emu.reg(Register::Ra));
+    emu.return_from_breakpoint();
     Ok(())
 }
 
+/// `calloc()` zero-initializes the allocation, so writing it through `peek()`
+/// also makes it readable when uninitialized memory is tracked
 fn calloc_bp(emu: &mut Emulator) -> Result<(), VmExit> {
     let nmemb = emu.reg(Register::A1) as usize;
     let size  = emu.reg(Register::A2) as usize;
@@ -575,7 +528,7 @@ fn calloc_bp(emu: &mut Emulator) -> Result<(), VmExit> {
     }).unwrap_or(VirtAddr(0));
 
     emu.set_reg(Register::A0, result.0 as u64);
-    emu.set_reg(Register::Pc, emu.reg(Register::Ra));
+    emu.return_from_breakpoint();
     Ok(())
 }
 
@@ -589,7 +542,8 @@ fn realloc_bp(emu: &mut Emulator) -> Result<(), VmExit> {
         0
     } else {
         // Attempt to get the old allocation size
-        emu.memory.get_alc(old_alc).ok_or(VmExit::InvalidFree(old_alc))?
+        emu.memory.get_alc(old_alc)
+            .ok_or_else(|| emu.memory.free_fault(old_alc))?
     };
 
     // Compute the size to copy
@@ -618,7 +572,7 @@ fn realloc_bp(emu: &mut Emulator) -> Result<(), VmExit> {
     }).unwrap_or(VirtAddr(0));
 
     emu.set_reg(Register::A0, new_alc.0 as u64);
-    emu.set_reg(Register::Pc, emu.reg(Register::Ra));
+    emu.return_from_breakpoint();
     Ok(())
 }
 
@@ -627,51 +581,234 @@ fn free_bp(emu: &mut Emulator) -> Result<(), VmExit> {
     if base != VirtAddr(0) {
         emu.memory.free(base)?;
     }
-    emu.set_reg(Register::Pc, emu.reg(Register::Ra));
+    emu.return_from_breakpoint();
     Ok(())
 }
 
-fn _end_case(_emu: &mut Emulator) -> Result<(), VmExit> {
-    Err(VmExit::Exit)
+/// Run every seed in `seed_files` once from the snapshot of `emu`, reporting
+/// the seeds which crash, time out, or take no coverage edges at all, such
+/// that a broken seed set is noticed before fuzzing with it
+fn dry_run(emu: &Arc<Emulator>, corpus: &Corpus,
+           seed_files: &[PathBuf]) -> io::Result<()> {
+    let mut dry_emu = emu.fork();
+    dry_emu.set_snapshot(emu.clone());
+
+    let (mut crashed, mut timed_out, mut no_coverage) = (0, 0, 0);
+    for filename in seed_files {
+        let input = std::fs::read(filename)?;
+        let (vmexit, edges) = dry_emu.dry_run(&input, corpus);
+
+        if let Some((fault, addr)) = dry_emu.crash_type(&vmexit) {
+            let pc = VirtAddr(dry_emu.reg(Register::Pc) as usize);
+            print!("Seed {:?} crashed: {:?} {:#x} at {:#x} ({})\n",
+                   filename, fault, addr.0, pc.0, dry_emu.symbolize(pc));
+            crashed += 1;
+        } else if vmexit == VmExit::Timeout {
+            print!("Seed {:?} timed out\n", filename);
+            timed_out += 1;
+        } else if edges.is_empty() {
+            print!("Seed {:?} took no coverage edges\n", filename);
+            no_coverage += 1;
+        }
+    }
+
+    print!("Dry run of {} seeds: {} crashed, {} timed out, {} without \
+            coverage\n", seed_files.len(), crashed, timed_out, no_coverage);
+    Ok(())
 }
 
 fn main() -> io::Result<()> {
+    // Parse the command line
+    let config = Config::from_args().unwrap_or_else(|err| {
+        print!("{}", err);
+        std::process::exit(1);
+    });
+
     std::fs::create_dir_all("inputs")?;
     std::fs::create_dir_all("crashes")?;
 
     // Create a corpus
-    let corpus = Arc::new(Corpus {
-        input_hashes: Aht::new(),
-        inputs: AtomicVec::new(),
-        hasher: FalkHasher::new(),
-        unique_crashes: Aht::new(),
-        code_coverage: Aht::new(),
-        compile_jobs: Default::default(),
-        coverage_bitmap: vec![0u64; 1024 * 1024],
-    });
+    let corpus = Arc::new(Corpus::with_coverage_bits(config.coverage_bits));
     
-    // Load the initial corpus
+    // Load the initial corpus, remembering the files for the dry run
+    let mut seed_files = Vec::new();
     for filename in std::fs::read_dir("inputs")?{
         let filename = filename?.path();
-        let data = std::fs::read(filename)?;
+        let data = std::fs::read(&filename)?;
+        seed_files.push(filename);
         let hash = corpus.hasher.hash(&data);
 
         // Save the input and log it in the hash table
         corpus.input_hashes.entry_or_insert(&hash, hash as usize, || {
-            corpus.inputs.push(Box::new(data));
+            corpus.inputs.push(Box::new(Input::new(data)));
             Box::new(())
         });
     }
 
-    // Create a JIT cache
-    let jit_cache = Arc::new(JitCache::new(VirtAddr(4 * 1024 * 1024)));
+    // Resume from the corpus and coverage of a previous campaign
+    if let Some(path) = &config.import_corpus {
+        let imported = corpus.import_corpus(path)?;
+        print!("Imported {} inputs, {} coverage edges\n", imported,
+               corpus.code_coverage.len());
+    }
+
+    // Number of inputs we loaded from disk, anything after this in the corpus
+    // was found during this run
+    let seeds = corpus.inputs.len();
+
+    // Configure an emulator
+    let mut builder = EmulatorBuilder::new()
+        .memory_size(32 * 1024 * 1024);
+
+    if config.argv_input {
+        // Only the program name is fixed, the rest comes from the input
+        builder = builder.args(&["objdump"]).argv_input();
+    } else {
+        builder = builder.args(&["objdump", "-g", "testfn"]);
+    }
+
+    // Call the harness function with the fuzz input rather than running the
+    // whole program
+    if let Some(entry) = config.harness {
+        let (input_arg, len_arg) = config.harness_args.unwrap_or((0, Some(1)));
+        builder = builder.harness(FunctionHarness {
+            entry: VirtAddr(entry as usize),
+            input_arg,
+            len_arg,
+        });
+    }
+
+    // Throw away JIT code cached by previous runs
+    if config.clear_jit_cache {
+        match std::fs::remove_dir_all(jitcache::CACHE_DIR) {
+            Ok(()) => print!("Cleared the JIT cache\n"),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => panic!("Failed to clear the JIT cache: {}", err),
+        }
+    }
+
+    // Configure how JIT code is compiled
+    let mut jit_config = JitConfig::default();
+    if let Some(compiler) = &config.jit_compiler {
+        jit_config.compiler = compiler.clone();
+    }
+    jit_config.extra_flags.extend(config.jit_flags.iter().cloned());
+    if let Some(ldscript) = &config.jit_ldscript {
+        jit_config.linker_script = ldscript.clone();
+    }
+
+    // Use the JIT unless it was disabled or we can't compile JIT code
+    let mut shared_jit = None;
+    if config.no_jit {
+        print!("JIT disabled, using the interpreter\n");
+    } else if !jit_config.toolchain_available() &&
+            config.dump_jit.is_none() {
+        if config.verify {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                format!("{} or objcopy is not available, --verify requires \
+                         the JIT", jit_config.compiler)));
+        }
+
+        print!("{} or objcopy is not available to compile JIT code, \
+                falling back to the interpreter\n", jit_config.compiler);
+    } else {
+        // Create a JIT cache covering all the memory the guest may grow to,
+        // as any of it may be made executable
+        let max_memory = config.max_memory.unwrap_or(DEFAULT_MAX_MEMORY_SIZE);
+        let mut jit_cache = JitCache::new(VirtAddr(max_memory), jit_config);
+        if let Some(instrs) = config.jit_block_size {
+            jit_cache.set_max_block_instrs(instrs);
+        }
+        if let Some(size) = config.jit_max_size {
+            jit_cache.set_max_size(size);
+        }
+        let jit_cache = Arc::new(jit_cache);
+        builder = builder.jit(jit_cache.clone());
+        shared_jit = Some(jit_cache);
+    }
+
+    // Limit the number of instructions a fuzz case may execute
+    if let Some(timeout) = config.timeout {
+        builder = builder.timeout(timeout);
+    }
+    builder = builder.cycle_weights(config.cycle_weights);
+    if !config.fuzz_files.is_empty() {
+        builder = builder.fuzz_files(config.fuzz_files.iter()
+            .map(|name| name.as_bytes().to_vec()).collect());
+    }
+    for name in &config.open_fuzz_files {
+        builder = builder.open_fuzz_file(name.as_bytes().to_vec());
+    }
+
+    // Limit how far guest memory may grow
+    if let Some(size) = config.max_memory {
+        builder = builder.max_memory_size(size);
+    }
+
+    // Catch reads of uninitialized memory
+    if config.detect_uninit {
+        builder = builder.detect_uninit();
+    }
+
+    // Move the stack and the heap around, such that crashes which depend on
+    // absolute addresses can be told apart from layout coincidences
+    if config.randomize_layout {
+        let seed = config.seed.unwrap_or_else(|| Rng::new().rand() as u64);
+        print!("Randomizing the memory layout with seed {:#x}\n", seed);
+        builder = builder.layout_seed(seed);
+    }
+
+    // Distinguish coverage by the calling context
+    if config.context_coverage {
+        builder = builder.context_coverage();
+    }
+    if config.block_coverage {
+        builder = builder.block_coverage();
+    }
+
+    // Keep noisy code from reporting coverage
+    for &(start, end) in &config.coverage_blacklist {
+        builder = builder.coverage_blacklist(VirtAddr(start as usize),
+                                             VirtAddr(end as usize));
+    }
+    if config.signal_delivery {
+        builder = builder.signal_delivery();
+    }
+    if !config.no_stack_guard {
+        builder = builder.stack_guard();
+    }
+    for &pc in &config.fuzz_ends {
+        builder = builder.fuzz_end(VirtAddr(pc as usize));
+    }
+
+    // Return fixed values from the syscalls we were asked to ignore
+    let ignored = if !config.ignore_syscalls.is_empty() ||
+            !config.stub_syscalls.is_empty() {
+        let mut handler =
+            IgnoreSyscalls::new(Arc::new(DefaultSyscallHandler));
+        for &num in &config.ignore_syscalls {
+            handler = handler.ignore(num);
+        }
+        for &num in &config.stub_syscalls {
+            handler = handler.stub(num);
+        }
 
-    // Create an emulator using the JIT
-    let mut emu = Emulator::new(32 * 1024 * 1024).enable_jit(jit_cache);
+        let handler = Arc::new(handler);
+        builder = builder.syscall_handler(handler.clone());
+        Some(handler)
+    } else {
+        None
+    };
+
+    // Allow the guest to open the whitelisted host files
+    if let Some(host_dir) = &config.host_dir {
+        builder = builder.host_files(Arc::new(
+            HostFiles::load(host_dir, &config.host_files)?));
+    }
 
     // Load the application into the emulator
     if true {
-        emu.memory.load("./objdump_riscv", &[
+        builder = builder.load("./objdump_riscv", vec![
             Section {
                 file_off:    0x0000000000000000,
                 virt_addr:   VirtAddr(0x0000000000010000),
@@ -686,18 +823,21 @@ fn main() -> io::Result<()> {
                 mem_size:    0x000000000000fd98,
                 permissions: Perm(PERM_READ | PERM_WRITE),
             },
-        ]).expect("Failed to load test application into address space");
-
-        emu.add_breakpoint(VirtAddr(0x1151d0), malloc_bp);
-        emu.add_breakpoint(VirtAddr(0x1120e8), calloc_bp);
-        emu.add_breakpoint(VirtAddr(0x113610), free_bp);
-        emu.add_breakpoint(VirtAddr(0x117930), realloc_bp);
-        //emu.add_breakpoint(VirtAddr(0x1c1f0), _end_case);
-        
-        // Set the program entry point
-        emu.set_reg(Register::Pc, 0x109a4);
+        ])
+        .breakpoint(VirtAddr(0x1151d0), malloc_bp)
+        .breakpoint(VirtAddr(0x1120e8), calloc_bp)
+        .breakpoint(VirtAddr(0x113610), free_bp)
+        .breakpoint(VirtAddr(0x117930), realloc_bp)
+        .entry(VirtAddr(0x109a4));
+
+        // Load the symbols for symbolizing crashes
+        match Symbols::load("./objdump_riscv") {
+            Some(symbols) => builder = builder.symbols(Arc::new(symbols)),
+            None => print!("No symbols found, crashes will not be \
+                            symbolized\n"),
+        }
     } else {
-        emu.memory.load("./objdump_old", &[
+        builder = builder.load("./objdump_old", vec![
             Section {
                 file_off:    0x0000000000000000,
                 virt_addr:   VirtAddr(0x0000000000010000),
@@ -712,85 +852,162 @@ fn main() -> io::Result<()> {
                 mem_size:    0x00000000000046c8,
                 permissions: Perm(PERM_READ | PERM_WRITE),
             },
-        ]).expect("Failed to load test application into address space");
-    
-        // Set the program entry point
-        emu.set_reg(Register::Pc, 0x104e8);
-    }
-
-    // Set up a stack
-    let stack = emu.memory.allocate(32 * 1024)
-        .expect("Failed to allocate stack");
-    emu.set_reg(Register::Sp, stack.0 as u64 + 32 * 1024);
-
-    // Set up the program name
-    let progname = emu.memory.allocate(4096)
-        .expect("Failed to allocate program name");
-    emu.memory.write_from(progname, b"objdump\0")
-        .expect("Failed to write program name");
-    let arg1 = emu.memory.allocate(4096)
-        .expect("Failed to allocate arg1");
-    emu.memory.write_from(arg1, b"-g\0")
-        .expect("Failed to write arg1");
-    let arg2 = emu.memory.allocate(4096)
-        .expect("Failed to allocate arg1");
-    emu.memory.write_from(arg2, b"testfn\0")
-        .expect("Failed to write arg2");
-
-    macro_rules! push {
-        ($expr:expr) => {
-            let sp = emu.reg(Register::Sp) -
-                core::mem::size_of_val(&$expr) as u64;
-            emu.memory.write(VirtAddr(sp as usize), $expr)
-                .expect("Push failed");
-            emu.set_reg(Register::Sp, sp);
-        }
+        ])
+        .entry(VirtAddr(0x104e8));
     }
 
-    // Set up the initial program stack state
-    push!(0u64);   // Auxp
-    push!(0u64);   // Envp
-    push!(0u64);   // Argv end
-    push!(arg2.0); // Argv
-    push!(arg1.0); // Argv
-    push!(progname.0); // Argv
-    push!(3u64);   // Argc
+    // Create the emulator with the program loaded and the stack set up
+    let mut emu = builder.build().expect("Failed to build emulator");
 
-    loop {
-        // Run the emulator to a certain point
-        let mut tmp = 0;
-        let vmexit = emu.run_emu(&mut tmp, &*corpus)
-            .expect_err("Failed to execute emulator");
-
-        match vmexit {
-            VmExit::Syscall => {
-                print!("Syscall {}\n", emu.reg(Register::A7));
-                if emu.reg(Register::A7) == 1024 {
-                    break;
-                }
+    if config.stdin_input {
+        // Serve the (first file of the) fuzz input to the guest via stdin
+        *emu.files.get_file(0).unwrap() =
+            Some(EmuFile::FuzzInput { index: 0, cursor: 0 });
+    }
 
-                if let Err(_vmexit) = handle_syscall(&mut emu) {
-                    break;
+    // When fuzzing argv the snapshot is taken at the entry point, as the
+    // arguments are consumed right away by `_start`. The harness function is
+    // called from the entry point as well.
+    if !config.argv_input && config.harness.is_none() {
+        loop {
+            // Run the emulator to a certain point
+            let mut tmp = 0;
+            let vmexit = emu.run_emu(&mut tmp, &*corpus)
+                .expect_err("Failed to execute emulator");
+
+            match vmexit {
+                VmExit::Syscall => {
+                    print!("Syscall {}\n", emu.reg(Register::A7));
+
+                    // Take the snapshot right before the fuzz input is first
+                    // accessed, either by opening it with `open()` or
+                    // `openat()`, or by reading from stdin
+                    let num = emu.reg(Register::A7);
+                    if num == 1024 || num == 56 || (config.stdin_input &&
+                            num == 63 && emu.reg(Register::A0) == 0) {
+                        break;
+                    }
+
+                    if let Err(_vmexit) = emu.handle_syscall() {
+                        break;
+                    }
                 }
-    
-                // Advance PC
-                let pc = emu.reg(Register::Pc);
-                emu.set_reg(Register::Pc, pc.wrapping_add(4));
+                _ => break,
             }
-            _ => break,
         }
     }
 
     print!("Took snapshot at {:#x}\n", emu.reg(Register::Pc));
 
+    // Make syscalls fail from here on, the startup of the program up to the
+    // snapshot is always the same
+    if let Some(probability) = config.fault_injection {
+        let handler = FaultInjection::new(emu.syscall_handler(),
+                                          config.seed.unwrap_or(0),
+                                          probability);
+        emu.set_syscall_handler(Arc::new(handler));
+    }
+
+    if let Some(pc) = config.dump_jit {
+        // Write the C++ the JIT generates for `pc`, rather than fuzzing
+        let source = emu.lift_jit(VirtAddr(pc as usize), &corpus)
+            .map_err(|vmexit| io::Error::new(io::ErrorKind::Other,
+                format!("Failed to lift {:#x}: {:?}", pc, vmexit)))?;
+        let filename = format!("jit_{:x}.cpp", pc);
+        std::fs::write(&filename, source)?;
+        print!("Wrote the JIT source for {:#x} to {}\n", pc, filename);
+        return Ok(());
+    }
+
+    if let Some(port) = config.gdb {
+        // Debug a single fuzz case from the snapshot rather than fuzzing
+        if let Some(input) = &config.gdb_input {
+            emu.fuzz_input = std::fs::read(input)?;
+        }
+        return gdb::serve(port, emu, &corpus);
+    }
+
     // Wrap the original emulator in an `Arc`
     let emu = Arc::new(emu);
 
-    // Create a new stats structure
-    let stats = Arc::new(Mutex::new(Statistics::default()));
+    if let Some(replay) = &config.replay {
+        // Run a single fuzz case with the input rather than fuzzing
+        let input = std::fs::read(replay)?;
+        let mut replay_emu = emu.fork().enable_output_capture();
+        if let Some(edges) = config.branch_trace {
+            replay_emu = replay_emu.enable_branch_trace(edges);
+        }
+        replay_emu.set_snapshot(emu.clone());
+        let vmexit = replay_emu.run_input(&input, &*corpus);
+
+        let output = replay_emu.take_output();
+        if !output.is_empty() {
+            print!("Guest output:\n{}\n",
+                   String::from_utf8_lossy(&output));
+        }
+
+        let pc = VirtAddr(replay_emu.reg(Register::Pc) as usize);
+        print!("{:?} at {:#x} ({}): {}\n\n{}\n\nCall stack:\n{}", vmexit,
+               pc.0, replay_emu.symbolize(pc), replay_emu.disasm_at(pc),
+               replay_emu, replay_emu.backtrace());
+        print!("Executed {} instructions, {} cycles\n",
+               replay_emu.instrs_execed(), replay_emu.cycles_execed());
+        print!("\nMemory layout:\n{}", replay_emu.dump_layout());
+        if config.branch_trace.is_some() {
+            print!("\nBranch trace:\n{}", replay_emu.dump_branch_trace());
+        }
+        return Ok(());
+    }
+
+    if config.dry_run {
+        dry_run(&emu, &corpus, &seed_files)?;
+    }
+
+    // Create the statistics of every worker
+    let stats: Arc<Vec<SharedStatistics>> = Arc::new(
+        (0..config.threads).map(|_| SharedStatistics::default()).collect());
+
+    // Request a graceful shutdown on Ctrl-C
+    const SIGINT: i32 = 2;
+    unsafe { signal(SIGINT, sigint_handler); }
+
+    // Create the progress of every worker for the watchdog
+    let status: Arc<Vec<WorkerStatus>> = Arc::new(
+        (0..config.threads).map(|_| WorkerStatus::default()).collect());
+
+    // Spawn the workers
+    let mut workers = Vec::new();
+    for thr_id in 0..config.threads {
+        let new_emu = emu.fork();
+        let stats   = stats.clone();
+        let status  = status.clone();
+        let parent  = emu.clone();
+        let corpus  = corpus.clone();
+        let verify  = config.verify;
+        let crash_alc_offset = config.crash_alc_offset;
+        let max_len = config.max_len.unwrap_or(DEFAULT_MAX_LEN);
+        let deterministic = !config.no_deterministic;
+        let batch_cases = config.batch_cases;
+
+        // Give each worker its own random number generator, seeded from the
+        // configured seed such that a single worker is deterministic
+        let rng = match config.seed {
+            Some(seed) => Rng::with_seed(seed.wrapping_add(thr_id as u64)),
+            None       => Rng::new(),
+        };
+
+        let mutator = Mutator::new(rng, config.mutator);
+
+        workers.push(std::thread::spawn(move || {
+            worker(new_emu, parent, stats, corpus, mutator, status, thr_id,
+                   verify, crash_alc_offset, max_len, deterministic,
+                   batch_cases);
+        }));
+    }
 
     // Create the stats thread
-    {
+    let export_corpus = config.export_corpus.clone();
+    let stats_thread = {
         let corpus = corpus.clone();
         let stats  = stats.clone();
         std::thread::spawn(move || {
@@ -798,59 +1015,233 @@ fn main() -> io::Result<()> {
             let start = Instant::now();
 
             let mut last_time = Instant::now();
+            let mut last_prune = Instant::now();
+
+            // Fuzz case count of every worker when it last made progress,
+            // when that was, and if it was reported as wedged since
+            let mut progress: Vec<(u64, Instant, bool)> = status.iter()
+                .map(|_| (0, Instant::now(), false)).collect();
 
             let mut log = File::create("stats.txt").unwrap();
             loop {
                 std::thread::sleep(Duration::from_millis(10));
+
+                // If a shutdown was requested, wait for all the workers to
+                // flush their final statistics before we report them
+                let shutdown = SHUTDOWN.load(Ordering::SeqCst);
+                if shutdown {
+                    for worker in workers.drain(..) {
+                        let _ = worker.join();
+                    }
+                }
                     
-                // Get access to the stats structure
-                let stats   = stats.lock().unwrap();
+                // Sum up the statistics of all workers
+                let stats   = Statistics::sum(&stats);
                 let elapsed = start.elapsed().as_secs_f64();
 
-                write!(log, "{:.6},{},{},{},{}\n", elapsed, stats.fuzz_cases,
-                       corpus.code_coverage.len(), corpus.unique_crashes.len(),
-                       corpus.inputs.len())
-                    .unwrap();
-
-                if last_time.elapsed() >= Duration::from_millis(1000) {
-                    let fuzz_cases = stats.fuzz_cases;
-                    let instrs = stats.instrs_execed;
-
-                    // Compute performance numbers
-                    let resetc = stats.reset_cycles as f64 /
-                        stats.total_cycles as f64;
-                    let vmc = stats.vm_cycles as f64 /
-                        stats.total_cycles as f64;
+                let fuzz_cases = stats.fuzz_cases;
+                let instrs = stats.instrs_execed;
+
+                // Compute performance numbers
+                let resetc = stats.reset_cycles as f64 /
+                    core::cmp::max(stats.total_cycles, 1) as f64;
+                let vmc = stats.vm_cycles as f64 /
+                    core::cmp::max(stats.total_cycles, 1) as f64;
+
+                match config.stats_format {
+                    StatsFormat::Csv => {
+                        write!(log, "{:.6},{},{},{},{}\n", elapsed, fuzz_cases,
+                               corpus.code_coverage.len(),
+                               corpus.unique_crashes.len(),
+                               corpus.inputs.len())
+                            .unwrap();
+                    }
+                    StatsFormat::Json => {
+                        write!(log, "{{\"elapsed\":{:.6},\"fuzz_cases\":{},\
+                                \"execs_per_sec\":{:.1},\"edges\":{},\
+                                \"crashes\":{},\"inputs\":{},\
+                                \"reset_ratio\":{:.6},\"vm_ratio\":{:.6},\
+                                \"exits\":{},\"top_crashes\":{}}}\n",
+                               elapsed, fuzz_cases,
+                               fuzz_cases as f64 / elapsed,
+                               corpus.code_coverage.len(),
+                               corpus.unique_crashes.len(),
+                               corpus.inputs.len(), resetc, vmc,
+                               stats.exits_json(),
+                               Statistics::top_crashes_json(&corpus,
+                                                            TOP_CRASHES))
+                            .unwrap();
+                    }
+                }
 
+                if shutdown ||
+                        last_time.elapsed() >= Duration::from_millis(1000) {
                     print!("[{:10.4}] cases {:10} | inputs {:10} | \
                             unique crashes {:10} | \
-                            fcps {:10.1} | code {:10} | Minst/sec {:10.1} | \
-                            reset {:8.4} | vm {:8.4}\n",
+                            fcps {:10.1} | code {:10} | collisions ~{:8} | \
+                            Minst/sec {:10.1} | \
+                            reset {:8.4} | vm {:8.4} | exits {} | \
+                            top crashes {}\n",
                            elapsed, fuzz_cases, corpus.inputs.len(),
                            corpus.unique_crashes.len(),
                            fuzz_cases as f64 / elapsed,
                            corpus.code_coverage.len(),
+                           corpus.estimated_collisions(),
                            instrs as f64 / elapsed / 1_000_000.,
-                           resetc, vmc);
+                           resetc, vmc, stats.exits(),
+                           Statistics::top_crashes(&corpus,
+                                                   TOP_CRASHES_LINE));
 
                     last_time = Instant::now();
                 }
+
+                // Evict JIT code and cache files to stay within the limits
+                if last_prune.elapsed() >= JIT_PRUNE_INTERVAL {
+                    if let Some(jit_cache) = &shared_jit {
+                        jit_cache.prune();
+                    }
+                    if let Some(max_size) = config.jit_cache_max_size {
+                        if let Err(err) = jitcache::prune_cache_dir(max_size) {
+                            print!("Failed to prune the JIT cache: {}\n",
+                                   err);
+                        }
+                    }
+                    last_prune = Instant::now();
+                }
+
+                // Report workers which stopped making progress, they either
+                // panicked or are stuck
+                for (thr_id, (status, progress)) in status.iter()
+                        .zip(progress.iter_mut()).enumerate() {
+                    let fuzz_cases = status.fuzz_cases.load(Ordering::Relaxed);
+                    if fuzz_cases != progress.0 {
+                        *progress = (fuzz_cases, Instant::now(), false);
+                    } else if !progress.2 && !shutdown &&
+                            progress.1.elapsed() >= WATCHDOG_TIMEOUT {
+                        print!("Worker {} is wedged, no fuzz cases finished \
+                                for {:?}, last PC {:#x}\n", thr_id,
+                               progress.1.elapsed(),
+                               status.last_pc.load(Ordering::Relaxed));
+                        progress.2 = true;
+                    }
+                }
+
+                if shutdown {
+                    // Report which crashes were hit the most, a crash which
+                    // is hit far more often than the others likely hides
+                    // rarer ones
+                    let top = corpus.top_crashes(TOP_CRASHES);
+                    if !top.is_empty() {
+                        print!("Most hit crashes:\n");
+                        for crash in top {
+                            print!("{:12} {}\n",
+                                   crash.hits.load(Ordering::Relaxed),
+                                   crash.name);
+                        }
+                    }
+
+                    // Report how often the syscalls we stubbed out were
+                    // performed
+                    if let Some(ignored) = &ignored {
+                        print!("Ignored syscalls:\n");
+                        for (num, count) in ignored.counts() {
+                            print!("{:12} syscall {}\n", count, num);
+                        }
+                    }
+
+                    log.sync_all().expect("Failed to flush stats");
+                    break;
+                }
             }
-        });
-    }
+        })
+    };
 
-    for _ in 0..192 {
-        let new_emu = emu.fork();
-        let stats   = stats.clone();
-        let parent  = emu.clone();
-        let corpus  = corpus.clone();
-        std::thread::spawn(move || {
-            worker(new_emu, parent, stats, corpus);
-        });
+    // Wait for the workers to stop and the final stats to be written
+    stats_thread.join().expect("Stats thread panicked");
+
+    // Save all inputs which were discovered during this run, such that they
+    // are used as seeds for the next run
+    let new_inputs = corpus.inputs.len() - seeds;
+    for idx in seeds..corpus.inputs.len() {
+        if let Some(input) = corpus.inputs.get(idx) {
+            let hash = corpus.hasher.hash(&input.data);
+            std::fs::write(Path::new("inputs").join(
+                format!("{:032x}", hash)), &input.data)?;
+        }
     }
+    print!("Saved {} new inputs, shut down cleanly\n", new_inputs);
 
-    loop {
-        std::thread::sleep(Duration::from_millis(5000));
+    if let Some(path) = export_corpus {
+        corpus.export_corpus(&path)?;
+        print!("Exported {} inputs to {:?}\n", corpus.inputs.len(), path);
     }
+
+    Ok(())
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shared_statistics() {
+        const THREADS: u64 = 8;
+        const BATCHES: u64 = 1000;
+
+        // Statistics of batch `batch` of thread `thr`
+        let batch_stats = |thr: u64, batch: u64| {
+            let mut stats = Statistics {
+                fuzz_cases:    thr + batch,
+                instrs_execed: thr * batch,
+                crashes:       batch % 3,
+                total_cycles:  thr * 1000 + batch,
+                reset_cycles:  thr,
+                vm_cycles:     batch,
+                ..Default::default()
+            };
+            stats.exits[(thr + batch) as usize % VmExit::KINDS.len()] = thr;
+            stats
+        };
+
+        // Two threads add to every shard, such that they race on it
+        let shards: Vec<SharedStatistics> = (0..THREADS / 2)
+            .map(|_| SharedStatistics::default()).collect();
+        std::thread::scope(|scope| {
+            for thr in 0..THREADS {
+                let shard = &shards[(thr / 2) as usize];
+                scope.spawn(move || {
+                    for batch in 0..BATCHES {
+                        shard.add(&batch_stats(thr, batch));
+                    }
+                });
+            }
+        });
+
+        // Add up the same batches one after another
+        let mut expected = Statistics::default();
+        for thr in 0..THREADS {
+            for batch in 0..BATCHES {
+                let stats = batch_stats(thr, batch);
+                expected.fuzz_cases    += stats.fuzz_cases;
+                expected.instrs_execed += stats.instrs_execed;
+                expected.crashes       += stats.crashes;
+                expected.total_cycles  += stats.total_cycles;
+                expected.reset_cycles  += stats.reset_cycles;
+                expected.vm_cycles     += stats.vm_cycles;
+                for (total, count) in expected.exits.iter_mut()
+                        .zip(stats.exits.iter()) {
+                    *total += count;
+                }
+            }
+        }
+
+        let stats = Statistics::sum(&shards);
+        assert!(stats.fuzz_cases    == expected.fuzz_cases);
+        assert!(stats.instrs_execed == expected.instrs_execed);
+        assert!(stats.crashes       == expected.crashes);
+        assert!(stats.total_cycles  == expected.total_cycles);
+        assert!(stats.reset_cycles  == expected.reset_cycles);
+        assert!(stats.vm_cycles     == expected.vm_cycles);
+        assert!(stats.exits         == expected.exits);
+    }
+}
@@ -3,7 +3,7 @@
 
 use std::path::Path;
 use std::collections::BTreeMap;
-use crate::emulator::VmExit;
+use crate::emulator::{VmExit, FaultType};
 use crate::primitive::Primitive;
 
 /// Block size used for resetting and tracking memory which has been modified
@@ -12,10 +12,6 @@ use crate::primitive::Primitive;
 /// It seems the sweet spot is often 128-4096 bytes
 pub const DIRTY_BLOCK_SIZE: usize = 1024;
 
-/// If `true` the logic for uninitialized memory tracking will be disabled and
-/// all memory will be marked as readable if it has the RAW bit set
-const DISABLE_UNINIT: bool = true;
-
 // Don't change these, they're hardcoded in the JIT (namely write vs raw dist,
 // during raw bit updates in writes)
 pub const PERM_READ:  u8 = 1 << 0;
@@ -64,6 +60,25 @@ pub struct Mmu {
 
     /// Map an active allocation to its size
     active_alcs: BTreeMap<VirtAddr, usize>,
+
+    /// Map a freed allocation to its size, addresses are never allocated
+    /// again so these do not overlap active allocations
+    freed_alcs: BTreeMap<VirtAddr, usize>,
+
+    /// Set when executable memory is modified, such that JIT code lifted
+    /// from it can be invalidated. Cleared by `take_code_modified()`.
+    code_modified: bool,
+
+    /// Set when executable memory has been modified since the last reset,
+    /// as the reset then modifies it again by restoring the original code
+    code_dirty: bool,
+
+    /// Size `memory` may grow up to when allocating
+    max_size: usize,
+
+    /// If `false`, uninitialized memory tracking is disabled and all memory
+    /// is marked as readable if it has the RAW bit set
+    detect_uninit: bool,
 }
 
 impl Mmu {
@@ -76,6 +91,11 @@ impl Mmu {
             dirty_bitmap: vec![0u64; size / DIRTY_BLOCK_SIZE / 64 + 1],
             cur_alc:      VirtAddr(0x10000),
             active_alcs:  BTreeMap::new(),
+            freed_alcs:   BTreeMap::new(),
+            code_modified: false,
+            code_dirty:   false,
+            max_size:     size,
+            detect_uninit: false,
         }
     }
 
@@ -90,21 +110,91 @@ impl Mmu {
             dirty_bitmap: vec![0u64; size / DIRTY_BLOCK_SIZE / 64 + 1],
             cur_alc:      self.cur_alc.clone(),
             active_alcs:  self.active_alcs.clone(),
+            freed_alcs:   self.freed_alcs.clone(),
+            code_modified: false,
+            code_dirty:   false,
+            max_size:     self.max_size,
+            detect_uninit: self.detect_uninit,
         }
     }
 
+    /// Compare the dirtied memory against `other`, which must have been
+    /// forked or reset from the same original state. Returns the first
+    /// address whose contents or permissions differ, or the start of the
+    /// first block which was only dirtied in one of the address spaces.
+    pub fn diff_dirty(&self, other: &Mmu) -> Option<VirtAddr> {
+        let mut blocks = self.dirty.clone();
+        blocks.extend_from_slice(&other.dirty);
+        blocks.sort();
+        blocks.dedup();
+
+        for block in blocks {
+            let start = block * DIRTY_BLOCK_SIZE;
+            let end   = std::cmp::min((block + 1) * DIRTY_BLOCK_SIZE,
+                std::cmp::min(self.memory.len(), other.memory.len()));
+
+            if let Some(addr) = (start..end).find(|&addr| {
+                self.memory[addr] != other.memory[addr] ||
+                    self.permissions[addr] != other.permissions[addr]
+            }) {
+                return Some(VirtAddr(addr));
+            }
+
+            if !self.dirty.contains(&block) || !other.dirty.contains(&block) {
+                return Some(VirtAddr(start));
+            }
+        }
+
+        if self.memory.len() != other.memory.len() {
+            // One of them grew further
+            return Some(VirtAddr(
+                std::cmp::min(self.memory.len(), other.memory.len())));
+        }
+
+        None
+    }
+
+    /// Get the memory dirtied since the last reset as `(address, size)`
+    /// ranges, in order of address. Adjacent dirty blocks are coalesced into
+    /// one range, thus ranges are multiples of `DIRTY_BLOCK_SIZE`.
+    pub fn dirtied_ranges(&self) -> impl Iterator<Item = (VirtAddr, usize)> {
+        let mut blocks = self.dirty.clone();
+        blocks.sort();
+
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for block in blocks {
+            match ranges.last_mut() {
+                Some((_, end)) if *end == block => *end = block + 1,
+                _ => ranges.push((block, block + 1)),
+            }
+        }
+
+        let len = self.memory.len();
+        ranges.into_iter().map(move |(start, end)| {
+            let start = start * DIRTY_BLOCK_SIZE;
+            let end   = std::cmp::min(end * DIRTY_BLOCK_SIZE, len);
+            (VirtAddr(start), end - start)
+        })
+    }
+
     /// Restores memory back to the original state (eg. restores all dirty
     /// blocks to the state of `other`)
     pub fn reset(&mut self, other: &Mmu) {
         for &block in &self.dirty {
-            // Get the start and end addresses of the dirtied memory
+            // Get the start and end addresses of the dirtied memory, memory
+            // which `other` does not have is dropped below
             let start = block * DIRTY_BLOCK_SIZE;
-            let end   = (block + 1) * DIRTY_BLOCK_SIZE;
+            let end   = std::cmp::min((block + 1) * DIRTY_BLOCK_SIZE,
+                                      other.memory.len());
 
             // Zero the bitmap. This hits wide, but it's fine, we have to do
             // a 64-bit write anyways, no reason to compute the bit index
             self.dirty_bitmap[block / 64] = 0;
 
+            if start >= end {
+                continue;
+            }
+
             // Restore memory state
             self.memory[start..end].copy_from_slice(&other.memory[start..end]);
 
@@ -116,12 +206,31 @@ impl Mmu {
         // Clear the dirty list
         self.dirty.clear();
 
+        // Restore the size of memory, dropping anything it grew by
+        if self.memory.len() != other.memory.len() {
+            self.memory.truncate(other.memory.len());
+            self.permissions.truncate(other.memory.len());
+
+            let len = self.memory.len();
+            self.memory.extend_from_slice(&other.memory[len..]);
+            self.permissions.extend_from_slice(&other.permissions[len..]);
+            self.grow_dirty();
+        }
+
         // Restore allocator state
         self.cur_alc = other.cur_alc;
 
         // Clear active allocation state
         self.active_alcs.clear();
         self.active_alcs.extend(other.active_alcs.iter());
+        self.freed_alcs.clear();
+        self.freed_alcs.extend(other.freed_alcs.iter());
+
+        // Restoring modified code is a modification too
+        if self.code_dirty {
+            self.code_dirty    = false;
+            self.code_modified = true;
+        }
 
         if false {
             // Tests to make sure everything to reset perfectly
@@ -135,7 +244,7 @@ impl Mmu {
     /// Allocate a region of memory as RW in the address space
     pub fn allocate(&mut self, size: usize) -> Option<VirtAddr> {
         // Add some padding and alignment
-        let align_size = (size + 0x1f) & !0xf;
+        let align_size = Self::padded_size(size);
 
         // Get the current allocation base
         let base = self.cur_alc;
@@ -145,18 +254,12 @@ impl Mmu {
             return Some(base);
         }
 
-        // Cannot allocate
-        if base.0 >= self.memory.len() {
-            return None;
-        }
+        // Grow memory if needed, we cannot allocate past the maximum size
+        let end = base.0.checked_add(align_size)?;
+        self.grow(end)?;
 
         // Update the allocation size
-        self.cur_alc = VirtAddr(self.cur_alc.0.checked_add(align_size)?);
-
-        // Could not satisfy allocation without going OOM
-        if self.cur_alc.0 > self.memory.len() {
-            return None;
-        }
+        self.cur_alc = VirtAddr(end);
 
         // Mark the memory as un-initialized and writable
         self.set_permissions(base, size, Perm(PERM_RAW | PERM_WRITE));
@@ -167,11 +270,84 @@ impl Mmu {
         Some(base)
     }
 
+    /// Get the base address of the next allocation
+    pub fn alloc_base(&self) -> VirtAddr {
+        self.cur_alc
+    }
+
+    /// Make allocations continue from `base`, leaving the memory between the
+    /// current allocation base and `base` unmapped. The base never moves
+    /// backwards, such that allocations never overlap.
+    pub fn set_alloc_base(&mut self, base: VirtAddr) {
+        assert!(base >= self.cur_alc, "Allocation base moved backwards");
+        self.cur_alc = base;
+    }
+
+    /// Get the amount of the heap an allocation of `size` bytes takes up,
+    /// that is the size plus some padding and alignment
+    fn padded_size(size: usize) -> usize {
+        (size + 0x1f) & !0xf
+    }
+
+    /// Get the offset of `addr` into the active allocation it lies in. The
+    /// padding following an allocation counts as part of it, such that small
+    /// overflows are attributed to the allocation they overflowed.
+    pub fn alc_offset(&self, addr: VirtAddr) -> Option<usize> {
+        let (base, size) = self.active_alcs.range(..=addr).next_back()?;
+        let offset = addr.0 - base.0;
+        if offset < Self::padded_size(*size) {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    /// Check if `addr` lies in an allocation which was freed, or the padding
+    /// following it
+    pub fn is_freed(&self, addr: VirtAddr) -> bool {
+        self.freed_alcs.range(..=addr).next_back().map_or(false,
+            |(base, size)| addr.0 - base.0 < Self::padded_size(*size))
+    }
+
+    /// Get the lowest address which has any permissions, or the size of
+    /// memory if nothing is mapped
+    pub fn lowest_mapped(&self) -> VirtAddr {
+        VirtAddr(self.permissions.iter().position(|perm| perm.0 != 0)
+                 .unwrap_or(self.permissions.len()))
+    }
+
     /// Get the size of an active allocation if `base` is an active allocation
     pub fn get_alc(&self, base: VirtAddr) -> Option<usize> {
         self.active_alcs.get(&base).copied()
     }
 
+    /// Get the permissions every byte of `[addr, addr + len)` has, that is the
+    /// intersection of their permissions. Returns `None` if the range is
+    /// empty or any byte of it is unmapped or outside of memory.
+    pub fn perms_at(&self, addr: VirtAddr, len: usize) -> Option<Perm> {
+        let perms = self.permissions.get(addr.0..addr.0.checked_add(len)?)?;
+        if perms.is_empty() || perms.iter().any(|perm| perm.0 == 0) {
+            return None;
+        }
+
+        Some(Perm(perms.iter().fold(!0, |acc, perm| acc & perm.0)))
+    }
+
+    /// Check if all of `[addr, addr + size)` is mapped, that is, either has
+    /// permissions or is part of an active allocation
+    pub fn is_mapped(&self, addr: VirtAddr, size: usize) -> bool {
+        let end = match addr.0.checked_add(size) {
+            Some(end) if end <= self.memory.len() => end,
+            _ => return false,
+        };
+
+        (addr.0..end).all(|addr| {
+            self.permissions[addr].0 != 0 ||
+                self.active_alcs.range(..=VirtAddr(addr)).next_back()
+                    .map_or(false, |(base, size)| addr < base.0 + size)
+        })
+    }
+
     /// Free a region of memory based on the allocation from a prior `allocate`
     /// call
     pub fn free(&mut self, base: VirtAddr) -> Result<(), VmExit> {
@@ -179,24 +355,46 @@ impl Mmu {
             // Clear permissions
             self.set_permissions(base, size, Perm(0));
 
+            // Remember it to tell accesses to it apart
+            self.freed_alcs.insert(base, size);
+
             Ok(())
         } else {
-            Err(VmExit::InvalidFree(base))
+            Err(self.free_fault(base))
+        }
+    }
+
+    /// Get the fault for freeing `base`, which is not an active allocation.
+    /// Freeing the base of an allocation which was already freed is a double
+    /// free, anything else was never allocated at all.
+    pub fn free_fault(&self, base: VirtAddr) -> VmExit {
+        if self.freed_alcs.contains_key(&base) {
+            VmExit::DoubleFree(base)
+        } else {
+            VmExit::InvalidFree(base)
         }
     }
 
     /// Apply permissions to a region of memory
     pub fn set_permissions(&mut self, addr: VirtAddr, size: usize,
                            mut perm: Perm) -> Option<()> {
-        if DISABLE_UNINIT {
+        if !self.detect_uninit {
             // If memory is marked as RAW, mark it as readable right away if
             // we have uninit tracking disabled
             if perm.0 & PERM_RAW != 0 { perm.0 |= PERM_READ; }
         }
 
-        // Apply permissions
+        // Apply permissions, making or unmaking executable memory modifies
+        // the code
+        let mut exec = perm.0 & PERM_EXEC != 0;
         self.permissions.get_mut(addr.0..addr.0.checked_add(size)?)?
-            .iter_mut().for_each(|x| *x = perm);
+            .iter_mut().for_each(|x| {
+                exec |= x.0 & PERM_EXEC != 0;
+                *x = perm;
+            });
+        if exec {
+            self.mark_code_modified();
+        }
         
         // Compute dirty bit blocks
         let block_start = addr.0 / DIRTY_BLOCK_SIZE;
@@ -219,12 +417,77 @@ impl Mmu {
         Some(())
     }
 
-    /// Get the maximum size of guest memory
+    /// Record that executable memory has been modified
+    fn mark_code_modified(&mut self) {
+        self.code_modified = true;
+        self.code_dirty    = true;
+    }
+
+    /// Check if executable memory has been modified since the last call,
+    /// meaning any JIT code lifted from it may be stale
+    pub fn take_code_modified(&mut self) -> bool {
+        std::mem::replace(&mut self.code_modified, false)
+    }
+
+    /// Check if executable memory has been modified since the MMU was forked
+    /// or last reset, meaning it no longer holds the code it was reset to
+    pub fn code_dirty(&self) -> bool {
+        self.code_dirty
+    }
+
+    /// Get the current size of guest memory
     #[inline]
     pub fn len(&self) -> usize {
         self.memory.len()
     }
 
+    /// Enable or disable uninitialized memory tracking for memory which is
+    /// allocated or has its permissions set from now on. If enabled, reading
+    /// newly allocated memory before writing it is a `VmExit::UninitFault`.
+    pub fn set_detect_uninit(&mut self, enabled: bool) {
+        self.detect_uninit = enabled;
+    }
+
+    /// Get the size guest memory may grow up to
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Allow guest memory to grow up to `size` bytes when allocating. This
+    /// never shrinks the memory which is already there.
+    pub fn set_max_size(&mut self, size: usize) {
+        self.max_size = std::cmp::max(size, self.memory.len());
+    }
+
+    /// Grow guest memory such that it holds at least `size` bytes, at least
+    /// doubling it to keep the number of growths down. Fails if this would
+    /// exceed the maximum size.
+    fn grow(&mut self, size: usize) -> Option<()> {
+        if size <= self.memory.len() {
+            return Some(());
+        }
+        if size > self.max_size {
+            return None;
+        }
+
+        let size = std::cmp::max(size, self.memory.len() * 2);
+        let size = std::cmp::min(size, self.max_size);
+        self.memory.resize(size, 0);
+        self.permissions.resize(size, Perm(0));
+        self.grow_dirty();
+        Some(())
+    }
+
+    /// Make sure the dirty list and bitmap can track all of memory, the JIT
+    /// appends to the dirty list without checking its capacity
+    fn grow_dirty(&mut self) {
+        let blocks = self.memory.len() / DIRTY_BLOCK_SIZE + 1;
+        self.dirty.reserve(blocks.saturating_sub(self.dirty.len()));
+        if self.dirty_bitmap.len() < blocks / 64 + 1 {
+            self.dirty_bitmap.resize(blocks / 64 + 1, 0);
+        }
+    }
+
     /// Get the dirty list length
     #[inline]
     pub fn dirty_len(&self) -> usize {
@@ -252,17 +515,50 @@ impl Mmu {
     /// Write the bytes from `buf` into `addr`
     pub fn write_from(&mut self, addr: VirtAddr, buf: &[u8])
             -> Result<(), VmExit> {
+        self.write_with(addr, buf.len(), |memory| {
+            memory[addr.0..addr.0 + buf.len()].copy_from_slice(buf)
+        })
+    }
+
+    /// Set the `len` bytes at `addr` to `val`, checking permissions like a
+    /// write of all of them would
+    pub fn memset(&mut self, addr: VirtAddr, val: u8, len: usize)
+            -> Result<(), VmExit> {
+        self.write_with(addr, len, |memory| {
+            memory[addr.0..addr.0 + len].iter_mut().for_each(|x| *x = val)
+        })
+    }
+
+    /// Copy the `len` bytes at `src` to `dst`, the regions may overlap. The
+    /// source must be readable and the destination writable. Both are
+    /// checked before anything is copied, and the copy is done in place, such
+    /// that a bogus `len` faults rather than allocating a buffer for it.
+    pub fn memmove(&mut self, dst: VirtAddr, src: VirtAddr, len: usize)
+            -> Result<(), VmExit> {
+        self.check_perms(src, len, Perm(PERM_READ))?;
+        self.write_with(dst, len, |memory| {
+            memory.copy_within(src.0..src.0 + len, dst.0)
+        })
+    }
+
+    /// Check that the `len` bytes at `addr` are writable and update their
+    /// permissions and dirty state, then let `write` fill them in. `write` is
+    /// given all of memory, of which only these bytes may be written.
+    fn write_with<F>(&mut self, addr: VirtAddr, len: usize, write: F)
+            -> Result<(), VmExit> where F: FnOnce(&mut [u8]) {
         let perms =
-            self.permissions.get_mut(addr.0..addr.0.checked_add(buf.len())
-                .ok_or(VmExit::AddressIntegerOverflow)?)
-            .ok_or(VmExit::AddressMiss(addr, buf.len()))?;
+            self.permissions.get_mut(addr.0..addr.0.checked_add(len)
+                .ok_or(VmExit::AddressIntegerOverflow(addr))?)
+            .ok_or(VmExit::AddressMiss(addr, len))?;
 
         // Check permissions
         let mut has_raw = false;
+        let mut exec    = false;
         for (idx, &perm) in perms.iter().enumerate() {
             // Accumulate if any permission has the raw bit set, this will
             // allow us to bypass permission updates if no RAW is in use
             has_raw |= (perm.0 & PERM_RAW) != 0;
+            exec    |= (perm.0 & PERM_EXEC) != 0;
 
             if (perm.0 & PERM_WRITE) == 0 {
                 // Permission denied, return error
@@ -271,11 +567,11 @@ impl Mmu {
         }
 
         // Copy the buffer into memory!
-        self.memory[addr.0..addr.0 + buf.len()].copy_from_slice(buf);
+        write(&mut self.memory);
 
         // Compute dirty bit blocks
         let block_start = addr.0 / DIRTY_BLOCK_SIZE;
-        let block_end   = (addr.0 + buf.len()) / DIRTY_BLOCK_SIZE;
+        let block_end   = (addr.0 + len) / DIRTY_BLOCK_SIZE;
         for block in block_start..=block_end {
             // Determine the bitmap position of the dirty block
             let idx = block / 64;
@@ -301,6 +597,10 @@ impl Mmu {
             });
         }
 
+        if exec {
+            self.mark_code_modified();
+        }
+
         Ok(())
     }
     
@@ -310,11 +610,12 @@ impl Mmu {
                 exp_perms: Perm) -> Result<&mut [u8], VmExit> {
         let perms =
             self.permissions.get_mut(addr.0..addr.0.checked_add(size)
-                .ok_or(VmExit::AddressIntegerOverflow)?)
+                .ok_or(VmExit::AddressIntegerOverflow(addr))?)
             .ok_or(VmExit::AddressMiss(addr, size))?;
 
         // Check permissions
         let mut has_raw = false;
+        let mut exec    = false;
         for (idx, perm) in perms.iter_mut().enumerate() {
             if (perm.0 & exp_perms.0) != exp_perms.0 {
                 if exp_perms.0 == PERM_READ && (perm.0 & PERM_RAW) != 0 {
@@ -333,6 +634,9 @@ impl Mmu {
             if (exp_perms.0 & PERM_WRITE) != 0 && (perm.0 & PERM_RAW) != 0 {
                 has_raw = true;
             }
+
+            exec |= (exp_perms.0 & PERM_WRITE) != 0 &&
+                (perm.0 & PERM_EXEC) != 0;
         }
 
         if has_raw {
@@ -347,6 +651,11 @@ impl Mmu {
             }
         }
 
+        // The caller may modify executable memory through the slice
+        if exec {
+            self.mark_code_modified();
+        }
+
         // Return a slice to the memory
         Ok(&mut self.memory[addr.0..addr.0 + size])
     }
@@ -356,10 +665,22 @@ impl Mmu {
     /// permission bytes. If this is zero, we ignore permissions entirely.
     pub fn read_into_perms(&self, addr: VirtAddr, buf: &mut [u8],
                            exp_perms: Perm) -> Result<(), VmExit> {
+        self.check_perms(addr, buf.len(), exp_perms)?;
+
+        // Copy the memory
+        buf.copy_from_slice(&self.memory[addr.0..addr.0 + buf.len()]);
+
+        Ok(())
+    }
+
+    /// Check that the `len` bytes at `addr` are mapped and have `exp_perms`,
+    /// faulting at the first byte which does not
+    fn check_perms(&self, addr: VirtAddr, len: usize, exp_perms: Perm)
+            -> Result<(), VmExit> {
         let perms =
-            self.permissions.get(addr.0..addr.0.checked_add(buf.len())
-                .ok_or(VmExit::AddressIntegerOverflow)?)
-            .ok_or(VmExit::AddressMiss(addr, buf.len()))?;
+            self.permissions.get(addr.0..addr.0.checked_add(len)
+                .ok_or(VmExit::AddressIntegerOverflow(addr))?)
+            .ok_or(VmExit::AddressMiss(addr, len))?;
 
         // Check permissions
         for (idx, &perm) in perms.iter().enumerate() {
@@ -376,9 +697,6 @@ impl Mmu {
             }
         }
 
-        // Copy the memory
-        buf.copy_from_slice(&self.memory[addr.0..addr.0 + buf.len()]);
-
         Ok(())
     }
 
@@ -413,6 +731,113 @@ impl Mmu {
         self.write_from(addr, tmp)
     }
 
+    /// Read the nul-terminated string at `addr`, without the terminator.
+    /// Returns `None` if there's no terminator within `max_len` bytes, the
+    /// terminator included, such that an unterminated string does not turn
+    /// into a scan of all of memory.
+    pub fn read_cstr(&self, addr: VirtAddr, max_len: usize)
+            -> Result<Option<Vec<u8>>, VmExit> {
+        let mut string = Vec::new();
+        for ii in 0..max_len {
+            let byte_addr = addr.0.checked_add(ii)
+                .ok_or(VmExit::AddressIntegerOverflow(addr))?;
+            match self.read::<u8>(VirtAddr(byte_addr))? {
+                0    => return Ok(Some(string)),
+                byte => string.push(byte),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Write `string` followed by a nul terminator to `addr`. On failure
+    /// nothing is written.
+    pub fn write_cstr(&mut self, addr: VirtAddr, string: &[u8])
+            -> Result<(), VmExit> {
+        let mut bytes = Vec::with_capacity(string.len() + 1);
+        bytes.extend_from_slice(string);
+        bytes.push(0);
+        self.write_from(addr, &bytes)
+    }
+
+    /// Read `len` bytes at `addr`. On failure the type of the fault and the
+    /// address of the first byte which could not be read are returned.
+    pub fn try_read_bytes(&self, addr: VirtAddr, len: usize)
+            -> Result<Vec<u8>, (FaultType, VirtAddr)> {
+        let mut buf = vec![0u8; len];
+        self.read_into(addr, &mut buf)
+            .map_err(|vmexit| self.fault_info(vmexit, addr))?;
+        Ok(buf)
+    }
+
+    /// Write `buf` to `addr`. On failure nothing is written, and the type of
+    /// the fault and the address of the first byte which could not be
+    /// written are returned.
+    pub fn try_write_bytes(&mut self, addr: VirtAddr, buf: &[u8])
+            -> Result<(), (FaultType, VirtAddr)> {
+        self.write_from(addr, buf)
+            .map_err(|vmexit| self.fault_info(vmexit, addr))
+    }
+
+    /// Convert the `vmexit` of a failed access at `addr` into the type of the
+    /// fault and the address of the first byte which faulted
+    fn fault_info(&self, vmexit: VmExit, addr: VirtAddr)
+            -> (FaultType, VirtAddr) {
+        match vmexit {
+            VmExit::AddressMiss(..) | VmExit::AddressIntegerOverflow(_) => {
+                // The first byte past the end of memory faulted
+                (FaultType::Bounds,
+                 VirtAddr(std::cmp::max(addr.0, self.memory.len())))
+            }
+            _ => vmexit.is_crash().expect("Memory access failed without fault"),
+        }
+    }
+
+    /// Format the memory map, like `/proc/self/maps`. Mapped memory outside
+    /// of allocations is listed as ranges of bytes with the same permissions,
+    /// as `r`, `w`, `x`, and `u` for uninitialized, followed by every live
+    /// allocation and its size.
+    pub fn dump_layout(&self) -> String {
+        let mut layout = String::new();
+        let len = self.permissions.len();
+
+        let mut addr = 0;
+        while addr < len {
+            // Skip over allocations, they are listed below
+            match self.active_alcs.get(&VirtAddr(addr)) {
+                Some(&size) if size > 0 => {
+                    addr += size;
+                    continue;
+                }
+                _ => {}
+            }
+
+            // Extend the range up to the next allocation at most
+            let limit = self.active_alcs.range(VirtAddr(addr + 1)..).next()
+                .map_or(len, |(base, _)| std::cmp::min(base.0, len));
+            let perm  = self.permissions[addr];
+            let start = addr;
+            addr += 1;
+            while addr < limit && self.permissions[addr] == perm {
+                addr += 1;
+            }
+
+            if perm.0 != 0 {
+                let flag = |bit, chr| if perm.0 & bit != 0 { chr } else { '-' };
+                layout += &format!("{:#018x}-{:#018x} {}{}{}{}\n",
+                    start, addr, flag(PERM_READ, 'r'), flag(PERM_WRITE, 'w'),
+                    flag(PERM_EXEC, 'x'), flag(PERM_RAW, 'u'));
+            }
+        }
+
+        for (base, size) in &self.active_alcs {
+            layout += &format!("{:#018x}-{:#018x} alloc {:#x} bytes\n",
+                               base.0, base.0 + size, size);
+        }
+
+        layout
+    }
+
     /// Load a file into the emulators address space using the sections as
     /// described
     pub fn load<P: AsRef<Path>>(&mut self, filename: P,
@@ -457,3 +882,92 @@ impl Mmu {
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_perms_at() {
+        let mut memory = Mmu::new(1024 * 1024);
+        memory.set_permissions(VirtAddr(0x1000), 0x10,
+                               Perm(PERM_READ | PERM_EXEC)).unwrap();
+        memory.set_permissions(VirtAddr(0x1010), 0x10,
+                               Perm(PERM_READ | PERM_WRITE)).unwrap();
+
+        // Ranges get the permissions all of their bytes have
+        assert!(memory.perms_at(VirtAddr(0x1000), 0x10) ==
+                Some(Perm(PERM_READ | PERM_EXEC)));
+        assert!(memory.perms_at(VirtAddr(0x1008), 0x10) ==
+                Some(Perm(PERM_READ)));
+
+        // Unmapped bytes, empty ranges, and the end of memory have none
+        assert!(memory.perms_at(VirtAddr(0x1010), 0x11).is_none());
+        assert!(memory.perms_at(VirtAddr(0x1000), 0).is_none());
+        assert!(memory.perms_at(VirtAddr(!0), 2).is_none());
+        assert!(memory.perms_at(VirtAddr(1024 * 1024), 1).is_none());
+    }
+
+    #[test]
+    fn test_dirtied_ranges() {
+        let mut memory = Mmu::new(128 * 1024);
+        let alc = memory.allocate(8 * DIRTY_BLOCK_SIZE).unwrap();
+        let snapshot = memory.fork();
+        let mut memory = snapshot.fork();
+
+        // Writes to adjacent blocks are coalesced, in address order
+        let base = (alc.0 + DIRTY_BLOCK_SIZE - 1) & !(DIRTY_BLOCK_SIZE - 1);
+        memory.write(VirtAddr(base + 5 * DIRTY_BLOCK_SIZE), 1u8).unwrap();
+        memory.write(VirtAddr(base + DIRTY_BLOCK_SIZE), 1u8).unwrap();
+        memory.write(VirtAddr(base), 1u8).unwrap();
+        let ranges: Vec<_> = memory.dirtied_ranges().collect();
+        assert!(ranges == vec![
+            (VirtAddr(base), 2 * DIRTY_BLOCK_SIZE),
+            (VirtAddr(base + 5 * DIRTY_BLOCK_SIZE), DIRTY_BLOCK_SIZE),
+        ]);
+
+        // Nothing is dirty after a reset
+        memory.reset(&snapshot);
+        assert!(memory.dirtied_ranges().next().is_none());
+    }
+
+    #[test]
+    fn test_try_bytes() {
+        let mut memory = Mmu::new(128 * 1024);
+        memory.set_detect_uninit(true);
+        let alc = memory.allocate(16).unwrap();
+        memory.write_from(alc, &[1, 2, 3, 4]).unwrap();
+
+        assert!(memory.try_read_bytes(alc, 4) == Ok(vec![1, 2, 3, 4]));
+        assert!(memory.try_read_bytes(alc, 8) ==
+                Err((FaultType::Uninit, VirtAddr(alc.0 + 4))));
+        assert!(memory.try_write_bytes(VirtAddr(alc.0 + 12), &[0; 8]) ==
+                Err((FaultType::Write, VirtAddr(alc.0 + 16))));
+        assert!(memory.try_read_bytes(VirtAddr(128 * 1024 - 2), 4) ==
+                Err((FaultType::Bounds, VirtAddr(128 * 1024))));
+        assert!(memory.try_read_bytes(VirtAddr(!0), 2) ==
+                Err((FaultType::Bounds, VirtAddr(!0))));
+
+        // Failed writes write nothing
+        assert!(memory.try_write_bytes(alc, &[5, 6, 7, 8]) == Ok(()));
+        assert!(memory.try_read_bytes(alc, 4) == Ok(vec![5, 6, 7, 8]));
+    }
+
+    #[test]
+    fn test_cstr() {
+        let mut memory = Mmu::new(1024 * 1024);
+        let buf = memory.allocate(16).unwrap();
+
+        memory.write_cstr(buf, b"abc").unwrap();
+        assert!(memory.read_cstr(buf, 16).unwrap().unwrap() == b"abc");
+        assert!(memory.read_cstr(buf, 4).unwrap().unwrap() == b"abc");
+        assert!(memory.read_cstr(buf, 3).unwrap().is_none());
+
+        // Reading past the end of the allocation faults
+        memory.write_from(buf, &[b'a'; 16]).unwrap();
+        assert!(memory.read_cstr(buf, 32).is_err());
+
+        // Nothing is written if the terminator does not fit
+        assert!(memory.write_cstr(buf, &[b'b'; 16]).is_err());
+        assert!(memory.read::<u8>(buf).unwrap() == b'a');
+    }
+}
@@ -0,0 +1,334 @@
+//! Random mutation of fuzz inputs, with tunable weights for each strategy
+
+use crate::{Rng, Corpus};
+
+/// Maximum size of a block the mutator duplicates within an input
+const MAX_DUP_BLOCK: usize = 64;
+
+/// Default maximum number of bytes randomized by one havoc mutation
+pub const DEFAULT_HAVOC_DEPTH: usize = 128;
+
+/// Largest factor the havoc depth is scaled by for stale inputs, see
+/// `MutatorConfig::havoc_depth()`
+const MAX_DEPTH_SCALE: u64 = 4;
+
+/// A way of mutating an input
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// Overwrite random bytes of the input with random values
+    Havoc,
+
+    /// Duplicate a block of the input at a random spot
+    Duplicate,
+
+    /// Replace the tail of the input with the tail of another corpus input
+    Splice,
+
+    /// Replace the tail of the input with the tail of an input which found a
+    /// unique crash, mining the neighborhood of known bugs for variants
+    CrashSplice,
+}
+
+/// Relative weights of the mutation strategies. Every mutation picks one
+/// strategy with a probability proportional to its weight, such that a
+/// weight of 0 disables a strategy. Also sets how many bytes havoc changes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MutatorConfig {
+    /// Weight of `Strategy::Havoc`
+    pub havoc: u64,
+
+    /// Weight of `Strategy::Duplicate`
+    pub duplicate: u64,
+
+    /// Weight of `Strategy::Splice`
+    pub splice: u64,
+
+    /// Weight of `Strategy::CrashSplice`
+    pub crash_splice: u64,
+
+    /// Maximum number of bytes randomized by one havoc mutation
+    pub havoc_depth: usize,
+
+    /// Scale the havoc depth by how stale the input is, see `havoc_depth()`
+    pub adaptive_depth: bool,
+}
+
+impl Default for MutatorConfig {
+    fn default() -> Self {
+        MutatorConfig {
+            havoc:          6,
+            duplicate:      2,
+            splice:         1,
+            crash_splice:   1,
+            havoc_depth:    DEFAULT_HAVOC_DEPTH,
+            adaptive_depth: false,
+        }
+    }
+}
+
+impl MutatorConfig {
+    /// Get the weight of the strategy `name`, as used on the command line
+    /// (eg. `havoc`)
+    pub fn strategy_mut(&mut self, name: &str) -> Option<&mut u64> {
+        match name {
+            "havoc"     => Some(&mut self.havoc),
+            "duplicate" => Some(&mut self.duplicate),
+            "splice"    => Some(&mut self.splice),
+            "crash"     => Some(&mut self.crash_splice),
+            _           => None,
+        }
+    }
+
+    /// Get the sum of the weights of all strategies
+    pub fn total_weight(&self) -> u64 {
+        self.havoc + self.duplicate + self.splice + self.crash_splice
+    }
+
+    /// Get the maximum number of bytes randomized by one havoc mutation of an
+    /// input, which `staleness` fuzz cases were derived from since one last
+    /// found new coverage.
+    ///
+    /// With `adaptive_depth` inputs which just found coverage are mutated
+    /// gently, at an eighth of the depth, as their structure likely matters.
+    /// The depth then grows with the log of the staleness, reaching the
+    /// configured depth after about a hundred fuzz cases and up to
+    /// `MAX_DEPTH_SCALE` times that for inputs which stopped paying off.
+    pub fn havoc_depth(&self, staleness: u64) -> usize {
+        if !self.adaptive_depth {
+            return self.havoc_depth;
+        }
+
+        let bucket = 65 - staleness.leading_zeros() as u64;
+        let scaled = self.havoc_depth as u64 *
+            std::cmp::min(bucket, MAX_DEPTH_SCALE * 8) / 8;
+        std::cmp::max(scaled, 1) as usize
+    }
+
+    /// Get the strategy which `roll`, from 0 up to `total_weight()`, falls on
+    pub fn strategy(&self, roll: u64) -> Strategy {
+        assert!(roll < self.total_weight(), "Roll out of range");
+
+        if roll < self.havoc {
+            Strategy::Havoc
+        } else if roll < self.havoc + self.duplicate {
+            Strategy::Duplicate
+        } else if roll < self.havoc + self.duplicate + self.splice {
+            Strategy::Splice
+        } else {
+            Strategy::CrashSplice
+        }
+    }
+}
+
+/// Mutates inputs with a strategy picked at random, weighted by its config
+pub struct Mutator {
+    /// Random number generator driving the mutations
+    pub rng: Rng,
+
+    /// Weights of the strategies
+    config: MutatorConfig,
+}
+
+impl Mutator {
+    /// Create a new mutator. At least one strategy must have a non-zero
+    /// weight in `config`.
+    pub fn new(rng: Rng, config: MutatorConfig) -> Self {
+        assert!(config.total_weight() > 0, "All mutator weights are zero");
+        Mutator { rng, config }
+    }
+
+    /// Mutate `input` in place with a randomly picked strategy. `corpus`
+    /// provides the inputs which are spliced in, `staleness` is that of the
+    /// corpus input `input` was derived from, see `Input::staleness()`.
+    pub fn mutate(&mut self, input: &mut Vec<u8>, corpus: &Corpus,
+                  staleness: u64) {
+        if input.is_empty() {
+            return;
+        }
+
+        let roll = self.rng.rand() as u64 % self.config.total_weight();
+        match self.config.strategy(roll) {
+            Strategy::Havoc => {
+                let depth = self.config.havoc_depth(staleness);
+                for _ in 0..1 + self.rng.rand() % depth {
+                    let sel = self.rng.rand() % input.len();
+                    input[sel] = self.rng.rand() as u8;
+                }
+            }
+            Strategy::Duplicate => {
+                let src = self.rng.rand() % input.len();
+                let len = 1 + self.rng.rand() %
+                    std::cmp::min(input.len() - src, MAX_DUP_BLOCK);
+                let dst = self.rng.rand() % (input.len() + 1);
+                let block = input[src..src + len].to_vec();
+                input.splice(dst..dst, block);
+            }
+            Strategy::CrashSplice if corpus.crashes.len() > 0 => {
+                let len = corpus.crashes.len();
+                if let Some(crash) = corpus.crashes.get(self.rng.rand() % len) {
+                    self.splice(input, &crash.input);
+                }
+            }
+            Strategy::Splice | Strategy::CrashSplice => {
+                // Also taken while there are no crashes to splice from.
+                // Pick the other input uniformly rather than through the
+                // scheduler, such that splicing does not skew its energy.
+                let len = corpus.inputs.len();
+                if len == 0 {
+                    return;
+                }
+                if let Some(other) = corpus.inputs.get(self.rng.rand() % len) {
+                    self.splice(input, &other.data);
+                }
+            }
+        }
+    }
+
+    /// Replace the tail of `input` from a random point on with the tail of
+    /// `other` from a random point on
+    fn splice(&mut self, input: &mut Vec<u8>, other: &[u8]) {
+        if other.is_empty() {
+            return;
+        }
+
+        let split = self.rng.rand() % input.len();
+        let other_split = self.rng.rand() % other.len();
+        input.truncate(split);
+        input.extend_from_slice(&other[other_split..]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Input;
+    use crate::mmu::VirtAddr;
+    use crate::emulator::{FaultType, AddressType};
+
+    #[test]
+    fn test_strategy_selection() {
+        let config = MutatorConfig {
+            havoc: 2, duplicate: 0, splice: 1, crash_splice: 0,
+            ..Default::default()
+        };
+        assert!(config.total_weight() == 3);
+        assert!(config.strategy(0) == Strategy::Havoc);
+        assert!(config.strategy(1) == Strategy::Havoc);
+        assert!(config.strategy(2) == Strategy::Splice);
+
+        let mut config = MutatorConfig::default();
+        *config.strategy_mut("splice").unwrap() = 0;
+        assert!(config.splice == 0);
+        assert!(config.strategy_mut("cmplog").is_none());
+        assert!((0..config.total_weight())
+                .all(|roll| config.strategy(roll) != Strategy::Splice));
+    }
+
+    #[test]
+    fn test_mutate() {
+        let corpus = Corpus::new();
+        let seed = vec![0x41u8; 32];
+
+        // Duplicating a block only ever grows the input
+        let config = MutatorConfig {
+            havoc: 0, duplicate: 1, splice: 0, crash_splice: 0,
+            ..Default::default()
+        };
+        let mut mutator = Mutator::new(Rng::with_seed(0), config);
+        for _ in 0..100 {
+            let mut input = seed.clone();
+            mutator.mutate(&mut input, &corpus, 0);
+            assert!(input.len() > seed.len());
+            assert!(input.iter().all(|&x| x == 0x41));
+        }
+
+        // Havoc keeps the length
+        let config = MutatorConfig {
+            havoc: 1, duplicate: 0, splice: 0, crash_splice: 0,
+            ..Default::default()
+        };
+        let mut mutator = Mutator::new(Rng::with_seed(0), config);
+        let mut input = seed.clone();
+        for _ in 0..100 {
+            mutator.mutate(&mut input, &corpus, 0);
+            assert!(input.len() == seed.len());
+        }
+        assert!(input != seed);
+
+        // Splicing takes the tail from the corpus
+        corpus.inputs.push(Box::new(Input::new(vec![0x42u8; 32])));
+        let config = MutatorConfig {
+            havoc: 0, duplicate: 0, splice: 1, crash_splice: 0,
+            ..Default::default()
+        };
+        let mut mutator = Mutator::new(Rng::with_seed(0), config);
+        for _ in 0..100 {
+            let mut input = seed.clone();
+            mutator.mutate(&mut input, &corpus, 0);
+            assert!(input.ends_with(&[0x42]));
+            assert!(input.iter().skip_while(|&&x| x == 0x41)
+                    .all(|&x| x == 0x42));
+        }
+    }
+
+    #[test]
+    fn test_crash_splice() {
+        let corpus = Corpus::new();
+        let seed = vec![0x41u8; 32];
+        let config = MutatorConfig {
+            havoc: 0, duplicate: 0, splice: 0, crash_splice: 1,
+            ..Default::default()
+        };
+        assert!(config.strategy(0) == Strategy::CrashSplice);
+        let mut mutator = Mutator::new(Rng::with_seed(0), config);
+
+        // Without crashes the corpus is spliced from instead
+        corpus.inputs.push(Box::new(Input::new(vec![0x42u8; 32])));
+        let mut input = seed.clone();
+        mutator.mutate(&mut input, &corpus, 0);
+        assert!(input.ends_with(&[0x42]));
+
+        // Once there are crashes only their inputs are spliced in
+        let key = (VirtAddr(0x1000), FaultType::Read, AddressType::Null, 0,
+                   None);
+        corpus.record_crash(&key, &[0x43u8; 32], || "crash".into());
+        for _ in 0..100 {
+            let mut input = seed.clone();
+            mutator.mutate(&mut input, &corpus, 0);
+            assert!(input.ends_with(&[0x43]));
+            assert!(input.iter().skip_while(|&&x| x == 0x41)
+                    .all(|&x| x == 0x43));
+        }
+    }
+
+    #[test]
+    fn test_havoc_depth() {
+        let mut config = MutatorConfig::default();
+        assert!(config.havoc_depth(0) == DEFAULT_HAVOC_DEPTH);
+        assert!(config.havoc_depth(1 << 40) == DEFAULT_HAVOC_DEPTH);
+
+        // Hot inputs are mutated gently, stale ones harder, within bounds
+        config.adaptive_depth = true;
+        assert!(config.havoc_depth(0) == DEFAULT_HAVOC_DEPTH / 8);
+        assert!(config.havoc_depth(100) == DEFAULT_HAVOC_DEPTH);
+        assert!(config.havoc_depth(1 << 20) > DEFAULT_HAVOC_DEPTH);
+        assert!(config.havoc_depth(!0) ==
+                DEFAULT_HAVOC_DEPTH * MAX_DEPTH_SCALE as usize);
+        config.havoc_depth = 1;
+        assert!(config.havoc_depth(0) == 1);
+
+        // Havoc never changes more bytes than the depth allows
+        let corpus = Corpus::new();
+        let config = MutatorConfig {
+            havoc: 1, duplicate: 0, splice: 0, crash_splice: 0,
+            havoc_depth: 2,
+            ..Default::default()
+        };
+        let mut mutator = Mutator::new(Rng::with_seed(0), config);
+        for _ in 0..100 {
+            let mut input = vec![0u8; 32];
+            mutator.mutate(&mut input, &corpus, 0);
+            assert!(input.iter().filter(|&&x| x != 0).count() <= 2);
+        }
+    }
+}
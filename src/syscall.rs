@@ -0,0 +1,1755 @@
+//! Linux syscall emulation for the guest
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::BTreeMap;
+use crate::mmu::{Mmu, VirtAddr, Perm, PERM_READ, PERM_WRITE, PERM_EXEC};
+use crate::emulator::{Emulator, Register, VmExit, EmuFile, Files};
+use crate::emulator::{carve_fuzz_file, SigAction};
+
+/// Linux error numbers, syscalls return these negated
+const EPERM:   i64 = 1;
+const ENOENT:  i64 = 2;
+const ESRCH:   i64 = 3;
+const EIO:     i64 = 5;
+const EBADF:   i64 = 9;
+const ENOMEM:  i64 = 12;
+const EACCES:  i64 = 13;
+const EFAULT:  i64 = 14;
+const ENOTDIR: i64 = 20;
+const EINVAL:  i64 = 22;
+const ENOTTY:  i64 = 25;
+const ESPIPE:  i64 = 29;
+const ENAMETOOLONG: i64 = 36;
+const ENOSYS:  i64 = 38;
+
+/// Maximum length of a path, including the nul terminator
+const PATH_MAX: usize = 4096;
+
+/// `dirfd` for the `*at()` syscalls meaning the current working directory
+const AT_FDCWD: i64 = -100;
+
+/// `*at()` flag to operate on `dirfd` itself if the path is empty
+const AT_EMPTY_PATH: u64 = 0x1000;
+
+/// Mask of the access mode in the `open()` flags, the guest may only open
+/// files read-only
+const O_ACCMODE: u64 = 3;
+
+/// Page size of the guest, `mprotect()` works on whole pages
+const PAGE_SIZE: usize = 4096;
+
+/// `mprotect()` protection flags
+const PROT_READ:  u64 = 1;
+const PROT_WRITE: u64 = 2;
+const PROT_EXEC:  u64 = 4;
+
+/// Process ID of the guest, which is also the ID of its only thread
+const GUEST_PID: u64 = 1000;
+
+/// Signal number of `SIGABRT`, raised by `abort()`
+const SIGABRT: u64 = 6;
+
+/// User and group IDs of the guest, a regular non-root user
+const GUEST_UID: u64 = 1000;
+const GUEST_GID: u64 = 1000;
+
+/// Fields of the `struct utsname` reported by `uname()`, in order
+const GUEST_UTSNAME: [&[u8]; 6] = [
+    b"Linux", b"fuzz", b"5.8.0", b"#1 SMP", b"riscv64", b"(none)",
+];
+
+/// Size of each field of a `struct utsname`
+const UTSNAME_FIELD_LEN: usize = 65;
+
+/// Maximum number of file descriptors the guest may have, matching the usual
+/// `RLIMIT_NOFILE`
+const MAX_FDS: usize = 1024;
+
+/// `poll()` events
+const POLLIN:   u16 = 0x01;
+const POLLOUT:  u16 = 0x04;
+const POLLNVAL: u16 = 0x20;
+
+/// `fcntl()` commands
+const F_DUPFD:         u64 = 0;
+const F_GETFD:         u64 = 1;
+const F_SETFD:         u64 = 2;
+const F_DUPFD_CLOEXEC: u64 = 1030;
+
+/// Wall clock time, in seconds since the Unix epoch, the guest observes at the
+/// snapshot
+const GUEST_EPOCH: u64 = 0x5f0fe246;
+
+/// Clocks the guest can read and sleep on
+const CLOCK_REALTIME:  u64 = 0;
+const CLOCK_MONOTONIC: u64 = 1;
+
+/// `clock_nanosleep()` flag for sleeping until an absolute time
+const TIMER_ABSTIME: u64 = 1;
+
+/// Stat structure from kernel_stat64
+#[repr(C)]
+#[derive(Default, Debug)]
+struct Stat {
+    st_dev:     u64,
+    st_ino:     u64,
+    st_mode:    u32,
+    st_nlink:   u32,
+    st_uid:     u32,
+    st_gid:     u32,
+    st_rdev:    u64,
+    __pad1:     u64,
+
+    st_size:    i64,
+    st_blksize: i32,
+    __pad2:     i32,
+
+    st_blocks: i64,
+
+    st_atime:     u64,
+    st_atimensec: u64,
+    st_mtime:     u64,
+    st_mtimensec: u64,
+    st_ctime:     u64,
+    st_ctimensec: u64,
+    
+    __glibc_reserved: [i32; 2],
+}
+
+/// Write `len` bytes at guest address `buf` to `fd`, returns the syscall
+/// return value
+fn write_fd(emu: &mut Emulator, fd: usize, buf: u64, len: u64)
+        -> Result<u64, VmExit> {
+    let file = emu.files.get_file(fd);
+    if let Some(Some(file)) = file {
+        if file == &EmuFile::Stdout || file == &EmuFile::Stderr {
+            // Writes to stdout and stderr
+
+            // Get access to the underlying bytes to write
+            let bytes = emu.memory.peek(VirtAddr(buf as usize),
+                len as usize, Perm(PERM_READ))?;
+
+            if let Some(output) = &mut emu.output {
+                output.extend_from_slice(bytes);
+            }
+
+            // Set that all bytes were read
+            Ok(len)
+        } else {
+            // Stdin, fuzz files, and host files are read-only, like for
+            // `pwrite64()`
+            Ok(-EBADF as u64)
+        }
+    } else {
+        // Unknown FD
+        Ok(-EBADF as u64)
+    }
+}
+
+/// Read up to `len` bytes from `fd` into guest address `buf`, returns the
+/// syscall return value
+fn read_fd(emu: &mut Emulator, fd: usize, buf: usize, len: usize)
+        -> Result<u64, VmExit> {
+    let fuzz_files = emu.fuzz_files().len();
+    if let Some((contents, cursor)) = file_contents(
+            &mut emu.files, &emu.fuzz_input, fuzz_files, fd) {
+        return read_contents(&mut emu.memory, contents, cursor, buf, len);
+    }
+
+    match emu.files.get_file(fd) {
+        // Nothing is piped into stdin
+        Some(Some(EmuFile::Stdin)) => Ok(0),
+
+        // FD was not valid or can't be read from, return out with an error
+        _ => Ok(!0),
+    }
+}
+
+/// Get the bytes backing the open file `fd` and its cursor. Fuzz files are
+/// carved out of `fuzz_input`, which is shared by `fuzz_files` files. Returns
+/// `None` if `fd` is not open or is not backed by bytes (eg. stdin).
+fn file_contents<'a>(files: &'a mut Files, fuzz_input: &'a [u8],
+                     fuzz_files: usize, fd: usize)
+        -> Option<(&'a [u8], &'a mut usize)> {
+    match files.get_file(fd)? {
+        Some(EmuFile::FuzzInput { index, cursor }) => Some((
+            carve_fuzz_file(fuzz_input, fuzz_files, *index), cursor)),
+        Some(EmuFile::HostFile { contents, cursor }) => {
+            let contents: &'a Vec<u8> = contents;
+            Some((contents, cursor))
+        }
+        _ => None,
+    }
+}
+
+/// Read up to `len` bytes of `contents` at `cursor` into guest address `buf`,
+/// advancing the cursor. Returns the number of bytes read.
+fn read_contents(memory: &mut Mmu, contents: &[u8], cursor: &mut usize,
+                 buf: usize, len: usize) -> Result<u64, VmExit> {
+    // Compute the ending cursor from this read, the cursor may be past the
+    // end of the contents
+    let start = core::cmp::min(*cursor, contents.len());
+    let end   = core::cmp::min(start.saturating_add(len), contents.len());
+
+    // Write in the bytes
+    memory.write_from(VirtAddr(buf), &contents[start..end])?;
+
+    // Update the cursor
+    *cursor = end;
+
+    // Return number of bytes read
+    Ok((end - start) as u64)
+}
+
+/// Sleep for the `struct timespec` at guest address `req` by returning right
+/// away, as actually sleeping would only slow down fuzzing. The remaining
+/// time is written to `rem` as zero unless it is null. Returns the syscall
+/// return value.
+fn skip_sleep(emu: &mut Emulator, req: usize, rem: usize) -> u64 {
+    let secs  = emu.memory.read::<u64>(VirtAddr(req));
+    let nsecs = emu.memory.read::<u64>(VirtAddr(req.wrapping_add(8)));
+    match (secs, nsecs) {
+        (Ok(secs), Ok(nsecs)) => {
+            if (secs as i64) < 0 || nsecs >= 1_000_000_000 {
+                return -EINVAL as u64;
+            }
+        }
+        _ => return -EFAULT as u64,
+    }
+
+    if rem != 0 && emu.memory.write_from(VirtAddr(rem), &[0u8; 16]).is_err() {
+        return -EFAULT as u64;
+    }
+
+    0
+}
+
+/// Read the nul-terminated filename at guest address `filename`. Returns
+/// `None` if there's no nul terminator within `PATH_MAX` bytes, such that an
+/// unterminated filename does not turn into a scan of all of memory.
+fn read_filename(emu: &mut Emulator, filename: usize)
+        -> Result<Option<Vec<u8>>, VmExit> {
+    emu.memory.read_cstr(VirtAddr(filename), PATH_MAX)
+}
+
+/// Open the file `filename` read-only, returns the new file descriptor or
+/// `None` if there is no such file
+fn open_file(emu: &mut Emulator, filename: &[u8]) -> Option<usize> {
+    let index = emu.fuzz_files().iter().position(|x| x == filename);
+    let file = if let Some(index) = index {
+        // Mark that this file should be backed by our fuzz input
+        EmuFile::FuzzInput { index, cursor: 0 }
+    } else {
+        // Whitelisted host file, back it by the host file contents
+        let contents = emu.host_file(filename)?;
+        EmuFile::HostFile { contents, cursor: 0 }
+    };
+
+    // Create a new file descriptor
+    let fd = emu.alloc_file();
+
+    // Get access to the file, unwrap here is safe because there's no way the
+    // file is not a valid FD if we got it from our own APIs
+    *emu.files.get_file(fd).unwrap() = Some(file);
+    Some(fd)
+}
+
+/// Get the size of the file `filename`, or `None` if there is no such file
+fn file_size(emu: &Emulator, filename: &[u8]) -> Option<usize> {
+    let index = emu.fuzz_files().iter().position(|x| x == filename);
+    if let Some(index) = index {
+        Some(emu.fuzz_file(index).len())
+    } else {
+        emu.host_file(filename).map(|contents| contents.len())
+    }
+}
+
+/// Get the size of the file open as `fd`, or `None` if `fd` is not an open
+/// file
+fn fd_size(emu: &mut Emulator, fd: usize) -> Option<usize> {
+    let fuzz_files = emu.fuzz_files().len();
+    file_contents(&mut emu.files, &emu.fuzz_input, fuzz_files, fd)
+        .map(|(contents, _)| contents.len())
+}
+
+/// Write the stat structure for a regular file of `size` bytes to guest
+/// address `statbuf`
+fn write_stat(emu: &mut Emulator, statbuf: usize, size: usize)
+        -> Result<(), VmExit> {
+    let mut stat = Stat::default();
+    stat.st_dev = 0x803;
+    stat.st_ino = 0x81889;
+    stat.st_mode = 0x81a4;
+    stat.st_nlink = 0x1;
+    stat.st_uid = 0x3e8;
+    stat.st_gid = 0x3e8;
+    stat.st_rdev = 0x0;
+    stat.st_size = size as i64;
+    stat.st_blksize = 0x1000;
+    stat.st_blocks = (size as i64 + 511) / 512;
+    stat.st_atime = 0x5f0fe246;
+    stat.st_mtime = 0x5f0fe244;
+    stat.st_ctime = 0x5f0fe244;
+
+    // Cast the stat structure to raw bytes
+    let stat = unsafe {
+        core::slice::from_raw_parts(
+            &stat as *const Stat as *const u8,
+            core::mem::size_of_val(&stat))
+    };
+
+    // Write in the stat data
+    emu.memory.write_from(VirtAddr(statbuf), stat)
+}
+
+/// Check that a path passed to an `*at()` syscall with `dirfd` can be
+/// resolved. Only `AT_FDCWD` and absolute paths are supported, as the guest
+/// cannot open directories. Returns the negated errno on failure.
+fn check_dirfd(emu: &mut Emulator, dirfd: u64, filename: &[u8])
+        -> Result<(), u64> {
+    if dirfd as i64 == AT_FDCWD || filename.first() == Some(&b'/') {
+        Ok(())
+    } else if let Some(Some(_)) = emu.files.get_file(dirfd as usize) {
+        Err(-ENOTDIR as u64)
+    } else {
+        Err(-EBADF as u64)
+    }
+}
+
+/// Duplicate `fd` into the lowest free descriptor which is at least `min_fd`,
+/// returns the syscall return value.
+///
+/// Unlike Linux, where both descriptors share the file offset, the new
+/// descriptor gets its own copy of the cursor. This keeps the file table plain
+/// data which is cheaply reset to the snapshot.
+fn dup_fd(emu: &mut Emulator, fd: usize, min_fd: usize) -> u64 {
+    let file = match emu.files.get_file(fd) {
+        Some(Some(file)) => file.clone(),
+        _ => return -EBADF as u64,
+    };
+
+    // Find the lowest free descriptor
+    let mut newfd = min_fd;
+    while let Some(Some(_)) = emu.files.get_file(newfd) {
+        newfd += 1;
+    }
+    if newfd >= MAX_FDS {
+        return -EINVAL as u64;
+    }
+
+    emu.files.insert(newfd, file);
+    newfd as u64
+}
+
+/// Guest time since the snapshot, as a `(seconds, nanoseconds)` tuple. This is
+/// derived from the number of instructions executed rather than the wall
+/// clock such that fuzz cases are reproducible, each instruction is treated as
+/// taking one nanosecond.
+fn guest_time(emu: &Emulator) -> (u64, u64) {
+    let ns = emu.instrs_execed();
+    (ns / 1_000_000_000, ns % 1_000_000_000)
+}
+
+/// What to do after a syscall was handled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyscallAction {
+    /// Resume execution after the `ecall`
+    Continue,
+
+    /// Resume execution at the current PC, which the handler has changed
+    Redirect,
+}
+
+/// Syscall policy for the guest. A handler may handle only the syscalls it
+/// cares about and forward the rest to `DefaultSyscallHandler`.
+pub trait SyscallHandler: Send + Sync {
+    /// Handle the syscall `num` the guest requested with `ecall`. Arguments
+    /// are in `a0`-`a5` and the return value should be placed in `a0`.
+    fn dispatch(&self, emu: &mut Emulator, num: u64)
+        -> Result<SyscallAction, VmExit>;
+}
+
+/// Returns a fixed value from a set of syscalls rather than handling them,
+/// forwarding all others to another handler. This gets a target past
+/// syscalls which are not supported without writing a handler for them.
+///
+/// Every ignored syscall is counted, such that it is known what is stubbed.
+pub struct IgnoreSyscalls {
+    /// Handler for the syscalls which are not ignored
+    inner: Arc<dyn SyscallHandler>,
+
+    /// Maps an ignored syscall number to the value it returns and the number
+    /// of times it was performed
+    ignored: BTreeMap<u64, (u64, AtomicU64)>,
+}
+
+impl IgnoreSyscalls {
+    /// Create a new handler which forwards all syscalls to `inner`
+    pub fn new(inner: Arc<dyn SyscallHandler>) -> Self {
+        IgnoreSyscalls {
+            inner:   inner,
+            ignored: BTreeMap::new(),
+        }
+    }
+
+    /// Make syscall `num` fail with `-ENOSYS`, as if it does not exist
+    pub fn ignore(mut self, num: u64) -> Self {
+        self.ignored.insert(num, (-ENOSYS as u64, AtomicU64::new(0)));
+        self
+    }
+
+    /// Make syscall `num` return 0, as if it succeeded
+    pub fn stub(mut self, num: u64) -> Self {
+        self.ignored.insert(num, (0, AtomicU64::new(0)));
+        self
+    }
+
+    /// Get the number of times each ignored syscall was performed, as
+    /// `(syscall number, count)` tuples
+    pub fn counts(&self) -> Vec<(u64, u64)> {
+        self.ignored.iter().map(|(&num, (_, count))| {
+            (num, count.load(Ordering::Relaxed))
+        }).collect()
+    }
+}
+
+impl SyscallHandler for IgnoreSyscalls {
+    fn dispatch(&self, emu: &mut Emulator, num: u64)
+            -> Result<SyscallAction, VmExit> {
+        if let Some((ret, count)) = self.ignored.get(&num) {
+            count.fetch_add(1, Ordering::Relaxed);
+            emu.set_reg(Register::A0, *ret);
+            return Ok(SyscallAction::Continue);
+        }
+
+        self.inner.dispatch(emu, num)
+    }
+}
+
+/// Syscalls which `FaultInjection` makes fail, and the errno they fail with
+const INJECTED_FAULTS: &[(u64, i64)] = &[
+    (56,   ENOENT), // openat()
+    (63,   EIO),    // read()
+    (64,   EIO),    // write()
+    (65,   EIO),    // readv()
+    (66,   EIO),    // writev()
+    (222,  ENOMEM), // mmap()
+    (1024, ENOENT), // open()
+];
+
+/// Makes a fraction of the syscalls which can fail fail, forwarding all
+/// others to another handler. This exercises the error handling paths of the
+/// guest, which otherwise never run.
+///
+/// Whether a syscall fails is derived from the seed and the number of
+/// instructions executed so far in the fuzz case, thus a fuzz case always
+/// sees the same failures when it is run again with the same seed.
+pub struct FaultInjection {
+    /// Handler for the syscalls which are not made to fail
+    inner: Arc<dyn SyscallHandler>,
+
+    /// Seed the failures are derived from
+    seed: u64,
+
+    /// Syscalls fail if their hash is below this, out of `u32::MAX`
+    threshold: u64,
+}
+
+impl FaultInjection {
+    /// Create a new handler which makes syscalls fail with `probability`,
+    /// between 0 and 1, forwarding all others to `inner`
+    pub fn new(inner: Arc<dyn SyscallHandler>, seed: u64,
+               probability: f64) -> Self {
+        FaultInjection {
+            inner:     inner,
+            seed:      seed,
+            threshold: (probability * (1u64 << 32) as f64) as u64,
+        }
+    }
+}
+
+impl SyscallHandler for FaultInjection {
+    fn dispatch(&self, emu: &mut Emulator, num: u64)
+            -> Result<SyscallAction, VmExit> {
+        if let Some(&(_, errno)) =
+                INJECTED_FAULTS.iter().find(|&&(x, _)| x == num) {
+            // Mix the seed, instruction count, and syscall into a hash
+            let mut hash = self.seed ^ emu.instrs_execed() ^
+                num.wrapping_mul(0x9e3779b97f4a7c15);
+            hash ^= hash >> 33;
+            hash  = hash.wrapping_mul(0xff51afd7ed558ccd);
+            hash ^= hash >> 33;
+
+            if (hash >> 32) < self.threshold {
+                emu.set_reg(Register::A0, -errno as u64);
+                return Ok(SyscallAction::Continue);
+            }
+        }
+
+        self.inner.dispatch(emu, num)
+    }
+}
+
+/// The Linux syscalls the fuzzer supports out of the box
+pub struct DefaultSyscallHandler;
+
+impl SyscallHandler for DefaultSyscallHandler {
+    fn dispatch(&self, emu: &mut Emulator, num: u64)
+            -> Result<SyscallAction, VmExit> {
+        //print!("Syscall {}\n", num);
+
+        match num {
+            214 => {
+                // brk()
+                let req_base = emu.reg(Register::A0);
+                if req_base == 0 {
+                    emu.set_reg(Register::A0, 0);
+                    return Ok(SyscallAction::Continue);
+                }
+
+                panic!("Not expecting brk");
+
+                /*
+                let increment = if req_base != 0 {
+                    (req_base as i64).checked_sub(cur_base.0 as i64)
+                        .ok_or(VmExit::SyscallIntegerOverflow)?
+                } else {
+                    0
+                };
+
+                // We don't handle negative brks yet
+                if increment < 0 {
+                    emu.set_reg(Register::A0, cur_base.0 as u64);
+                    return Ok(SyscallAction::Continue);
+                }
+
+                // Attempt to extend data section by increment
+                if let Some(_) = emu.memory.allocate(increment as usize) {
+                    let new_base = cur_base.0 + increment as usize;
+                    emu.set_reg(Register::A0, new_base as u64);
+                } else {
+                    emu.set_reg(Register::A0, !0);
+                }
+
+                Ok(SyscallAction::Continue)*/
+            }
+            64 => {
+                // write()
+                let fd  = emu.reg(Register::A0) as usize;
+                let buf = emu.reg(Register::A1);
+                let len = emu.reg(Register::A2);
+
+                let ret = write_fd(emu, fd, buf, len)?;
+                emu.set_reg(Register::A0, ret);
+                Ok(SyscallAction::Continue)
+            }
+            63 => {
+                // read()
+                let fd  = emu.reg(Register::A0) as usize;
+                let buf = emu.reg(Register::A1) as usize;
+                let len = emu.reg(Register::A2) as usize;
+
+                let ret = read_fd(emu, fd, buf, len)?;
+                emu.set_reg(Register::A0, ret);
+                Ok(SyscallAction::Continue)
+            }
+            67 => {
+                // pread64()
+                let fd     = emu.reg(Register::A0) as usize;
+                let buf    = emu.reg(Register::A1) as usize;
+                let len    = emu.reg(Register::A2) as usize;
+                let offset = emu.reg(Register::A3) as i64;
+
+                // Read from a copy of the cursor, leaving the file's alone
+                let fuzz_files = emu.fuzz_files().len();
+                let ret = match file_contents(
+                        &mut emu.files, &emu.fuzz_input, fuzz_files, fd) {
+                    Some(_) if offset < 0 => -EINVAL as u64,
+                    Some((contents, _)) => {
+                        let mut cursor = offset as usize;
+                        read_contents(&mut emu.memory, contents, &mut cursor,
+                                      buf, len)?
+                    }
+                    None => match emu.files.get_file(fd) {
+                        // Stdio can't seek
+                        Some(Some(_)) => -ESPIPE as u64,
+                        _             => -EBADF as u64,
+                    }
+                };
+
+                emu.set_reg(Register::A0, ret);
+                Ok(SyscallAction::Continue)
+            }
+            68 => {
+                // pwrite64()
+                // All files which can seek are read-only
+                let fd  = emu.reg(Register::A0) as usize;
+                let ret = match emu.files.get_file(fd) {
+                    Some(Some(EmuFile::FuzzInput { .. })) |
+                        Some(Some(EmuFile::HostFile { .. })) => -EBADF,
+                    Some(Some(_)) => -ESPIPE,
+                    _             => -EBADF,
+                };
+
+                emu.set_reg(Register::A0, ret as u64);
+                Ok(SyscallAction::Continue)
+            }
+            66 | 65 => {
+                // writev() and readv()
+                let fd     = emu.reg(Register::A0) as usize;
+                let iov    = emu.reg(Register::A1) as usize;
+                let iovcnt = emu.reg(Register::A2) as usize;
+
+                let mut transferred = 0u64;
+                for ii in 0..iovcnt {
+                    // Read the `struct iovec { void *base; size_t len; }`
+                    let ent  = ii.checked_mul(16)
+                        .and_then(|x| x.checked_add(iov))
+                        .ok_or(VmExit::SyscallIntegerOverflow)?;
+                    let base = emu.memory.read::<u64>(VirtAddr(ent))?;
+                    let len  = emu.memory.read::<u64>(VirtAddr(ent + 8))?;
+
+                    let ret = if num == 66 {
+                        write_fd(emu, fd, base, len)?
+                    } else {
+                        read_fd(emu, fd, base as usize, len as usize)?
+                    };
+
+                    if (ret as i64) < 0 {
+                        // Report the error only if nothing was transferred yet
+                        if ii == 0 { transferred = ret; }
+                        break;
+                    }
+
+                    transferred += ret;
+
+                    // Stop on a short transfer (eg. end of file)
+                    if ret < len {
+                        break;
+                    }
+                }
+
+                emu.set_reg(Register::A0, transferred);
+                Ok(SyscallAction::Continue)
+            }
+            73 => {
+                // ppoll(), there is no poll() on RISC-V, libc implements it
+                // with ppoll(). Every file is always ready, so this never
+                // waits regardless of the timeout.
+                let fds  = emu.reg(Register::A0) as usize;
+                let nfds = emu.reg(Register::A1) as usize;
+                if nfds > MAX_FDS {
+                    emu.set_reg(Register::A0, -EINVAL as u64);
+                    return Ok(SyscallAction::Continue);
+                }
+
+                let mut ready = 0u64;
+                for ii in 0..nfds {
+                    // Read the `struct pollfd { int fd; short events;
+                    // short revents; }`
+                    let ent    = ii.checked_mul(8)
+                        .and_then(|x| x.checked_add(fds))
+                        .ok_or(VmExit::SyscallIntegerOverflow)?;
+                    let fd     = emu.memory.read::<i32>(VirtAddr(ent))?;
+                    let events = emu.memory.read::<u16>(VirtAddr(ent + 4))?;
+
+                    // Negative fds are ignored
+                    let revents = if fd < 0 {
+                        0
+                    } else {
+                        match emu.files.get_file(fd as usize) {
+                            Some(Some(EmuFile::Stdout)) |
+                                Some(Some(EmuFile::Stderr)) =>
+                                    events & POLLOUT,
+                            Some(Some(_)) => events & POLLIN,
+                            _             => POLLNVAL,
+                        }
+                    };
+
+                    emu.memory.write(VirtAddr(ent + 6), revents)?;
+                    if revents != 0 {
+                        ready += 1;
+                    }
+                }
+
+                emu.set_reg(Register::A0, ready);
+                Ok(SyscallAction::Continue)
+            }
+            62 => {
+                // lseek()
+                let fd     = emu.reg(Register::A0) as usize;
+                let offset = emu.reg(Register::A1) as i64;
+                let whence = emu.reg(Register::A2) as i32;
+
+                const SEEK_SET: i32 = 0;
+                const SEEK_CUR: i32 = 1;
+                const SEEK_END: i32 = 2;
+
+                // Get the cursor and the size of the file backing
+                let fuzz_files = emu.fuzz_files().len();
+                let (cursor, file_size) = match file_contents(
+                        &mut emu.files, &emu.fuzz_input, fuzz_files, fd) {
+                    Some((contents, cursor)) => (cursor, contents.len()),
+                    None => {
+                        // FD was not valid or can't seek, return an error
+                        emu.set_reg(Register::A0, !0);
+                        return Ok(SyscallAction::Continue);
+                    }
+                };
+
+                let new_cursor = match whence {
+                    SEEK_SET => offset,
+                    SEEK_CUR => (*cursor as i64).saturating_add(offset),
+                    SEEK_END => (file_size as i64).saturating_add(offset),
+                    _ => {
+                        // Invalid whence, return error
+                        emu.set_reg(Register::A0, !0);
+                        return Ok(SyscallAction::Continue);
+                    }
+                };
+
+                // Make sure the cursor falls in bounds of [0, file_size]
+                let new_cursor = core::cmp::max(0i64, new_cursor);
+                let new_cursor =
+                    core::cmp::min(new_cursor, file_size as i64);
+
+                // Update the cursor
+                *cursor = new_cursor as usize;
+
+                // Return the new cursor position
+                emu.set_reg(Register::A0, new_cursor as u64);
+
+                Ok(SyscallAction::Continue)
+            }
+            1024 => {
+                // open()
+                let filename = emu.reg(Register::A0) as usize;
+                let flags    = emu.reg(Register::A1);
+                let _mode    = emu.reg(Register::A2);
+
+                assert!(flags == 0, "Currently we only handle O_RDONLY");
+
+                let bytes = match read_filename(emu, filename)? {
+                    Some(bytes) => bytes,
+                    None => {
+                        emu.set_reg(Register::A0, -ENAMETOOLONG as u64);
+                        return Ok(SyscallAction::Continue);
+                    }
+                };
+                let ret = open_file(emu, &bytes)
+                    .map(|fd| fd as u64).unwrap_or(!0);
+                emu.set_reg(Register::A0, ret);
+                Ok(SyscallAction::Continue)
+            }
+            56 => {
+                // openat()
+                let dirfd    = emu.reg(Register::A0);
+                let filename = emu.reg(Register::A1) as usize;
+                let flags    = emu.reg(Register::A2);
+                let _mode    = emu.reg(Register::A3);
+
+                let bytes = match read_filename(emu, filename)? {
+                    Some(bytes) => bytes,
+                    None => {
+                        emu.set_reg(Register::A0, -ENAMETOOLONG as u64);
+                        return Ok(SyscallAction::Continue);
+                    }
+                };
+                let ret = if let Err(err) = check_dirfd(emu, dirfd, &bytes) {
+                    err
+                } else if flags & O_ACCMODE != 0 {
+                    // Only reading is supported, flags such as `O_CLOEXEC`
+                    // don't matter
+                    -EACCES as u64
+                } else {
+                    open_file(emu, &bytes)
+                        .map(|fd| fd as u64).unwrap_or(-ENOENT as u64)
+                };
+
+                emu.set_reg(Register::A0, ret);
+                Ok(SyscallAction::Continue)
+            }
+            1038 => {
+                // stat()
+                let filename = emu.reg(Register::A0) as usize;
+                let statbuf  = emu.reg(Register::A1) as usize;
+
+                let bytes = match read_filename(emu, filename)? {
+                    Some(bytes) => bytes,
+                    None => {
+                        emu.set_reg(Register::A0, -ENAMETOOLONG as u64);
+                        return Ok(SyscallAction::Continue);
+                    }
+                };
+                if let Some(size) = file_size(emu, &bytes) {
+                    write_stat(emu, statbuf, size)?;
+                    emu.set_reg(Register::A0, 0);
+                } else {
+                    // Error
+                    emu.set_reg(Register::A0, !0);
+                }
+
+                Ok(SyscallAction::Continue)
+            }
+            80 => {
+                // fstat()
+                let fd      = emu.reg(Register::A0) as usize;
+                let statbuf = emu.reg(Register::A1) as usize;
+
+                if let Some(size) = fd_size(emu, fd) {
+                    write_stat(emu, statbuf, size)?;
+                    emu.set_reg(Register::A0, 0);
+                } else {
+                    // FD was not valid
+                    emu.set_reg(Register::A0, !0);
+                }
+
+                Ok(SyscallAction::Continue)
+            }
+            79 => {
+                // newfstatat()
+                let dirfd    = emu.reg(Register::A0);
+                let filename = emu.reg(Register::A1) as usize;
+                let statbuf  = emu.reg(Register::A2) as usize;
+                let flags    = emu.reg(Register::A3);
+
+                let bytes = match read_filename(emu, filename)? {
+                    Some(bytes) => bytes,
+                    None => {
+                        emu.set_reg(Register::A0, -ENAMETOOLONG as u64);
+                        return Ok(SyscallAction::Continue);
+                    }
+                };
+                let size = if bytes.is_empty() && flags & AT_EMPTY_PATH != 0 {
+                    // Stat `dirfd` itself, like `fstat()`
+                    fd_size(emu, dirfd as usize).ok_or(-EBADF as u64)
+                } else {
+                    check_dirfd(emu, dirfd, &bytes).and_then(|()| {
+                        file_size(emu, &bytes).ok_or(-ENOENT as u64)
+                    })
+                };
+
+                let ret = match size {
+                    Ok(size) => {
+                        write_stat(emu, statbuf, size)?;
+                        0
+                    }
+                    Err(err) => err,
+                };
+
+                emu.set_reg(Register::A0, ret);
+                Ok(SyscallAction::Continue)
+            }
+            57 => {
+                // close()
+                let fd = emu.reg(Register::A0) as usize;
+
+                if let Some(file) = emu.files.get_file(fd) {
+                    if file.is_some() {
+                        // File was present and currently open, close it
+                       
+                        // Close the file
+                        *file = None;
+
+                        // Just return success for now
+                        emu.set_reg(Register::A0, 0);
+                    } else {
+                        // File was in a closed state
+                        emu.set_reg(Register::A0, !0);
+                    }
+                } else {
+                    // FD out of bounds
+                    emu.set_reg(Register::A0, !0);
+                }
+
+                Ok(SyscallAction::Continue)
+            }
+            23 => {
+                // dup()
+                let fd = emu.reg(Register::A0) as usize;
+                let ret = dup_fd(emu, fd, 0);
+                emu.set_reg(Register::A0, ret);
+                Ok(SyscallAction::Continue)
+            }
+            24 => {
+                // dup3()
+                let fd    = emu.reg(Register::A0) as usize;
+                let newfd = emu.reg(Register::A1) as usize;
+                let flags = emu.reg(Register::A2);
+
+                // Only `O_CLOEXEC` is valid, which we ignore as we never exec
+                const O_CLOEXEC: u64 = 0o2000000;
+                let ret = if fd == newfd || flags & !O_CLOEXEC != 0 {
+                    -EINVAL as u64
+                } else if newfd >= MAX_FDS {
+                    -EBADF as u64
+                } else {
+                    match emu.files.get_file(fd) {
+                        Some(Some(file)) => {
+                            // Replace `newfd`, closing it if it was open
+                            let file = file.clone();
+                            emu.files.insert(newfd, file);
+                            newfd as u64
+                        }
+                        _ => -EBADF as u64,
+                    }
+                };
+
+                emu.set_reg(Register::A0, ret);
+                Ok(SyscallAction::Continue)
+            }
+            25 => {
+                // fcntl()
+                let fd  = emu.reg(Register::A0) as usize;
+                let cmd = emu.reg(Register::A1);
+                let arg = emu.reg(Register::A2);
+
+                let ret = if let Some(Some(_)) = emu.files.get_file(fd) {
+                    match cmd {
+                        F_DUPFD | F_DUPFD_CLOEXEC => {
+                            if arg as usize >= MAX_FDS {
+                                -EINVAL as u64
+                            } else {
+                                dup_fd(emu, fd, arg as usize)
+                            }
+                        }
+                        // There is no exec, so the close-on-exec flag is
+                        // never set and setting it has no effect
+                        F_GETFD | F_SETFD => 0,
+                        _ => -EINVAL as u64,
+                    }
+                } else {
+                    -EBADF as u64
+                };
+
+                emu.set_reg(Register::A0, ret);
+                Ok(SyscallAction::Continue)
+            }
+            29 => {
+                // ioctl()
+                let fd = emu.reg(Register::A0) as usize;
+
+                // None of the files are terminals, thus every request,
+                // including `TCGETS` used by `isatty()`, fails. This makes
+                // the guest fully buffer stdout and stderr, which saves on
+                // write syscalls.
+                let ret = if let Some(Some(_)) = emu.files.get_file(fd) {
+                    -ENOTTY
+                } else {
+                    -EBADF
+                };
+
+                emu.set_reg(Register::A0, ret as u64);
+                Ok(SyscallAction::Continue)
+            }
+            96 => {
+                // set_tid_address()
+                // Stub: the clear-child-tid address is ignored as there is
+                // only ever one thread, returns our fake thread ID
+                emu.set_reg(Register::A0, GUEST_PID);
+                Ok(SyscallAction::Continue)
+            }
+            99 => {
+                // set_robust_list()
+                // Stub: the list is ignored as there is only ever one thread,
+                // returns success
+                emu.set_reg(Register::A0, 0);
+                Ok(SyscallAction::Continue)
+            }
+            160 => {
+                // uname()
+                let buf = emu.reg(Register::A0) as usize;
+
+                let mut utsname =
+                    [0u8; GUEST_UTSNAME.len() * UTSNAME_FIELD_LEN];
+                for (field, value) in utsname.chunks_mut(UTSNAME_FIELD_LEN)
+                        .zip(GUEST_UTSNAME.iter()) {
+                    field[..value.len()].copy_from_slice(value);
+                }
+
+                if emu.memory.write_from(VirtAddr(buf), &utsname).is_ok() {
+                    emu.set_reg(Register::A0, 0);
+                } else {
+                    // Buffer was not writable
+                    emu.set_reg(Register::A0, -EFAULT as u64);
+                }
+                Ok(SyscallAction::Continue)
+            }
+            172 => {
+                // getpid()
+                emu.set_reg(Register::A0, GUEST_PID);
+                Ok(SyscallAction::Continue)
+            }
+            174 | 175 => {
+                // getuid() and geteuid()
+                emu.set_reg(Register::A0, GUEST_UID);
+                Ok(SyscallAction::Continue)
+            }
+            176 | 177 => {
+                // getgid() and getegid()
+                emu.set_reg(Register::A0, GUEST_GID);
+                Ok(SyscallAction::Continue)
+            }
+            134 => {
+                // rt_sigaction()
+                // Actions are recorded, but only memory faults are ever
+                // delivered, and only with `enable_signal_delivery()`
+                let signum = emu.reg(Register::A0);
+                let act    = emu.reg(Register::A1) as usize;
+                let oldact = emu.reg(Register::A2) as usize;
+
+                let old = match emu.sigaction(signum) {
+                    Some(old) => old,
+                    None => {
+                        emu.set_reg(Register::A0, -EINVAL as u64);
+                        return Ok(SyscallAction::Continue);
+                    }
+                };
+
+                if act != 0 {
+                    let action = SigAction {
+                        handler: emu.memory.read(VirtAddr(act))?,
+                        flags:   emu.memory.read(VirtAddr(act + 8))?,
+                        mask:    emu.memory.read(VirtAddr(act + 16))?,
+                    };
+                    emu.set_sigaction(signum, action);
+                }
+
+                if oldact != 0 {
+                    emu.memory.write(VirtAddr(oldact), old.handler)?;
+                    emu.memory.write(VirtAddr(oldact + 8), old.flags)?;
+                    emu.memory.write(VirtAddr(oldact + 16), old.mask)?;
+                }
+
+                emu.set_reg(Register::A0, 0);
+                Ok(SyscallAction::Continue)
+            }
+            135 => {
+                // rt_sigprocmask()
+                // Stub: signals are never delivered, so the mask is not
+                // recorded. The old mask reads back as empty, returns
+                // success.
+                let oldset = emu.reg(Register::A2) as usize;
+                if oldset != 0 {
+                    emu.memory.write(VirtAddr(oldset), 0u64)?;
+                }
+
+                emu.set_reg(Register::A0, 0);
+                Ok(SyscallAction::Continue)
+            }
+            113 => {
+                // clock_gettime()
+                let clockid = emu.reg(Register::A0);
+                let tp      = emu.reg(Register::A1) as usize;
+
+                let (secs, nsecs) = guest_time(emu);
+                let secs = match clockid {
+                    CLOCK_REALTIME  => secs + GUEST_EPOCH,
+                    CLOCK_MONOTONIC => secs,
+                    _ => {
+                        // Unsupported clock
+                        emu.set_reg(Register::A0, -EINVAL as u64);
+                        return Ok(SyscallAction::Continue);
+                    }
+                };
+
+                // Write in the `struct timespec`
+                emu.memory.write(VirtAddr(tp), secs)?;
+                emu.memory.write(VirtAddr(tp.wrapping_add(8)), nsecs)?;
+                emu.set_reg(Register::A0, 0);
+
+                Ok(SyscallAction::Continue)
+            }
+            101 => {
+                // nanosleep()
+                let req = emu.reg(Register::A0) as usize;
+                let rem = emu.reg(Register::A1) as usize;
+
+                let ret = skip_sleep(emu, req, rem);
+                emu.set_reg(Register::A0, ret);
+                Ok(SyscallAction::Continue)
+            }
+            115 => {
+                // clock_nanosleep()
+                let clockid = emu.reg(Register::A0);
+                let flags   = emu.reg(Register::A1);
+                let req     = emu.reg(Register::A2) as usize;
+                let rem     = emu.reg(Register::A3) as usize;
+
+                let ret = if clockid != CLOCK_REALTIME &&
+                        clockid != CLOCK_MONOTONIC {
+                    // Unsupported clock
+                    -EINVAL as u64
+                } else if flags & TIMER_ABSTIME != 0 {
+                    // The remaining time is not reported for absolute sleeps
+                    skip_sleep(emu, req, 0)
+                } else {
+                    skip_sleep(emu, req, rem)
+                };
+
+                emu.set_reg(Register::A0, ret);
+                Ok(SyscallAction::Continue)
+            }
+            169 => {
+                // gettimeofday()
+                let tv = emu.reg(Register::A0) as usize;
+                let tz = emu.reg(Register::A1) as usize;
+
+                let (secs, nsecs) = guest_time(emu);
+
+                // Write in the `struct timeval`
+                if tv != 0 {
+                    emu.memory.write(VirtAddr(tv), secs + GUEST_EPOCH)?;
+                    emu.memory.write(VirtAddr(tv.wrapping_add(8)),
+                                     nsecs / 1000)?;
+                }
+
+                // Write in a UTC `struct timezone`
+                if tz != 0 {
+                    emu.memory.write(VirtAddr(tz), 0u64)?;
+                }
+
+                emu.set_reg(Register::A0, 0);
+                Ok(SyscallAction::Continue)
+            }
+            278 => {
+                // getrandom()
+                let buf    = emu.reg(Register::A0) as usize;
+                let len    = emu.reg(Register::A1) as usize;
+                let _flags = emu.reg(Register::A2);
+
+                // A buffer larger than guest memory can never be writable
+                if len > emu.memory.len() {
+                    emu.set_reg(Register::A0, -EFAULT as u64);
+                    return Ok(SyscallAction::Continue);
+                }
+
+                // Generate the deterministic random bytes
+                let bytes: Vec<u8> =
+                    (0..len).map(|_| emu.guest_random() as u8).collect();
+
+                if emu.memory.write_from(VirtAddr(buf), &bytes).is_ok() {
+                    emu.set_reg(Register::A0, len as u64);
+                } else {
+                    // Buffer was not writable
+                    emu.set_reg(Register::A0, -EFAULT as u64);
+                }
+
+                Ok(SyscallAction::Continue)
+            }
+            226 => {
+                // mprotect()
+                let addr = emu.reg(Register::A0) as usize;
+                let len  = emu.reg(Register::A1) as usize;
+                let prot = emu.reg(Register::A2);
+
+                // The length is rounded up to whole pages
+                let len = len.checked_add(PAGE_SIZE - 1)
+                    .map(|len| len & !(PAGE_SIZE - 1));
+
+                let ret = match len {
+                    Some(len) if addr % PAGE_SIZE == 0 &&
+                            prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) == 0 &&
+                            emu.memory.is_mapped(VirtAddr(addr), len) => {
+                        let mut perm = 0;
+                        if prot & PROT_READ  != 0 { perm |= PERM_READ;  }
+                        if prot & PROT_WRITE != 0 { perm |= PERM_WRITE; }
+                        if prot & PROT_EXEC  != 0 { perm |= PERM_EXEC;  }
+
+                        emu.memory.set_permissions(VirtAddr(addr), len,
+                                                   Perm(perm))
+                            .expect("Mapped memory out of bounds?");
+                        0
+                    }
+                    _ => -EINVAL as u64,
+                };
+
+                emu.set_reg(Register::A0, ret);
+                Ok(SyscallAction::Continue)
+            }
+            129 | 130 | 131 => {
+                // kill(), tkill() and tgkill()
+                // Only signals sent to ourselves are supported, `SIGABRT`
+                // ends the fuzz case as an abort, others are not delivered
+                let (target, signal) = match num {
+                    131 => (emu.reg(Register::A1), emu.reg(Register::A2)),
+                    _   => (emu.reg(Register::A0), emu.reg(Register::A1)),
+                };
+
+                let ret = if target != GUEST_PID &&
+                        !(num == 129 && target == 0) {
+                    -ESRCH
+                } else if signal == SIGABRT {
+                    return Err(VmExit::Abort);
+                } else if signal == 0 {
+                    0
+                } else {
+                    -EPERM
+                };
+
+                emu.set_reg(Register::A0, ret as u64);
+                Ok(SyscallAction::Continue)
+            }
+            259 | 283 => {
+                // riscv_flush_icache() and membarrier(), hints about the
+                // hardware which are safe to ignore. Stores to executable
+                // memory already invalidate the JIT code lifted from it, and
+                // the guest only has one thread to order memory against.
+                emu.set_reg(Register::A0, 0);
+                Ok(SyscallAction::Continue)
+            }
+            93 | 94 => {
+                // exit() and exit_group()
+                Err(VmExit::Exit(emu.reg(Register::A0) as i64))
+            }
+            _ => {
+                panic!("Unhandled syscall {} @ {:#x}\n", num,
+                       emu.reg(Register::Pc));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emulator::{pack_fuzz_files, FaultType};
+
+    /// Perform syscall `num` with `args`, returning the result
+    fn syscall(emu: &mut Emulator, num: u64, args: &[u64]) -> u64 {
+        let regs = [Register::A0, Register::A1, Register::A2, Register::A3];
+        for (&reg, &arg) in regs.iter().zip(args) {
+            emu.set_reg(reg, arg);
+        }
+        DefaultSyscallHandler.dispatch(emu, num).unwrap();
+        emu.reg(Register::A0)
+    }
+
+    #[test]
+    fn test_dup() {
+        let mut emu = Emulator::new(1024 * 1024);
+        let buf = emu.memory.allocate(16).unwrap();
+        emu.fuzz_input.extend_from_slice(b"abcdef");
+        emu.files.insert(3, EmuFile::FuzzInput { index: 0, cursor: 0 });
+
+        // Read a couple bytes from `fd` into `buf`
+        let read = |emu: &mut Emulator, fd: u64| {
+            assert!(syscall(emu, 63, &[fd, buf.0 as u64, 2]) == 2);
+            let mut bytes = [0u8; 2];
+            emu.memory.read_into(buf, &mut bytes).unwrap();
+            bytes
+        };
+
+        assert!(&read(&mut emu, 3) == b"ab");
+
+        // The duplicate gets a copy of the cursor
+        assert!(syscall(&mut emu, 23, &[3]) == 4);
+        assert!(&read(&mut emu, 4) == b"cd");
+        assert!(&read(&mut emu, 3) == b"cd");
+        assert!(&read(&mut emu, 4) == b"ef");
+
+        // dup3() and F_DUPFD pick the descriptor
+        assert!(syscall(&mut emu, 24, &[3, 10, 0]) == 10);
+        assert!(&read(&mut emu, 10) == b"ef");
+        assert!(syscall(&mut emu, 25, &[3, F_DUPFD, 7]) == 7);
+        assert!(syscall(&mut emu, 25, &[3, F_DUPFD, 7]) == 8);
+
+        // Bad descriptors
+        assert!(syscall(&mut emu, 23, &[100]) == -EBADF as u64);
+        assert!(syscall(&mut emu, 24, &[3, 3, 0]) == -EINVAL as u64);
+        assert!(syscall(&mut emu, 25, &[100, F_GETFD, 0]) == -EBADF as u64);
+    }
+
+    #[test]
+    fn test_ioctl() {
+        const TCGETS: u64 = 0x5401;
+
+        let mut emu = Emulator::new(1024 * 1024);
+        let termios = emu.memory.allocate(64).unwrap().0 as u64;
+        emu.files.insert(3, EmuFile::FuzzInput { index: 0, cursor: 0 });
+
+        for &fd in &[0, 1, 2, 3] {
+            assert!(syscall(&mut emu, 29, &[fd, TCGETS, termios]) ==
+                    -ENOTTY as u64);
+        }
+        assert!(syscall(&mut emu, 29, &[4, TCGETS, termios]) ==
+                -EBADF as u64);
+    }
+
+    #[test]
+    fn test_openat() {
+        const O_CLOEXEC: u64 = 0x80000;
+        const O_WRONLY:  u64 = 1;
+
+        let mut emu = Emulator::new(1024 * 1024);
+        emu.fuzz_input.extend_from_slice(b"abcdef");
+        let statbuf = emu.memory.allocate(core::mem::size_of::<Stat>())
+            .unwrap().0 as u64;
+        let testfn = emu.memory.allocate(16).unwrap();
+        emu.memory.write_from(testfn, b"testfn\0").unwrap();
+        let missing = emu.memory.allocate(16).unwrap();
+        emu.memory.write_from(missing, b"missing\0").unwrap();
+        let empty = emu.memory.allocate(1).unwrap();
+        emu.memory.write_from(empty, b"\0").unwrap();
+        let (testfn, missing, empty) =
+            (testfn.0 as u64, missing.0 as u64, empty.0 as u64);
+        let at_fdcwd = AT_FDCWD as u64;
+
+        // Read back the size from the stat structure
+        let st_size = |emu: &Emulator| {
+            emu.memory.read::<i64>(VirtAddr(statbuf as usize + 48)).unwrap()
+        };
+
+        assert!(syscall(&mut emu, 56, &[at_fdcwd, testfn, O_CLOEXEC, 0]) ==
+                3);
+        assert!(syscall(&mut emu, 56, &[at_fdcwd, missing, 0, 0]) ==
+                -ENOENT as u64);
+        assert!(syscall(&mut emu, 56, &[at_fdcwd, testfn, O_WRONLY, 0]) ==
+                -EACCES as u64);
+        assert!(syscall(&mut emu, 56, &[3, testfn, 0, 0]) ==
+                -ENOTDIR as u64);
+        assert!(syscall(&mut emu, 56, &[100, testfn, 0, 0]) ==
+                -EBADF as u64);
+
+        assert!(syscall(&mut emu, 79, &[at_fdcwd, testfn, statbuf, 0]) ==
+                0);
+        assert!(st_size(&emu) == 6);
+        assert!(syscall(&mut emu, 79, &[at_fdcwd, missing, statbuf, 0]) ==
+                -ENOENT as u64);
+
+        // An empty path with `AT_EMPTY_PATH` stats the descriptor
+        emu.fuzz_input.push(b'g');
+        assert!(syscall(&mut emu, 79, &[3, empty, statbuf, AT_EMPTY_PATH]) ==
+                0);
+        assert!(st_size(&emu) == 7);
+        assert!(syscall(&mut emu, 79, &[9, empty, statbuf, AT_EMPTY_PATH]) ==
+                -EBADF as u64);
+    }
+
+    #[test]
+    fn test_filename_too_long() {
+        let mut emu = Emulator::new(1024 * 1024);
+        let statbuf = emu.memory.allocate(core::mem::size_of::<Stat>())
+            .unwrap().0 as u64;
+        let name = emu.memory.allocate(PATH_MAX * 2).unwrap();
+        emu.memory.write_from(name, &[b'a'; PATH_MAX * 2]).unwrap();
+        let at_fdcwd = AT_FDCWD as u64;
+
+        // No nul terminator within `PATH_MAX` bytes
+        assert!(syscall(&mut emu, 56, &[at_fdcwd, name.0 as u64, 0, 0]) ==
+                -ENAMETOOLONG as u64);
+        assert!(syscall(&mut emu, 79,
+                        &[at_fdcwd, name.0 as u64, statbuf, 0]) ==
+                -ENAMETOOLONG as u64);
+
+        // The longest allowed filename
+        emu.memory.write(VirtAddr(name.0 + PATH_MAX - 1), 0u8).unwrap();
+        assert!(syscall(&mut emu, 56, &[at_fdcwd, name.0 as u64, 0, 0]) ==
+                -ENOENT as u64);
+    }
+
+    #[test]
+    fn test_rt_sigaction() {
+        let mut emu = Emulator::new(1024 * 1024);
+        let act = emu.memory.allocate(48).unwrap().0;
+        let oldact = act + 24;
+        emu.memory.write(VirtAddr(act), 0x1234u64).unwrap();
+        emu.memory.write(VirtAddr(act + 8), 4u64).unwrap();
+        emu.memory.write(VirtAddr(act + 16), 0u64).unwrap();
+        let (act, oldact) = (act as u64, oldact as u64);
+
+        assert!(syscall(&mut emu, 134, &[11, act, oldact]) == 0);
+        assert!(emu.memory.read::<u64>(VirtAddr(oldact as usize)).unwrap() ==
+                0);
+        assert!(syscall(&mut emu, 134, &[11, 0, oldact]) == 0);
+        assert!(emu.memory.read::<u64>(VirtAddr(oldact as usize)).unwrap() ==
+                0x1234);
+        assert!(emu.sigaction(11) == Some(SigAction {
+            handler: 0x1234,
+            flags:   4,
+            mask:    0,
+        }));
+
+        assert!(syscall(&mut emu, 134, &[0, act, 0]) == -EINVAL as u64);
+        assert!(syscall(&mut emu, 134, &[65, 0, oldact]) == -EINVAL as u64);
+    }
+
+    #[test]
+    fn test_fuzz_files() {
+        // Lengths are clamped to the input, missing files are empty
+        let input = b"\xff\0\0\0ab";
+        assert!(carve_fuzz_file(input, 2, 0) == b"ab");
+        assert!(carve_fuzz_file(input, 2, 1) == b"");
+        assert!(carve_fuzz_file(b"\x01\0", 3, 0) == b"");
+        assert!(carve_fuzz_file(input, 1, 0) == input);
+
+        let mut emu = Emulator::new(1024 * 1024);
+        emu.set_fuzz_files(vec![b"hdr".to_vec(), b"data".to_vec()]);
+        emu.fuzz_input = pack_fuzz_files(&[b"ab", b"cdef"]);
+        let statbuf = emu.memory.allocate(core::mem::size_of::<Stat>())
+            .unwrap().0 as u64;
+        let names = emu.memory.allocate(32).unwrap();
+        emu.memory.write_from(names, b"hdr\0data\0testfn\0").unwrap();
+        let (hdr, data, testfn) =
+            (names.0 as u64, names.0 as u64 + 4, names.0 as u64 + 9);
+        let buf = emu.memory.allocate(16).unwrap();
+        let at_fdcwd = AT_FDCWD as u64;
+
+        assert!(syscall(&mut emu, 56, &[at_fdcwd, data, 0, 0]) == 3);
+        assert!(syscall(&mut emu, 56, &[at_fdcwd, hdr, 0, 0]) == 4);
+        assert!(syscall(&mut emu, 56, &[at_fdcwd, testfn, 0, 0]) ==
+                -ENOENT as u64);
+
+        assert!(syscall(&mut emu, 63, &[4, buf.0 as u64, 16]) == 2);
+        assert!(emu.memory.peek(buf, 2, Perm(PERM_READ)).unwrap() == b"ab");
+        assert!(syscall(&mut emu, 63, &[3, buf.0 as u64, 16]) == 4);
+        assert!(emu.memory.peek(buf, 4, Perm(PERM_READ)).unwrap() == b"cdef");
+
+        assert!(syscall(&mut emu, 79, &[at_fdcwd, data, statbuf, 0]) == 0);
+        assert!(emu.memory.read::<i64>(VirtAddr(statbuf as usize + 48))
+                .unwrap() == 4);
+    }
+
+    #[test]
+    fn test_signal_stubs() {
+        let mut emu = Emulator::new(1024 * 1024);
+        let old = emu.memory.allocate(24).unwrap();
+        emu.memory.write_from(old, &[0xffu8; 24]).unwrap();
+
+        assert!(syscall(&mut emu, 96, &[0]) == GUEST_PID);
+        assert!(syscall(&mut emu, 99, &[0, 24]) == 0);
+
+        // The old action and mask read back as zeroed
+        assert!(syscall(&mut emu, 134, &[11, 0, old.0 as u64]) == 0);
+        let mut bytes = [0xffu8; 24];
+        emu.memory.read_into(old, &mut bytes).unwrap();
+        assert!(bytes == [0u8; 24]);
+
+        emu.memory.write_from(old, &[0xffu8; 8]).unwrap();
+        assert!(syscall(&mut emu, 135, &[0, 0, old.0 as u64]) == 0);
+        assert!(emu.memory.read::<u64>(old).unwrap() == 0);
+    }
+
+    #[test]
+    fn test_abort() {
+        let mut emu = Emulator::new(1024 * 1024);
+
+        // kill(), tkill() and tgkill() of ourselves with SIGABRT abort
+        for &(num, ref args) in &[
+            (129, vec![GUEST_PID, SIGABRT]),
+            (129, vec![0, SIGABRT]),
+            (130, vec![GUEST_PID, SIGABRT]),
+            (131, vec![GUEST_PID, GUEST_PID, SIGABRT]),
+        ] {
+            emu.set_reg(Register::A0, args[0]);
+            emu.set_reg(Register::A1, args[1]);
+            emu.set_reg(Register::A2, *args.get(2).unwrap_or(&0));
+            assert!(DefaultSyscallHandler.dispatch(&mut emu, num).err() ==
+                    Some(VmExit::Abort));
+        }
+        assert!(VmExit::Abort.is_crash() ==
+                Some((FaultType::Abort, VirtAddr(0))));
+
+        // Other processes and signals are not supported
+        assert!(syscall(&mut emu, 129, &[1, SIGABRT]) == -ESRCH as u64);
+        assert!(syscall(&mut emu, 130, &[GUEST_PID, 0]) == 0);
+        assert!(syscall(&mut emu, 131, &[GUEST_PID, GUEST_PID, 15]) ==
+                -EPERM as u64);
+    }
+
+    #[test]
+    fn test_identity() {
+        let mut emu = Emulator::new(1024 * 1024);
+        assert!(syscall(&mut emu, 172, &[]) == GUEST_PID);
+        assert!(syscall(&mut emu, 174, &[]) == GUEST_UID);
+        assert!(syscall(&mut emu, 175, &[]) == GUEST_UID);
+        assert!(syscall(&mut emu, 176, &[]) == GUEST_GID);
+        assert!(syscall(&mut emu, 177, &[]) == GUEST_GID);
+    }
+
+    #[test]
+    fn test_uname() {
+        let mut emu = Emulator::new(1024 * 1024);
+        let buf = emu.memory.allocate(6 * 65).unwrap();
+        assert!(syscall(&mut emu, 160, &[buf.0 as u64]) == 0);
+
+        let mut utsname = [0u8; 6 * 65];
+        emu.memory.read_into(buf, &mut utsname).unwrap();
+        assert!(&utsname[..6] == b"Linux\0");
+        assert!(&utsname[4 * 65..4 * 65 + 8] == b"riscv64\0");
+
+        // The whole structure must be writable
+        let small = emu.memory.allocate(65).unwrap();
+        assert!(syscall(&mut emu, 160, &[small.0 as u64]) == -EFAULT as u64);
+    }
+
+    #[test]
+    fn test_hint_syscalls() {
+        let mut emu = Emulator::new(1024 * 1024);
+        let code = emu.memory.allocate(4096).unwrap();
+
+        // riscv_flush_icache() and membarrier() succeed without effect
+        assert!(syscall(&mut emu, 259,
+                        &[code.0 as u64, code.0 as u64 + 4096, 0]) == 0);
+        assert!(syscall(&mut emu, 283, &[0, 0, 0]) == 0);
+    }
+
+    #[test]
+    fn test_ignore_syscalls() {
+        let mut emu = Emulator::new(1024 * 1024);
+        let handler = IgnoreSyscalls::new(Arc::new(DefaultSyscallHandler))
+            .ignore(500).stub(501);
+        let mut syscall = |num| {
+            handler.dispatch(&mut emu, num).unwrap();
+            emu.reg(Register::A0)
+        };
+
+        assert!(syscall(500) == -ENOSYS as u64);
+        assert!(syscall(501) == 0);
+        assert!(syscall(501) == 0);
+
+        // Other syscalls are still handled
+        assert!(syscall(172) == GUEST_PID);
+        assert!(handler.counts() == vec![(500, 1), (501, 2)]);
+    }
+
+    #[test]
+    fn test_output_capture() {
+        let mut emu = Emulator::new(1024 * 1024);
+        let buf = emu.memory.allocate(16).unwrap();
+        emu.memory.write_from(buf, b"hello").unwrap();
+
+        // Output is dropped unless captured
+        assert!(syscall(&mut emu, 64, &[1, buf.0 as u64, 5]) == 5);
+        assert!(emu.take_output().is_empty());
+
+        let mut emu = emu.enable_output_capture();
+        assert!(syscall(&mut emu, 64, &[1, buf.0 as u64, 5]) == 5);
+        assert!(syscall(&mut emu, 64, &[2, buf.0 as u64, 2]) == 2);
+        assert!(emu.take_output() == b"hellohe");
+        assert!(emu.take_output().is_empty());
+    }
+
+    #[test]
+    fn test_write_readonly() {
+        let mut emu = Emulator::new(1024 * 1024);
+        let buf = emu.memory.allocate(32).unwrap();
+        emu.memory.write_from(buf, b"hello").unwrap();
+        emu.files.insert(3, EmuFile::FuzzInput { index: 0, cursor: 0 });
+
+        // struct iovec { buf, 5 }
+        let iov = VirtAddr(buf.0 + 16);
+        emu.memory.write(iov, buf.0 as u64).unwrap();
+        emu.memory.write(VirtAddr(iov.0 + 8), 5u64).unwrap();
+
+        // Stdin, fuzz files, and unknown FDs can't be written to
+        for &fd in &[0, 3, 9] {
+            assert!(syscall(&mut emu, 64, &[fd, buf.0 as u64, 5]) ==
+                    -EBADF as u64);
+            assert!(syscall(&mut emu, 66, &[fd, iov.0 as u64, 1]) ==
+                    -EBADF as u64);
+        }
+        assert!(syscall(&mut emu, 68, &[3, buf.0 as u64, 5, 0]) ==
+                -EBADF as u64);
+
+        // Stdout can
+        assert!(syscall(&mut emu, 66, &[1, iov.0 as u64, 1]) == 5);
+    }
+
+    #[test]
+    fn test_fault_injection() {
+        let mut emu = Emulator::new(1024 * 1024);
+        let buf = emu.memory.allocate(16).unwrap();
+        emu.fuzz_input.extend_from_slice(b"abcdef");
+        emu.files.insert(3, EmuFile::FuzzInput { index: 0, cursor: 0 });
+
+        let read = |emu: &mut Emulator, handler: &FaultInjection| {
+            emu.set_reg(Register::A0, 3);
+            emu.set_reg(Register::A1, buf.0 as u64);
+            emu.set_reg(Register::A2, 1);
+            handler.dispatch(emu, 63).unwrap();
+            emu.reg(Register::A0)
+        };
+
+        let never  = FaultInjection::new(Arc::new(DefaultSyscallHandler),
+                                         1, 0.);
+        let always = FaultInjection::new(Arc::new(DefaultSyscallHandler),
+                                         1, 1.);
+        assert!(read(&mut emu, &never) == 1);
+        assert!(read(&mut emu, &always) == -EIO as u64);
+
+        // Syscalls which cannot fail are left alone
+        emu.set_reg(Register::A0, 3);
+        always.dispatch(&mut emu, 172).unwrap();
+        assert!(emu.reg(Register::A0) == GUEST_PID);
+
+        // Failures only depend on the seed and where in the case they are
+        let half = FaultInjection::new(Arc::new(DefaultSyscallHandler),
+                                       1, 0.5);
+        let failed: Vec<bool> = (0..64).map(|_| {
+            read(&mut emu, &half) == -EIO as u64
+        }).collect();
+        assert!(failed.iter().all(|&x| x == failed[0]));
+    }
+
+    #[test]
+    fn test_host_file_seek() {
+        let mut emu = Emulator::new(1024 * 1024);
+        let buf = emu.memory.allocate(16).unwrap();
+        emu.files.insert(3, EmuFile::HostFile {
+            contents: std::sync::Arc::new(b"hello world".to_vec()),
+            cursor:   0,
+        });
+
+        // Read `len` bytes from `fd` into `buf`
+        let read = |emu: &mut Emulator, fd: u64, len: usize| {
+            let ret = syscall(emu, 63, &[fd, buf.0 as u64, len as u64]);
+            let mut bytes = vec![0u8; len];
+            emu.memory.read_into(buf, &mut bytes).unwrap();
+            (ret, bytes)
+        };
+
+        // SEEK_SET, SEEK_CUR, and SEEK_END
+        assert!(syscall(&mut emu, 62, &[3, 6, 0]) == 6);
+        assert!(read(&mut emu, 3, 5) == (5, b"world".to_vec()));
+        assert!(syscall(&mut emu, 62, &[3, -3i64 as u64, 1]) == 8);
+        assert!(read(&mut emu, 3, 3) == (3, b"rld".to_vec()));
+        assert!(syscall(&mut emu, 62, &[3, -11i64 as u64, 2]) == 0);
+
+        // Reads are bounded by the end of the file
+        assert!(read(&mut emu, 3, 16).0 == 11);
+        assert!(read(&mut emu, 3, 16).0 == 0);
+
+        // Stdin is empty, stdout can't be seeked or read
+        assert!(read(&mut emu, 0, 4).0 == 0);
+        assert!(syscall(&mut emu, 62, &[1, 0, 0]) == !0);
+        assert!(read(&mut emu, 1, 4).0 == !0);
+    }
+
+    #[test]
+    fn test_pread() {
+        let mut emu = Emulator::new(1024 * 1024);
+        let buf = emu.memory.allocate(16).unwrap();
+        emu.files.insert(3, EmuFile::HostFile {
+            contents: std::sync::Arc::new(b"hello world".to_vec()),
+            cursor:   0,
+        });
+
+        // Read `len` bytes at `offset` from `fd` into `buf`
+        let pread = |emu: &mut Emulator, fd: u64, len: usize, offset: u64| {
+            let ret = syscall(emu, 67,
+                              &[fd, buf.0 as u64, len as u64, offset]);
+            let mut bytes = vec![0u8; len];
+            emu.memory.read_into(buf, &mut bytes).unwrap();
+            (ret, bytes)
+        };
+
+        // Positioned reads don't move the cursor
+        assert!(pread(&mut emu, 3, 5, 6) == (5, b"world".to_vec()));
+        assert!(syscall(&mut emu, 62, &[3, 0, 1]) == 0);
+        assert!(pread(&mut emu, 3, 16, 8).0 == 3);
+        assert!(pread(&mut emu, 3, 16, 100).0 == 0);
+        assert!(pread(&mut emu, 3, 4, -1i64 as u64).0 == -EINVAL as u64);
+
+        // Stdio can't seek, unknown fds are bad
+        assert!(pread(&mut emu, 1, 4, 0).0 == -ESPIPE as u64);
+        assert!(pread(&mut emu, 9, 4, 0).0 == -EBADF as u64);
+
+        // Nothing seekable is writable
+        assert!(syscall(&mut emu, 68, &[3, buf.0 as u64, 4, 0]) ==
+                -EBADF as u64);
+        assert!(syscall(&mut emu, 68, &[1, buf.0 as u64, 4, 0]) ==
+                -ESPIPE as u64);
+    }
+
+    #[test]
+    fn test_ppoll() {
+        let mut emu = Emulator::new(1024 * 1024);
+        let fds = emu.memory.allocate(32).unwrap();
+        emu.files.insert(3, EmuFile::FuzzInput { index: 0, cursor: 0 });
+
+        // `(fd, events)` of each `struct pollfd` and the expected `revents`
+        let polls: [(i32, u16, u16); 4] = [
+            (3,  POLLIN | POLLOUT, POLLIN),
+            (1,  POLLIN | POLLOUT, POLLOUT),
+            (-1, POLLIN,           0),
+            (9,  POLLIN,           POLLNVAL),
+        ];
+        for (ii, &(fd, events, _)) in polls.iter().enumerate() {
+            emu.memory.write(VirtAddr(fds.0 + ii * 8), fd).unwrap();
+            emu.memory.write(VirtAddr(fds.0 + ii * 8 + 4), events).unwrap();
+            emu.memory.write(VirtAddr(fds.0 + ii * 8 + 6), !0u16).unwrap();
+        }
+
+        // Returns right away even with an infinite timeout
+        assert!(syscall(&mut emu, 73, &[fds.0 as u64, 4, 0]) == 3);
+        for (ii, &(_, _, revents)) in polls.iter().enumerate() {
+            assert!(emu.memory.read::<u16>(VirtAddr(fds.0 + ii * 8 + 6))
+                    .unwrap() == revents);
+        }
+
+        assert!(syscall(&mut emu, 73, &[fds.0 as u64, 1 << 20, 0]) ==
+                -EINVAL as u64);
+    }
+
+    #[test]
+    fn test_mprotect() {
+        let mut emu = Emulator::new(1024 * 1024);
+        let alc  = emu.memory.allocate(3 * PAGE_SIZE).unwrap();
+        let page = VirtAddr((alc.0 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1));
+        let prot = |emu: &mut Emulator, addr: VirtAddr, len: usize, prot| {
+            syscall(emu, 226, &[addr.0 as u64, len as u64, prot])
+        };
+
+        // Read-only memory faults on writes, the length rounds up to a page
+        emu.memory.write(page, 5u32).unwrap();
+        assert!(prot(&mut emu, page, 1, PROT_READ) == 0);
+        assert!(emu.memory.read::<u32>(page).unwrap() == 5);
+        assert!(emu.memory.write(VirtAddr(page.0 + PAGE_SIZE - 4), 6u32)
+                .is_err());
+
+        // PROT_NONE memory is still mapped, so it can be made writable again
+        assert!(prot(&mut emu, page, PAGE_SIZE, 0) == 0);
+        assert!(emu.memory.read::<u32>(page).is_err());
+        assert!(prot(&mut emu, page, PAGE_SIZE,
+                     PROT_READ | PROT_WRITE) == 0);
+        emu.memory.write(page, 6u32).unwrap();
+
+        // Unaligned, unmapped, and unknown protections are invalid
+        assert!(prot(&mut emu, VirtAddr(page.0 + 4), 4, PROT_READ) ==
+                -EINVAL as u64);
+        assert!(prot(&mut emu, VirtAddr(0x100000 - PAGE_SIZE), PAGE_SIZE,
+                     PROT_READ) == -EINVAL as u64);
+        assert!(prot(&mut emu, page, PAGE_SIZE, 0x8) == -EINVAL as u64);
+    }
+
+    #[test]
+    fn test_sleep() {
+        let mut emu = Emulator::new(1024 * 1024);
+        let req = emu.memory.allocate(16).unwrap();
+        let rem = emu.memory.allocate(16).unwrap();
+        emu.memory.write(req, 5u64).unwrap();
+        emu.memory.write(VirtAddr(req.0 + 8), 10u64).unwrap();
+        emu.memory.write_from(rem, &[0xffu8; 16]).unwrap();
+
+        // Sleeps return right away with no time remaining
+        assert!(syscall(&mut emu, 101, &[req.0 as u64, rem.0 as u64]) == 0);
+        assert!(emu.memory.read::<u64>(rem).unwrap() == 0);
+        assert!(emu.memory.read::<u64>(VirtAddr(rem.0 + 8)).unwrap() == 0);
+        assert!(syscall(&mut emu, 101, &[req.0 as u64, 0]) == 0);
+        assert!(syscall(&mut emu, 115, &[CLOCK_MONOTONIC, TIMER_ABSTIME,
+                                         req.0 as u64, 0]) == 0);
+
+        // Bad pointers, times, and clocks are reported rather than faulting
+        assert!(syscall(&mut emu, 101, &[0, rem.0 as u64]) ==
+                -EFAULT as u64);
+        assert!(syscall(&mut emu, 101, &[req.0 as u64, 8]) ==
+                -EFAULT as u64);
+        assert!(syscall(&mut emu, 115, &[7, 0, req.0 as u64, 0]) ==
+                -EINVAL as u64);
+        emu.memory.write(VirtAddr(req.0 + 8), 1_000_000_000u64).unwrap();
+        assert!(syscall(&mut emu, 101, &[req.0 as u64, 0]) ==
+                -EINVAL as u64);
+    }
+}
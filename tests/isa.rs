@@ -0,0 +1,629 @@
+//! Tests of the RV64i implementation, running small hand encoded programs to
+//! completion through the interpreter, and through the JIT if the toolchain
+//! is available, and checking the registers and memory they leave behind
+
+use std::sync::Arc;
+use fuzz_with_emus::Corpus;
+use fuzz_with_emus::emulator::{Emulator, Register, VmExit};
+use fuzz_with_emus::emulator::Register::*;
+use fuzz_with_emus::jitcache::{JitCache, JitConfig};
+use fuzz_with_emus::mmu::{VirtAddr, Perm, PERM_READ, PERM_WRITE, PERM_EXEC};
+
+/// Size of the guest memory of the test programs
+const MEMORY_SIZE: usize = 1024 * 1024;
+
+/// Size of the data buffer handed to the test programs in `s0`
+const DATA_SIZE: usize = 4096;
+
+/// Environment variable which makes tests fail, rather than skip the JIT, if
+/// the JIT toolchain is missing
+const REQUIRE_JIT: &str = "FWE_REQUIRE_JIT";
+
+/// Encode an R-type instruction
+fn r(opcode: u32, funct3: u32, funct7: u32,
+     rd: Register, rs1: Register, rs2: Register) -> u32 {
+    funct7 << 25 | (rs2 as u32) << 20 | (rs1 as u32) << 15 | funct3 << 12 |
+        (rd as u32) << 7 | opcode
+}
+
+/// Encode an I-type instruction
+fn i(opcode: u32, funct3: u32, rd: Register, rs1: Register, imm: i32) -> u32 {
+    assert!((-2048..2048).contains(&imm));
+    (imm as u32) << 20 | (rs1 as u32) << 15 | funct3 << 12 |
+        (rd as u32) << 7 | opcode
+}
+
+/// Encode an S-type instruction
+fn s(funct3: u32, rs2: Register, rs1: Register, imm: i32) -> u32 {
+    assert!((-2048..2048).contains(&imm));
+    let imm = imm as u32;
+    (imm >> 5 & 0x7f) << 25 | (rs2 as u32) << 20 | (rs1 as u32) << 15 |
+        funct3 << 12 | (imm & 0x1f) << 7 | 0b0100011
+}
+
+/// Encode a B-type instruction, branching `imm` bytes from itself
+fn b(funct3: u32, rs1: Register, rs2: Register, imm: i32) -> u32 {
+    assert!(imm % 2 == 0 && (-4096..4096).contains(&imm));
+    let imm = imm as u32;
+    (imm >> 12 & 1) << 31 | (imm >> 5 & 0x3f) << 25 | (rs2 as u32) << 20 |
+        (rs1 as u32) << 15 | funct3 << 12 | (imm >> 1 & 0xf) << 8 |
+        (imm >> 11 & 1) << 7 | 0b1100011
+}
+
+/// Encode a U-type instruction, with `imm` being the upper 20 bits
+fn u(opcode: u32, rd: Register, imm: u32) -> u32 {
+    assert!(imm < 1 << 20);
+    imm << 12 | (rd as u32) << 7 | opcode
+}
+
+/// Encode a JAL, jumping `imm` bytes from itself
+fn jal(rd: Register, imm: i32) -> u32 {
+    assert!(imm % 2 == 0 && (-(1 << 20)..1 << 20).contains(&imm));
+    let imm = imm as u32;
+    (imm >> 20 & 1) << 31 | (imm >> 1 & 0x3ff) << 21 | (imm >> 11 & 1) << 20 |
+        (imm >> 12 & 0xff) << 12 | (rd as u32) << 7 | 0b1101111
+}
+
+fn lui(rd: Register, imm: u32) -> u32 {
+    u(0b0110111, rd, imm)
+}
+fn auipc(rd: Register, imm: u32) -> u32 {
+    u(0b0010111, rd, imm)
+}
+fn jalr(rd: Register, rs1: Register, imm: i32) -> u32 {
+    i(0b1100111, 0b000, rd, rs1, imm)
+}
+
+fn beq(rs1: Register, rs2: Register, imm: i32) -> u32 {
+    b(0b000, rs1, rs2, imm)
+}
+fn bne(rs1: Register, rs2: Register, imm: i32) -> u32 {
+    b(0b001, rs1, rs2, imm)
+}
+fn blt(rs1: Register, rs2: Register, imm: i32) -> u32 {
+    b(0b100, rs1, rs2, imm)
+}
+fn bge(rs1: Register, rs2: Register, imm: i32) -> u32 {
+    b(0b101, rs1, rs2, imm)
+}
+fn bltu(rs1: Register, rs2: Register, imm: i32) -> u32 {
+    b(0b110, rs1, rs2, imm)
+}
+fn bgeu(rs1: Register, rs2: Register, imm: i32) -> u32 {
+    b(0b111, rs1, rs2, imm)
+}
+
+fn lb(rd: Register, rs1: Register, imm: i32) -> u32 {
+    i(3, 0b000, rd, rs1, imm)
+}
+fn lh(rd: Register, rs1: Register, imm: i32) -> u32 {
+    i(3, 0b001, rd, rs1, imm)
+}
+fn lw(rd: Register, rs1: Register, imm: i32) -> u32 {
+    i(3, 0b010, rd, rs1, imm)
+}
+fn ld(rd: Register, rs1: Register, imm: i32) -> u32 {
+    i(3, 0b011, rd, rs1, imm)
+}
+fn lbu(rd: Register, rs1: Register, imm: i32) -> u32 {
+    i(3, 0b100, rd, rs1, imm)
+}
+fn lhu(rd: Register, rs1: Register, imm: i32) -> u32 {
+    i(3, 0b101, rd, rs1, imm)
+}
+fn lwu(rd: Register, rs1: Register, imm: i32) -> u32 {
+    i(3, 0b110, rd, rs1, imm)
+}
+
+fn sb(rs2: Register, rs1: Register, imm: i32) -> u32 {
+    s(0b000, rs2, rs1, imm)
+}
+fn sh(rs2: Register, rs1: Register, imm: i32) -> u32 {
+    s(0b001, rs2, rs1, imm)
+}
+fn sw(rs2: Register, rs1: Register, imm: i32) -> u32 {
+    s(0b010, rs2, rs1, imm)
+}
+fn sd(rs2: Register, rs1: Register, imm: i32) -> u32 {
+    s(0b011, rs2, rs1, imm)
+}
+
+fn addi(rd: Register, rs1: Register, imm: i32) -> u32 {
+    i(0b0010011, 0b000, rd, rs1, imm)
+}
+fn slti(rd: Register, rs1: Register, imm: i32) -> u32 {
+    i(0b0010011, 0b010, rd, rs1, imm)
+}
+fn sltiu(rd: Register, rs1: Register, imm: i32) -> u32 {
+    i(0b0010011, 0b011, rd, rs1, imm)
+}
+fn xori(rd: Register, rs1: Register, imm: i32) -> u32 {
+    i(0b0010011, 0b100, rd, rs1, imm)
+}
+fn ori(rd: Register, rs1: Register, imm: i32) -> u32 {
+    i(0b0010011, 0b110, rd, rs1, imm)
+}
+fn andi(rd: Register, rs1: Register, imm: i32) -> u32 {
+    i(0b0010011, 0b111, rd, rs1, imm)
+}
+fn slli(rd: Register, rs1: Register, shamt: i32) -> u32 {
+    i(0b0010011, 0b001, rd, rs1, shamt)
+}
+fn srli(rd: Register, rs1: Register, shamt: i32) -> u32 {
+    i(0b0010011, 0b101, rd, rs1, shamt)
+}
+fn srai(rd: Register, rs1: Register, shamt: i32) -> u32 {
+    i(0b0010011, 0b101, rd, rs1, shamt) | 0b0100000 << 25
+}
+fn addiw(rd: Register, rs1: Register, imm: i32) -> u32 {
+    i(0b0011011, 0b000, rd, rs1, imm)
+}
+fn slliw(rd: Register, rs1: Register, shamt: i32) -> u32 {
+    i(0b0011011, 0b001, rd, rs1, shamt)
+}
+fn srliw(rd: Register, rs1: Register, shamt: i32) -> u32 {
+    i(0b0011011, 0b101, rd, rs1, shamt)
+}
+fn sraiw(rd: Register, rs1: Register, shamt: i32) -> u32 {
+    i(0b0011011, 0b101, rd, rs1, shamt) | 0b0100000 << 25
+}
+
+fn add(rd: Register, rs1: Register, rs2: Register) -> u32 {
+    r(0b0110011, 0b000, 0b0000000, rd, rs1, rs2)
+}
+fn sub(rd: Register, rs1: Register, rs2: Register) -> u32 {
+    r(0b0110011, 0b000, 0b0100000, rd, rs1, rs2)
+}
+fn sll(rd: Register, rs1: Register, rs2: Register) -> u32 {
+    r(0b0110011, 0b001, 0b0000000, rd, rs1, rs2)
+}
+fn slt(rd: Register, rs1: Register, rs2: Register) -> u32 {
+    r(0b0110011, 0b010, 0b0000000, rd, rs1, rs2)
+}
+fn sltu(rd: Register, rs1: Register, rs2: Register) -> u32 {
+    r(0b0110011, 0b011, 0b0000000, rd, rs1, rs2)
+}
+fn sra(rd: Register, rs1: Register, rs2: Register) -> u32 {
+    r(0b0110011, 0b101, 0b0100000, rd, rs1, rs2)
+}
+fn and(rd: Register, rs1: Register, rs2: Register) -> u32 {
+    r(0b0110011, 0b111, 0b0000000, rd, rs1, rs2)
+}
+fn or(rd: Register, rs1: Register, rs2: Register) -> u32 {
+    r(0b0110011, 0b110, 0b0000000, rd, rs1, rs2)
+}
+fn addw(rd: Register, rs1: Register, rs2: Register) -> u32 {
+    r(0b0111011, 0b000, 0b0000000, rd, rs1, rs2)
+}
+fn subw(rd: Register, rs1: Register, rs2: Register) -> u32 {
+    r(0b0111011, 0b000, 0b0100000, rd, rs1, rs2)
+}
+fn sllw(rd: Register, rs1: Register, rs2: Register) -> u32 {
+    r(0b0111011, 0b001, 0b0000000, rd, rs1, rs2)
+}
+fn srlw(rd: Register, rs1: Register, rs2: Register) -> u32 {
+    r(0b0111011, 0b101, 0b0000000, rd, rs1, rs2)
+}
+fn sraw(rd: Register, rs1: Register, rs2: Register) -> u32 {
+    r(0b0111011, 0b101, 0b0100000, rd, rs1, rs2)
+}
+
+/// `li a7, 93; ecall; j .`, exiting with the status in `a0`. Test programs
+/// thus can not leave results in `a7`. The JIT lifts past the `ecall`, so it
+/// is followed by a jump to keep it from lifting the zeroed memory after it.
+const EXIT: [u32; 3] = [0x05d00893, 0x00000073, 0x0000006f];
+
+/// The state a test program left behind
+struct Finished {
+    /// How the program exited
+    vmexit: VmExit,
+
+    /// The registers at the exit
+    regs: [u64; 33],
+
+    /// The contents of the data buffer at the exit
+    data: Vec<u8>,
+}
+
+impl Finished {
+    /// Get the value of `reg`
+    fn reg(&self, reg: Register) -> u64 {
+        self.regs[reg as usize]
+    }
+
+    /// Get the `u64` at `offset` into the data buffer
+    fn data_u64(&self, offset: usize) -> u64 {
+        let mut tmp = [0u8; 8];
+        tmp.copy_from_slice(&self.data[offset..offset + 8]);
+        u64::from_le_bytes(tmp)
+    }
+}
+
+/// Load `prog` followed by an exit, with `s0` pointing to a zeroed data
+/// buffer of `DATA_SIZE` bytes starting with `data`, and run it to the exit
+/// in the interpreter. If the JIT toolchain is available, the program is run
+/// through the JIT as well and it has to leave the same state behind. A
+/// missing toolchain fails the test if `FWE_REQUIRE_JIT` is set.
+fn run(prog: &[u32], data: &[u8]) -> Finished {
+    let mut emu = Emulator::new(MEMORY_SIZE);
+
+    let code = emu.memory.allocate(4096).unwrap();
+    for (ii, inst) in prog.iter().chain(EXIT.iter()).enumerate() {
+        emu.memory.write(VirtAddr(code.0 + ii * 4), *inst).unwrap();
+    }
+    emu.memory.set_permissions(code, 4096,
+                               Perm(PERM_READ | PERM_EXEC)).unwrap();
+
+    let buf = emu.memory.allocate(DATA_SIZE).unwrap();
+    let mut init = vec![0u8; DATA_SIZE];
+    init[..data.len()].copy_from_slice(data);
+    emu.memory.set_permissions(buf, DATA_SIZE,
+                               Perm(PERM_READ | PERM_WRITE)).unwrap();
+    emu.memory.write_from(buf, &init).unwrap();
+
+    emu.set_reg(S0, buf.0 as u64);
+    emu.set_reg(Pc, code.0 as u64);
+
+    let finish = |emu: &Emulator, vmexit| {
+        let mut data = vec![0u8; DATA_SIZE];
+        emu.memory.read_into(buf, &mut data).unwrap();
+        Finished { vmexit, regs: emu.registers(), data }
+    };
+
+    let snapshot = Arc::new(emu);
+    let mut emu = snapshot.fork();
+    emu.set_snapshot(snapshot.clone());
+    let vmexit = emu.run_input(b"", &Corpus::new());
+    let interp = finish(&emu, vmexit);
+
+    let jit_available = JitConfig::default().toolchain_available();
+    assert!(jit_available || std::env::var_os(REQUIRE_JIT).is_none(),
+            "{} is set, but the JIT toolchain is missing", REQUIRE_JIT);
+
+    if jit_available {
+        let jit_cache = Arc::new(
+            JitCache::new(VirtAddr(MEMORY_SIZE), JitConfig::default()));
+        let jitted = Arc::new(snapshot.fork().enable_jit(jit_cache.clone()));
+        let mut emu = jitted.fork();
+        emu.set_snapshot(jitted);
+
+        // Keep running until everything the program runs is compiled
+        loop {
+            emu.run_input(b"", &Corpus::new());
+            if !jit_cache.compiling() {
+                break;
+            }
+
+            while jit_cache.compiling() {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+        }
+
+        let vmexit = emu.run_input(b"", &Corpus::new());
+        let jit = finish(&emu, vmexit);
+        assert!(jit.vmexit == interp.vmexit, "JIT exit {:?} interpreter {:?}",
+                jit.vmexit, interp.vmexit);
+        for reg in Register::all() {
+            assert!(jit.reg(reg) == interp.reg(reg),
+                    "JIT {:?} {:#x} interpreter {:#x}", reg, jit.reg(reg),
+                    interp.reg(reg));
+        }
+        assert!(jit.data == interp.data, "JIT and interpreter memory differ");
+    }
+
+    interp
+}
+
+#[test]
+fn test_arithmetic() {
+    let fin = run(&[
+        addi(T0, Zero, 100),
+        addi(T1, Zero, -58),
+        add(A0, T0, T1),
+        sub(A1, T1, T0),
+        xori(A2, T0, -1),
+        and(A3, T0, T1),
+        or(A4, T0, T1),
+        lui(A5, 0x12345),
+        addi(A5, A5, 0x678),
+        addi(Zero, T0, 1),
+        add(Zero, T0, T0),
+        addi(A6, Zero, 0),
+    ], &[]);
+    assert!(fin.vmexit == VmExit::Exit(42));
+    assert!(fin.reg(A1) == -158i64 as u64);
+    assert!(fin.reg(A2) == !100u64);
+    assert!(fin.reg(A3) == 100 & -58i64 as u64);
+    assert!(fin.reg(A4) == 100 | -58i64 as u64);
+    assert!(fin.reg(A5) == 0x12345678);
+    assert!(fin.reg(Zero) == 0);
+}
+
+#[test]
+fn test_upper_immediates() {
+    let fin = run(&[
+        // LUI sign extends the 32-bit result
+        lui(A1, 0x80000),
+        lui(A2, 0xfffff),
+        auipc(A3, 0),
+        auipc(A4, 1),
+        addi(A0, Zero, 0),
+    ], &[]);
+    assert!(fin.reg(A1) == 0xffffffff80000000);
+    assert!(fin.reg(A2) == 0xfffffffffffff000);
+    assert!(fin.reg(A4) == fin.reg(A3) + 4 + 0x1000);
+}
+
+#[test]
+fn test_comparisons() {
+    let fin = run(&[
+        addi(T0, Zero, -1),
+        addi(T1, Zero, 1),
+        slt(A1, T0, T1),
+        sltu(A2, T0, T1),
+        slti(A3, T0, 0),
+        sltiu(A4, T1, -1),
+        sltiu(A5, Zero, 1),
+        addi(A0, Zero, 0),
+    ], &[]);
+    assert!(fin.reg(A1) == 1);
+    assert!(fin.reg(A2) == 0);
+    assert!(fin.reg(A3) == 1);
+    assert!(fin.reg(A4) == 1);
+    assert!(fin.reg(A5) == 1);
+}
+
+#[test]
+fn test_negative_immediates() {
+    // Immediates are sign extended to 64 bits, also for unsigned compares
+    let fin = run(&[
+        addi(T0, Zero, 5),
+        addi(T1, Zero, -1),
+        // seqz
+        sltiu(A1, Zero, 1),
+        sltiu(A2, T0, 1),
+        // Everything but all ones is below -1 when compared unsigned
+        sltiu(A3, T0, -1),
+        sltiu(A4, T1, -1),
+        sltiu(A5, T1, -2048),
+        slti(A6, T0, -1),
+        slti(S1, T1, 0),
+        slti(S2, T1, -2048),
+        andi(S3, T1, -16),
+        andi(S4, T0, -2048),
+        ori(S5, T0, -2048),
+        ori(S6, Zero, -1),
+        xori(S7, T0, -1),
+        xori(S8, T1, -2048),
+        addi(A0, Zero, 0),
+    ], &[]);
+    assert!(fin.reg(A1) == 1);
+    assert!(fin.reg(A2) == 0);
+    assert!(fin.reg(A3) == 1);
+    assert!(fin.reg(A4) == 0);
+    assert!(fin.reg(A5) == 0);
+    assert!(fin.reg(A6) == 0);
+    assert!(fin.reg(S1) == 1);
+    assert!(fin.reg(S2) == 0);
+    assert!(fin.reg(S3) == 0xfffffffffffffff0);
+    assert!(fin.reg(S4) == 0);
+    assert!(fin.reg(S5) == 0xfffffffffffff805);
+    assert!(fin.reg(S6) == !0);
+    assert!(fin.reg(S7) == !5);
+    assert!(fin.reg(S8) == 0x7ff);
+}
+
+#[test]
+fn test_shifts() {
+    let fin = run(&[
+        addi(T0, Zero, -16),
+        srli(A1, T0, 60),
+        srai(A2, T0, 2),
+        slli(A3, T0, 63),
+        // Only the low 6 bits of the shift amount are used
+        addi(T1, Zero, 65),
+        sll(A4, T0, T1),
+        sra(A5, T0, T1),
+        addi(A0, Zero, 0),
+    ], &[]);
+    assert!(fin.reg(A1) == 0xf);
+    assert!(fin.reg(A2) == -4i64 as u64);
+    assert!(fin.reg(A3) == 0);
+    assert!(fin.reg(A4) == -32i64 as u64);
+    assert!(fin.reg(A5) == -8i64 as u64);
+}
+
+#[test]
+fn test_word_ops() {
+    let fin = run(&[
+        // 0x7fffffff + 1 wraps to a negative 32-bit value, sign extended
+        lui(T0, 0x80000),
+        addiw(T0, T0, -1),
+        addiw(A1, T0, 1),
+        addi(T1, Zero, 1),
+        addw(A2, T0, T1),
+        subw(A3, Zero, T0),
+        // The upper 32 bits of the sources are ignored
+        addi(T2, Zero, -1),
+        slli(T2, T2, 32),
+        addw(A4, T2, T1),
+        // Logical right shifts zero extend the low 32 bits first
+        addi(T3, Zero, -1),
+        srliw(A5, T3, 4),
+        sraiw(A6, T3, 4),
+        slliw(S4, T1, 31),
+        // Only the low 5 bits of the shift amount are used
+        addi(T4, Zero, 33),
+        sllw(S1, T1, T4),
+        srlw(S2, T3, T4),
+        sraw(S3, T3, T4),
+        addi(A0, Zero, 0),
+    ], &[]);
+    assert!(fin.reg(T0) == 0x7fffffff);
+    assert!(fin.reg(A1) == 0xffffffff80000000);
+    assert!(fin.reg(A2) == 0xffffffff80000000);
+    assert!(fin.reg(A3) == 0xffffffff80000001);
+    assert!(fin.reg(A4) == 1);
+    assert!(fin.reg(A5) == 0x0fffffff);
+    assert!(fin.reg(A6) == -1i64 as u64);
+    assert!(fin.reg(S4) == 0xffffffff80000000);
+    assert!(fin.reg(S1) == 2);
+    assert!(fin.reg(S2) == 0x7fffffff);
+    assert!(fin.reg(S3) == -1i64 as u64);
+}
+
+#[test]
+fn test_branches() {
+    // Every taken branch skips over a poisoning `addi a0, a0, 100`
+    let fin = run(&[
+        addi(T0, Zero, -1),
+        addi(T1, Zero, 1),
+        addi(A0, Zero, 0),
+        beq(T0, T0, 8),
+        addi(A0, A0, 100),
+        bne(T0, T1, 8),
+        addi(A0, A0, 100),
+        blt(T0, T1, 8),
+        addi(A0, A0, 100),
+        bge(T1, T0, 8),
+        addi(A0, A0, 100),
+        bltu(T1, T0, 8),
+        addi(A0, A0, 100),
+        bgeu(T0, T1, 8),
+        addi(A0, A0, 100),
+        // Not taken, each adds 1
+        beq(T0, T1, 8),
+        addi(A0, A0, 1),
+        blt(T1, T0, 8),
+        addi(A0, A0, 1),
+        bltu(T0, T1, 8),
+        addi(A0, A0, 1),
+        bge(T0, T1, 8),
+        addi(A0, A0, 1),
+        // A backwards branch looping 5 times
+        addi(T2, Zero, 5),
+        addi(A0, A0, 10),
+        addi(T2, T2, -1),
+        bne(T2, Zero, -8),
+    ], &[]);
+    assert!(fin.vmexit == VmExit::Exit(54));
+}
+
+#[test]
+fn test_jumps() {
+    let fin = run(&[
+        jal(Ra, 12),
+        addi(A0, A0, 1),
+        jal(Zero, 24),
+        // Called function, returns through ra
+        addi(A0, Zero, 40),
+        jalr(Zero, Ra, 0),
+        addi(A0, Zero, 100),
+        addi(A0, Zero, 100),
+        addi(A0, Zero, 100),
+        // jalr clears the lowest bit of the target
+        auipc(T0, 0),
+        jalr(T1, T0, 13),
+        addi(A0, Zero, 100),
+        addi(A0, A0, 1),
+    ], &[]);
+    assert!(fin.vmexit == VmExit::Exit(42));
+    assert!(fin.reg(T1) == fin.reg(T0) + 8);
+}
+
+#[test]
+fn test_loads_stores() {
+    let data = [0x80, 0xff, 0x01, 0x80, 0xfe, 0xff, 0xff, 0xff];
+    let fin = run(&[
+        lb(A1, S0, 0),
+        lbu(A2, S0, 0),
+        lh(A3, S0, 0),
+        lhu(A4, S0, 0),
+        lw(A5, S0, 4),
+        lwu(A6, S0, 4),
+        ld(S3, S0, 0),
+        // Stores only write their width
+        addi(T0, Zero, -1),
+        sd(T0, S0, 8),
+        sb(Zero, S0, 8),
+        sh(Zero, S0, 10),
+        addi(T1, Zero, 0x123),
+        sw(T1, S0, 12),
+        ld(S1, S0, 8),
+        // Negative offsets
+        addi(T2, S0, 24),
+        sd(T1, T2, -8),
+        ld(S2, S0, 16),
+        addi(A0, Zero, 0),
+    ], &data);
+    assert!(fin.reg(A1) == -128i64 as u64);
+    assert!(fin.reg(A2) == 0x80);
+    assert!(fin.reg(A3) == 0xffffffffffffff80);
+    assert!(fin.reg(A4) == 0xff80);
+    assert!(fin.reg(A5) == -2i64 as u64);
+    assert!(fin.reg(A6) == 0xfffffffe);
+    assert!(fin.reg(S3) == 0xfffffffe8001ff80);
+    assert!(fin.reg(S1) == 0x000001230000ff00);
+    assert!(fin.data_u64(8) == fin.reg(S1));
+    assert!(fin.reg(S2) == 0x123);
+    assert!(fin.data_u64(16) == 0x123);
+}
+
+#[test]
+fn test_load_extension() {
+    // Words and halves right at the sign bit, and right below it
+    let data = [
+        0x00, 0x00, 0x00, 0x80, 0xff, 0xff, 0xff, 0x7f,
+        0x00, 0x80, 0xff, 0x7f, 0x80, 0x7f, 0x00, 0x00,
+    ];
+    let fin = run(&[
+        lw(A1, S0, 0),
+        lwu(A2, S0, 0),
+        lw(A3, S0, 4),
+        lwu(A4, S0, 4),
+        lh(A5, S0, 8),
+        lhu(A6, S0, 8),
+        lh(S1, S0, 10),
+        lhu(S2, S0, 10),
+        lb(S3, S0, 12),
+        lbu(S4, S0, 12),
+        // A word stored from a register is extended the same way when it is
+        // loaded back
+        lui(T0, 0xfffff),
+        sw(T0, S0, 16),
+        lw(S5, S0, 16),
+        lwu(S6, S0, 16),
+        // Offsets computed from a sign extended word stay in bounds
+        addi(T1, Zero, -8),
+        sw(T1, S0, 20),
+        lw(T2, S0, 20),
+        add(T2, S0, T2),
+        ld(S7, T2, 8),
+        addi(A0, Zero, 0),
+    ], &data);
+    assert!(fin.vmexit == VmExit::Exit(0));
+    assert!(fin.reg(A1) == 0xffffffff80000000);
+    assert!(fin.reg(A2) == 0x0000000080000000);
+    assert!(fin.reg(A3) == 0x000000007fffffff);
+    assert!(fin.reg(A4) == 0x000000007fffffff);
+    assert!(fin.reg(A5) == 0xffffffffffff8000);
+    assert!(fin.reg(A6) == 0x8000);
+    assert!(fin.reg(S1) == 0x7fff);
+    assert!(fin.reg(S2) == 0x7fff);
+    assert!(fin.reg(S3) == 0xffffffffffffff80);
+    assert!(fin.reg(S4) == 0x80);
+    assert!(fin.reg(S5) == 0xfffffffffffff000);
+    assert!(fin.reg(S6) == 0x00000000fffff000);
+    assert!(fin.reg(S7) == 0x7fffffff80000000);
+}
+
+#[test]
+fn test_faults() {
+    // Loads from unmapped memory fault at the address
+    let fin = run(&[addi(T0, Zero, 8), ld(A0, T0, 0)], &[]);
+    assert!(fin.vmexit == VmExit::ReadFault(VirtAddr(8)));
+
+    // Storing past the end of the data buffer faults
+    let fin = run(&[lui(T0, 1), add(T0, S0, T0), sb(Zero, T0, 0)], &[]);
+    assert!(fin.vmexit.is_crash().is_some());
+}